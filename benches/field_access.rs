@@ -0,0 +1,75 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use loxi::loxi;
+use std::collections::HashMap;
+use std::io;
+use std::rc::Rc;
+
+// Run 'field_access.lox' on each backend, discarding its output.
+fn field_access(c: &mut Criterion) {
+    let source = include_str!("field_access.lox");
+
+    for (name, backend) in [
+        ("tree", loxi::Backend::TreeWalker),
+        ("vm", loxi::Backend::Vm),
+    ] {
+        let options = loxi::Options {
+            backend,
+            ..loxi::Options::default()
+        };
+        c.bench_function(&format!("field_access/{name}"), |b| {
+            b.iter(|| loxi::run_with_options(source, options, Box::new(io::sink())).unwrap())
+        });
+    }
+}
+
+// The globals and fields of 'field_access.lox', and the names one iteration
+// of its loop looks up among them.
+const NAMES: [&str; 10] = [
+    "clock", "Vector", "position", "velocity", "total", "x", "y", "add", "init", "other",
+];
+const LOOKUPS: [&str; 16] = [
+    "position", "add", "velocity", "x", "x", "x", "y", "y", "y", "total", "position", "x",
+    "velocity", "y", "position", "y",
+];
+
+// Look the same names up in a table keyed by symbols, as the interpreters
+// do, and in one keyed by strings, as they did before names were interned.
+// The difference is the time interning saves on each variable, field and
+// method access.
+fn lookup(c: &mut Criterion) {
+    let by_symbol: HashMap<loxi::Symbol, usize> = NAMES
+        .iter()
+        .enumerate()
+        .map(|(index, name)| (loxi::Symbol::intern(name), index))
+        .collect();
+    let symbols: Vec<loxi::Symbol> = LOOKUPS
+        .iter()
+        .map(|name| loxi::Symbol::intern(name))
+        .collect();
+    c.bench_function("lookup/symbol", |b| {
+        b.iter(|| {
+            black_box(&symbols)
+                .iter()
+                .map(|symbol| by_symbol[symbol])
+                .sum::<usize>()
+        })
+    });
+
+    let by_string: HashMap<Rc<str>, usize> = NAMES
+        .iter()
+        .enumerate()
+        .map(|(index, &name)| (Rc::from(name), index))
+        .collect();
+    let strings: Vec<Rc<str>> = LOOKUPS.iter().map(|&name| Rc::from(name)).collect();
+    c.bench_function("lookup/string", |b| {
+        b.iter(|| {
+            black_box(&strings)
+                .iter()
+                .map(|string| by_string[string])
+                .sum::<usize>()
+        })
+    });
+}
+
+criterion_group!(benches, field_access, lookup);
+criterion_main!(benches);
@@ -0,0 +1,56 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use loxi::loxi;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// The system allocator, keeping track of the most memory in use at once.
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        PEAK.fetch_max(allocated, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+// Run 'values.lox' on the VM. Comparing a run with '--features nan-boxing'
+// against one without shows the difference between the two representations
+// of values, in time and in the peak memory used by a single run.
+fn values(c: &mut Criterion) {
+    let source = include_str!("values.lox");
+    let options = loxi::Options {
+        backend: loxi::Backend::Vm,
+        ..loxi::Options::default()
+    };
+    let name = if cfg!(feature = "nan-boxing") {
+        "values/nan-boxed"
+    } else {
+        "values/enum"
+    };
+
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+    loxi::run_with_options(source, options, Box::new(io::sink())).unwrap();
+    let peak = PEAK.load(Ordering::Relaxed) - before;
+    println!("{name}: peak memory {} KiB", peak / 1024);
+
+    c.bench_function(name, |b| {
+        b.iter(|| loxi::run_with_options(source, options, Box::new(io::sink())).unwrap())
+    });
+}
+
+criterion_group!(benches, values);
+criterion_main!(benches);
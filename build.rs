@@ -0,0 +1,63 @@
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+
+// With the 'aot' feature, build the runtime in 'runtime' that 'loxi build'
+// links executables against, and tell 'aot' where it is and which system
+// libraries it needs.
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    if env::var_os("CARGO_FEATURE_AOT").is_none() {
+        return;
+    }
+    for path in ["Cargo.toml", "src", "runtime/Cargo.toml", "runtime/src"] {
+        println!("cargo:rerun-if-changed={path}");
+    }
+
+    let manifest_dir = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap());
+    let target_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap()).join("runtime");
+    let target = env::var("TARGET").unwrap();
+
+    let mut cargo = Command::new(env::var_os("CARGO").unwrap());
+    cargo
+        .arg("rustc")
+        .arg("--release")
+        .arg("--manifest-path")
+        .arg(manifest_dir.join("runtime/Cargo.toml"))
+        .arg("--target")
+        .arg(&target)
+        .arg("--target-dir")
+        .arg(&target_dir)
+        // Every crate the runtime uses is a dependency of this one, so it
+        // has been downloaded already.
+        .arg("--offline")
+        .arg("--")
+        .arg("--print")
+        .arg("native-static-libs");
+    // The features of this build would otherwise leak into the build script
+    // of the 'loxi' the runtime uses, which would then build the runtime
+    // again.
+    for (name, _) in env::vars_os() {
+        if name.to_string_lossy().starts_with("CARGO_FEATURE_") {
+            cargo.env_remove(name);
+        }
+    }
+
+    let output = cargo.output().expect("could not run cargo");
+    let messages = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        output.status.success(),
+        "building the runtime failed:\n{messages}"
+    );
+    // Cargo repeats the note rustc printed even when the runtime was already
+    // built.
+    let libraries = messages
+        .lines()
+        .find_map(|line| line.split_once("native-static-libs: "))
+        .map(|(_, libraries)| libraries.trim())
+        .expect("rustc did not print the runtime's native libraries");
+
+    let library = target_dir.join(target).join("release/libloxi_runtime.a");
+    println!("cargo:rustc-env=LOXI_RUNTIME_PATH={}", library.display());
+    println!("cargo:rustc-env=LOXI_SYSTEM_LIBRARIES={libraries}");
+}
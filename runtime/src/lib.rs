@@ -0,0 +1,39 @@
+use loxi::loxi;
+use std::io::{self, Write};
+use std::slice;
+
+// The entry point of built executables: run the serialized script 'program'
+// with the native code in 'functions', allowing calls to nest 'max_depth'
+// deep, and return the exit status. Runtime errors are traced to 'file', the
+// name of the script the executable was built from.
+//
+// # Safety
+//
+// 'program' must point to 'length' bytes, 'functions' to 'count' pointers,
+// each the code 'loxi build' compiled for the function at that index, and
+// 'file' to 'file_length' bytes of UTF-8.
+#[no_mangle]
+unsafe extern "C" fn loxi_runtime_main(
+    program: *const u8,
+    length: u64,
+    functions: *const *const u8,
+    count: u64,
+    file: *const u8,
+    file_length: u64,
+    max_depth: u64,
+) -> i32 {
+    let program = slice::from_raw_parts(program, length as usize);
+    let code = slice::from_raw_parts(functions, count as usize);
+    let file = String::from_utf8_lossy(slice::from_raw_parts(file, file_length as usize));
+
+    let result = loxi::run_built(program, code, &file, max_depth as usize);
+    io::stdout().flush().ok();
+
+    match result {
+        Ok(()) => 0,
+        Err(error) => {
+            eprintln!("{}", error);
+            1
+        }
+    }
+}
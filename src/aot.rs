@@ -0,0 +1,365 @@
+use crate::bytecode;
+use crate::chunk::{Chunk, Constant, FunctionProto, OpCode};
+use crate::native;
+use cranelift_codegen::ir::{types, AbiParam, InstBuilder, JumpTableData, MemFlags, TrapCode};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_module::{default_libcall_names, DataDescription, FuncId, Linkage, Module};
+use cranelift_object::{ObjectBuilder, ObjectModule};
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+// The system libraries the runtime needs, as 'rustc --print
+// native-static-libs' reported them when the build script built it.
+const SYSTEM_LIBRARIES: &str = env!("LOXI_SYSTEM_LIBRARIES");
+
+// Build a standalone executable running 'script', compiled from 'file', at
+// 'output', allowed to nest calls 'max_depth' deep.
+//
+// The executable contains the script's bytecode, native code for each of
+// its functions, and a 'main' passing both to 'loxi_runtime_main' together
+// with the file name, which runtime errors are traced to, and the maximum
+// depth. The native code makes the jumps between a function's instructions
+// itself and calls into the runtime to execute the others, see
+// 'native::NativeCode', so the script behaves exactly as it would with 'loxi
+// run'. The executable is linked against 'runtime', see 'runtime_library'.
+pub fn build(
+    script: &Rc<FunctionProto>,
+    file: &str,
+    max_depth: usize,
+    runtime: &Path,
+    output: &Path,
+) -> Result<()> {
+    let object = emit_object(script, file, max_depth)?;
+    let directory = temporary_directory()?;
+    let linked = link(&directory.join("loxi.o"), &object, runtime, output);
+    fs::remove_dir_all(&directory)?;
+    linked
+}
+
+// Write 'object' to 'path' and link it with 'runtime' into the executable
+// 'output'.
+fn link(path: &Path, object: &[u8], runtime: &Path, output: &Path) -> Result<()> {
+    fs::write(path, object)?;
+    let linked = Command::new("cc")
+        .arg(path)
+        .arg(runtime)
+        .args(SYSTEM_LIBRARIES.split_whitespace())
+        .arg("-Wl,--gc-sections")
+        .arg("-o")
+        .arg(output)
+        .output()
+        .map_err(|e| format!("could not run the linker 'cc': {e}"))?;
+    if !linked.status.success() {
+        return Err(format!(
+            "linking failed:\n{}",
+            String::from_utf8_lossy(&linked.stderr)
+        )
+        .into());
+    }
+    Ok(())
+}
+
+// A new, empty directory for the object file, so that building never touches
+// a file of the user's. It is unique to this process and build, as several
+// builds may run at once.
+fn temporary_directory() -> Result<PathBuf> {
+    static BUILDS: AtomicUsize = AtomicUsize::new(0);
+    let build = BUILDS.fetch_add(1, Ordering::Relaxed);
+    let directory = env::temp_dir().join(format!("loxi-object-{}-{build}", process::id()));
+    fs::create_dir(&directory)
+        .map_err(|e| format!("could not create '{}': {e}", directory.display()))?;
+    Ok(directory)
+}
+
+// The runtime library, which is looked for at '$LOXI_RUNTIME' and then where
+// the build script built it from 'runtime'.
+pub fn runtime_library() -> Result<PathBuf> {
+    let path = env::var_os("LOXI_RUNTIME")
+        .map_or_else(|| PathBuf::from(env!("LOXI_RUNTIME_PATH")), PathBuf::from);
+    if !path.is_file() {
+        return Err(format!(
+            "could not find the runtime library '{}', set LOXI_RUNTIME to its path",
+            path.display()
+        )
+        .into());
+    }
+    Ok(path)
+}
+
+fn emit_object(script: &Rc<FunctionProto>, file: &str, max_depth: usize) -> Result<Vec<u8>> {
+    let mut flags = settings::builder();
+    flags.set("is_pic", "true")?;
+    flags.set("opt_level", "speed")?;
+    // Executables may run on older machines than the one building them, so
+    // their code only uses the instructions every CPU of the architecture has,
+    // unlike the JIT's.
+    let isa = cranelift_native::builder_with_options(false)?.finish(settings::Flags::new(flags))?;
+    let builder = ObjectBuilder::new(isa, "loxi", default_libcall_names())?;
+    let mut module = ObjectModule::new(builder);
+    let pointer = module.target_config().pointer_type();
+
+    let program = bytecode::serialize(script);
+    let program_id = module.declare_data("loxi_program", Linkage::Local, false, false)?;
+    let mut data = DataDescription::new();
+    data.define(program.clone().into_boxed_slice());
+    module.define_data(program_id, &data)?;
+
+    let file_id = module.declare_data("loxi_file", Linkage::Local, false, false)?;
+    let mut data = DataDescription::new();
+    data.define(Box::from(file.as_bytes()));
+    module.define_data(file_id, &data)?;
+
+    // A table holding the native code of each function.
+    let functions = bytecode::functions(script);
+    // The table is writable so that the linker can relocate it.
+    let table_id = module.declare_data("loxi_functions", Linkage::Local, true, false)?;
+    let mut table = DataDescription::new();
+    let entry_size = pointer.bytes() as usize;
+    table.define(vec![0; functions.len() * entry_size].into_boxed_slice());
+    table.set_align(entry_size as u64);
+    for (index, function) in functions.iter().enumerate() {
+        let id = define(&mut module, &format!("loxi_function_{index}"), function)?;
+        let reference = module.declare_func_in_data(id, &mut table);
+        table.write_function_addr((index * entry_size) as u32, reference);
+    }
+    module.define_data(table_id, &table)?;
+
+    let mut runtime_signature = module.make_signature();
+    runtime_signature.params.extend([
+        AbiParam::new(pointer),
+        AbiParam::new(types::I64),
+        AbiParam::new(pointer),
+        AbiParam::new(types::I64),
+        AbiParam::new(pointer),
+        AbiParam::new(types::I64),
+        AbiParam::new(types::I64),
+    ]);
+    runtime_signature.returns.push(AbiParam::new(types::I32));
+    let runtime =
+        module.declare_function("loxi_runtime_main", Linkage::Import, &runtime_signature)?;
+
+    let mut main_signature = module.make_signature();
+    main_signature
+        .params
+        .extend([AbiParam::new(types::I32), AbiParam::new(pointer)]);
+    main_signature.returns.push(AbiParam::new(types::I32));
+    let main = module.declare_function("main", Linkage::Export, &main_signature)?;
+
+    let mut context = module.make_context();
+    context.func.signature = main_signature;
+    let mut builder_context = FunctionBuilderContext::new();
+    let mut builder = FunctionBuilder::new(&mut context.func, &mut builder_context);
+    let block = builder.create_block();
+    builder.append_block_params_for_function_params(block);
+    builder.switch_to_block(block);
+    builder.seal_block(block);
+
+    let runtime = module.declare_func_in_func(runtime, builder.func);
+    let program_value = module.declare_data_in_func(program_id, builder.func);
+    let table_value = module.declare_data_in_func(table_id, builder.func);
+    let file_value = module.declare_data_in_func(file_id, builder.func);
+    let program_address = builder.ins().global_value(pointer, program_value);
+    let program_length = builder.ins().iconst(types::I64, program.len() as i64);
+    let table_address = builder.ins().global_value(pointer, table_value);
+    let function_count = builder.ins().iconst(types::I64, functions.len() as i64);
+    let file_address = builder.ins().global_value(pointer, file_value);
+    let file_length = builder.ins().iconst(types::I64, file.len() as i64);
+    let max_depth = builder.ins().iconst(types::I64, max_depth as i64);
+    let call = builder.ins().call(
+        runtime,
+        &[
+            program_address,
+            program_length,
+            table_address,
+            function_count,
+            file_address,
+            file_length,
+            max_depth,
+        ],
+    );
+    let status = builder.inst_results(call)[0];
+    builder.ins().return_(&[status]);
+    builder.finalize();
+
+    module.define_function(main, &mut context)?;
+    Ok(module.finish().emit()?)
+}
+
+// Compile 'proto' into 'module' as the function 'name', see
+// 'native::NativeCode'.
+fn define<M: Module>(module: &mut M, name: &str, proto: &FunctionProto) -> Result<FuncId> {
+    let pointer = module.target_config().pointer_type();
+    let mut signature = module.make_signature();
+    signature.params.extend([AbiParam::new(pointer); 3]);
+    signature.returns.push(AbiParam::new(types::I32));
+    let mut helper_signature = module.make_signature();
+    helper_signature.params.extend([AbiParam::new(pointer); 2]);
+    helper_signature.returns.push(AbiParam::new(types::I32));
+    let id = module.declare_function(name, Linkage::Local, &signature)?;
+
+    let mut context = module.make_context();
+    context.func.signature = signature;
+    let mut builder_context = FunctionBuilderContext::new();
+    let mut builder = FunctionBuilder::new(&mut context.func, &mut builder_context);
+    let helper_signature = builder.import_signature(helper_signature);
+
+    let chunk = &proto.chunk;
+    let instructions = instructions(chunk);
+    // The block of each instruction, by its offset. Offsets in the middle of
+    // an instruction and the end of the code lead to 'invalid', which is
+    // never reached.
+    let invalid = builder.create_block();
+    let mut blocks = vec![invalid; chunk.code.len() + 1];
+    for &(offset, _) in &instructions {
+        blocks[offset] = builder.create_block();
+    }
+
+    // The entry jumps to the instruction the code is asked to start at.
+    let entry = builder.create_block();
+    builder.append_block_params_for_function_params(entry);
+    builder.switch_to_block(entry);
+    let &[vm_context, helpers, start] = builder.block_params(entry) else {
+        unreachable!("the signature has three parameters");
+    };
+    let targets = blocks[..chunk.code.len()]
+        .iter()
+        .map(|&block| builder.func.dfg.block_call(block, &[]))
+        .collect::<Vec<_>>();
+    let default = builder.func.dfg.block_call(invalid, &[]);
+    let table = builder.create_jump_table(JumpTableData::new(default, &targets));
+    let start = builder.ins().ireduce(types::I32, start);
+    builder.ins().br_table(start, table);
+
+    // Returns what a helper returned when it wasn't 'native::NEXT'.
+    let leave = builder.create_block();
+    let status = builder.append_block_param(leave, types::I32);
+    builder.switch_to_block(leave);
+    builder.ins().return_(&[status]);
+
+    builder.switch_to_block(invalid);
+    builder.ins().trap(TrapCode::unwrap_user(1));
+
+    for (index, &(offset, op)) in instructions.iter().enumerate() {
+        builder.switch_to_block(blocks[offset]);
+        let next = instructions
+            .get(index + 1)
+            .map_or(invalid, |&(next, _)| blocks[next]);
+        let call_helper = |builder: &mut FunctionBuilder, helper: usize| {
+            let address = builder.ins().load(
+                pointer,
+                MemFlags::trusted().with_readonly(),
+                helpers,
+                (helper * pointer.bytes() as usize) as i32,
+            );
+            let offset = builder.ins().iconst(pointer, offset as i64);
+            let call =
+                builder
+                    .ins()
+                    .call_indirect(helper_signature, address, &[vm_context, offset]);
+            builder.inst_results(call)[0]
+        };
+
+        match op {
+            OpCode::Jump => {
+                let target = offset + 3 + chunk.read_u16(offset + 1) as usize;
+                builder.ins().jump(blocks[target], &[]);
+            }
+            OpCode::Loop => {
+                let target = offset + 3 - chunk.read_u16(offset + 1) as usize;
+                builder.ins().jump(blocks[target], &[]);
+            }
+            OpCode::JumpIfFalse => {
+                let target = offset + 3 + chunk.read_u16(offset + 1) as usize;
+                let falsey = call_helper(&mut builder, native::FALSEY);
+                builder.ins().brif(falsey, blocks[target], &[], next, &[]);
+            }
+            // Nothing follows a return in the same frame.
+            OpCode::Return => {
+                let status = call_helper(&mut builder, op as usize);
+                builder.ins().return_(&[status]);
+            }
+            _ => {
+                let status = call_helper(&mut builder, op as usize);
+                builder.ins().brif(status, leave, &[status], next, &[]);
+            }
+        }
+    }
+
+    builder.seal_all_blocks();
+    builder.finalize();
+    module.define_function(id, &mut context)?;
+    module.clear_context(&mut context);
+    Ok(id)
+}
+
+// The offset and opcode of each instruction in 'chunk', which the compiler
+// produced.
+fn instructions(chunk: &Chunk) -> Vec<(usize, OpCode)> {
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let op = OpCode::from_byte(chunk.code[offset]).expect("compiled opcode");
+        instructions.push((offset, op));
+        offset += match op {
+            OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::Pop
+            | OpCode::Equal
+            | OpCode::NotEqual
+            | OpCode::Greater
+            | OpCode::GreaterEqual
+            | OpCode::Less
+            | OpCode::LessEqual
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Not
+            | OpCode::Negate
+            | OpCode::Print
+            | OpCode::CloseUpvalue
+            | OpCode::Return
+            | OpCode::Inherit
+            | OpCode::GetIndex
+            | OpCode::SetIndex
+            | OpCode::GetSlice => 1,
+            OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::GetUpvalue
+            | OpCode::SetUpvalue
+            | OpCode::CloseLocal
+            | OpCode::Call => 2,
+            OpCode::Constant
+            | OpCode::GetGlobal
+            | OpCode::DefineGlobal
+            | OpCode::SetGlobal
+            | OpCode::GetSuper
+            | OpCode::Class
+            | OpCode::Method
+            | OpCode::Jump
+            | OpCode::JumpIfFalse
+            | OpCode::Loop
+            | OpCode::BuildList
+            | OpCode::BuildMap => 3,
+            OpCode::GetProperty | OpCode::SetProperty => 5,
+            OpCode::Invoke => 6,
+            OpCode::Closure => {
+                let index = chunk.read_u16(offset + 1) as usize;
+                let Constant::Function(function) = &chunk.constants[index] else {
+                    unreachable!("closures are made of function constants");
+                };
+                3 + 2 * function.upvalue_count
+            }
+        };
+    }
+    instructions
+}
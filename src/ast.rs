@@ -1,7 +1,16 @@
+use std::cell::Cell;
 use std::fmt;
+use std::rc::Rc;
+
+// The number of scopes between a variable reference and the declaration it
+// refers to, filled in by the resolver. 'None' refers to a global variable.
+pub type Depth = Cell<Option<usize>>;
 
 pub enum Expression<T> {
-    Literal(LiteralValue),
+    Literal {
+        value: LiteralValue,
+        token: T,
+    },
     Unary {
         operator: T,
         right: Box<Expression<T>>,
@@ -18,17 +27,89 @@ pub enum Expression<T> {
         right: Box<Expression<T>>,
     },
     Grouping(Box<Expression<T>>),
+    Variable {
+        name: T,
+        depth: Depth,
+    },
+    Assign {
+        name: T,
+        value: Box<Expression<T>>,
+        depth: Depth,
+    },
+    Logical {
+        operator: T,
+        left: Box<Expression<T>>,
+        right: Box<Expression<T>>,
+    },
+    Call {
+        callee: Box<Expression<T>>,
+        paren: T,
+        arguments: Vec<Expression<T>>,
+    },
+    Get {
+        object: Box<Expression<T>>,
+        name: T,
+    },
+    Set {
+        object: Box<Expression<T>>,
+        name: T,
+        value: Box<Expression<T>>,
+    },
+    // A list literal; 'bracket' is the opening '['.
+    List {
+        bracket: T,
+        elements: Vec<Expression<T>>,
+    },
+    // A map literal; 'brace' is the opening '{'. Each entry is a key and its
+    // value.
+    Map {
+        brace: T,
+        entries: Vec<(Expression<T>, Expression<T>)>,
+    },
+    Index {
+        object: Box<Expression<T>>,
+        bracket: T,
+        index: Box<Expression<T>>,
+    },
+    // 'object[start:end]'. A bound left out is parsed as a 'nil' literal,
+    // which stands for the start or the end of the list.
+    Slice {
+        object: Box<Expression<T>>,
+        bracket: T,
+        start: Box<Expression<T>>,
+        end: Box<Expression<T>>,
+    },
+    SetIndex {
+        object: Box<Expression<T>>,
+        bracket: T,
+        index: Box<Expression<T>>,
+        value: Box<Expression<T>>,
+    },
+    This {
+        keyword: T,
+        depth: Depth,
+    },
+    Super {
+        keyword: T,
+        method: T,
+        depth: Depth,
+    },
 }
 
 impl<T: fmt::Display> fmt::Display for Expression<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Expression::Literal(value) => write!(f, "{value}"),
+            Expression::Literal { value, .. } => write!(f, "{value}"),
             Expression::Unary { operator, right } => write!(f, "({operator} {right})"),
             Expression::Binary {
                 operator,
                 left,
                 right,
+            }
+            | Expression::Logical {
+                operator,
+                left,
+                right,
             } => write!(f, "({operator} {left} {right})"),
             Expression::Ternary {
                 operator,
@@ -37,10 +118,182 @@ impl<T: fmt::Display> fmt::Display for Expression<T> {
                 right,
             } => write!(f, "({operator} {left} {middle} {right})"),
             Expression::Grouping(expression) => write!(f, "(group {expression})"),
+            Expression::Variable { name, .. } => write!(f, "{name}"),
+            Expression::Assign { name, value, .. } => write!(f, "(= {name} {value})"),
+            Expression::Call {
+                callee, arguments, ..
+            } => {
+                write!(f, "(call {callee}")?;
+                for argument in arguments {
+                    write!(f, " {argument}")?;
+                }
+                write!(f, ")")
+            }
+            Expression::Get { object, name } => write!(f, "(. {object} {name})"),
+            Expression::Set {
+                object,
+                name,
+                value,
+            } => write!(f, "(= (. {object} {name}) {value})"),
+            Expression::List { elements, .. } => {
+                write!(f, "(list")?;
+                for element in elements {
+                    write!(f, " {element}")?;
+                }
+                write!(f, ")")
+            }
+            Expression::Map { entries, .. } => {
+                write!(f, "(map")?;
+                for (key, value) in entries {
+                    write!(f, " (: {key} {value})")?;
+                }
+                write!(f, ")")
+            }
+            Expression::Index { object, index, .. } => write!(f, "([] {object} {index})"),
+            Expression::Slice {
+                object, start, end, ..
+            } => write!(f, "([:] {object} {start} {end})"),
+            Expression::SetIndex {
+                object,
+                index,
+                value,
+                ..
+            } => write!(f, "(= ([] {object} {index}) {value})"),
+            Expression::This { keyword, .. } => write!(f, "{keyword}"),
+            Expression::Super {
+                keyword, method, ..
+            } => write!(f, "(. {keyword} {method})"),
         }
     }
 }
 
+// Statements other than expression statements and blocks keep the keyword
+// they start with, so that they can be reported where they start.
+pub enum Statement<T> {
+    Expression(Box<Expression<T>>),
+    Print {
+        keyword: T,
+        expression: Box<Expression<T>>,
+    },
+    Var {
+        keyword: T,
+        name: T,
+        initializer: Option<Box<Expression<T>>>,
+    },
+    Block(Vec<Statement<T>>),
+    If {
+        keyword: T,
+        condition: Box<Expression<T>>,
+        then_branch: Box<Statement<T>>,
+        else_branch: Option<Box<Statement<T>>>,
+    },
+    While {
+        keyword: T,
+        condition: Box<Expression<T>>,
+        body: Box<Statement<T>>,
+    },
+    Function {
+        keyword: T,
+        declaration: Rc<FunctionDeclaration<T>>,
+    },
+    Return {
+        keyword: T,
+        value: Option<Box<Expression<T>>>,
+    },
+    Class {
+        keyword: T,
+        name: T,
+        superclass: Option<Box<Expression<T>>>,
+        methods: Vec<Rc<FunctionDeclaration<T>>>,
+    },
+}
+
+// A named function or method. Declarations are reference counted so that the
+// functions created from them at runtime can share the body.
+pub struct FunctionDeclaration<T> {
+    pub name: T,
+    pub params: Vec<T>,
+    pub body: Vec<Statement<T>>,
+}
+
+impl<T: fmt::Display> fmt::Display for Statement<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Statement::Expression(expression) => write!(f, "(; {expression})"),
+            Statement::Print { expression, .. } => write!(f, "(print {expression})"),
+            Statement::Var {
+                name,
+                initializer: Some(initializer),
+                ..
+            } => write!(f, "(var {name} {initializer})"),
+            Statement::Var {
+                name,
+                initializer: None,
+                ..
+            } => write!(f, "(var {name})"),
+            Statement::Block(statements) => {
+                write!(f, "(block")?;
+                for statement in statements {
+                    write!(f, " {statement}")?;
+                }
+                write!(f, ")")
+            }
+            Statement::If {
+                condition,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                write!(f, "(if {condition} {then_branch}")?;
+                if let Some(else_branch) = else_branch {
+                    write!(f, " {else_branch}")?;
+                }
+                write!(f, ")")
+            }
+            Statement::While {
+                condition, body, ..
+            } => write!(f, "(while {condition} {body})"),
+            Statement::Function { declaration, .. } => write!(f, "{declaration}"),
+            Statement::Return {
+                value: Some(value), ..
+            } => write!(f, "(return {value})"),
+            Statement::Return { value: None, .. } => write!(f, "(return)"),
+            Statement::Class {
+                name,
+                superclass,
+                methods,
+                ..
+            } => {
+                write!(f, "(class {name}")?;
+                if let Some(superclass) = superclass {
+                    write!(f, " (< {superclass})")?;
+                }
+                for method in methods {
+                    write!(f, " {method}")?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for FunctionDeclaration<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "(fun {} (", self.name)?;
+        for (i, param) in self.params.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{param}")?;
+        }
+        write!(f, ")")?;
+        for statement in &self.body {
+            write!(f, " {statement}")?;
+        }
+        write!(f, ")")
+    }
+}
+
 pub enum LiteralValue {
     Number(f64),
     String(String),
@@ -49,7 +302,7 @@ pub enum LiteralValue {
     Nil,
 }
 
-impl<'a> fmt::Display for LiteralValue {
+impl fmt::Display for LiteralValue {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             LiteralValue::Number(value) => write!(f, "{value}"),
@@ -66,9 +319,16 @@ mod tests {
     use super::*;
 
     #[test]
+    #[allow(clippy::approx_constant)]
     fn print_expression() {
-        let num_expr = Box::new(Expression::Literal(LiteralValue::Number(3.14)));
-        let num_expr_2 = Box::new(Expression::Literal(LiteralValue::Number(6.28)));
+        let num_expr = Box::new(Expression::Literal {
+            value: LiteralValue::Number(3.14),
+            token: "3.14",
+        });
+        let num_expr_2 = Box::new(Expression::Literal {
+            value: LiteralValue::Number(6.28),
+            token: "6.28",
+        });
         let minus_op = "-";
         let mul_op = "*";
         let negate_expr = Box::new(Expression::Unary {
@@ -85,4 +345,59 @@ mod tests {
 
         assert_eq!(output, "(* 3.14 (- 6.28))");
     }
+
+    #[test]
+    fn print_statement() {
+        let statement = Statement::Block(vec![
+            Statement::Var {
+                keyword: "var",
+                name: "a",
+                initializer: Some(Box::new(Expression::Literal {
+                    value: LiteralValue::Number(1.0),
+                    token: "1",
+                })),
+            },
+            Statement::Print {
+                keyword: "print",
+                expression: Box::new(Expression::Assign {
+                    name: "a",
+                    value: Box::new(Expression::Variable {
+                        name: "b",
+                        depth: Depth::default(),
+                    }),
+                    depth: Depth::default(),
+                }),
+            },
+        ]);
+
+        let output: String = format!("{statement}");
+
+        assert_eq!(output, "(block (var a 1) (print (= a b)))");
+    }
+
+    #[test]
+    fn print_function() {
+        let function = FunctionDeclaration {
+            name: "add",
+            params: vec!["a", "b"],
+            body: vec![Statement::Return {
+                keyword: "return",
+                value: Some(Box::new(Expression::Binary {
+                    operator: "+",
+                    left: Box::new(Expression::Variable {
+                        name: "a",
+                        depth: Depth::default(),
+                    }),
+                    right: Box::new(Expression::Variable {
+                        name: "b",
+                        depth: Depth::default(),
+                    }),
+                })),
+            }],
+        };
+
+        let output: String = format!("{function}");
+
+        assert_eq!(output, "(fun add (a b) (return (+ a b)))");
+    }
 }
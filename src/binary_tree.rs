@@ -1,10 +1,20 @@
-#[derive(Debug, PartialEq, Eq)]
+use std::cmp::Ordering;
+use std::mem;
+use std::ops::{Bound, RangeBounds};
+
+// A binary tree whose nodes record their height, the number of nodes on the
+// longest path down from them. 'SortedMap' and 'SortedSet' keep their trees
+// balanced as AVL trees: the heights of the two subtrees of any node differ by
+// at most one, so a tree of n nodes is O(log n) deep.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub enum BinaryTree<T> {
+    #[default]
     Empty,
     Node {
         value: T,
         left: Box<BinaryTree<T>>,
         right: Box<BinaryTree<T>>,
+        height: usize,
     },
 }
 
@@ -20,6 +30,7 @@ impl<T> BinaryTree<T> {
             value,
             left: Box::new(BinaryTree::Empty),
             right: Box::new(BinaryTree::Empty),
+            height: 1,
         }
     }
 
@@ -29,14 +40,25 @@ impl<T> BinaryTree<T> {
         left: Box<BinaryTree<T>>,
         right: Box<BinaryTree<T>>,
     ) -> BinaryTree<T> {
-        BinaryTree::Node { value, left, right }
+        let height = 1 + left.height().max(right.height());
+        BinaryTree::Node {
+            value,
+            left,
+            right,
+            height,
+        }
     }
 
     // Return true if this node is empty.
     pub fn is_empty(&self) -> bool {
+        matches!(*self, BinaryTree::Empty)
+    }
+
+    // Return the height of this node, which is 0 for an empty node.
+    pub fn height(&self) -> usize {
         match *self {
-            BinaryTree::Empty => true,
-            _ => false,
+            BinaryTree::Empty => 0,
+            BinaryTree::Node { height, .. } => height,
         }
     }
 
@@ -50,6 +72,7 @@ impl<T> BinaryTree<T> {
                 *left = node;
             }
         };
+        self.update_height();
     }
 
     // Set the right child of this node to the specified 'node'.
@@ -62,6 +85,490 @@ impl<T> BinaryTree<T> {
                 *right = node;
             }
         }
+        self.update_height();
+    }
+
+    // Iterate over the values of this tree with each node after its left
+    // subtree and before its right one.
+    pub fn in_order(&self) -> InOrder<'_, T> {
+        let mut iter = InOrder { stack: Vec::new() };
+        iter.push_left(self);
+        iter
+    }
+
+    // Iterate over the values of this tree with each node before its
+    // subtrees.
+    pub fn pre_order(&self) -> PreOrder<'_, T> {
+        PreOrder { stack: vec![self] }
+    }
+
+    // Iterate over the values of this tree with each node after its
+    // subtrees.
+    pub fn post_order(&self) -> PostOrder<'_, T> {
+        PostOrder {
+            stack: vec![(self, false)],
+        }
+    }
+
+    fn update_height(&mut self) {
+        if let BinaryTree::Node {
+            left,
+            right,
+            height,
+            ..
+        } = self
+        {
+            *height = 1 + left.height().max(right.height());
+        }
+    }
+
+    // How much taller the left subtree is than the right one.
+    fn balance(&self) -> isize {
+        match self {
+            BinaryTree::Empty => 0,
+            BinaryTree::Node { left, right, .. } => {
+                left.height() as isize - right.height() as isize
+            }
+        }
+    }
+
+    // Make the left child of this node its parent, keeping the order of the
+    // values.
+    fn rotate_right(&mut self) {
+        let BinaryTree::Node { left, .. } = self else {
+            return;
+        };
+        let mut pivot = mem::take(left);
+        let BinaryTree::Node {
+            right: pivot_right, ..
+        } = &mut *pivot
+        else {
+            *left = pivot;
+            return;
+        };
+        mem::swap(left, pivot_right);
+        self.update_height();
+
+        let node = mem::replace(self, *pivot);
+        self.set_right(Box::new(node));
+    }
+
+    // Make the right child of this node its parent, keeping the order of the
+    // values.
+    fn rotate_left(&mut self) {
+        let BinaryTree::Node { right, .. } = self else {
+            return;
+        };
+        let mut pivot = mem::take(right);
+        let BinaryTree::Node {
+            left: pivot_left, ..
+        } = &mut *pivot
+        else {
+            *right = pivot;
+            return;
+        };
+        mem::swap(right, pivot_left);
+        self.update_height();
+
+        let node = mem::replace(self, *pivot);
+        self.set_left(Box::new(node));
+    }
+
+    // Restore the balance of this node after one of its subtrees has grown
+    // or shrunk by one level, given that both subtrees are balanced.
+    fn rebalance(&mut self) {
+        self.update_height();
+        let balance = self.balance();
+        let BinaryTree::Node { left, right, .. } = self else {
+            return;
+        };
+        match balance {
+            2 => {
+                if left.balance() < 0 {
+                    left.rotate_left();
+                }
+                self.rotate_right();
+            }
+            -2 => {
+                if right.balance() > 0 {
+                    right.rotate_right();
+                }
+                self.rotate_left();
+            }
+            _ => {}
+        }
+    }
+
+    // Remove the smallest value of this non-empty tree.
+    fn remove_first(&mut self) -> T {
+        let BinaryTree::Node { left, right, .. } = self else {
+            panic!("Cannot remove from empty node");
+        };
+        if !left.is_empty() {
+            let value = left.remove_first();
+            self.rebalance();
+            return value;
+        }
+
+        let right = mem::take(right);
+        match mem::replace(self, *right) {
+            BinaryTree::Node { value, .. } => value,
+            BinaryTree::Empty => unreachable!(),
+        }
+    }
+
+    // Remove the value at this non-empty node, replacing it with the smallest
+    // value of its right subtree if it has two children.
+    fn remove_node(&mut self) -> T {
+        let BinaryTree::Node {
+            value, left, right, ..
+        } = self
+        else {
+            panic!("Cannot remove from empty node");
+        };
+        if left.is_empty() || right.is_empty() {
+            let child = if left.is_empty() {
+                mem::take(right)
+            } else {
+                mem::take(left)
+            };
+            return match mem::replace(self, *child) {
+                BinaryTree::Node { value, .. } => value,
+                BinaryTree::Empty => unreachable!(),
+            };
+        }
+
+        let successor = right.remove_first();
+        let value = mem::replace(value, successor);
+        self.rebalance();
+        value
+    }
+}
+
+// The nodes whose values are still to come, each on top of its ancestors.
+pub struct InOrder<'a, T> {
+    stack: Vec<&'a BinaryTree<T>>,
+}
+
+impl<'a, T> InOrder<'a, T> {
+    fn push_left(&mut self, mut tree: &'a BinaryTree<T>) {
+        while let BinaryTree::Node { left, .. } = tree {
+            self.stack.push(tree);
+            tree = left;
+        }
+    }
+}
+
+impl<'a, T> Iterator for InOrder<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        let BinaryTree::Node { value, right, .. } = self.stack.pop()? else {
+            return None;
+        };
+        self.push_left(right);
+        Some(value)
+    }
+}
+
+pub struct PreOrder<'a, T> {
+    stack: Vec<&'a BinaryTree<T>>,
+}
+
+impl<'a, T> Iterator for PreOrder<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        while let Some(tree) = self.stack.pop() {
+            if let BinaryTree::Node {
+                value, left, right, ..
+            } = tree
+            {
+                self.stack.push(right);
+                self.stack.push(left);
+                return Some(value);
+            }
+        }
+        None
+    }
+}
+
+// Each node on the stack is marked once its subtrees have been pushed above
+// it, after which it is next when it comes back to the top.
+pub struct PostOrder<'a, T> {
+    stack: Vec<(&'a BinaryTree<T>, bool)>,
+}
+
+impl<'a, T> Iterator for PostOrder<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        while let Some((tree, expanded)) = self.stack.pop() {
+            if let BinaryTree::Node {
+                value, left, right, ..
+            } = tree
+            {
+                if expanded {
+                    return Some(value);
+                }
+                self.stack.push((tree, true));
+                self.stack.push((right, false));
+                self.stack.push((left, false));
+            }
+        }
+        None
+    }
+}
+
+// A map whose entries are kept in an AVL tree ordered by key, so that lookups,
+// insertions and removals take O(log n) time and iteration is in key order.
+#[derive(Debug, Clone)]
+pub struct SortedMap<K, V> {
+    root: BinaryTree<(K, V)>,
+    len: usize,
+}
+
+impl<K, V> Default for SortedMap<K, V> {
+    fn default() -> Self {
+        SortedMap {
+            root: BinaryTree::Empty,
+            len: 0,
+        }
+    }
+}
+
+impl<K: Ord, V> SortedMap<K, V> {
+    pub fn new() -> SortedMap<K, V> {
+        SortedMap::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        let mut tree = &self.root;
+        while let BinaryTree::Node {
+            value: (k, v),
+            left,
+            right,
+            ..
+        } = tree
+        {
+            tree = match key.cmp(k) {
+                Ordering::Less => left,
+                Ordering::Greater => right,
+                Ordering::Equal => return Some(v),
+            };
+        }
+        None
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let mut tree = &mut self.root;
+        while let BinaryTree::Node {
+            value: (k, v),
+            left,
+            right,
+            ..
+        } = tree
+        {
+            tree = match key.cmp(k) {
+                Ordering::Less => left,
+                Ordering::Greater => right,
+                Ordering::Equal => return Some(v),
+            };
+        }
+        None
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    // Set the value of 'key', returning the value it replaced. A key already
+    // in the map is kept rather than replaced by 'key'.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let replaced = insert(&mut self.root, key, value);
+        if replaced.is_none() {
+            self.len += 1;
+        }
+        replaced
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let (_, value) = remove(&mut self.root, key)?;
+        self.len -= 1;
+        Some(value)
+    }
+
+    // The entries in key order.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.root.in_order().map(|(key, value)| (key, value))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.iter().map(|(key, _)| key)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, value)| value)
+    }
+
+    // The entries whose keys are in 'range', in key order. A range whose
+    // start is after its end is empty.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> impl Iterator<Item = (&K, &V)> {
+        // Only the path down to the first key in the range is pushed, and
+        // the walk stops at the first key past its end.
+        let mut iter = InOrder { stack: Vec::new() };
+        let mut tree = &self.root;
+        while let BinaryTree::Node {
+            value: (key, _),
+            left,
+            right,
+            ..
+        } = tree
+        {
+            let after_start = match range.start_bound() {
+                Bound::Included(start) => key >= start,
+                Bound::Excluded(start) => key > start,
+                Bound::Unbounded => true,
+            };
+            if after_start {
+                iter.stack.push(tree);
+                tree = left;
+            } else {
+                tree = right;
+            }
+        }
+
+        iter.take_while(move |(key, _)| match range.end_bound() {
+            Bound::Included(end) => key <= end,
+            Bound::Excluded(end) => key < end,
+            Bound::Unbounded => true,
+        })
+        .map(|(key, value)| (key, value))
+    }
+
+    // The entries with each node of the tree before its subtrees.
+    pub fn pre_order(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.root.pre_order().map(|(key, value)| (key, value))
+    }
+
+    // The entries with each node of the tree after its subtrees.
+    pub fn post_order(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.root.post_order().map(|(key, value)| (key, value))
+    }
+
+    pub fn tree(&self) -> &BinaryTree<(K, V)> {
+        &self.root
+    }
+}
+
+fn insert<K: Ord, V>(tree: &mut BinaryTree<(K, V)>, key: K, value: V) -> Option<V> {
+    let BinaryTree::Node {
+        value: (k, v),
+        left,
+        right,
+        ..
+    } = tree
+    else {
+        *tree = BinaryTree::new_node((key, value));
+        return None;
+    };
+
+    let replaced = match key.cmp(k) {
+        Ordering::Less => insert(left, key, value),
+        Ordering::Greater => insert(right, key, value),
+        Ordering::Equal => return Some(mem::replace(v, value)),
+    };
+    tree.rebalance();
+    replaced
+}
+
+fn remove<K: Ord, V>(tree: &mut BinaryTree<(K, V)>, key: &K) -> Option<(K, V)> {
+    let BinaryTree::Node {
+        value: (k, _),
+        left,
+        right,
+        ..
+    } = tree
+    else {
+        return None;
+    };
+
+    let removed = match key.cmp(k) {
+        Ordering::Less => remove(left, key),
+        Ordering::Greater => remove(right, key),
+        Ordering::Equal => return Some(tree.remove_node()),
+    };
+    tree.rebalance();
+    removed
+}
+
+// A set whose keys are kept in order in a 'SortedMap'.
+#[derive(Debug, Clone)]
+pub struct SortedSet<K> {
+    map: SortedMap<K, ()>,
+}
+
+impl<K> Default for SortedSet<K> {
+    fn default() -> Self {
+        SortedSet {
+            map: SortedMap::default(),
+        }
+    }
+}
+
+impl<K: Ord> SortedSet<K> {
+    pub fn new() -> SortedSet<K> {
+        SortedSet::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.map.contains_key(key)
+    }
+
+    // Add 'key', returning false if it was already in the set.
+    pub fn insert(&mut self, key: K) -> bool {
+        self.map.insert(key, ()).is_none()
+    }
+
+    // Remove 'key', returning false if it wasn't in the set.
+    pub fn remove(&mut self, key: &K) -> bool {
+        self.map.remove(key).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &K> {
+        self.map.keys()
+    }
+
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> impl Iterator<Item = &K> {
+        self.map.range(range).map(|(key, _)| key)
+    }
+
+    pub fn pre_order(&self) -> impl Iterator<Item = &K> {
+        self.map.pre_order().map(|(key, _)| key)
+    }
+
+    pub fn post_order(&self) -> impl Iterator<Item = &K> {
+        self.map.post_order().map(|(key, _)| key)
+    }
+
+    pub fn tree(&self) -> &BinaryTree<(K, ())> {
+        self.map.tree()
     }
 }
 
@@ -80,7 +587,9 @@ mod tests {
         let root = BinaryTree::new_node(42i32);
 
         match root {
-            BinaryTree::Node { value, left, right } => {
+            BinaryTree::Node {
+                value, left, right, ..
+            } => {
                 assert_eq!(value, 42i32);
                 assert!(left.is_empty());
                 assert!(right.is_empty());
@@ -98,7 +607,9 @@ mod tests {
         );
 
         match root {
-            BinaryTree::Node { value, left, right } => {
+            BinaryTree::Node {
+                value, left, right, ..
+            } => {
                 assert_eq!(value, 42);
                 assert_eq!(*left, BinaryTree::new_node(1i32));
                 assert!(right.is_empty());
@@ -108,12 +619,14 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn is_empty() {
         let root: BinaryTree<i32> = BinaryTree::new();
         assert_eq!(root.is_empty(), true)
     }
 
     #[test]
+    #[allow(clippy::needless_borrowed_reference)]
     fn set_left() {
         let mut root = BinaryTree::new_node(42i32);
         root.set_left(Box::new(BinaryTree::new_node(1i32)));
@@ -123,6 +636,7 @@ mod tests {
                 ref value,
                 ref left,
                 ref right,
+                ..
             } => {
                 assert_eq!(*value, 42);
 
@@ -131,6 +645,7 @@ mod tests {
                         ref value,
                         ref left,
                         ref right,
+                        ..
                     } => {
                         assert_eq!(*value, 1i32);
                         assert_eq!(**left, BinaryTree::Empty);
@@ -150,6 +665,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::needless_borrowed_reference)]
     fn set_right() {
         let mut root = BinaryTree::new_node(42i32);
         root.set_right(Box::new(BinaryTree::new_node(1i32)));
@@ -159,6 +675,7 @@ mod tests {
                 ref value,
                 ref left,
                 ref right,
+                ..
             } => {
                 assert_eq!(*value, 42i32);
 
@@ -173,6 +690,7 @@ mod tests {
                         ref value,
                         ref left,
                         ref right,
+                        ..
                     } => {
                         assert_eq!(*value, 1);
                         assert_eq!(**left, BinaryTree::Empty);
@@ -184,4 +702,164 @@ mod tests {
             _ => panic!("Unexpected variant"),
         }
     }
+
+    // Inserting 1 to 7 in order rotates the tree into a perfect one rooted
+    // at 4.
+    #[test]
+    fn traversals() {
+        let mut set = SortedSet::new();
+        for key in 1..=7 {
+            assert!(set.insert(key));
+        }
+        assert!(!set.insert(4));
+
+        assert_eq!(set.tree().height(), 3);
+        assert_eq!(
+            set.iter().copied().collect::<Vec<_>>(),
+            [1, 2, 3, 4, 5, 6, 7]
+        );
+        assert_eq!(
+            set.pre_order().copied().collect::<Vec<_>>(),
+            [4, 2, 1, 3, 6, 5, 7]
+        );
+        assert_eq!(
+            set.post_order().copied().collect::<Vec<_>>(),
+            [1, 3, 2, 5, 7, 6, 4]
+        );
+    }
+
+    #[test]
+    fn sorted_map() {
+        let mut map = SortedMap::new();
+        for key in ["m", "c", "x", "a", "e"] {
+            assert_eq!(map.insert(key, key.len()), None);
+        }
+        assert_eq!(map.insert("c", 10), Some(1));
+        *map.get_mut(&"x").unwrap() += 1;
+
+        assert_eq!(map.get(&"c"), Some(&10));
+        assert_eq!(map.remove(&"m"), Some(1));
+        assert_eq!(map.remove(&"m"), None);
+        assert!(!map.contains_key(&"m"));
+        assert_eq!(map.len(), 4);
+        assert_eq!(
+            map.iter()
+                .map(|(&key, &value)| (key, value))
+                .collect::<Vec<_>>(),
+            [("a", 1), ("c", 10), ("e", 1), ("x", 2)]
+        );
+        assert_eq!(map.range("b".."x").count(), 2);
+        assert_eq!(map.range("e"..).count(), 2);
+        assert_eq!(map.range(.."a").count(), 0);
+        assert_eq!(map.range("x".."a").count(), 0);
+    }
+
+    // A xorshift generator, so that the property tests below run the same
+    // operations every time.
+    struct Random(u64);
+
+    impl Random {
+        fn below(&mut self, bound: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % bound
+        }
+    }
+
+    // Check that every node of 'tree' records its height, is balanced and
+    // has its keys in order, returning the keys in order.
+    fn check_invariants<K: Ord + Copy + std::fmt::Debug, V>(tree: &BinaryTree<(K, V)>) -> Vec<K> {
+        fn check<K: Ord + Copy, V>(tree: &BinaryTree<(K, V)>, keys: &mut Vec<K>) -> usize {
+            let BinaryTree::Node {
+                value: (key, _),
+                left,
+                right,
+                height,
+            } = tree
+            else {
+                return 0;
+            };
+            let left_height = check(left, keys);
+            keys.push(*key);
+            let right_height = check(right, keys);
+
+            assert!(left_height.abs_diff(right_height) <= 1, "unbalanced node");
+            assert_eq!(*height, 1 + left_height.max(right_height), "wrong height");
+            *height
+        }
+
+        let mut keys = Vec::new();
+        check(tree, &mut keys);
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]), "{keys:?}");
+        keys
+    }
+
+    #[test]
+    fn random_operations_keep_the_tree_balanced() {
+        for seed in 1..=50u64 {
+            let mut random = Random(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+            let mut map = SortedMap::new();
+            let mut model = std::collections::BTreeMap::new();
+
+            for _ in 0..400 {
+                let key = random.below(100);
+                if random.below(3) == 0 {
+                    assert_eq!(map.remove(&key), model.remove(&key));
+                } else {
+                    let value = random.below(1000);
+                    assert_eq!(map.insert(key, value), model.insert(key, value));
+                }
+
+                let keys = check_invariants(map.tree());
+                assert_eq!(keys, model.keys().copied().collect::<Vec<_>>());
+                assert_eq!(map.len(), model.len());
+            }
+
+            // An AVL tree of n nodes is less than 1.45 log2(n + 2) deep.
+            let bound = 1.45 * ((map.len() + 2) as f64).log2();
+            assert!((map.tree().height() as f64) < bound);
+            assert!(map.values().eq(model.values()));
+        }
+    }
+
+    #[test]
+    fn random_ranges_match_btree_map() {
+        let mut random = Random(0x2545_f491_4f6c_dd1d);
+        let mut set = SortedSet::new();
+        let mut model = std::collections::BTreeSet::new();
+        for _ in 0..200 {
+            let key = random.below(300);
+            assert_eq!(set.insert(key), model.insert(key));
+        }
+
+        for _ in 0..500 {
+            let (start, end) = (random.below(320), random.below(320));
+            if start <= end {
+                assert!(set.range(start..end).eq(model.range(start..end)));
+                assert!(set.range(start..=end).eq(model.range(start..=end)));
+            } else {
+                assert_eq!(set.range(start..end).count(), 0);
+            }
+            assert!(set.range(start..).eq(model.range(start..)));
+            assert!(set.range(..end).eq(model.range(..end)));
+        }
+
+        // Every traversal visits each key once, the root first in pre-order
+        // and last in post-order.
+        let mut pre_order: Vec<_> = set.pre_order().copied().collect();
+        let mut post_order: Vec<_> = set.post_order().copied().collect();
+        let BinaryTree::Node {
+            value: (root, _), ..
+        } = set.tree()
+        else {
+            panic!("Expected a non-empty tree");
+        };
+        assert_eq!(pre_order[0], *root);
+        assert_eq!(post_order.last(), Some(root));
+        pre_order.sort();
+        post_order.sort();
+        assert!(pre_order.iter().eq(model.iter()));
+        assert!(post_order.iter().eq(model.iter()));
+    }
 }
@@ -0,0 +1,370 @@
+use crate::chunk::{Chunk, Constant, FunctionProto};
+use crate::result::{Error, Result};
+use crate::symbol::Symbol;
+use crate::verifier;
+use std::rc::Rc;
+
+// Compiled scripts are stored in '.loxc' files laid out as
+//
+//     magic     "LOXC"
+//     version   u16
+//     reserved  u16, zero
+//     length    u32, of the payload
+//     checksum  u32, CRC-32 of the payload
+//     payload   the script function
+//
+// with integers in little endian. A function is encoded as
+//
+//     name           string
+//     arity          u8
+//     upvalue count  u16
+//     cache count    u16
+//     code           u32 length followed by the bytes
+//     positions      u32 run count followed by (u32 count, u32 line,
+//                    u32 column) runs, covering every byte of code
+//     constants      u32 count followed by a tag and value for each:
+//                    0 number (f64), 1 string, 2 function
+//
+// and a string as a u32 length followed by UTF-8 bytes.
+const MAGIC: &[u8; 4] = b"LOXC";
+pub const VERSION: u16 = 7;
+const HEADER_LENGTH: usize = 16;
+
+// Functions nested deeper than this are rejected rather than risking a stack
+// overflow while reading them.
+const MAX_NESTING: usize = 256;
+
+const NUMBER_TAG: u8 = 0;
+const STRING_TAG: u8 = 1;
+const FUNCTION_TAG: u8 = 2;
+
+pub fn serialize(script: &FunctionProto) -> Vec<u8> {
+    let mut payload = Vec::new();
+    write_function(&mut payload, script);
+
+    let mut bytes = Vec::with_capacity(HEADER_LENGTH + payload.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&0u16.to_le_bytes());
+    bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&crc32(&payload).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    bytes
+}
+
+// Read a script written by 'serialize', checking it with 'verifier::verify'
+// so that the VM can run it safely.
+pub fn deserialize(bytes: &[u8]) -> Result<Rc<FunctionProto>> {
+    if bytes.len() < HEADER_LENGTH {
+        return Err(invalid("file is too short to be compiled Lox"));
+    }
+
+    let (header, payload) = bytes.split_at(HEADER_LENGTH);
+    if &header[0..4] != MAGIC {
+        return Err(invalid("not a compiled Lox file"));
+    }
+
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    if version != VERSION {
+        return Err(invalid(&format!(
+            "unsupported version {version}, expected {VERSION}"
+        )));
+    }
+
+    let length = u32::from_le_bytes([header[8], header[9], header[10], header[11]]) as usize;
+    if payload.len() != length {
+        return Err(invalid(&format!(
+            "expected {length} bytes of bytecode but found {}",
+            payload.len()
+        )));
+    }
+
+    let checksum = u32::from_le_bytes([header[12], header[13], header[14], header[15]]);
+    if crc32(payload) != checksum {
+        return Err(invalid("checksum mismatch"));
+    }
+
+    let mut reader = Reader {
+        bytes: payload,
+        offset: 0,
+    };
+    let script = reader.function(0)?;
+    if reader.offset != payload.len() {
+        return Err(invalid("unexpected data after the script"));
+    }
+
+    verifier::verify(&script)?;
+    Ok(Rc::new(script))
+}
+
+// The functions of 'script' in the order 'serialize' writes them, which is
+// the order they are read back in.
+pub fn functions(script: &Rc<FunctionProto>) -> Vec<Rc<FunctionProto>> {
+    fn visit(function: &Rc<FunctionProto>, functions: &mut Vec<Rc<FunctionProto>>) {
+        functions.push(Rc::clone(function));
+        for constant in &function.chunk.constants {
+            if let Constant::Function(nested) = constant {
+                visit(nested, functions);
+            }
+        }
+    }
+
+    let mut functions = Vec::new();
+    visit(script, &mut functions);
+    functions
+}
+
+fn invalid(message: &str) -> Error {
+    Error::InvalidBytecode(message.to_string())
+}
+
+fn write_u32(bytes: &mut Vec<u8>, value: usize) {
+    bytes.extend_from_slice(&(value as u32).to_le_bytes());
+}
+
+fn write_string(bytes: &mut Vec<u8>, value: &str) {
+    write_u32(bytes, value.len());
+    bytes.extend_from_slice(value.as_bytes());
+}
+
+fn write_function(bytes: &mut Vec<u8>, function: &FunctionProto) {
+    let chunk = &function.chunk;
+
+    write_string(bytes, &function.name);
+    bytes.push(function.arity as u8);
+    bytes.extend_from_slice(&(function.upvalue_count as u16).to_le_bytes());
+    bytes.extend_from_slice(&(function.cache_count as u16).to_le_bytes());
+
+    write_u32(bytes, chunk.code.len());
+    bytes.extend_from_slice(&chunk.code);
+
+    let mut runs: Vec<(usize, (usize, usize))> = Vec::new();
+    for &position in &chunk.positions {
+        match runs.last_mut() {
+            Some((count, last)) if *last == position => *count += 1,
+            _ => runs.push((1, position)),
+        }
+    }
+    write_u32(bytes, runs.len());
+    for (count, (line, column)) in runs {
+        write_u32(bytes, count);
+        write_u32(bytes, line);
+        write_u32(bytes, column);
+    }
+
+    write_u32(bytes, chunk.constants.len());
+    for constant in &chunk.constants {
+        match constant {
+            Constant::Number(value) => {
+                bytes.push(NUMBER_TAG);
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            Constant::String(value) => {
+                bytes.push(STRING_TAG);
+                write_string(bytes, value.as_str());
+            }
+            Constant::Function(function) => {
+                bytes.push(FUNCTION_TAG);
+                write_function(bytes, function);
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, length: usize) -> Result<&[u8]> {
+        let end = self
+            .offset
+            .checked_add(length)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| invalid("unexpected end of file"))?;
+
+        let bytes = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<usize> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+
+    fn f64(&mut self) -> Result<f64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(f64::from_le_bytes(bytes))
+    }
+
+    fn string(&mut self) -> Result<Rc<str>> {
+        let length = self.u32()?;
+        let bytes = self.take(length)?;
+        std::str::from_utf8(bytes)
+            .map(Rc::from)
+            .map_err(|_| invalid("string is not valid UTF-8"))
+    }
+
+    fn function(&mut self, depth: usize) -> Result<FunctionProto> {
+        if depth > MAX_NESTING {
+            return Err(invalid("functions are nested too deeply"));
+        }
+
+        let name = self.string()?;
+        let arity = self.u8()? as usize;
+        let upvalue_count = self.u16()? as usize;
+        let cache_count = self.u16()? as usize;
+
+        let code_length = self.u32()?;
+        let code = self.take(code_length)?.to_vec();
+
+        let run_count = self.u32()?;
+        let mut positions = Vec::with_capacity(code.len());
+        for _ in 0..run_count {
+            let count = self.u32()?;
+            let position = (self.u32()?, self.u32()?);
+            if positions.len() + count > code.len() {
+                return Err(invalid("line table is longer than the code"));
+            }
+            positions.extend(std::iter::repeat_n(position, count));
+        }
+        if positions.len() != code.len() {
+            return Err(invalid("line table does not cover the code"));
+        }
+
+        // Every constant takes at least two bytes, which bounds the count
+        // before allocating.
+        let constant_count = self.u32()?;
+        if constant_count > self.bytes.len() - self.offset {
+            return Err(invalid("unexpected end of file"));
+        }
+        let mut constants = Vec::with_capacity(constant_count);
+        for _ in 0..constant_count {
+            let constant = match self.u8()? {
+                NUMBER_TAG => Constant::Number(self.f64()?),
+                STRING_TAG => Constant::String(Symbol::intern(&self.string()?)),
+                FUNCTION_TAG => Constant::Function(Rc::new(self.function(depth + 1)?)),
+                tag => return Err(invalid(&format!("unknown constant tag {tag}"))),
+            };
+            constants.push(constant);
+        }
+
+        Ok(FunctionProto {
+            name,
+            arity,
+            upvalue_count,
+            cache_count,
+            chunk: Chunk {
+                code,
+                constants,
+                positions,
+            },
+        })
+    }
+}
+
+// CRC-32 as used by zlib and PNG.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::disassembler::disassemble;
+    use crate::lexer::lex;
+    use crate::parser::parse;
+    use crate::resolver::resolve;
+
+    fn compile_source(source: &str) -> Rc<FunctionProto> {
+        let tokens = lex(source).unwrap();
+        let statements = parse(&tokens).unwrap();
+        resolve(&statements).unwrap();
+        compile(&statements).unwrap()
+    }
+
+    const PROGRAM: &str = "
+        class A { init(x) { this.x = x; } }
+        fun make(n) { fun get() { return n * 1.5; } return get; }
+        print make(2)() + A(\"s\").x.len;";
+
+    #[test]
+    fn checksum() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn round_trip() {
+        let script = compile_source(PROGRAM);
+        let loaded = deserialize(&serialize(&script)).unwrap();
+
+        assert_eq!(disassemble(&loaded), disassemble(&script));
+        assert_eq!(loaded.chunk.positions, script.chunk.positions);
+    }
+
+    #[test]
+    fn rejects_damaged_files() {
+        let bytes = serialize(&compile_source(PROGRAM));
+
+        let error = |bytes: &[u8]| deserialize(bytes).unwrap_err().to_string();
+
+        assert!(error(&bytes[..10]).contains("too short"));
+        assert!(error(&bytes[..bytes.len() - 1]).contains("expected"));
+        assert!(error(b"#!/usr/bin/env lox\nprint 1;").contains("not a compiled Lox file"));
+
+        let mut corrupted = bytes.clone();
+        *corrupted.last_mut().unwrap() ^= 0xFF;
+        assert!(error(&corrupted).contains("checksum"));
+
+        let mut future = bytes.clone();
+        future[4] = 99;
+        assert!(error(&future).contains("unsupported version"));
+    }
+
+    // A file with a correct header around a damaged payload, as a file
+    // written by something other than 'serialize' might have.
+    fn with_header(payload: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&0u16.to_le_bytes());
+        bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&crc32(payload).to_le_bytes());
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    #[test]
+    fn rejects_truncated_payloads() {
+        let bytes = serialize(&compile_source(PROGRAM));
+        let payload = &bytes[HEADER_LENGTH..];
+
+        for length in 0..payload.len() {
+            assert!(
+                deserialize(&with_header(&payload[..length])).is_err(),
+                "accepted a payload truncated to {length} bytes"
+            );
+        }
+    }
+}
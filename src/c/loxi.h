@@ -0,0 +1,2102 @@
+// The runtime for C programs generated by 'loxi emit-c'.
+//
+// Values are tagged unions of nil, booleans, numbers and pointers to heap
+// objects. Scopes are heap objects too, chained like the tree-walking
+// interpreter's environments, so that closures capture variables rather than
+// values. Objects are freed by a mark-sweep garbage collector. Generated code
+// keeps every value it is working with in an array of temporaries registered
+// with the collector through a 'LoxFrame', which together with the globals
+// and constants are the roots. Defining 'LOX_GC_STRESS' collects garbage on
+// every allocation.
+//
+// Runtime errors are reported on stderr in the same format as loxi and exit
+// with status 1.
+//
+// Programs only call the helpers they need, so the helpers are 'static inline'
+// to keep compilers from warning about the rest.
+#ifndef LOXI_H
+#define LOXI_H
+
+#include <math.h>
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <time.h>
+
+// The maximum depth of nested calls, counting the script itself, before
+// reporting a stack overflow. Generated programs define it as the maximum depth
+// they were translated with. Compile with '-DLOX_MAX_DEPTH=<depth>' to change
+// it.
+#ifndef LOX_MAX_DEPTH
+#define LOX_MAX_DEPTH 64
+#endif
+
+// The maximum number of arguments to a call.
+#define LOX_MAX_ARGUMENTS 255
+
+// Never collect while fewer bytes than this are allocated.
+#define LOX_MIN_HEAP (1024 * 1024)
+
+typedef enum {
+    LOX_NIL,
+    LOX_BOOL,
+    LOX_NUMBER,
+    LOX_OBJECT,
+    // A global variable that has not been defined yet.
+    LOX_UNDEFINED,
+} LoxType;
+
+typedef struct LoxObject LoxObject;
+
+typedef struct {
+    LoxType type;
+    union {
+        bool boolean;
+        double number;
+        LoxObject *object;
+    } as;
+} LoxValue;
+
+typedef enum {
+    LOX_STRING,
+    LOX_ENV,
+    LOX_FUNCTION,
+    LOX_NATIVE,
+    LOX_CLASS,
+    LOX_INSTANCE,
+    LOX_LIST,
+    LOX_LIST_METHOD,
+    LOX_MAP,
+    LOX_MAP_METHOD,
+    LOX_SORTED_CLASS,
+    LOX_SORTED,
+    LOX_SORTED_METHOD,
+} LoxObjectType;
+
+struct LoxObject {
+    LoxObjectType type;
+    bool marked;
+    size_t size;
+    LoxObject *next;
+};
+
+typedef struct {
+    LoxObject object;
+    size_t length;
+    char chars[];
+} LoxString;
+
+// A scope holding the variables declared in it, in order of declaration.
+typedef struct LoxEnv {
+    LoxObject object;
+    struct LoxEnv *enclosing;
+    int count;
+    LoxValue slots[];
+} LoxEnv;
+
+typedef struct LoxFunction LoxFunction;
+
+// The C function generated for a Lox function. It is passed the closure being
+// called and its arguments, which the caller keeps alive.
+typedef LoxValue (*LoxCode)(LoxFunction *function, LoxValue *arguments);
+
+typedef struct {
+    const char *name;
+    int arity;
+    LoxCode code;
+} LoxFunctionInfo;
+
+struct LoxFunction {
+    LoxObject object;
+    const LoxFunctionInfo *info;
+    LoxEnv *env;
+    // Initializers return 'this', which is the only variable of the scope a
+    // method is bound in.
+    bool is_initializer;
+};
+
+typedef struct {
+    LoxObject object;
+    const char *name;
+    int arity;
+    LoxValue (*function)(LoxValue *arguments);
+} LoxNative;
+
+// Fields and methods, keyed by interned names. Names are only ever compared
+// by address.
+typedef struct {
+    LoxString *name;
+    LoxValue value;
+} LoxEntry;
+
+typedef struct {
+    LoxEntry *entries;
+    int count;
+    int capacity;
+} LoxTable;
+
+typedef struct LoxClass {
+    LoxObject object;
+    LoxString *name;
+    struct LoxClass *superclass;
+    LoxTable methods;
+} LoxClass;
+
+typedef struct {
+    LoxObject object;
+    LoxClass *klass;
+    LoxTable fields;
+} LoxInstance;
+
+typedef struct {
+    LoxObject object;
+    LoxValue *items;
+    size_t count;
+    size_t capacity;
+} LoxList;
+
+// The methods of lists, in the order of 'lox_list_methods'.
+typedef enum {
+    LOX_LIST_PUSH,
+    LOX_LIST_POP,
+    LOX_LIST_INSERT,
+    LOX_LIST_REMOVE,
+    LOX_LIST_LEN,
+    LOX_LIST_MAP,
+    LOX_LIST_FILTER,
+    LOX_LIST_REDUCE,
+    LOX_LIST_SORT,
+} LoxListMethodKind;
+
+static const struct {
+    const char *name;
+    int arity;
+} lox_list_methods[] = {
+    {"push", 1}, {"pop", 0},    {"insert", 2}, {"remove", 1}, {"len", 0},
+    {"map", 1},  {"filter", 1}, {"reduce", 2}, {"sort", 1},
+};
+
+// A method of a list, which is called like a function.
+typedef struct {
+    LoxObject object;
+    LoxList *list;
+    LoxListMethodKind kind;
+} LoxListMethod;
+
+// An entry of a map: the key it was first inserted with, what it is looked up
+// by, which for an instance is what its 'hash' method returned, the hash of
+// that and the value. Removed entries stay behind as holes until the map is
+// compacted.
+typedef struct {
+    LoxValue key;
+    LoxValue lookup;
+    uint32_t hash;
+    bool removed;
+    LoxValue value;
+} LoxMapEntry;
+
+// The entries of a map in the order they were first inserted, found through
+// an open-addressed index of their positions plus one, where 0 is an empty
+// slot.
+typedef struct {
+    LoxObject object;
+    LoxMapEntry *entries;
+    size_t count;
+    size_t live;
+    size_t capacity;
+    size_t *index;
+    size_t index_capacity;
+} LoxMap;
+
+// The methods of maps, in the order of 'lox_map_methods'.
+typedef enum {
+    LOX_MAP_HAS,
+    LOX_MAP_REMOVE,
+    LOX_MAP_KEYS,
+    LOX_MAP_VALUES,
+    LOX_MAP_LEN,
+} LoxMapMethodKind;
+
+static const struct {
+    const char *name;
+    int arity;
+} lox_map_methods[] = {
+    {"has", 1}, {"remove", 1}, {"keys", 0}, {"values", 0}, {"len", 0},
+};
+
+// A method of a map, which is called like a function.
+typedef struct {
+    LoxObject object;
+    LoxMap *map;
+    LoxMapMethodKind kind;
+} LoxMapMethod;
+
+// 'SortedMap' or 'SortedSet', which create an empty sorted map or set when
+// called.
+typedef struct {
+    LoxObject object;
+    bool is_set;
+} LoxSortedClass;
+
+// A sorted map or set, whose keys are kept in order in an array. Sets have no
+// values.
+typedef struct {
+    LoxObject object;
+    bool is_set;
+    LoxValue *keys;
+    LoxValue *values;
+    size_t count;
+    size_t capacity;
+} LoxSorted;
+
+// The methods of sorted maps and sets, in the order of 'lox_sorted_methods'.
+typedef enum {
+    LOX_SORTED_ADD,
+    LOX_SORTED_HAS,
+    LOX_SORTED_REMOVE,
+    LOX_SORTED_KEYS,
+    LOX_SORTED_VALUES,
+    LOX_SORTED_LEN,
+    LOX_SORTED_RANGE,
+} LoxSortedMethodKind;
+
+static const struct {
+    const char *name;
+    int arity;
+} lox_sorted_methods[] = {
+    {"add", 1}, {"has", 1}, {"remove", 1}, {"keys", 0},
+    {"values", 0}, {"len", 0}, {"range", 2},
+};
+
+// A method of a sorted map or set, which is called like a function.
+typedef struct {
+    LoxObject object;
+    LoxSorted *sorted;
+    LoxSortedMethodKind kind;
+} LoxSortedMethod;
+
+// The temporaries and current scope of a running function, which the
+// collector treats as roots. 'function' is null for the top level.
+typedef struct LoxFrame {
+    struct LoxFrame *previous;
+    LoxFunction *function;
+    LoxEnv *env;
+    LoxValue *temporaries;
+    int count;
+} LoxFrame;
+
+// A string constant of the program.
+typedef struct {
+    const char *chars;
+    size_t length;
+} LoxConstant;
+
+static struct {
+    LoxObject *objects;
+    size_t allocated;
+    size_t next_gc;
+    LoxObject **gray;
+    int gray_count;
+    int gray_capacity;
+
+    LoxFrame *frames;
+    // The calls in progress, innermost last: the name of the function
+    // running, the line of the call that started it and the number of calls
+    // in tail position it has made in place of returning.
+    int depth;
+    struct {
+        const char *name;
+        int line;
+        int tail_calls;
+    } calls[LOX_MAX_DEPTH];
+
+    // The script the program was translated from, or NULL if it didn't
+    // come from a file.
+    const char *file;
+    LoxValue *globals;
+    const char *const *global_names;
+    int global_count;
+    LoxValue *constants;
+    int constant_count;
+    LoxString **names;
+    int name_count;
+    // The name of initializers.
+    LoxString *init;
+    // The name of the method instances used as map keys are hashed with.
+    LoxString *hash;
+
+    // A call in tail position, made by 'lox_call_function' once the
+    // function making it has returned: the callee followed by 'tail_count'
+    // arguments.
+    bool tail_pending;
+    int tail_count;
+    LoxValue tail_call[LOX_MAX_ARGUMENTS + 1];
+
+    // Objects the runtime itself is holding on to while it allocates.
+    LoxValue roots[8];
+    int root_count;
+} lox;
+
+static inline LoxValue lox_nil(void) {
+    LoxValue value = {LOX_NIL, {.number = 0}};
+    return value;
+}
+
+static inline LoxValue lox_bool(bool boolean) {
+    LoxValue value = {LOX_BOOL, {.boolean = boolean}};
+    return value;
+}
+
+static inline LoxValue lox_number(double number) {
+    LoxValue value = {LOX_NUMBER, {.number = number}};
+    return value;
+}
+
+static inline LoxValue lox_object(void *object) {
+    LoxValue value = {LOX_OBJECT, {.object = (LoxObject *)object}};
+    return value;
+}
+
+static inline bool lox_is(LoxValue value, LoxObjectType type) {
+    return value.type == LOX_OBJECT && value.as.object->type == type;
+}
+
+// 'nil' and 'false' are falsey, everything else is truthy.
+static inline bool lox_truthy(LoxValue value) {
+    return !(value.type == LOX_NIL || (value.type == LOX_BOOL && !value.as.boolean));
+}
+
+// Print where a frame of a runtime error's trace is running.
+static inline void lox_trace_line(int line) {
+    if (lox.file != NULL) {
+        fprintf(stderr, "[%s, line %d] in ", lox.file, line);
+    } else {
+        fprintf(stderr, "[line %d] in ", line);
+    }
+}
+
+static inline void lox_error(int line, int column, const char *format, ...) {
+    va_list arguments;
+    fflush(stdout);
+    fprintf(stderr, "Runtime Error [ln: %d, col: %d]: ", line, column);
+    va_start(arguments, format);
+    vfprintf(stderr, format, arguments);
+    va_end(arguments);
+    fputc('\n', stderr);
+
+    // The calls in progress, innermost first, each at the line it is running.
+    for (int i = lox.depth - 1; i >= 0; i--) {
+        lox_trace_line(line);
+        fprintf(stderr, "%s()", lox.calls[i].name);
+        int tail_calls = lox.calls[i].tail_calls;
+        if (tail_calls == 1) {
+            fprintf(stderr, " (1 tail call omitted)");
+        } else if (tail_calls > 1) {
+            fprintf(stderr, " (%d tail calls omitted)", tail_calls);
+        }
+        fputc('\n', stderr);
+        line = lox.calls[i].line;
+    }
+    lox_trace_line(line);
+    fprintf(stderr, "script\n");
+    exit(1);
+}
+
+static inline void *lox_reallocate(void *pointer, size_t size) {
+    void *result = realloc(pointer, size);
+    if (result == NULL) {
+        fflush(stdout);
+        fprintf(stderr, "out of memory\n");
+        exit(1);
+    }
+    return result;
+}
+
+static inline void lox_push_root(LoxValue value) {
+    lox.roots[lox.root_count++] = value;
+}
+
+static inline void lox_pop_root(void) {
+    lox.root_count--;
+}
+
+// Garbage collection.
+
+static inline void lox_mark_object(LoxObject *object) {
+    if (object == NULL || object->marked) {
+        return;
+    }
+    object->marked = true;
+    if (lox.gray_count == lox.gray_capacity) {
+        lox.gray_capacity = lox.gray_capacity < 64 ? 64 : lox.gray_capacity * 2;
+        // The gray stack is not counted as allocated, so growing it never
+        // starts a collection.
+        lox.gray = lox_reallocate(lox.gray, sizeof(LoxObject *) * lox.gray_capacity);
+    }
+    lox.gray[lox.gray_count++] = object;
+}
+
+static inline void lox_mark_value(LoxValue value) {
+    if (value.type == LOX_OBJECT) {
+        lox_mark_object(value.as.object);
+    }
+}
+
+static inline void lox_mark_table(LoxTable *table) {
+    for (int i = 0; i < table->count; i++) {
+        lox_mark_object(&table->entries[i].name->object);
+        lox_mark_value(table->entries[i].value);
+    }
+}
+
+static inline void lox_blacken(LoxObject *object) {
+    switch (object->type) {
+    case LOX_STRING:
+    case LOX_NATIVE:
+        break;
+    case LOX_ENV: {
+        LoxEnv *env = (LoxEnv *)object;
+        lox_mark_object((LoxObject *)env->enclosing);
+        for (int i = 0; i < env->count; i++) {
+            lox_mark_value(env->slots[i]);
+        }
+        break;
+    }
+    case LOX_FUNCTION:
+        lox_mark_object((LoxObject *)((LoxFunction *)object)->env);
+        break;
+    case LOX_CLASS: {
+        LoxClass *klass = (LoxClass *)object;
+        lox_mark_object(&klass->name->object);
+        lox_mark_object((LoxObject *)klass->superclass);
+        lox_mark_table(&klass->methods);
+        break;
+    }
+    case LOX_INSTANCE: {
+        LoxInstance *instance = (LoxInstance *)object;
+        lox_mark_object((LoxObject *)instance->klass);
+        lox_mark_table(&instance->fields);
+        break;
+    }
+    case LOX_LIST: {
+        LoxList *list = (LoxList *)object;
+        for (size_t i = 0; i < list->count; i++) {
+            lox_mark_value(list->items[i]);
+        }
+        break;
+    }
+    case LOX_LIST_METHOD:
+        lox_mark_object((LoxObject *)((LoxListMethod *)object)->list);
+        break;
+    case LOX_MAP: {
+        LoxMap *map = (LoxMap *)object;
+        for (size_t i = 0; i < map->count; i++) {
+            if (!map->entries[i].removed) {
+                lox_mark_value(map->entries[i].key);
+                lox_mark_value(map->entries[i].lookup);
+                lox_mark_value(map->entries[i].value);
+            }
+        }
+        break;
+    }
+    case LOX_MAP_METHOD:
+        lox_mark_object((LoxObject *)((LoxMapMethod *)object)->map);
+        break;
+    case LOX_SORTED_CLASS:
+        break;
+    case LOX_SORTED: {
+        LoxSorted *sorted = (LoxSorted *)object;
+        for (size_t i = 0; i < sorted->count; i++) {
+            lox_mark_value(sorted->keys[i]);
+            if (!sorted->is_set) {
+                lox_mark_value(sorted->values[i]);
+            }
+        }
+        break;
+    }
+    case LOX_SORTED_METHOD:
+        lox_mark_object((LoxObject *)((LoxSortedMethod *)object)->sorted);
+        break;
+    }
+}
+
+static inline void lox_free(LoxObject *object) {
+    lox.allocated -= object->size;
+    switch (object->type) {
+    case LOX_CLASS:
+        lox.allocated -= sizeof(LoxEntry) * ((LoxClass *)object)->methods.capacity;
+        free(((LoxClass *)object)->methods.entries);
+        break;
+    case LOX_INSTANCE:
+        lox.allocated -= sizeof(LoxEntry) * ((LoxInstance *)object)->fields.capacity;
+        free(((LoxInstance *)object)->fields.entries);
+        break;
+    case LOX_LIST:
+        lox.allocated -= sizeof(LoxValue) * ((LoxList *)object)->capacity;
+        free(((LoxList *)object)->items);
+        break;
+    case LOX_MAP: {
+        LoxMap *map = (LoxMap *)object;
+        lox.allocated -= sizeof(LoxMapEntry) * map->capacity;
+        lox.allocated -= sizeof(size_t) * map->index_capacity;
+        free(map->entries);
+        free(map->index);
+        break;
+    }
+    case LOX_SORTED: {
+        LoxSorted *sorted = (LoxSorted *)object;
+        lox.allocated -= sizeof(LoxValue) * sorted->capacity * (sorted->is_set ? 1 : 2);
+        free(sorted->keys);
+        free(sorted->values);
+        break;
+    }
+    default:
+        break;
+    }
+    free(object);
+}
+
+static inline void lox_collect(void) {
+    for (LoxFrame *frame = lox.frames; frame != NULL; frame = frame->previous) {
+        lox_mark_object((LoxObject *)frame->function);
+        lox_mark_object((LoxObject *)frame->env);
+        for (int i = 0; i < frame->count; i++) {
+            lox_mark_value(frame->temporaries[i]);
+        }
+    }
+    for (int i = 0; i < lox.global_count; i++) {
+        lox_mark_value(lox.globals[i]);
+    }
+    for (int i = 0; i < lox.constant_count; i++) {
+        lox_mark_value(lox.constants[i]);
+    }
+    for (int i = 0; i < lox.name_count; i++) {
+        lox_mark_object(&lox.names[i]->object);
+    }
+    for (int i = 0; i < lox.root_count; i++) {
+        lox_mark_value(lox.roots[i]);
+    }
+    if (lox.tail_pending) {
+        for (int i = 0; i <= lox.tail_count; i++) {
+            lox_mark_value(lox.tail_call[i]);
+        }
+    }
+
+    while (lox.gray_count > 0) {
+        lox_blacken(lox.gray[--lox.gray_count]);
+    }
+
+    LoxObject **link = &lox.objects;
+    while (*link != NULL) {
+        LoxObject *object = *link;
+        if (object->marked) {
+            object->marked = false;
+            link = &object->next;
+        } else {
+            *link = object->next;
+            lox_free(object);
+        }
+    }
+
+    lox.next_gc = lox.allocated * 2 < LOX_MIN_HEAP ? LOX_MIN_HEAP : lox.allocated * 2;
+}
+
+// Allocate an object, first collecting garbage if enough has been allocated
+// since the last collection. Every object the caller still needs must be
+// reachable from the roots.
+static inline void *lox_allocate(LoxObjectType type, size_t size) {
+#ifdef LOX_GC_STRESS
+    lox_collect();
+#else
+    if (lox.allocated + size > lox.next_gc) {
+        lox_collect();
+    }
+#endif
+    LoxObject *object = lox_reallocate(NULL, size);
+    object->type = type;
+    object->marked = false;
+    object->size = size;
+    object->next = lox.objects;
+    lox.objects = object;
+    lox.allocated += size;
+    return object;
+}
+
+// Objects.
+
+static inline LoxString *lox_new_string(const char *chars, size_t length) {
+    LoxString *string = lox_allocate(LOX_STRING, sizeof(LoxString) + length + 1);
+    string->length = length;
+    memcpy(string->chars, chars, length);
+    string->chars[length] = '\0';
+    return string;
+}
+
+static inline LoxEnv *lox_new_env(LoxEnv *enclosing, int count) {
+    LoxEnv *env = lox_allocate(LOX_ENV, sizeof(LoxEnv) + sizeof(LoxValue) * count);
+    env->enclosing = enclosing;
+    env->count = count;
+    for (int i = 0; i < count; i++) {
+        env->slots[i] = lox_nil();
+    }
+    return env;
+}
+
+// The scope 'depth' levels up from 'env'.
+static inline LoxEnv *lox_env_at(LoxEnv *env, int depth) {
+    while (depth-- > 0) {
+        env = env->enclosing;
+    }
+    return env;
+}
+
+static inline LoxValue lox_closure(const LoxFunctionInfo *info, LoxEnv *env) {
+    LoxFunction *function = lox_allocate(LOX_FUNCTION, sizeof(LoxFunction));
+    function->info = info;
+    function->env = env;
+    function->is_initializer = false;
+    return lox_object(function);
+}
+
+static inline LoxValue *lox_table_find(LoxTable *table, LoxString *name) {
+    for (int i = 0; i < table->count; i++) {
+        if (table->entries[i].name == name) {
+            return &table->entries[i].value;
+        }
+    }
+    return NULL;
+}
+
+static inline void lox_table_set(LoxTable *table, LoxString *name, LoxValue value) {
+    LoxValue *existing = lox_table_find(table, name);
+    if (existing != NULL) {
+        *existing = value;
+        return;
+    }
+    if (table->count == table->capacity) {
+        int capacity = table->capacity < 4 ? 4 : table->capacity * 2;
+        lox.allocated += sizeof(LoxEntry) * (capacity - table->capacity);
+        table->entries = lox_reallocate(table->entries, sizeof(LoxEntry) * capacity);
+        table->capacity = capacity;
+    }
+    table->entries[table->count].name = name;
+    table->entries[table->count].value = value;
+    table->count++;
+}
+
+// Look up the method 'name' in 'klass' or the closest superclass that
+// defines it.
+static inline LoxFunction *lox_find_method(LoxClass *klass, LoxString *name) {
+    for (; klass != NULL; klass = klass->superclass) {
+        LoxValue *method = lox_table_find(&klass->methods, name);
+        if (method != NULL) {
+            return (LoxFunction *)method->as.object;
+        }
+    }
+    return NULL;
+}
+
+// Create a copy of 'method' whose closure binds 'this' to 'instance'. Both
+// must be reachable from the roots.
+static inline LoxValue lox_bind(LoxFunction *method, LoxValue instance) {
+    LoxEnv *env = lox_new_env(method->env, 1);
+    env->slots[0] = instance;
+    lox_push_root(lox_object(env));
+    LoxValue bound = lox_closure(method->info, env);
+    lox_pop_root();
+    ((LoxFunction *)bound.as.object)->is_initializer = method->is_initializer;
+    return bound;
+}
+
+// An empty list with room for 'capacity' elements.
+static inline LoxList *lox_new_list(size_t capacity) {
+    LoxList *list = lox_allocate(LOX_LIST, sizeof(LoxList));
+    list->items = NULL;
+    list->count = 0;
+    list->capacity = 0;
+    if (capacity > 0) {
+        lox.allocated += sizeof(LoxValue) * capacity;
+        list->items = lox_reallocate(NULL, sizeof(LoxValue) * capacity);
+        list->capacity = capacity;
+    }
+    return list;
+}
+
+// Make room for one more element in 'list'. Like tables, lists grow without
+// starting a collection.
+static inline void lox_list_grow(LoxList *list) {
+    if (list->count < list->capacity) {
+        return;
+    }
+    size_t capacity = list->capacity < 8 ? 8 : list->capacity * 2;
+    lox.allocated += sizeof(LoxValue) * (capacity - list->capacity);
+    list->items = lox_reallocate(list->items, sizeof(LoxValue) * capacity);
+    list->capacity = capacity;
+}
+
+static inline void lox_list_push(LoxList *list, LoxValue value) {
+    lox_list_grow(list);
+    list->items[list->count++] = value;
+}
+
+// Printing.
+
+// Format 'number' like Rust does: the shortest digits that read back as the
+// same number, written out in full rather than with an exponent.
+static inline void lox_write_number(FILE *file, double number) {
+    if (number != number) {
+        fputs("NaN", file);
+        return;
+    }
+    if (number == 1.0 / 0.0 || number == -1.0 / 0.0) {
+        fputs(number > 0 ? "inf" : "-inf", file);
+        return;
+    }
+
+    char buffer[32];
+    for (int precision = 1; precision <= 17; precision++) {
+        snprintf(buffer, sizeof(buffer), "%.*e", precision - 1, number);
+        if (strtod(buffer, NULL) == number) {
+            break;
+        }
+    }
+
+    // 'buffer' is '[-]d[.ddd]e(+|-)dd'.
+    char *mantissa = buffer;
+    if (*mantissa == '-') {
+        fputc('-', file);
+        mantissa++;
+    }
+    char *exponent_start = strchr(mantissa, 'e');
+    int exponent = atoi(exponent_start + 1);
+    char digits[20];
+    int count = 0;
+    for (char *c = mantissa; c < exponent_start; c++) {
+        if (*c != '.') {
+            digits[count++] = *c;
+        }
+    }
+    while (count > 1 && digits[count - 1] == '0') {
+        count--;
+    }
+
+    if (exponent < 0) {
+        fputs("0.", file);
+        for (int i = 0; i < -exponent - 1; i++) {
+            fputc('0', file);
+        }
+        fwrite(digits, 1, count, file);
+    } else if (exponent + 1 >= count) {
+        fwrite(digits, 1, count, file);
+        for (int i = 0; i < exponent + 1 - count; i++) {
+            fputc('0', file);
+        }
+    } else {
+        fwrite(digits, 1, exponent + 1, file);
+        fputc('.', file);
+        fwrite(digits + exponent + 1, 1, count - exponent - 1, file);
+    }
+}
+
+// The lists and maps being written, innermost first, so that a list containing
+// itself is written as '[...]' and a map as '{...}' rather than forever.
+typedef struct LoxOpen {
+    const LoxObject *object;
+    const struct LoxOpen *enclosing;
+} LoxOpen;
+
+static inline void lox_write(FILE *file, LoxValue value, const LoxOpen *open);
+
+static inline bool lox_is_open(const LoxObject *object, const LoxOpen *open) {
+    for (const LoxOpen *enclosing = open; enclosing != NULL; enclosing = enclosing->enclosing) {
+        if (enclosing->object == object) {
+            return true;
+        }
+    }
+    return false;
+}
+
+static inline void lox_write_list(FILE *file, const LoxList *list, const LoxOpen *open) {
+    if (lox_is_open(&list->object, open)) {
+        fputs("[...]", file);
+        return;
+    }
+
+    LoxOpen inner = {&list->object, open};
+    fputc('[', file);
+    for (size_t i = 0; i < list->count; i++) {
+        if (i > 0) {
+            fputs(", ", file);
+        }
+        lox_write(file, list->items[i], &inner);
+    }
+    fputc(']', file);
+}
+
+static inline void lox_write_map(FILE *file, const LoxMap *map, const LoxOpen *open) {
+    if (lox_is_open(&map->object, open)) {
+        fputs("{...}", file);
+        return;
+    }
+
+    LoxOpen inner = {&map->object, open};
+    bool first = true;
+    fputc('{', file);
+    for (size_t i = 0; i < map->count; i++) {
+        if (map->entries[i].removed) {
+            continue;
+        }
+        if (!first) {
+            fputs(", ", file);
+        }
+        first = false;
+        lox_write(file, map->entries[i].key, &inner);
+        fputs(": ", file);
+        lox_write(file, map->entries[i].value, &inner);
+    }
+    fputc('}', file);
+}
+
+static inline void lox_write_sorted(FILE *file, const LoxSorted *sorted, const LoxOpen *open) {
+    if (lox_is_open(&sorted->object, open)) {
+        fputs("{...}", file);
+        return;
+    }
+
+    LoxOpen inner = {&sorted->object, open};
+    fputc('{', file);
+    for (size_t i = 0; i < sorted->count; i++) {
+        if (i > 0) {
+            fputs(", ", file);
+        }
+        lox_write(file, sorted->keys[i], &inner);
+        if (!sorted->is_set) {
+            fputs(": ", file);
+            lox_write(file, sorted->values[i], &inner);
+        }
+    }
+    fputc('}', file);
+}
+
+static inline void lox_write(FILE *file, LoxValue value, const LoxOpen *open) {
+    switch (value.type) {
+    case LOX_NIL:
+    case LOX_UNDEFINED:
+        fputs("nil", file);
+        return;
+    case LOX_BOOL:
+        fputs(value.as.boolean ? "true" : "false", file);
+        return;
+    case LOX_NUMBER:
+        lox_write_number(file, value.as.number);
+        return;
+    case LOX_OBJECT:
+        break;
+    }
+
+    LoxObject *object = value.as.object;
+    switch (object->type) {
+    case LOX_STRING:
+        fwrite(((LoxString *)object)->chars, 1, ((LoxString *)object)->length, file);
+        break;
+    case LOX_ENV:
+        fputs("<env>", file);
+        break;
+    case LOX_FUNCTION:
+        fprintf(file, "<fn %s>", ((LoxFunction *)object)->info->name);
+        break;
+    case LOX_NATIVE:
+        fputs("<native fn>", file);
+        break;
+    case LOX_CLASS:
+        fputs(((LoxClass *)object)->name->chars, file);
+        break;
+    case LOX_INSTANCE:
+        fprintf(file, "%s instance", ((LoxInstance *)object)->klass->name->chars);
+        break;
+    case LOX_LIST:
+        lox_write_list(file, (LoxList *)object, open);
+        break;
+    case LOX_MAP:
+        lox_write_map(file, (LoxMap *)object, open);
+        break;
+    case LOX_SORTED_CLASS:
+        fputs(((LoxSortedClass *)object)->is_set ? "SortedSet" : "SortedMap", file);
+        break;
+    case LOX_SORTED:
+        lox_write_sorted(file, (LoxSorted *)object, open);
+        break;
+    case LOX_LIST_METHOD:
+    case LOX_MAP_METHOD:
+    case LOX_SORTED_METHOD:
+        fputs("<native fn>", file);
+        break;
+    }
+}
+
+static inline void lox_write_value(FILE *file, LoxValue value) {
+    lox_write(file, value, NULL);
+}
+
+static inline void lox_print(LoxValue value) {
+    lox_write_value(stdout, value);
+    fputc('\n', stdout);
+}
+
+// Operators.
+
+static inline LoxValue lox_negate(LoxValue value, int line, int column) {
+    if (value.type != LOX_NUMBER) {
+        lox_error(line, column, "operand must be a number");
+    }
+    return lox_number(-value.as.number);
+}
+
+static inline LoxValue lox_not(LoxValue value) {
+    return lox_bool(!lox_truthy(value));
+}
+
+// Numbers, strings and booleans compare by value, everything else by
+// identity.
+static inline bool lox_equal(LoxValue a, LoxValue b) {
+    if (a.type != b.type) {
+        return false;
+    }
+    switch (a.type) {
+    case LOX_NIL:
+    case LOX_UNDEFINED:
+        return true;
+    case LOX_BOOL:
+        return a.as.boolean == b.as.boolean;
+    case LOX_NUMBER:
+        return a.as.number == b.as.number;
+    case LOX_OBJECT:
+        break;
+    }
+    if (lox_is(a, LOX_STRING) && lox_is(b, LOX_STRING)) {
+        LoxString *x = (LoxString *)a.as.object;
+        LoxString *y = (LoxString *)b.as.object;
+        return x->length == y->length && memcmp(x->chars, y->chars, x->length) == 0;
+    }
+    return a.as.object == b.as.object;
+}
+
+static inline LoxValue lox_add(LoxValue a, LoxValue b, int line, int column) {
+    if (a.type == LOX_NUMBER && b.type == LOX_NUMBER) {
+        return lox_number(a.as.number + b.as.number);
+    }
+    if (!lox_is(a, LOX_STRING) || !lox_is(b, LOX_STRING)) {
+        lox_error(line, column, "operands must be two numbers or two strings");
+    }
+
+    // Both strings belong to the caller's temporaries, so they survive a
+    // collection while the result is allocated.
+    LoxString *x = (LoxString *)a.as.object;
+    LoxString *y = (LoxString *)b.as.object;
+    LoxString *result = lox_allocate(LOX_STRING, sizeof(LoxString) + x->length + y->length + 1);
+    result->length = x->length + y->length;
+    memcpy(result->chars, x->chars, x->length);
+    memcpy(result->chars + x->length, y->chars, y->length);
+    result->chars[result->length] = '\0';
+    return lox_object(result);
+}
+
+static inline void lox_check_numbers(LoxValue a, LoxValue b, int line, int column) {
+    if (a.type != LOX_NUMBER || b.type != LOX_NUMBER) {
+        lox_error(line, column, "operands must be numbers");
+    }
+}
+
+static inline LoxValue lox_subtract(LoxValue a, LoxValue b, int line, int column) {
+    lox_check_numbers(a, b, line, column);
+    return lox_number(a.as.number - b.as.number);
+}
+
+static inline LoxValue lox_multiply(LoxValue a, LoxValue b, int line, int column) {
+    lox_check_numbers(a, b, line, column);
+    return lox_number(a.as.number * b.as.number);
+}
+
+static inline LoxValue lox_divide(LoxValue a, LoxValue b, int line, int column) {
+    lox_check_numbers(a, b, line, column);
+    return lox_number(a.as.number / b.as.number);
+}
+
+static inline LoxValue lox_greater(LoxValue a, LoxValue b, int line, int column) {
+    lox_check_numbers(a, b, line, column);
+    return lox_bool(a.as.number > b.as.number);
+}
+
+static inline LoxValue lox_greater_equal(LoxValue a, LoxValue b, int line, int column) {
+    lox_check_numbers(a, b, line, column);
+    return lox_bool(a.as.number >= b.as.number);
+}
+
+static inline LoxValue lox_less(LoxValue a, LoxValue b, int line, int column) {
+    lox_check_numbers(a, b, line, column);
+    return lox_bool(a.as.number < b.as.number);
+}
+
+static inline LoxValue lox_less_equal(LoxValue a, LoxValue b, int line, int column) {
+    lox_check_numbers(a, b, line, column);
+    return lox_bool(a.as.number <= b.as.number);
+}
+
+// Variables.
+
+static inline LoxValue lox_get_global(int global, int line, int column) {
+    if (lox.globals[global].type == LOX_UNDEFINED) {
+        lox_error(line, column, "undefined variable '%s'", lox.global_names[global]);
+    }
+    return lox.globals[global];
+}
+
+static inline void lox_set_global(int global, LoxValue value, int line, int column) {
+    if (lox.globals[global].type == LOX_UNDEFINED) {
+        lox_error(line, column, "undefined variable '%s'", lox.global_names[global]);
+    }
+    lox.globals[global] = value;
+}
+
+static inline void lox_define_global(int global, LoxValue value) {
+    lox.globals[global] = value;
+}
+
+// Functions and calls.
+
+static inline void lox_enter(LoxFrame *frame, LoxFunction *function, LoxValue *temporaries,
+                             int count) {
+    for (int i = 0; i < count; i++) {
+        temporaries[i] = lox_nil();
+    }
+    frame->previous = lox.frames;
+    frame->function = function;
+    frame->env = function != NULL ? function->env : NULL;
+    frame->temporaries = temporaries;
+    frame->count = count;
+    lox.frames = frame;
+}
+
+// Leave 'frame', returning 'value' or, from an initializer, 'this'.
+static inline LoxValue lox_return(LoxFrame *frame, LoxValue value) {
+    lox.frames = frame->previous;
+    if (frame->function != NULL && frame->function->is_initializer) {
+        return frame->function->env->slots[0];
+    }
+    return value;
+}
+
+static inline void lox_check_arity(int arity, int count, int line, int column) {
+    if (arity != count) {
+        lox_error(line, column, "expected %d arguments but got %d", arity, count);
+    }
+}
+
+static inline LoxValue lox_call_function(LoxFunction *function, LoxValue *arguments, int line,
+                                         int column) {
+    if (lox.depth + 1 >= LOX_MAX_DEPTH) {
+        lox_error(line, column, "stack overflow");
+    }
+    lox.calls[lox.depth].name = function->info->name;
+    lox.calls[lox.depth].line = line;
+    lox.calls[lox.depth].tail_calls = 0;
+    lox.depth++;
+    LoxValue result = function->info->code(function, arguments);
+
+    // Calls in tail position are made here in turn, so that they don't nest.
+    // The callee and arguments of each are kept alive by a frame of their
+    // own.
+    if (lox.tail_pending) {
+        LoxValue call[LOX_MAX_ARGUMENTS + 1];
+        LoxFrame frame;
+        lox_enter(&frame, NULL, call, LOX_MAX_ARGUMENTS + 1);
+        while (lox.tail_pending) {
+            lox.tail_pending = false;
+            frame.count = lox.tail_count + 1;
+            memcpy(call, lox.tail_call, frame.count * sizeof(LoxValue));
+            function = (LoxFunction *)call[0].as.object;
+            lox.calls[lox.depth - 1].name = function->info->name;
+            lox.calls[lox.depth - 1].tail_calls++;
+            result = function->info->code(function, call + 1);
+        }
+        lox.frames = frame.previous;
+    }
+
+    lox.depth--;
+    return result;
+}
+
+// Replace the class 'callee' with a new instance of it, called with 'count'
+// arguments. If the class has an initializer, the instance is replaced in
+// turn by the initializer bound to it, which is left for the caller to call,
+// and true is returned.
+static inline bool lox_instantiate(LoxValue *callee, int count, int line, int column) {
+    LoxClass *klass = (LoxClass *)callee->as.object;
+    LoxFunction *init = lox_find_method(klass, lox.init);
+    lox_check_arity(init != NULL ? init->info->arity : 0, count, line, column);
+
+    LoxInstance *instance = lox_allocate(LOX_INSTANCE, sizeof(LoxInstance));
+    instance->klass = klass;
+    instance->fields.entries = NULL;
+    instance->fields.count = 0;
+    instance->fields.capacity = 0;
+    // The instance keeps the class alive, and the bound initializer the
+    // instance.
+    *callee = lox_object(instance);
+    if (init == NULL) {
+        return false;
+    }
+    *callee = lox_bind(init, *callee);
+    return true;
+}
+
+static inline LoxValue lox_call_list_method(LoxListMethod *method, LoxValue *arguments, int line,
+                                            int column);
+static inline LoxValue lox_call_map_method(LoxMapMethod *method, LoxValue *arguments, int line,
+                                           int column);
+static inline LoxValue lox_call_sorted_method(LoxSortedMethod *method, LoxValue *arguments,
+                                              int line, int column);
+
+// Call 'callee' with the 'count' arguments following it. The callee and the
+// arguments are the caller's temporaries.
+static inline LoxValue lox_call(LoxValue *callee, int count, int line, int column) {
+    LoxValue *arguments = callee + 1;
+    if (callee->type != LOX_OBJECT) {
+        lox_error(line, column, "can only call functions and classes");
+    }
+
+    switch (callee->as.object->type) {
+    case LOX_FUNCTION: {
+        LoxFunction *function = (LoxFunction *)callee->as.object;
+        lox_check_arity(function->info->arity, count, line, column);
+        return lox_call_function(function, arguments, line, column);
+    }
+    case LOX_NATIVE: {
+        LoxNative *native = (LoxNative *)callee->as.object;
+        lox_check_arity(native->arity, count, line, column);
+        return native->function(arguments);
+    }
+    case LOX_LIST_METHOD: {
+        LoxListMethod *method = (LoxListMethod *)callee->as.object;
+        lox_check_arity(lox_list_methods[method->kind].arity, count, line, column);
+        return lox_call_list_method(method, arguments, line, column);
+    }
+    case LOX_MAP_METHOD: {
+        LoxMapMethod *method = (LoxMapMethod *)callee->as.object;
+        lox_check_arity(lox_map_methods[method->kind].arity, count, line, column);
+        return lox_call_map_method(method, arguments, line, column);
+    }
+    case LOX_SORTED_METHOD: {
+        LoxSortedMethod *method = (LoxSortedMethod *)callee->as.object;
+        lox_check_arity(lox_sorted_methods[method->kind].arity, count, line, column);
+        return lox_call_sorted_method(method, arguments, line, column);
+    }
+    case LOX_SORTED_CLASS: {
+        lox_check_arity(0, count, line, column);
+        LoxSorted *sorted = lox_allocate(LOX_SORTED, sizeof(LoxSorted));
+        sorted->is_set = ((LoxSortedClass *)callee->as.object)->is_set;
+        sorted->keys = NULL;
+        sorted->values = NULL;
+        sorted->count = 0;
+        sorted->capacity = 0;
+        return lox_object(sorted);
+    }
+    case LOX_CLASS:
+        if (!lox_instantiate(callee, count, line, column)) {
+            return *callee;
+        }
+        return lox_call_function((LoxFunction *)callee->as.object, arguments, line, column);
+    default:
+        lox_error(line, column, "can only call functions and classes");
+        return lox_nil();
+    }
+}
+
+// Leave 'frame' to call 'callee' with the 'count' arguments following it, as
+// 'lox_call' does. A Lox function is called by 'lox_call_function' once the
+// returning function's C frame is gone.
+static inline LoxValue lox_tail_call(LoxFrame *frame, LoxValue *callee, int count, int line,
+                                     int column) {
+    // An initializer returns the new instance, so it is left to the caller in
+    // the same way.
+    if (lox_is(*callee, LOX_CLASS)) {
+        if (!lox_instantiate(callee, count, line, column)) {
+            return lox_return(frame, *callee);
+        }
+    } else if (!lox_is(*callee, LOX_FUNCTION)) {
+        return lox_return(frame, lox_call(callee, count, line, column));
+    }
+
+    LoxFunction *function = (LoxFunction *)callee->as.object;
+    lox_check_arity(function->info->arity, count, line, column);
+    memcpy(lox.tail_call, callee, (count + 1) * sizeof(LoxValue));
+    lox.tail_count = count;
+    lox.tail_pending = true;
+    return lox_return(frame, lox_nil());
+}
+
+// Lists. Indices and slice bounds count as in 'list.rs', and 'sort' compares
+// the same pairs in the same order as the other backends.
+
+// A list of the 'count' values of 'elements', which must be reachable from
+// the roots.
+static inline LoxValue lox_list(const LoxValue *elements, size_t count) {
+    LoxList *list = lox_new_list(count);
+    if (count > 0) {
+        memcpy(list->items, elements, sizeof(LoxValue) * count);
+    }
+    list->count = count;
+    return lox_object(list);
+}
+
+static inline LoxList *lox_as_list(LoxValue object, int line, int column) {
+    if (!lox_is(object, LOX_LIST)) {
+        lox_error(line, column, "can only index lists and maps");
+    }
+    return (LoxList *)object.as.object;
+}
+
+static inline bool lox_is_integer(double number) {
+    if (number != number || number == 1.0 / 0.0 || number == -1.0 / 0.0) {
+        return false;
+    }
+    // Numbers this large have no fraction.
+    if (number >= 9007199254740992.0 || number <= -9007199254740992.0) {
+        return true;
+    }
+    return (double)(long long)number == number;
+}
+
+// The element 'index' refers to in a list of 'count' elements, counting back
+// from the end if it is negative. 'insert' also allows the end of the list.
+static inline size_t lox_list_index(LoxValue index, size_t count, bool insert, int line,
+                                    int column) {
+    if (index.type != LOX_NUMBER || !lox_is_integer(index.as.number)) {
+        lox_error(line, column, "list index must be an integer");
+    }
+    double position = index.as.number;
+    if (insert && position == (double)count) {
+        return count;
+    }
+    if (position < 0) {
+        position += (double)count;
+    }
+    if (position < 0 || position >= (double)count) {
+        lox_error(line, column, "list index out of range");
+    }
+    return (size_t)position;
+}
+
+// A slice bound, where 'nil' stands for 'missing'. Bounds outside the list
+// are clamped to it.
+static inline size_t lox_slice_bound(LoxValue bound, size_t count, size_t missing, int line,
+                                     int column) {
+    if (bound.type == LOX_NIL) {
+        return missing;
+    }
+    if (bound.type != LOX_NUMBER || !lox_is_integer(bound.as.number)) {
+        lox_error(line, column, "slice bounds must be integers");
+    }
+    double position = bound.as.number;
+    if (position < 0) {
+        position += (double)count;
+    }
+    if (position < 0) {
+        return 0;
+    }
+    return position > (double)count ? count : (size_t)position;
+}
+
+static inline LoxValue lox_map_get(LoxMap *map, LoxValue key, int line, int column);
+static inline void lox_map_set(LoxMap *map, LoxValue key, LoxValue value, int line, int column);
+static inline bool lox_is_sorted_map(LoxValue object);
+static inline LoxValue lox_sorted_get(LoxSorted *sorted, LoxValue key, int line, int column);
+static inline void lox_sorted_set(LoxSorted *sorted, LoxValue key, LoxValue value, int line,
+                                  int column);
+
+static inline LoxValue lox_get_index(LoxValue object, LoxValue index, int line, int column) {
+    if (lox_is(object, LOX_MAP)) {
+        return lox_map_get((LoxMap *)object.as.object, index, line, column);
+    }
+    if (lox_is_sorted_map(object)) {
+        return lox_sorted_get((LoxSorted *)object.as.object, index, line, column);
+    }
+    LoxList *list = lox_as_list(object, line, column);
+    return list->items[lox_list_index(index, list->count, false, line, column)];
+}
+
+static inline LoxValue lox_set_index(LoxValue object, LoxValue index, LoxValue value, int line,
+                                     int column) {
+    if (lox_is(object, LOX_MAP)) {
+        lox_map_set((LoxMap *)object.as.object, index, value, line, column);
+        return value;
+    }
+    if (lox_is_sorted_map(object)) {
+        lox_sorted_set((LoxSorted *)object.as.object, index, value, line, column);
+        return value;
+    }
+    LoxList *list = lox_as_list(object, line, column);
+    list->items[lox_list_index(index, list->count, false, line, column)] = value;
+    return value;
+}
+
+// The list 'object' is one of the caller's temporaries, so it survives
+// allocating the slice.
+static inline LoxValue lox_get_slice(LoxValue object, LoxValue start, LoxValue end, int line,
+                                     int column) {
+    if (!lox_is(object, LOX_LIST)) {
+        lox_error(line, column, "can only slice lists");
+    }
+    LoxList *list = (LoxList *)object.as.object;
+    size_t from = lox_slice_bound(start, list->count, 0, line, column);
+    size_t to = lox_slice_bound(end, list->count, list->count, line, column);
+    if (to < from) {
+        to = from;
+    }
+    LoxList *slice = lox_new_list(to - from);
+    if (to > from) {
+        memcpy(slice->items, list->items + from, sizeof(LoxValue) * (to - from));
+    }
+    slice->count = to - from;
+    return lox_object(slice);
+}
+
+// The method 'name' of 'object', which is one of the caller's temporaries.
+static inline LoxValue lox_list_method(LoxValue object, int name, int line, int column) {
+    for (size_t kind = 0; kind < sizeof(lox_list_methods) / sizeof(lox_list_methods[0]); kind++) {
+        if (strcmp(lox.names[name]->chars, lox_list_methods[kind].name) == 0) {
+            LoxListMethod *method = lox_allocate(LOX_LIST_METHOD, sizeof(LoxListMethod));
+            method->list = (LoxList *)object.as.object;
+            method->kind = (LoxListMethodKind)kind;
+            return lox_object(method);
+        }
+    }
+    lox_error(line, column, "undefined property '%s'", lox.names[name]->chars);
+    return lox_nil();
+}
+
+// Call 'function' with the 'count' values of 'arguments' for a list or map
+// method.
+// The call is kept alive by a frame of its own, but the caller must keep the
+// result alive itself.
+static inline LoxValue lox_call_back(LoxValue function, const LoxValue *arguments, int count,
+                                     int line, int column) {
+    LoxValue call[3];
+    LoxFrame frame;
+    lox_enter(&frame, NULL, call, 3);
+    call[0] = function;
+    if (count > 0) {
+        memcpy(call + 1, arguments, sizeof(LoxValue) * count);
+    }
+    LoxValue result = lox_call(call, count, line, column);
+    lox.frames = frame.previous;
+    return result;
+}
+
+// Sort 'items' with a stable, bottom-up merge sort, asking 'comparator'
+// whether each pair belongs the other way around. 'items' belongs to a list
+// the collector sees, which holds every element while they are compared.
+static inline void lox_sort(LoxValue *items, size_t count, LoxValue comparator, int line,
+                            int column) {
+    LoxValue *merged = lox_reallocate(NULL, sizeof(LoxValue) * (count + 1));
+    for (size_t width = 1; width < count; width *= 2) {
+        for (size_t start = 0; start + width < count;) {
+            size_t middle = start + width;
+            size_t end = middle + width < count ? middle + width : count;
+            size_t i = start, j = middle, k = 0;
+
+            while (i < middle && j < end) {
+                LoxValue pair[2] = {items[i], items[j]};
+                LoxValue order = lox_call_back(comparator, pair, 2, line, column);
+                if (order.type != LOX_NUMBER) {
+                    lox_error(line, column, "comparator must return a number");
+                }
+                merged[k++] = order.as.number > 0 ? items[j++] : items[i++];
+            }
+            while (i < middle) {
+                merged[k++] = items[i++];
+            }
+            while (j < end) {
+                merged[k++] = items[j++];
+            }
+            memcpy(items + start, merged, sizeof(LoxValue) * k);
+
+            start = end;
+        }
+    }
+    free(merged);
+}
+
+// Call 'method' with its arguments, which are the caller's temporaries along
+// with the method itself. The methods taking a function call it on a copy of
+// the list, so that it may change the list as it likes.
+static inline LoxValue lox_call_list_method(LoxListMethod *method, LoxValue *arguments, int line,
+                                            int column) {
+    LoxList *list = method->list;
+    // A copy of the list and the result being built, kept alive while
+    // functions are called.
+    LoxValue held[2];
+    LoxFrame frame;
+    LoxValue result = lox_nil();
+
+    switch (method->kind) {
+    case LOX_LIST_PUSH:
+        lox_list_push(list, arguments[0]);
+        return lox_nil();
+    case LOX_LIST_POP:
+        if (list->count == 0) {
+            lox_error(line, column, "can't pop from an empty list");
+        }
+        return list->items[--list->count];
+    case LOX_LIST_INSERT: {
+        size_t index = lox_list_index(arguments[0], list->count, true, line, column);
+        lox_list_grow(list);
+        memmove(list->items + index + 1, list->items + index,
+                sizeof(LoxValue) * (list->count - index));
+        list->items[index] = arguments[1];
+        list->count++;
+        return lox_nil();
+    }
+    case LOX_LIST_REMOVE: {
+        size_t index = lox_list_index(arguments[0], list->count, false, line, column);
+        LoxValue removed = list->items[index];
+        memmove(list->items + index, list->items + index + 1,
+                sizeof(LoxValue) * (list->count - index - 1));
+        list->count--;
+        return removed;
+    }
+    case LOX_LIST_LEN:
+        return lox_number((double)list->count);
+    default:
+        break;
+    }
+
+    lox_enter(&frame, NULL, held, 2);
+    LoxList *copy = (LoxList *)lox_list(list->items, list->count).as.object;
+    held[0] = lox_object(copy);
+    switch (method->kind) {
+    case LOX_LIST_MAP: {
+        LoxList *mapped = lox_new_list(copy->count);
+        held[1] = lox_object(mapped);
+        for (size_t i = 0; i < copy->count; i++) {
+            LoxValue value = lox_call_back(arguments[0], &copy->items[i], 1, line, column);
+            lox_list_push(mapped, value);
+        }
+        result = held[1];
+        break;
+    }
+    case LOX_LIST_FILTER: {
+        LoxList *kept = lox_new_list(0);
+        held[1] = lox_object(kept);
+        for (size_t i = 0; i < copy->count; i++) {
+            if (lox_truthy(lox_call_back(arguments[0], &copy->items[i], 1, line, column))) {
+                lox_list_push(kept, copy->items[i]);
+            }
+        }
+        result = held[1];
+        break;
+    }
+    case LOX_LIST_REDUCE:
+        held[1] = arguments[1];
+        for (size_t i = 0; i < copy->count; i++) {
+            LoxValue pair[2] = {held[1], copy->items[i]};
+            held[1] = lox_call_back(arguments[0], pair, 2, line, column);
+        }
+        result = held[1];
+        break;
+    case LOX_LIST_SORT:
+        lox_sort(copy->items, copy->count, arguments[0], line, column);
+        list->count = 0;
+        for (size_t i = 0; i < copy->count; i++) {
+            lox_list_push(list, copy->items[i]);
+        }
+        break;
+    default:
+        break;
+    }
+    lox.frames = frame.previous;
+    return result;
+}
+
+// Maps. Which values are keys and when two keys are the same follow 'map.rs':
+// numbers that compare equal are the same key, NaN finds itself, strings are
+// compared by their characters and an instance is looked up by what its
+// 'hash' method returns.
+
+static inline LoxValue lox_new_map(void) {
+    LoxMap *map = lox_allocate(LOX_MAP, sizeof(LoxMap));
+    map->entries = NULL;
+    map->count = 0;
+    map->live = 0;
+    map->capacity = 0;
+    map->index = NULL;
+    map->index_capacity = 0;
+    return lox_object(map);
+}
+
+// The value a key that needs no 'hash' method is looked up by, or an
+// undefined value if it can't be a key.
+static inline LoxValue lox_hashable(LoxValue value) {
+    switch (value.type) {
+    case LOX_NIL:
+    case LOX_BOOL:
+        return value;
+    case LOX_NUMBER:
+        if (value.as.number == 0) {
+            return lox_number(0);
+        }
+        return value.as.number != value.as.number ? lox_number(NAN) : value;
+    case LOX_OBJECT:
+        if (lox_is(value, LOX_STRING)) {
+            return value;
+        }
+        break;
+    case LOX_UNDEFINED:
+        break;
+    }
+    LoxValue undefined = {LOX_UNDEFINED, {.number = 0}};
+    return undefined;
+}
+
+// What 'key', which the caller keeps alive, is looked up by. The result of a
+// 'hash' method is only kept alive by the map it is stored in.
+static inline LoxValue lox_map_lookup(LoxValue key, int line, int column) {
+    if (!lox_is(key, LOX_INSTANCE)) {
+        LoxValue lookup = lox_hashable(key);
+        if (lookup.type == LOX_UNDEFINED) {
+            lox_error(line, column,
+                      "map keys must be nil, booleans, numbers, strings or instances with "
+                      "a 'hash' method");
+        }
+        return lookup;
+    }
+
+    LoxFunction *method = lox_find_method(((LoxInstance *)key.as.object)->klass, lox.hash);
+    if (method == NULL) {
+        lox_error(line, column,
+                  "map keys must be nil, booleans, numbers, strings or instances with a "
+                  "'hash' method");
+    }
+    LoxValue bound = lox_bind(method, key);
+    LoxValue lookup = lox_hashable(lox_call_back(bound, NULL, 0, line, column));
+    if (lookup.type == LOX_UNDEFINED) {
+        lox_error(line, column, "'hash' must return nil, a boolean, a number or a string");
+    }
+    return lookup;
+}
+
+static inline uint32_t lox_hash_bytes(const void *bytes, size_t length) {
+    uint32_t hash = 2166136261u;
+    for (size_t i = 0; i < length; i++) {
+        hash ^= ((const unsigned char *)bytes)[i];
+        hash *= 16777619u;
+    }
+    return hash;
+}
+
+static inline uint32_t lox_hash(LoxValue lookup) {
+    switch (lookup.type) {
+    case LOX_BOOL:
+        return lookup.as.boolean ? 1 : 2;
+    case LOX_NUMBER:
+        return lox_hash_bytes(&lookup.as.number, sizeof(double));
+    case LOX_OBJECT: {
+        LoxString *string = (LoxString *)lookup.as.object;
+        return lox_hash_bytes(string->chars, string->length);
+    }
+    default:
+        return 0;
+    }
+}
+
+static inline bool lox_same_key(LoxValue a, LoxValue b) {
+    if (a.type == LOX_NUMBER && b.type == LOX_NUMBER && a.as.number != a.as.number) {
+        return b.as.number != b.as.number;
+    }
+    return lox_equal(a, b);
+}
+
+// The slot of the index holding the entry looked up by 'lookup', or the
+// empty slot it would go in.
+static inline size_t lox_map_slot(const LoxMap *map, LoxValue lookup, uint32_t hash) {
+    size_t mask = map->index_capacity - 1;
+    for (size_t slot = hash & mask;; slot = (slot + 1) & mask) {
+        size_t position = map->index[slot];
+        if (position == 0) {
+            return slot;
+        }
+        const LoxMapEntry *entry = &map->entries[position - 1];
+        if (!entry->removed && entry->hash == hash && lox_same_key(entry->lookup, lookup)) {
+            return slot;
+        }
+    }
+}
+
+static inline LoxMapEntry *lox_map_find(const LoxMap *map, LoxValue lookup) {
+    if (map->live == 0) {
+        return NULL;
+    }
+    size_t position = map->index[lox_map_slot(map, lookup, lox_hash(lookup))];
+    return position == 0 ? NULL : &map->entries[position - 1];
+}
+
+// Squeeze the holes out of the entries and rebuild the index with room for
+// at least 'count' entries. Like lists, maps grow without starting a
+// collection.
+static inline void lox_map_rebuild(LoxMap *map, size_t count) {
+    size_t live = 0;
+    for (size_t i = 0; i < map->count; i++) {
+        if (!map->entries[i].removed) {
+            map->entries[live++] = map->entries[i];
+        }
+    }
+    map->count = live;
+
+    if (count > map->capacity) {
+        size_t capacity = map->capacity < 8 ? 8 : map->capacity;
+        while (capacity < count) {
+            capacity *= 2;
+        }
+        lox.allocated += sizeof(LoxMapEntry) * (capacity - map->capacity);
+        map->entries = lox_reallocate(map->entries, sizeof(LoxMapEntry) * capacity);
+        map->capacity = capacity;
+    }
+
+    size_t index_capacity = 16;
+    while (index_capacity * 3 < map->capacity * 4) {
+        index_capacity *= 2;
+    }
+    lox.allocated += sizeof(size_t) * index_capacity;
+    lox.allocated -= sizeof(size_t) * map->index_capacity;
+    free(map->index);
+    map->index = lox_reallocate(NULL, sizeof(size_t) * index_capacity);
+    memset(map->index, 0, sizeof(size_t) * index_capacity);
+    map->index_capacity = index_capacity;
+    for (size_t i = 0; i < map->count; i++) {
+        map->index[lox_map_slot(map, map->entries[i].lookup, map->entries[i].hash)] = i + 1;
+    }
+}
+
+// Set the value of the key looked up by 'lookup', keeping the key it was
+// first inserted with if it is already there.
+static inline void lox_map_insert(LoxMap *map, LoxValue key, LoxValue lookup, LoxValue value) {
+    LoxMapEntry *entry = lox_map_find(map, lookup);
+    if (entry != NULL) {
+        entry->value = value;
+        return;
+    }
+    if (map->count == map->capacity) {
+        lox_map_rebuild(map, map->live + 1);
+    }
+
+    uint32_t hash = lox_hash(lookup);
+    entry = &map->entries[map->count++];
+    entry->key = key;
+    entry->lookup = lookup;
+    entry->hash = hash;
+    entry->removed = false;
+    entry->value = value;
+    map->index[lox_map_slot(map, lookup, hash)] = map->count;
+    map->live++;
+}
+
+static inline LoxValue lox_map_get(LoxMap *map, LoxValue key, int line, int column) {
+    LoxMapEntry *entry = lox_map_find(map, lox_map_lookup(key, line, column));
+    if (entry == NULL) {
+        lox_error(line, column, "key not found in map");
+    }
+    return entry->value;
+}
+
+// 'key' and 'value' are the caller's temporaries.
+static inline void lox_map_set(LoxMap *map, LoxValue key, LoxValue value, int line, int column) {
+    lox_map_insert(map, key, lox_map_lookup(key, line, column), value);
+}
+
+// A map of the 'count' pairs of keys and values in 'entries', which must be
+// reachable from the roots. Each key is hashed once the map is kept alive by
+// a frame of its own.
+static inline LoxValue lox_map(const LoxValue *entries, size_t count, int line, int column) {
+    LoxValue held[1];
+    LoxFrame frame;
+    lox_enter(&frame, NULL, held, 1);
+    held[0] = lox_new_map();
+    for (size_t i = 0; i < count; i++) {
+        lox_map_set((LoxMap *)held[0].as.object, entries[2 * i], entries[2 * i + 1], line,
+                    column);
+    }
+    lox.frames = frame.previous;
+    return held[0];
+}
+
+// The method 'name' of 'object', which is one of the caller's temporaries.
+static inline LoxValue lox_map_method(LoxValue object, int name, int line, int column) {
+    for (size_t kind = 0; kind < sizeof(lox_map_methods) / sizeof(lox_map_methods[0]); kind++) {
+        if (strcmp(lox.names[name]->chars, lox_map_methods[kind].name) == 0) {
+            LoxMapMethod *method = lox_allocate(LOX_MAP_METHOD, sizeof(LoxMapMethod));
+            method->map = (LoxMap *)object.as.object;
+            method->kind = (LoxMapMethodKind)kind;
+            return lox_object(method);
+        }
+    }
+    lox_error(line, column, "undefined property '%s'", lox.names[name]->chars);
+    return lox_nil();
+}
+
+// Call 'method' with its arguments, which are the caller's temporaries along
+// with the method itself.
+static inline LoxValue lox_call_map_method(LoxMapMethod *method, LoxValue *arguments, int line,
+                                           int column) {
+    LoxMap *map = method->map;
+    switch (method->kind) {
+    case LOX_MAP_HAS:
+        return lox_bool(lox_map_find(map, lox_map_lookup(arguments[0], line, column)) != NULL);
+    case LOX_MAP_REMOVE: {
+        LoxMapEntry *entry = lox_map_find(map, lox_map_lookup(arguments[0], line, column));
+        if (entry == NULL) {
+            lox_error(line, column, "key not found in map");
+        }
+        LoxValue value = entry->value;
+        entry->removed = true;
+        map->live--;
+        if (map->count > 2 * map->live) {
+            lox_map_rebuild(map, 0);
+        }
+        return value;
+    }
+    case LOX_MAP_KEYS:
+    case LOX_MAP_VALUES: {
+        LoxList *list = lox_new_list(map->live);
+        for (size_t i = 0; i < map->count; i++) {
+            if (!map->entries[i].removed) {
+                LoxMapEntry *entry = &map->entries[i];
+                lox_list_push(list, method->kind == LOX_MAP_KEYS ? entry->key : entry->value);
+            }
+        }
+        return lox_object(list);
+    }
+    case LOX_MAP_LEN:
+        return lox_number((double)map->live);
+    }
+    return lox_nil();
+}
+
+// Sorted maps and sets. Keys are ordered as in 'sorted.rs': numbers before
+// strings, which are ordered by their bytes. The other backends keep keys in
+// a balanced tree, which visits them in the same order as the array here.
+
+static inline bool lox_is_sorted_map(LoxValue object) {
+    return lox_is(object, LOX_SORTED) && !((LoxSorted *)object.as.object)->is_set;
+}
+
+// 'value' as a key, with '-0' as '0'. NaN can't be ordered, so it isn't one.
+static inline LoxValue lox_sorted_key(LoxValue value, int line, int column) {
+    if (value.type == LOX_NUMBER && value.as.number == value.as.number) {
+        return value.as.number == 0 ? lox_number(0) : value;
+    }
+    if (!lox_is(value, LOX_STRING)) {
+        lox_error(line, column, "sorted keys must be strings or numbers other than NaN");
+    }
+    return value;
+}
+
+static inline int lox_compare_keys(LoxValue a, LoxValue b) {
+    if (a.type == LOX_NUMBER && b.type == LOX_NUMBER) {
+        return (a.as.number > b.as.number) - (a.as.number < b.as.number);
+    }
+    if (a.type == LOX_NUMBER || b.type == LOX_NUMBER) {
+        return a.type == LOX_NUMBER ? -1 : 1;
+    }
+    LoxString *x = (LoxString *)a.as.object;
+    LoxString *y = (LoxString *)b.as.object;
+    int order = memcmp(x->chars, y->chars, x->length < y->length ? x->length : y->length);
+    if (order != 0) {
+        return order;
+    }
+    return (x->length > y->length) - (x->length < y->length);
+}
+
+// The position of the first key of 'sorted' that isn't before 'key'.
+static inline size_t lox_sorted_position(const LoxSorted *sorted, LoxValue key) {
+    size_t low = 0, high = sorted->count;
+    while (low < high) {
+        size_t middle = low + (high - low) / 2;
+        if (lox_compare_keys(sorted->keys[middle], key) < 0) {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    return low;
+}
+
+// Whether 'key' is in 'sorted', storing its position or the one it would be
+// inserted at in 'position'.
+static inline bool lox_sorted_find(const LoxSorted *sorted, LoxValue key, size_t *position) {
+    *position = lox_sorted_position(sorted, key);
+    return *position < sorted->count && lox_compare_keys(sorted->keys[*position], key) == 0;
+}
+
+// Add 'key' with 'value', or set the value of 'key' if it is already there,
+// returning false in that case. Like lists, sorted maps and sets grow
+// without starting a collection.
+static inline bool lox_sorted_insert(LoxSorted *sorted, LoxValue key, LoxValue value) {
+    size_t position;
+    if (lox_sorted_find(sorted, key, &position)) {
+        if (!sorted->is_set) {
+            sorted->values[position] = value;
+        }
+        return false;
+    }
+
+    if (sorted->count == sorted->capacity) {
+        size_t capacity = sorted->capacity < 8 ? 8 : sorted->capacity * 2;
+        size_t arrays = sorted->is_set ? 1 : 2;
+        lox.allocated += sizeof(LoxValue) * (capacity - sorted->capacity) * arrays;
+        sorted->keys = lox_reallocate(sorted->keys, sizeof(LoxValue) * capacity);
+        if (!sorted->is_set) {
+            sorted->values = lox_reallocate(sorted->values, sizeof(LoxValue) * capacity);
+        }
+        sorted->capacity = capacity;
+    }
+
+    size_t moved = sorted->count - position;
+    memmove(sorted->keys + position + 1, sorted->keys + position, sizeof(LoxValue) * moved);
+    sorted->keys[position] = key;
+    if (!sorted->is_set) {
+        memmove(sorted->values + position + 1, sorted->values + position,
+                sizeof(LoxValue) * moved);
+        sorted->values[position] = value;
+    }
+    sorted->count++;
+    return true;
+}
+
+static inline LoxValue lox_sorted_get(LoxSorted *sorted, LoxValue key, int line, int column) {
+    size_t position;
+    if (!lox_sorted_find(sorted, lox_sorted_key(key, line, column), &position)) {
+        lox_error(line, column, "key not found in map");
+    }
+    return sorted->values[position];
+}
+
+static inline void lox_sorted_set(LoxSorted *sorted, LoxValue key, LoxValue value, int line,
+                                  int column) {
+    lox_sorted_insert(sorted, lox_sorted_key(key, line, column), value);
+}
+
+// The method 'name' of 'object', which is one of the caller's temporaries.
+// Sets are added to rather than indexed, and their keys are their values.
+static inline LoxValue lox_sorted_method(LoxValue object, int name, int line, int column) {
+    LoxSorted *sorted = (LoxSorted *)object.as.object;
+    LoxSortedMethodKind missing = sorted->is_set ? LOX_SORTED_KEYS : LOX_SORTED_ADD;
+    for (size_t kind = 0; kind < sizeof(lox_sorted_methods) / sizeof(lox_sorted_methods[0]);
+         kind++) {
+        if (kind != missing && strcmp(lox.names[name]->chars, lox_sorted_methods[kind].name) == 0) {
+            LoxSortedMethod *method = lox_allocate(LOX_SORTED_METHOD, sizeof(LoxSortedMethod));
+            method->sorted = sorted;
+            method->kind = (LoxSortedMethodKind)kind;
+            return lox_object(method);
+        }
+    }
+    lox_error(line, column, "undefined property '%s'", lox.names[name]->chars);
+    return lox_nil();
+}
+
+// Call 'method' with its arguments, which are the caller's temporaries along
+// with the method itself.
+static inline LoxValue lox_call_sorted_method(LoxSortedMethod *method, LoxValue *arguments,
+                                              int line, int column) {
+    LoxSorted *sorted = method->sorted;
+    size_t position;
+    switch (method->kind) {
+    case LOX_SORTED_ADD:
+        return lox_bool(
+            lox_sorted_insert(sorted, lox_sorted_key(arguments[0], line, column), lox_nil()));
+    case LOX_SORTED_HAS:
+        return lox_bool(
+            lox_sorted_find(sorted, lox_sorted_key(arguments[0], line, column), &position));
+    case LOX_SORTED_REMOVE: {
+        bool found = lox_sorted_find(sorted, lox_sorted_key(arguments[0], line, column), &position);
+        if (!found && !sorted->is_set) {
+            lox_error(line, column, "key not found in map");
+        }
+        if (!found) {
+            return lox_bool(false);
+        }
+        size_t moved = sorted->count - position - 1;
+        LoxValue removed = lox_bool(true);
+        memmove(sorted->keys + position, sorted->keys + position + 1, sizeof(LoxValue) * moved);
+        if (!sorted->is_set) {
+            removed = sorted->values[position];
+            memmove(sorted->values + position, sorted->values + position + 1,
+                    sizeof(LoxValue) * moved);
+        }
+        sorted->count--;
+        return removed;
+    }
+    case LOX_SORTED_KEYS:
+        return lox_list(sorted->keys, sorted->count);
+    case LOX_SORTED_VALUES:
+        return lox_list(sorted->is_set ? sorted->keys : sorted->values, sorted->count);
+    case LOX_SORTED_LEN:
+        return lox_number((double)sorted->count);
+    case LOX_SORTED_RANGE: {
+        // The keys from the start up to but not including the end, where
+        // 'nil' leaves that end of the range open.
+        size_t from = 0, to = sorted->count;
+        if (arguments[0].type != LOX_NIL) {
+            from = lox_sorted_position(sorted, lox_sorted_key(arguments[0], line, column));
+        }
+        if (arguments[1].type != LOX_NIL) {
+            to = lox_sorted_position(sorted, lox_sorted_key(arguments[1], line, column));
+        }
+        return lox_list(sorted->keys + from, to > from ? to - from : 0);
+    }
+    }
+    return lox_nil();
+}
+
+// Classes and instances.
+
+static inline void lox_check_superclass(LoxValue superclass, int line, int column) {
+    if (!lox_is(superclass, LOX_CLASS)) {
+        lox_error(line, column, "superclass must be a class");
+    }
+}
+
+static inline LoxValue lox_class(int name, LoxValue superclass) {
+    LoxClass *klass = lox_allocate(LOX_CLASS, sizeof(LoxClass));
+    klass->name = lox.names[name];
+    klass->superclass = superclass.type == LOX_OBJECT ? (LoxClass *)superclass.as.object : NULL;
+    klass->methods.entries = NULL;
+    klass->methods.count = 0;
+    klass->methods.capacity = 0;
+    return lox_object(klass);
+}
+
+static inline void lox_add_method(LoxValue klass, int name, LoxValue method) {
+    ((LoxFunction *)method.as.object)->is_initializer = lox.names[name] == lox.init;
+    lox_table_set(&((LoxClass *)klass.as.object)->methods, lox.names[name], method);
+}
+
+// Look up the field or method 'name' on 'object'. Fields shadow methods.
+static inline LoxValue lox_get_property(LoxValue object, int name, int line, int column) {
+    if (lox_is(object, LOX_LIST)) {
+        return lox_list_method(object, name, line, column);
+    }
+    if (lox_is(object, LOX_MAP)) {
+        return lox_map_method(object, name, line, column);
+    }
+    if (lox_is(object, LOX_SORTED)) {
+        return lox_sorted_method(object, name, line, column);
+    }
+    if (!lox_is(object, LOX_INSTANCE)) {
+        lox_error(line, column, "only instances have properties");
+    }
+    LoxInstance *instance = (LoxInstance *)object.as.object;
+    LoxValue *field = lox_table_find(&instance->fields, lox.names[name]);
+    if (field != NULL) {
+        return *field;
+    }
+    LoxFunction *method = lox_find_method(instance->klass, lox.names[name]);
+    if (method == NULL) {
+        lox_error(line, column, "undefined property '%s'", lox.names[name]->chars);
+    }
+    return lox_bind(method, object);
+}
+
+static inline LoxValue lox_set_property(LoxValue object, int name, LoxValue value, int line,
+                                        int column) {
+    if (!lox_is(object, LOX_INSTANCE)) {
+        lox_error(line, column, "only instances have fields");
+    }
+    lox_table_set(&((LoxInstance *)object.as.object)->fields, lox.names[name], value);
+    return value;
+}
+
+// The method 'name' of 'superclass' bound to 'instance'.
+static inline LoxValue lox_super(LoxValue superclass, LoxValue instance, int name, int line,
+                                 int column) {
+    LoxFunction *method = lox_find_method((LoxClass *)superclass.as.object, lox.names[name]);
+    if (method == NULL) {
+        lox_error(line, column, "undefined property '%s'", lox.names[name]->chars);
+    }
+    return lox_bind(method, instance);
+}
+
+// Native functions.
+
+static inline LoxValue lox_clock(LoxValue *arguments) {
+    (void)arguments;
+    struct timespec now;
+    timespec_get(&now, TIME_UTC);
+    return lox_number((double)now.tv_sec + (double)now.tv_nsec / 1e9);
+}
+
+static const struct {
+    const char *name;
+    int arity;
+    LoxValue (*function)(LoxValue *arguments);
+} lox_natives[] = {
+    {"clock", 0, lox_clock},
+};
+
+// Set up the runtime for a program with the given globals, string constants
+// and names of properties and classes, which must include 'init' and 'hash'.
+static inline void lox_init(const char *file, const char *const *global_names, int global_count,
+                            const LoxConstant *constants, int constant_count,
+                            const char *const *names, int name_count) {
+    lox.next_gc = LOX_MIN_HEAP;
+    lox.file = file;
+
+    lox.globals = lox_reallocate(NULL, sizeof(LoxValue) * (global_count + 1));
+    lox.global_names = global_names;
+    for (int i = 0; i < global_count; i++) {
+        lox.globals[i].type = LOX_UNDEFINED;
+    }
+    lox.global_count = global_count;
+
+    lox.constants = lox_reallocate(NULL, sizeof(LoxValue) * (constant_count + 1));
+    for (int i = 0; i < constant_count; i++) {
+        lox.constants[i] = lox_object(lox_new_string(constants[i].chars, constants[i].length));
+        lox.constant_count++;
+    }
+
+    lox.names = lox_reallocate(NULL, sizeof(LoxString *) * (name_count + 1));
+    for (int i = 0; i < name_count; i++) {
+        lox.names[i] = lox_new_string(names[i], strlen(names[i]));
+        lox.name_count++;
+        if (strcmp(names[i], "init") == 0) {
+            lox.init = lox.names[i];
+        } else if (strcmp(names[i], "hash") == 0) {
+            lox.hash = lox.names[i];
+        }
+    }
+
+    for (size_t i = 0; i < sizeof(lox_natives) / sizeof(lox_natives[0]); i++) {
+        for (int global = 0; global < global_count; global++) {
+            if (strcmp(global_names[global], lox_natives[i].name) == 0) {
+                LoxNative *native = lox_allocate(LOX_NATIVE, sizeof(LoxNative));
+                native->name = lox_natives[i].name;
+                native->arity = lox_natives[i].arity;
+                native->function = lox_natives[i].function;
+                lox.globals[global] = lox_object(native);
+            }
+        }
+    }
+    for (int global = 0; global < global_count; global++) {
+        bool is_set = strcmp(global_names[global], "SortedSet") == 0;
+        if (is_set || strcmp(global_names[global], "SortedMap") == 0) {
+            LoxSortedClass *klass = lox_allocate(LOX_SORTED_CLASS, sizeof(LoxSortedClass));
+            klass->is_set = is_set;
+            lox.globals[global] = lox_object(klass);
+        }
+    }
+}
+
+#endif
@@ -0,0 +1,224 @@
+use crate::lexer::SourcePosition;
+use crate::symbol::Symbol;
+use std::rc::Rc;
+
+// Instructions executed by 'vm::Vm'. Operands follow the opcode byte:
+// constant indices, inline cache indices and jump offsets are two bytes (big
+// endian), local, upvalue and argument counts are one byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum OpCode {
+    // [constant: u16]
+    Constant,
+    Nil,
+    True,
+    False,
+    Pop,
+    // [slot: u8]
+    GetLocal,
+    SetLocal,
+    // [name constant: u16]
+    GetGlobal,
+    DefineGlobal,
+    SetGlobal,
+    // [upvalue: u8]
+    GetUpvalue,
+    SetUpvalue,
+    // [name constant: u16, cache: u16]
+    GetProperty,
+    SetProperty,
+    // [name constant: u16]
+    GetSuper,
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Not,
+    Negate,
+    Print,
+    // [offset: u16]
+    Jump,
+    JumpIfFalse,
+    Loop,
+    // [argument count: u8]
+    Call,
+    // [name constant: u16, cache: u16, argument count: u8]
+    //
+    // Call the method or field 'name' of the receiver below the arguments,
+    // without creating a bound method.
+    Invoke,
+    // [function constant: u16] followed by [is_local: u8, index: u8] for
+    // each upvalue of the function.
+    Closure,
+    CloseUpvalue,
+    // [slot: u8]
+    //
+    // Close the upvalue capturing the local 'slot', if any, so that the slot
+    // can hold a new variable while closures keep the old one.
+    CloseLocal,
+    Return,
+    // [name constant: u16]
+    Class,
+    Inherit,
+    // [name constant: u16]
+    Method,
+    // [element count: u16]
+    //
+    // Replace the elements on top of the stack with a list of them.
+    BuildList,
+    GetIndex,
+    SetIndex,
+    // Replace a list and the start and end bounds above it with the slice
+    // between them. A bound is 'nil' when it was left out.
+    GetSlice,
+    // [entry count: u16]
+    //
+    // Replace the keys and values on top of the stack, each key below its
+    // value, with a map of them.
+    BuildMap,
+}
+
+impl OpCode {
+    pub const ALL: [OpCode; 45] = [
+        OpCode::Constant,
+        OpCode::Nil,
+        OpCode::True,
+        OpCode::False,
+        OpCode::Pop,
+        OpCode::GetLocal,
+        OpCode::SetLocal,
+        OpCode::GetGlobal,
+        OpCode::DefineGlobal,
+        OpCode::SetGlobal,
+        OpCode::GetUpvalue,
+        OpCode::SetUpvalue,
+        OpCode::GetProperty,
+        OpCode::SetProperty,
+        OpCode::GetSuper,
+        OpCode::Equal,
+        OpCode::NotEqual,
+        OpCode::Greater,
+        OpCode::GreaterEqual,
+        OpCode::Less,
+        OpCode::LessEqual,
+        OpCode::Add,
+        OpCode::Subtract,
+        OpCode::Multiply,
+        OpCode::Divide,
+        OpCode::Not,
+        OpCode::Negate,
+        OpCode::Print,
+        OpCode::Jump,
+        OpCode::JumpIfFalse,
+        OpCode::Loop,
+        OpCode::Call,
+        OpCode::Invoke,
+        OpCode::Closure,
+        OpCode::CloseUpvalue,
+        OpCode::CloseLocal,
+        OpCode::Return,
+        OpCode::Class,
+        OpCode::Inherit,
+        OpCode::Method,
+        OpCode::BuildList,
+        OpCode::GetIndex,
+        OpCode::SetIndex,
+        OpCode::GetSlice,
+        OpCode::BuildMap,
+    ];
+
+    pub fn from_byte(byte: u8) -> Option<OpCode> {
+        OpCode::ALL.get(byte as usize).copied()
+    }
+}
+
+// A constant referenced by an instruction.
+#[derive(Debug)]
+pub enum Constant {
+    Number(f64),
+    String(Symbol),
+    Function(Rc<FunctionProto>),
+}
+
+// A sequence of instructions along with the constants they refer to and the
+// source position each instruction was compiled from, which runtime errors
+// report.
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub code: Vec<u8>,
+    pub constants: Vec<Constant>,
+    pub positions: Vec<SourcePosition>,
+}
+
+impl Chunk {
+    pub fn write(&mut self, byte: u8, position: SourcePosition) {
+        self.code.push(byte);
+        self.positions.push(position);
+    }
+
+    pub fn write_op(&mut self, op: OpCode, position: SourcePosition) {
+        self.write(op as u8, position);
+    }
+
+    pub fn write_u16(&mut self, value: u16, position: SourcePosition) {
+        let [high, low] = value.to_be_bytes();
+        self.write(high, position);
+        self.write(low, position);
+    }
+
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        u16::from_be_bytes([self.code[offset], self.code[offset + 1]])
+    }
+
+    // Add 'constant' to the constant table, returning its index. Returns
+    // 'None' if the table is full.
+    pub fn add_constant(&mut self, constant: Constant) -> Option<u16> {
+        let index = u16::try_from(self.constants.len()).ok()?;
+        self.constants.push(constant);
+        Some(index)
+    }
+}
+
+// A compiled function: the top-level script or a function declaration.
+#[derive(Debug)]
+pub struct FunctionProto {
+    // Empty for the top-level script.
+    pub name: Rc<str>,
+    pub arity: usize,
+    pub upvalue_count: usize,
+    // The number of inline caches used by the property instructions in
+    // 'chunk', which are numbered from zero.
+    pub cache_count: usize,
+    pub chunk: Chunk,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opcode_bytes() {
+        for (byte, op) in OpCode::ALL.iter().enumerate() {
+            assert_eq!(*op as u8, byte as u8);
+            assert_eq!(OpCode::from_byte(byte as u8), Some(*op));
+        }
+        assert_eq!(OpCode::from_byte(OpCode::ALL.len() as u8), None);
+    }
+
+    #[test]
+    fn operands() {
+        let mut chunk = Chunk::default();
+        chunk.write_op(OpCode::Constant, (1, 1));
+        chunk.write_u16(0x1234, (1, 1));
+
+        assert_eq!(chunk.code, [OpCode::Constant as u8, 0x12, 0x34]);
+        assert_eq!(chunk.read_u16(1), 0x1234);
+        assert_eq!(chunk.positions.len(), chunk.code.len());
+    }
+}
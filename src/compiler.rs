@@ -0,0 +1,918 @@
+use crate::ast::{Expression, FunctionDeclaration, LiteralValue, Statement};
+use crate::chunk::{Chunk, Constant, FunctionProto, OpCode};
+use crate::lexer::{OwnedToken, SourcePosition, TokenType};
+use crate::result::{Error, Result};
+use crate::symbol::Symbol;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// Locals and upvalues are addressed with a single byte operand, inline caches
+// with two.
+const MAX_LOCALS: usize = 256;
+const MAX_UPVALUES: usize = 256;
+const MAX_CACHES: usize = 65536;
+
+// Compile a program into the function for its top-level script. The program
+// must have been checked with 'resolver::resolve' first; the compiler does
+// its own scope resolution but relies on the resolver's static errors.
+pub fn compile(statements: &[Statement<OwnedToken>]) -> Result<Rc<FunctionProto>> {
+    let mut compiler = Compiler {
+        functions: vec![FunctionState::new(Rc::from(""), FunctionKind::Script)],
+        position: (1, 1),
+        errors: Vec::new(),
+    };
+
+    for statement in statements {
+        compiler.statement(statement);
+    }
+
+    compiler.finish()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FunctionKind {
+    Script,
+    Function,
+    Method,
+    Initializer,
+}
+
+struct Local {
+    name: Symbol,
+    // 'None' until the variable's initializer has been compiled.
+    depth: Option<usize>,
+    is_captured: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Upvalue {
+    index: u8,
+    // Whether 'index' is a local slot of the enclosing function rather than
+    // one of its upvalues.
+    is_local: bool,
+}
+
+// The state of a function being compiled.
+struct FunctionState {
+    name: Rc<str>,
+    kind: FunctionKind,
+    arity: usize,
+    chunk: Chunk,
+    locals: Vec<Local>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: usize,
+    // Indices of the string constants already in 'chunk'.
+    strings: HashMap<Symbol, u16>,
+    // Each property access gets its own inline cache.
+    cache_count: usize,
+}
+
+impl FunctionState {
+    fn new(name: Rc<str>, kind: FunctionKind) -> FunctionState {
+        // Slot zero holds the closure being called, or the receiver in
+        // methods, where it is accessible as 'this'.
+        let slot_zero = match kind {
+            FunctionKind::Method | FunctionKind::Initializer => "this",
+            FunctionKind::Script | FunctionKind::Function => "",
+        };
+
+        FunctionState {
+            name,
+            kind,
+            arity: 0,
+            chunk: Chunk::default(),
+            locals: vec![Local {
+                name: Symbol::intern(slot_zero),
+                depth: Some(0),
+                is_captured: false,
+            }],
+            upvalues: Vec::new(),
+            scope_depth: 0,
+            strings: HashMap::new(),
+            cache_count: 0,
+        }
+    }
+
+    fn resolve_local(&self, name: Symbol) -> Option<usize> {
+        self.locals.iter().rposition(|local| local.name == name)
+    }
+}
+
+struct Compiler {
+    // The functions being compiled, innermost last.
+    functions: Vec<FunctionState>,
+    // The position of the token most recently compiled, recorded for each
+    // emitted instruction.
+    position: SourcePosition,
+    errors: Vec<Error>,
+}
+
+impl Compiler {
+    fn finish(mut self) -> Result<Rc<FunctionProto>> {
+        self.emit_return();
+        let script = self.functions.pop().expect("script function");
+
+        match self.errors.len() {
+            0 => Ok(Rc::new(FunctionProto {
+                name: script.name,
+                arity: 0,
+                upvalue_count: 0,
+                cache_count: script.cache_count,
+                chunk: script.chunk,
+            })),
+            1 => Err(self.errors.remove(0)),
+            _ => Err(Error::MultipleErrors(self.errors)),
+        }
+    }
+
+    fn error(&mut self, message: &str) {
+        self.errors.push(Error::ParseError {
+            message: message.to_string(),
+            source_position: self.position,
+        });
+    }
+
+    fn current(&mut self) -> &mut FunctionState {
+        self.functions.last_mut().expect("function being compiled")
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.current().chunk
+    }
+
+    fn emit(&mut self, op: OpCode) {
+        let position = self.position;
+        self.chunk().write_op(op, position);
+    }
+
+    fn emit_byte(&mut self, byte: u8) {
+        let position = self.position;
+        self.chunk().write(byte, position);
+    }
+
+    fn emit_u16(&mut self, value: u16) {
+        let position = self.position;
+        self.chunk().write_u16(value, position);
+    }
+
+    fn emit_with_u16(&mut self, op: OpCode, operand: u16) {
+        self.emit(op);
+        self.emit_u16(operand);
+    }
+
+    fn emit_return(&mut self) {
+        if self.current().kind == FunctionKind::Initializer {
+            self.emit(OpCode::GetLocal);
+            self.emit_byte(0);
+        } else {
+            self.emit(OpCode::Nil);
+        }
+        self.emit(OpCode::Return);
+    }
+
+    // Emit a forward jump with a placeholder offset, returning the offset of
+    // the operand to patch.
+    fn emit_jump(&mut self, op: OpCode) -> usize {
+        self.emit_with_u16(op, u16::MAX);
+        self.chunk().code.len() - 2
+    }
+
+    fn patch_jump(&mut self, operand: usize) {
+        let jump = self.chunk().code.len() - operand - 2;
+        let Ok(jump) = u16::try_from(jump) else {
+            self.error("too much code to jump over");
+            return;
+        };
+
+        let [high, low] = jump.to_be_bytes();
+        let code = &mut self.chunk().code;
+        code[operand] = high;
+        code[operand + 1] = low;
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit(OpCode::Loop);
+        let offset = self.chunk().code.len() - loop_start + 2;
+        match u16::try_from(offset) {
+            Ok(offset) => self.emit_u16(offset),
+            Err(_) => self.error("loop body too large"),
+        }
+    }
+
+    fn make_constant(&mut self, constant: Constant) -> u16 {
+        match self.chunk().add_constant(constant) {
+            Some(index) => index,
+            None => {
+                self.error("too many constants in one chunk");
+                0
+            }
+        }
+    }
+
+    // The constant holding the string 'name', shared by every use of the name
+    // in the current function.
+    fn identifier_constant(&mut self, name: Symbol) -> u16 {
+        if let Some(&index) = self.current().strings.get(&name) {
+            return index;
+        }
+
+        let index = self.make_constant(Constant::String(name));
+        self.current().strings.insert(name, index);
+        index
+    }
+
+    // Emit 'op' on the property 'name' with a new inline cache.
+    fn emit_property(&mut self, op: OpCode, name: Symbol) {
+        let constant = self.identifier_constant(name);
+        self.emit_with_u16(op, constant);
+
+        let cache = self.current().cache_count;
+        if cache >= MAX_CACHES {
+            self.error("too many property accesses in one function");
+        }
+        self.current().cache_count += 1;
+        self.emit_u16(cache as u16);
+    }
+
+    fn begin_scope(&mut self) {
+        self.current().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.current().scope_depth -= 1;
+
+        loop {
+            let function = self.current();
+            let Some(local) = function.locals.last() else {
+                break;
+            };
+            if local
+                .depth
+                .is_some_and(|depth| depth <= function.scope_depth)
+            {
+                break;
+            }
+
+            let op = if local.is_captured {
+                OpCode::CloseUpvalue
+            } else {
+                OpCode::Pop
+            };
+            function.locals.pop();
+            self.emit(op);
+        }
+    }
+
+    fn add_local(&mut self, name: Symbol) {
+        if self.current().locals.len() >= MAX_LOCALS {
+            self.error("too many local variables in function");
+            return;
+        }
+
+        self.current().locals.push(Local {
+            name,
+            depth: None,
+            is_captured: false,
+        });
+    }
+
+    // Declare 'name' in the current scope. Globals are late bound, so only
+    // locals need declaring.
+    fn declare_variable(&mut self, name: &OwnedToken) {
+        if self.current().scope_depth > 0 {
+            self.add_local(name.lexeme);
+        }
+    }
+
+    // Make the variable just declared as 'name' available, either by marking
+    // the local initialized or by storing the value on the stack as a global.
+    fn define_variable(&mut self, name: &OwnedToken) {
+        let function = self.current();
+        if function.scope_depth > 0 {
+            let depth = function.scope_depth;
+            if let Some(local) = function.locals.last_mut() {
+                local.depth = Some(depth);
+            }
+            return;
+        }
+
+        let constant = self.identifier_constant(name.lexeme);
+        self.emit_with_u16(OpCode::DefineGlobal, constant);
+    }
+
+    fn add_upvalue(&mut self, function: usize, upvalue: Upvalue) -> u8 {
+        let upvalues = &mut self.functions[function].upvalues;
+        if let Some(index) = upvalues.iter().position(|&u| u == upvalue) {
+            return index as u8;
+        }
+
+        if upvalues.len() >= MAX_UPVALUES {
+            self.error("too many closure variables in function");
+            return 0;
+        }
+
+        upvalues.push(upvalue);
+        (upvalues.len() - 1) as u8
+    }
+
+    // Find 'name' among the locals of the functions enclosing
+    // 'self.functions[function]', adding upvalues to each function in between.
+    fn resolve_upvalue(&mut self, function: usize, name: Symbol) -> Option<u8> {
+        let enclosing = function.checked_sub(1)?;
+
+        if let Some(local) = self.functions[enclosing].resolve_local(name) {
+            self.functions[enclosing].locals[local].is_captured = true;
+            let upvalue = Upvalue {
+                index: local as u8,
+                is_local: true,
+            };
+            return Some(self.add_upvalue(function, upvalue));
+        }
+
+        let index = self.resolve_upvalue(enclosing, name)?;
+        let upvalue = Upvalue {
+            index,
+            is_local: false,
+        };
+        Some(self.add_upvalue(function, upvalue))
+    }
+
+    // Emit a load of the variable 'name', or a store if 'assign' is set.
+    fn named_variable(&mut self, name: Symbol, assign: bool) {
+        let function = self.functions.len() - 1;
+
+        if let Some(slot) = self.functions[function].resolve_local(name) {
+            self.emit(if assign {
+                OpCode::SetLocal
+            } else {
+                OpCode::GetLocal
+            });
+            self.emit_byte(slot as u8);
+        } else if let Some(index) = self.resolve_upvalue(function, name) {
+            self.emit(if assign {
+                OpCode::SetUpvalue
+            } else {
+                OpCode::GetUpvalue
+            });
+            self.emit_byte(index);
+        } else {
+            let constant = self.identifier_constant(name);
+            let op = if assign {
+                OpCode::SetGlobal
+            } else {
+                OpCode::GetGlobal
+            };
+            self.emit_with_u16(op, constant);
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement<OwnedToken>) {
+        match statement {
+            Statement::Expression(expression) => {
+                self.expression(expression);
+                self.emit(OpCode::Pop);
+            }
+            Statement::Print { expression, .. } => {
+                self.expression(expression);
+                self.emit(OpCode::Print);
+            }
+            Statement::Var {
+                name, initializer, ..
+            } => {
+                self.position = name.source_position;
+                self.declare_variable(name);
+                match initializer {
+                    Some(initializer) => self.expression(initializer),
+                    None => self.emit(OpCode::Nil),
+                }
+                self.position = name.source_position;
+                self.define_variable(name);
+            }
+            Statement::Block(statements) => {
+                self.begin_scope();
+                for statement in statements {
+                    self.statement(statement);
+                }
+                self.end_scope();
+            }
+            Statement::If {
+                condition,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                self.expression(condition);
+                let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit(OpCode::Pop);
+                self.statement(then_branch);
+
+                let else_jump = self.emit_jump(OpCode::Jump);
+                self.patch_jump(then_jump);
+                self.emit(OpCode::Pop);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch);
+                }
+                self.patch_jump(else_jump);
+            }
+            Statement::While {
+                condition, body, ..
+            } => {
+                let loop_start = self.chunk().code.len();
+                self.expression(condition);
+                let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit(OpCode::Pop);
+                self.statement(body);
+                self.emit_loop(loop_start);
+
+                self.patch_jump(exit_jump);
+                self.emit(OpCode::Pop);
+            }
+            Statement::Function { declaration, .. } => {
+                self.position = declaration.name.source_position;
+                self.declare_variable(&declaration.name);
+                // Mark a local function initialized before compiling the body
+                // so that it can refer to itself.
+                self.mark_initialized();
+                self.function(declaration, FunctionKind::Function);
+                self.define_variable(&declaration.name);
+            }
+            Statement::Return { keyword, value } => {
+                self.position = keyword.source_position;
+                match value {
+                    Some(value) => {
+                        self.expression(value);
+                        self.emit(OpCode::Return);
+                    }
+                    None => self.emit_return(),
+                }
+            }
+            Statement::Class {
+                name,
+                superclass,
+                methods,
+                ..
+            } => self.class(name, superclass.as_deref(), methods),
+        }
+    }
+
+    fn mark_initialized(&mut self) {
+        let function = self.current();
+        if function.scope_depth > 0 {
+            let depth = function.scope_depth;
+            if let Some(local) = function.locals.last_mut() {
+                local.depth = Some(depth);
+            }
+        }
+    }
+
+    fn function(&mut self, declaration: &FunctionDeclaration<OwnedToken>, kind: FunctionKind) {
+        self.functions.push(FunctionState::new(
+            Rc::from(declaration.name.lexeme.as_str()),
+            kind,
+        ));
+        self.begin_scope();
+
+        for param in &declaration.params {
+            self.position = param.source_position;
+            self.current().arity += 1;
+            self.declare_variable(param);
+            self.define_variable(param);
+        }
+
+        for statement in &declaration.body {
+            self.statement(statement);
+        }
+        self.emit_return();
+
+        // The function's locals are discarded along with its frame, so there
+        // is no need to end its scope.
+        let function = self.functions.pop().expect("function being compiled");
+        let proto = FunctionProto {
+            name: function.name,
+            arity: function.arity,
+            upvalue_count: function.upvalues.len(),
+            cache_count: function.cache_count,
+            chunk: function.chunk,
+        };
+
+        self.position = declaration.name.source_position;
+        let constant = self.make_constant(Constant::Function(Rc::new(proto)));
+        self.emit_with_u16(OpCode::Closure, constant);
+        for upvalue in function.upvalues {
+            self.emit_byte(upvalue.is_local as u8);
+            self.emit_byte(upvalue.index);
+        }
+    }
+
+    fn class(
+        &mut self,
+        name: &OwnedToken,
+        superclass: Option<&Expression<OwnedToken>>,
+        methods: &[Rc<FunctionDeclaration<OwnedToken>>],
+    ) {
+        self.position = name.source_position;
+        let name_constant = self.identifier_constant(name.lexeme);
+        self.declare_variable(name);
+        self.emit_with_u16(OpCode::Class, name_constant);
+        self.define_variable(name);
+
+        // Methods of a subclass close over a local holding the superclass,
+        // which 'super' expressions refer to.
+        if let Some(superclass) = superclass {
+            self.expression(superclass);
+            self.begin_scope();
+            self.add_local(Symbol::intern("super"));
+            self.mark_initialized();
+
+            self.named_variable(name.lexeme, false);
+            self.emit(OpCode::Inherit);
+        }
+
+        self.position = name.source_position;
+        self.named_variable(name.lexeme, false);
+        for method in methods {
+            self.position = method.name.source_position;
+            let method_constant = self.identifier_constant(method.name.lexeme);
+            let kind = if method.name.lexeme.as_str() == "init" {
+                FunctionKind::Initializer
+            } else {
+                FunctionKind::Method
+            };
+
+            self.function(method, kind);
+            self.emit_with_u16(OpCode::Method, method_constant);
+        }
+        self.emit(OpCode::Pop);
+
+        if superclass.is_some() {
+            self.end_scope();
+        }
+    }
+
+    fn expression(&mut self, expression: &Expression<OwnedToken>) {
+        match expression {
+            Expression::Literal { value, token } => {
+                self.position = token.source_position;
+                match value {
+                    LiteralValue::Number(n) => {
+                        let constant = self.make_constant(Constant::Number(*n));
+                        self.emit_with_u16(OpCode::Constant, constant);
+                    }
+                    LiteralValue::String(s) => {
+                        let constant = self.identifier_constant(Symbol::intern(s));
+                        self.emit_with_u16(OpCode::Constant, constant);
+                    }
+                    LiteralValue::True => self.emit(OpCode::True),
+                    LiteralValue::False => self.emit(OpCode::False),
+                    LiteralValue::Nil => self.emit(OpCode::Nil),
+                }
+            }
+            Expression::Grouping(expression) => self.expression(expression),
+            Expression::Unary { operator, right } => {
+                self.expression(right);
+                self.position = operator.source_position;
+                match operator.token_type {
+                    TokenType::Minus => self.emit(OpCode::Negate),
+                    TokenType::Bang => self.emit(OpCode::Not),
+                    _ => self.error(&format!("unexpected operator '{}'", operator.lexeme)),
+                }
+            }
+            Expression::Binary {
+                operator,
+                left,
+                right,
+            } => {
+                self.expression(left);
+                if operator.token_type == TokenType::Comma {
+                    self.emit(OpCode::Pop);
+                    self.expression(right);
+                    return;
+                }
+
+                self.expression(right);
+                self.position = operator.source_position;
+                let op = match operator.token_type {
+                    TokenType::EqualEqual => OpCode::Equal,
+                    TokenType::BangEqual => OpCode::NotEqual,
+                    TokenType::GreaterThan => OpCode::Greater,
+                    TokenType::GreaterThanOrEqual => OpCode::GreaterEqual,
+                    TokenType::LessThan => OpCode::Less,
+                    TokenType::LessThanOrEqual => OpCode::LessEqual,
+                    TokenType::Plus => OpCode::Add,
+                    TokenType::Minus => OpCode::Subtract,
+                    TokenType::Asterisk => OpCode::Multiply,
+                    TokenType::Slash => OpCode::Divide,
+                    _ => {
+                        self.error(&format!("unexpected operator '{}'", operator.lexeme));
+                        return;
+                    }
+                };
+                self.emit(op);
+            }
+            Expression::Ternary {
+                left,
+                middle,
+                right,
+                ..
+            } => {
+                self.expression(left);
+                let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit(OpCode::Pop);
+                self.expression(middle);
+                let end_jump = self.emit_jump(OpCode::Jump);
+
+                self.patch_jump(else_jump);
+                self.emit(OpCode::Pop);
+                self.expression(right);
+                self.patch_jump(end_jump);
+            }
+            Expression::Logical {
+                operator,
+                left,
+                right,
+            } => {
+                self.expression(left);
+                self.position = operator.source_position;
+
+                if operator.token_type == TokenType::And {
+                    let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+                    self.emit(OpCode::Pop);
+                    self.expression(right);
+                    self.patch_jump(end_jump);
+                } else {
+                    let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+                    let end_jump = self.emit_jump(OpCode::Jump);
+                    self.patch_jump(else_jump);
+                    self.emit(OpCode::Pop);
+                    self.expression(right);
+                    self.patch_jump(end_jump);
+                }
+            }
+            Expression::Variable { name, .. } => {
+                self.position = name.source_position;
+                self.named_variable(name.lexeme, false);
+            }
+            Expression::Assign { name, value, .. } => {
+                self.expression(value);
+                self.position = name.source_position;
+                self.named_variable(name.lexeme, true);
+            }
+            Expression::Call {
+                callee,
+                paren,
+                arguments,
+            } => {
+                // A method call is compiled to a single 'Invoke', whose
+                // argument count carries the position of the call so that
+                // errors in the lookup and in the call are reported where
+                // they would be for a separate 'GetProperty' and 'Call'.
+                if let Expression::Get { object, name } = &**callee {
+                    self.expression(object);
+                    for argument in arguments {
+                        self.expression(argument);
+                    }
+                    self.position = name.source_position;
+                    self.emit_property(OpCode::Invoke, name.lexeme);
+                } else {
+                    self.expression(callee);
+                    for argument in arguments {
+                        self.expression(argument);
+                    }
+                    self.position = paren.source_position;
+                    self.emit(OpCode::Call);
+                }
+                self.position = paren.source_position;
+                self.emit_byte(arguments.len() as u8);
+            }
+            Expression::Get { object, name } => {
+                self.expression(object);
+                self.position = name.source_position;
+                self.emit_property(OpCode::GetProperty, name.lexeme);
+            }
+            Expression::Set {
+                object,
+                name,
+                value,
+            } => {
+                self.expression(object);
+                self.expression(value);
+                self.position = name.source_position;
+                self.emit_property(OpCode::SetProperty, name.lexeme);
+            }
+            Expression::List { bracket, elements } => {
+                for element in elements {
+                    self.expression(element);
+                }
+                self.position = bracket.source_position;
+                let count = u16::try_from(elements.len()).unwrap_or_else(|_| {
+                    self.error("too many elements in a list");
+                    0
+                });
+                self.emit(OpCode::BuildList);
+                self.emit_u16(count);
+            }
+            Expression::Map { brace, entries } => {
+                for (key, value) in entries {
+                    self.expression(key);
+                    self.expression(value);
+                }
+                self.position = brace.source_position;
+                let count = u16::try_from(entries.len()).unwrap_or_else(|_| {
+                    self.error("too many entries in a map");
+                    0
+                });
+                self.emit(OpCode::BuildMap);
+                self.emit_u16(count);
+            }
+            Expression::Index {
+                object,
+                bracket,
+                index,
+            } => {
+                self.expression(object);
+                self.expression(index);
+                self.position = bracket.source_position;
+                self.emit(OpCode::GetIndex);
+            }
+            Expression::Slice {
+                object,
+                bracket,
+                start,
+                end,
+            } => {
+                self.expression(object);
+                self.expression(start);
+                self.expression(end);
+                self.position = bracket.source_position;
+                self.emit(OpCode::GetSlice);
+            }
+            Expression::SetIndex {
+                object,
+                bracket,
+                index,
+                value,
+            } => {
+                self.expression(object);
+                self.expression(index);
+                self.expression(value);
+                self.position = bracket.source_position;
+                self.emit(OpCode::SetIndex);
+            }
+            Expression::This { keyword, .. } => {
+                self.position = keyword.source_position;
+                self.named_variable(keyword.lexeme, false);
+            }
+            Expression::Super {
+                keyword, method, ..
+            } => {
+                self.position = keyword.source_position;
+                self.named_variable(Symbol::intern("this"), false);
+                self.named_variable(keyword.lexeme, false);
+
+                self.position = method.source_position;
+                let constant = self.identifier_constant(method.lexeme);
+                self.emit_with_u16(OpCode::GetSuper, constant);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex;
+    use crate::parser::parse;
+    use crate::resolver::resolve;
+
+    fn compile_source(source: &str) -> Result<Rc<FunctionProto>> {
+        let tokens = lex(source)?;
+        let statements = parse(&tokens)?;
+        resolve(&statements)?;
+        compile(&statements)
+    }
+
+    fn ops(chunk: &Chunk) -> Vec<OpCode> {
+        let mut ops = Vec::new();
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            let op = OpCode::from_byte(chunk.code[offset]).unwrap();
+            ops.push(op);
+            offset += 1 + match op {
+                OpCode::GetLocal
+                | OpCode::SetLocal
+                | OpCode::GetUpvalue
+                | OpCode::SetUpvalue
+                | OpCode::Call => 1,
+                OpCode::GetProperty | OpCode::SetProperty => 4,
+                OpCode::Invoke => 5,
+                OpCode::Constant
+                | OpCode::GetGlobal
+                | OpCode::DefineGlobal
+                | OpCode::SetGlobal
+                | OpCode::GetSuper
+                | OpCode::Jump
+                | OpCode::JumpIfFalse
+                | OpCode::Loop
+                | OpCode::Class
+                | OpCode::Method
+                | OpCode::BuildList
+                | OpCode::BuildMap => 2,
+                OpCode::Closure => {
+                    let constant = chunk.read_u16(offset + 1) as usize;
+                    match &chunk.constants[constant] {
+                        Constant::Function(function) => 2 + 2 * function.upvalue_count,
+                        _ => panic!("Expected Function"),
+                    }
+                }
+                _ => 0,
+            };
+        }
+        ops
+    }
+
+    #[test]
+    fn expression_statement() {
+        let script = compile_source("print 1 + 2 * -3;").unwrap();
+
+        assert_eq!(
+            ops(&script.chunk),
+            [
+                OpCode::Constant,
+                OpCode::Constant,
+                OpCode::Constant,
+                OpCode::Negate,
+                OpCode::Multiply,
+                OpCode::Add,
+                OpCode::Print,
+                OpCode::Nil,
+                OpCode::Return
+            ]
+        );
+        assert_eq!(script.chunk.positions[3 * 3], (1, 15));
+    }
+
+    #[test]
+    fn locals_and_globals() {
+        let script = compile_source("var a = 1; { var b = a; b = 2; }").unwrap();
+
+        assert_eq!(
+            ops(&script.chunk),
+            [
+                OpCode::Constant,
+                OpCode::DefineGlobal,
+                OpCode::GetGlobal,
+                OpCode::Constant,
+                OpCode::SetLocal,
+                OpCode::Pop,
+                OpCode::Pop,
+                OpCode::Nil,
+                OpCode::Return
+            ]
+        );
+    }
+
+    #[test]
+    fn closures_capture_upvalues() {
+        let script =
+            compile_source("fun outer() { var x = 1; fun middle() { fun inner() { return x; } } }")
+                .unwrap();
+
+        let Constant::Function(outer) = &script.chunk.constants[0] else {
+            panic!("Expected Function");
+        };
+        let middle = outer
+            .chunk
+            .constants
+            .iter()
+            .find_map(|constant| match constant {
+                Constant::Function(function) => Some(function),
+                _ => None,
+            })
+            .unwrap();
+        let inner = middle
+            .chunk
+            .constants
+            .iter()
+            .find_map(|constant| match constant {
+                Constant::Function(function) => Some(function),
+                _ => None,
+            })
+            .unwrap();
+
+        assert_eq!(&*outer.name, "outer");
+        assert_eq!(outer.upvalue_count, 0);
+        assert_eq!(middle.upvalue_count, 1);
+        assert_eq!(inner.upvalue_count, 1);
+    }
+
+    #[test]
+    fn too_many_locals() {
+        let mut source = String::from("{");
+        for i in 0..MAX_LOCALS {
+            source.push_str(&format!("var v{i};"));
+        }
+        source.push('}');
+
+        assert!(compile_source(&source).is_err());
+    }
+}
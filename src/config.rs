@@ -0,0 +1,371 @@
+use crate::repl::{CONTINUATION_PROMPT, PROMPT};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::Value;
+
+// Settings for the REPL, read from 'config.toml' in the loxi configuration
+// directory and then overridden by 'LOXI_*' environment variables. A missing
+// config file leaves every setting at its default.
+//
+//     [history]
+//     file = "~/.loxi.history"    # "" disables history
+//     size = 1000
+//     ignore_dups = true
+//
+//     [editor]
+//     mode = "emacs"              # or "vi"
+//     color = true
+//     prompt = "> "
+//     continuation_prompt = ".. "
+//
+//     [session]
+//     startup = "~/.config/loxi/init.lox"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub history_file: Option<PathBuf>,
+    pub history_size: usize,
+    pub history_ignore_dups: bool,
+    pub edit_mode: EditMode,
+    pub color: bool,
+    pub prompt: String,
+    pub continuation_prompt: String,
+    // Script run at the start of every session, including after ':reset'.
+    pub startup_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditMode {
+    Emacs,
+    Vi,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            history_file: dirs::home_dir().map(|home| home.join(".loxi.history")),
+            history_size: 1000,
+            history_ignore_dups: true,
+            edit_mode: EditMode::Emacs,
+            color: true,
+            prompt: PROMPT.to_string(),
+            continuation_prompt: CONTINUATION_PROMPT.to_string(),
+            startup_file: None,
+        }
+    }
+}
+
+impl Config {
+    // Load the config file, if there is one, and apply the environment
+    // overrides, returning the config along with a message for each part of
+    // it that was ignored.
+    pub fn load() -> (Config, Vec<String>) {
+        Config::load_with(config_file().as_deref(), |name| env::var(name).ok())
+    }
+
+    // Load the config file at 'path' and apply the overrides looked up with
+    // 'var'. A config file that can't be read or is invalid is ignored as a
+    // whole, and so are the overrides if any is invalid, so that one mistake
+    // doesn't leave the config half applied.
+    fn load_with(
+        path: Option<&Path>,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> (Config, Vec<String>) {
+        let mut config = Config::default();
+        let mut ignored = Vec::new();
+
+        if let Some(path) = path {
+            if let Err(message) = config.apply_file(path) {
+                ignored.push(format!(
+                    "Ignoring invalid config file {}: {}",
+                    path.display(),
+                    message
+                ));
+                config = Config::default();
+            }
+        }
+
+        let mut overridden = config.clone();
+        match overridden.apply_env(var) {
+            Ok(()) => config = overridden,
+            Err(message) => ignored.push(format!(
+                "Ignoring invalid environment overrides: {}",
+                message
+            )),
+        }
+
+        (config, ignored)
+    }
+
+    // Apply the settings in the config file at 'path'. A missing file has
+    // none.
+    fn apply_file(&mut self, path: &Path) -> Result<(), String> {
+        match fs::read_to_string(path) {
+            Ok(source) => self.apply_toml(&source),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+
+    fn apply_toml(&mut self, source: &str) -> Result<(), String> {
+        let table: Value = source.parse().map_err(|error| format!("{}", error))?;
+
+        for (section, key, value) in settings(&table)? {
+            let setting = format!("{}.{}", section, key);
+            match (section, key) {
+                ("history", "file") => self.history_file = path(string(&setting, value)?),
+                ("history", "size") => self.history_size = size(&setting, value)?,
+                ("history", "ignore_dups") => self.history_ignore_dups = boolean(&setting, value)?,
+                ("editor", "mode") => self.edit_mode = edit_mode(string(&setting, value)?)?,
+                ("editor", "color") => self.color = boolean(&setting, value)?,
+                ("editor", "prompt") => self.prompt = string(&setting, value)?.to_string(),
+                ("editor", "continuation_prompt") => {
+                    self.continuation_prompt = string(&setting, value)?.to_string()
+                }
+                ("session", "startup") => self.startup_file = path(string(&setting, value)?),
+                _ => return Err(format!("unknown setting '{}'", setting)),
+            }
+        }
+
+        Ok(())
+    }
+
+    // Apply overrides from environment variables, looked up with 'var'.
+    fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<(), String> {
+        let parse_bool = |name: &str, value: &str| match value {
+            "1" | "true" | "yes" | "on" => Ok(true),
+            "0" | "false" | "no" | "off" => Ok(false),
+            _ => Err(format!(
+                "expected a boolean for '{}', found '{}'",
+                name, value
+            )),
+        };
+
+        if let Some(value) = var("LOXI_HISTORY_FILE") {
+            self.history_file = path(&value);
+        }
+        if let Some(value) = var("LOXI_HISTORY_SIZE") {
+            self.history_size = value.parse().map_err(|_| {
+                format!("expected a size for 'LOXI_HISTORY_SIZE', found '{}'", value)
+            })?;
+        }
+        if let Some(value) = var("LOXI_HISTORY_IGNORE_DUPS") {
+            self.history_ignore_dups = parse_bool("LOXI_HISTORY_IGNORE_DUPS", &value)?;
+        }
+        if let Some(value) = var("LOXI_EDIT_MODE") {
+            self.edit_mode = edit_mode(&value)?;
+        }
+        // https://no-color.org: any non-empty value disables colour.
+        if var("NO_COLOR").is_some_and(|value| !value.is_empty()) {
+            self.color = false;
+        }
+        if let Some(value) = var("LOXI_COLOR") {
+            self.color = parse_bool("LOXI_COLOR", &value)?;
+        }
+        if let Some(value) = var("LOXI_PROMPT") {
+            self.prompt = value;
+        }
+        if let Some(value) = var("LOXI_CONTINUATION_PROMPT") {
+            self.continuation_prompt = value;
+        }
+        if let Some(value) = var("LOXI_STARTUP") {
+            self.startup_file = path(&value);
+        }
+
+        Ok(())
+    }
+}
+
+// The config file: '$LOXI_CONFIG' if set, otherwise 'loxi/config.toml' under
+// '$XDG_CONFIG_HOME' or the platform's configuration directory.
+fn config_file() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("LOXI_CONFIG") {
+        return Some(PathBuf::from(path));
+    }
+
+    env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(dirs::config_dir)
+        .map(|dir| dir.join("loxi").join("config.toml"))
+}
+
+// Flatten the '[section]' tables of the config file into
+// '(section, key, value)' triples.
+fn settings(table: &Value) -> Result<Vec<(&str, &str, &Value)>, String> {
+    let mut settings = Vec::new();
+
+    for (section, values) in table.as_table().into_iter().flatten() {
+        let Some(values) = values.as_table() else {
+            return Err(format!("expected '[{}]' to be a table", section));
+        };
+
+        for (key, value) in values {
+            settings.push((section.as_str(), key.as_str(), value));
+        }
+    }
+
+    Ok(settings)
+}
+
+// A path setting, with a leading '~' expanded to the home directory. An empty
+// path disables the setting.
+fn path(value: &str) -> Option<PathBuf> {
+    if value.is_empty() {
+        return None;
+    }
+
+    match value.strip_prefix("~/") {
+        Some(rest) => Some(dirs::home_dir().unwrap_or_default().join(rest)),
+        None => Some(Path::new(value).to_path_buf()),
+    }
+}
+
+fn edit_mode(value: &str) -> Result<EditMode, String> {
+    match value {
+        "emacs" => Ok(EditMode::Emacs),
+        "vi" => Ok(EditMode::Vi),
+        _ => Err(format!(
+            "expected 'emacs' or 'vi' for the edit mode, found '{}'",
+            value
+        )),
+    }
+}
+
+fn string<'a>(setting: &str, value: &'a Value) -> Result<&'a str, String> {
+    value
+        .as_str()
+        .ok_or_else(|| format!("expected a string for '{}'", setting))
+}
+
+fn boolean(setting: &str, value: &Value) -> Result<bool, String> {
+    value
+        .as_bool()
+        .ok_or_else(|| format!("expected a boolean for '{}'", setting))
+}
+
+fn size(setting: &str, value: &Value) -> Result<usize, String> {
+    value
+        .as_integer()
+        .and_then(|size| usize::try_from(size).ok())
+        .ok_or_else(|| format!("expected a non-negative integer for '{}'", setting))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn config_file_settings() {
+        let mut config = Config::default();
+        config
+            .apply_toml(
+                r#"
+                [history]
+                file = "/tmp/history"
+                size = 50
+                ignore_dups = false
+
+                [editor]
+                mode = "vi"
+                color = false
+                prompt = "lox> "
+                continuation_prompt = "...> "
+
+                [session]
+                startup = "init.lox"
+                "#,
+            )
+            .unwrap();
+
+        assert_eq!(
+            config,
+            Config {
+                history_file: Some(PathBuf::from("/tmp/history")),
+                history_size: 50,
+                history_ignore_dups: false,
+                edit_mode: EditMode::Vi,
+                color: false,
+                prompt: String::from("lox> "),
+                continuation_prompt: String::from("...> "),
+                startup_file: Some(PathBuf::from("init.lox")),
+            }
+        );
+    }
+
+    #[test]
+    fn empty_history_file_disables_history() {
+        let mut config = Config::default();
+        config.apply_toml("[history]\nfile = \"\"").unwrap();
+
+        assert_eq!(config.history_file, None);
+    }
+
+    #[test]
+    fn invalid_config_file() {
+        let mut config = Config::default();
+
+        assert!(config.apply_toml("[history").is_err());
+        assert!(config.apply_toml("[history]\nsize = -1").is_err());
+        assert!(config.apply_toml("[history]\nsize = \"big\"").is_err());
+        assert!(config.apply_toml("[editor]\nmode = \"nano\"").is_err());
+        assert!(config.apply_toml("[editor]\nunknown = 1").is_err());
+        assert!(config.apply_toml("prompt = \"> \"").is_err());
+    }
+
+    #[test]
+    fn environment_overrides() {
+        let vars: HashMap<&str, &str> = [
+            ("LOXI_HISTORY_SIZE", "10"),
+            ("LOXI_HISTORY_IGNORE_DUPS", "no"),
+            ("LOXI_EDIT_MODE", "vi"),
+            ("NO_COLOR", "1"),
+            ("LOXI_PROMPT", ">> "),
+            ("LOXI_STARTUP", "/tmp/init.lox"),
+        ]
+        .into_iter()
+        .collect();
+
+        let mut config = Config::default();
+        config.apply_toml("[history]\nsize = 50").unwrap();
+        config
+            .apply_env(|name| vars.get(name).map(|value| value.to_string()))
+            .unwrap();
+
+        assert_eq!(config.history_size, 10);
+        assert!(!config.history_ignore_dups);
+        assert_eq!(config.edit_mode, EditMode::Vi);
+        assert!(!config.color);
+        assert_eq!(config.prompt, ">> ");
+        assert_eq!(config.continuation_prompt, CONTINUATION_PROMPT);
+        assert_eq!(config.startup_file, Some(PathBuf::from("/tmp/init.lox")));
+
+        assert!(config
+            .apply_env(|name| (name == "LOXI_COLOR").then(|| String::from("maybe")))
+            .is_err());
+    }
+
+    // An invalid config file doesn't cost the environment overrides, and the
+    // message names the file.
+    #[test]
+    fn invalid_config_file_keeps_environment_overrides() {
+        let path = env::temp_dir().join(format!("loxi-config-{}.toml", std::process::id()));
+        fs::write(&path, "[editor]\nmode = \"nano\"").unwrap();
+        let (config, ignored) = Config::load_with(Some(&path), |name| {
+            (name == "LOXI_PROMPT").then(|| String::from(">> "))
+        });
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.prompt, ">> ");
+        assert_eq!(config.edit_mode, EditMode::Emacs);
+        assert_eq!(ignored.len(), 1);
+        assert!(ignored[0].contains(&path.display().to_string()));
+
+        let (config, ignored) = Config::load_with(Some(&path), |name| {
+            (name == "LOXI_COLOR").then(|| String::from("maybe"))
+        });
+        assert_eq!(config, Config::default());
+        assert_eq!(ignored.len(), 1);
+    }
+}
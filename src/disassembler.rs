@@ -0,0 +1,290 @@
+use crate::chunk::{Chunk, Constant, FunctionProto, OpCode};
+use std::fmt::Write;
+
+// Disassemble 'script' and every function nested within it, each under a
+// '== name ==' header. Each instruction is printed on one line as
+//
+//     offset line Opcode          operands
+//
+// where the line is replaced by '|' when it repeats the previous
+// instruction's.
+pub fn disassemble(script: &FunctionProto) -> String {
+    let mut text = String::new();
+    disassemble_function(script, &mut text);
+    text
+}
+
+fn disassemble_function(function: &FunctionProto, text: &mut String) {
+    if !text.is_empty() {
+        text.push('\n');
+    }
+    writeln!(text, "== {} ==", function_name(function)).unwrap();
+
+    let chunk = &function.chunk;
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let same_line = offset > 0 && chunk.positions[offset].0 == chunk.positions[offset - 1].0;
+        offset = disassemble_instruction(chunk, offset, same_line, text);
+    }
+
+    for constant in &chunk.constants {
+        if let Constant::Function(nested) = constant {
+            disassemble_function(nested, text);
+        }
+    }
+}
+
+// Append the instruction at 'offset' to 'text', returning the offset of the
+// next instruction. 'same_line' replaces the line number with '|'.
+pub fn disassemble_instruction(
+    chunk: &Chunk,
+    offset: usize,
+    same_line: bool,
+    text: &mut String,
+) -> usize {
+    write!(text, "{:04} ", offset).unwrap();
+    if same_line {
+        write!(text, "   | ").unwrap();
+    } else {
+        write!(text, "{:4} ", chunk.positions[offset].0).unwrap();
+    }
+
+    let Some(op) = OpCode::from_byte(chunk.code[offset]) else {
+        writeln!(text, "Unknown {}", chunk.code[offset]).unwrap();
+        return offset + 1;
+    };
+
+    match op {
+        OpCode::Constant
+        | OpCode::GetGlobal
+        | OpCode::DefineGlobal
+        | OpCode::SetGlobal
+        | OpCode::GetSuper
+        | OpCode::Class
+        | OpCode::Method => {
+            let index = chunk.read_u16(offset + 1);
+            writeln!(
+                text,
+                "{:<16} {:4} {}",
+                format!("{op:?}"),
+                index,
+                constant(chunk, index)
+            )
+            .unwrap();
+            offset + 3
+        }
+        OpCode::GetProperty | OpCode::SetProperty | OpCode::Invoke => {
+            let index = chunk.read_u16(offset + 1);
+            write!(
+                text,
+                "{:<16} {:4} {} cache {}",
+                format!("{op:?}"),
+                index,
+                constant(chunk, index),
+                chunk.read_u16(offset + 3)
+            )
+            .unwrap();
+
+            if op == OpCode::Invoke {
+                writeln!(text, " ({} args)", chunk.code[offset + 5]).unwrap();
+                offset + 6
+            } else {
+                writeln!(text).unwrap();
+                offset + 5
+            }
+        }
+        OpCode::GetLocal
+        | OpCode::SetLocal
+        | OpCode::CloseLocal
+        | OpCode::GetUpvalue
+        | OpCode::SetUpvalue
+        | OpCode::Call => {
+            writeln!(
+                text,
+                "{:<16} {:4}",
+                format!("{op:?}"),
+                chunk.code[offset + 1]
+            )
+            .unwrap();
+            offset + 2
+        }
+        OpCode::BuildList | OpCode::BuildMap => {
+            writeln!(
+                text,
+                "{:<16} {:4}",
+                format!("{op:?}"),
+                chunk.read_u16(offset + 1)
+            )
+            .unwrap();
+            offset + 3
+        }
+        OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => {
+            let jump = chunk.read_u16(offset + 1) as usize;
+            let target = if op == OpCode::Loop {
+                (offset + 3).wrapping_sub(jump)
+            } else {
+                offset + 3 + jump
+            };
+            writeln!(
+                text,
+                "{:<16} {:4} -> {:04}",
+                format!("{op:?}"),
+                offset,
+                target
+            )
+            .unwrap();
+            offset + 3
+        }
+        OpCode::Closure => {
+            let index = chunk.read_u16(offset + 1);
+            writeln!(
+                text,
+                "{:<16} {:4} {}",
+                format!("{op:?}"),
+                index,
+                constant(chunk, index)
+            )
+            .unwrap();
+
+            let upvalue_count = match chunk.constants.get(index as usize) {
+                Some(Constant::Function(function)) => function.upvalue_count,
+                _ => 0,
+            };
+
+            let mut offset = offset + 3;
+            for _ in 0..upvalue_count {
+                let kind = if chunk.code[offset] == 1 {
+                    "local"
+                } else {
+                    "upvalue"
+                };
+                writeln!(
+                    text,
+                    "{:04}    |   {} {}",
+                    offset,
+                    kind,
+                    chunk.code[offset + 1]
+                )
+                .unwrap();
+                offset += 2;
+            }
+            offset
+        }
+        OpCode::Nil
+        | OpCode::True
+        | OpCode::False
+        | OpCode::Pop
+        | OpCode::Equal
+        | OpCode::NotEqual
+        | OpCode::Greater
+        | OpCode::GreaterEqual
+        | OpCode::Less
+        | OpCode::LessEqual
+        | OpCode::Add
+        | OpCode::Subtract
+        | OpCode::Multiply
+        | OpCode::Divide
+        | OpCode::Not
+        | OpCode::Negate
+        | OpCode::Print
+        | OpCode::CloseUpvalue
+        | OpCode::Return
+        | OpCode::Inherit
+        | OpCode::GetIndex
+        | OpCode::SetIndex
+        | OpCode::GetSlice => {
+            writeln!(text, "{op:?}").unwrap();
+            offset + 1
+        }
+    }
+}
+
+fn function_name(function: &FunctionProto) -> String {
+    if function.name.is_empty() {
+        String::from("<script>")
+    } else {
+        format!("<fn {}>", function.name)
+    }
+}
+
+fn constant(chunk: &Chunk, index: u16) -> String {
+    match chunk.constants.get(index as usize) {
+        Some(Constant::Number(value)) => format!("{value}"),
+        Some(Constant::String(value)) => format!("{:?}", value),
+        Some(Constant::Function(function)) => function_name(function),
+        None => String::from("<invalid constant>"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::lexer::lex;
+    use crate::parser::parse;
+    use crate::resolver::resolve;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    fn disassemble_source(source: &str) -> String {
+        let tokens = lex(source).unwrap();
+        let statements = parse(&tokens).unwrap();
+        resolve(&statements).unwrap();
+        disassemble(&compile(&statements).unwrap())
+    }
+
+    #[test]
+    fn instructions() {
+        assert_eq!(
+            disassemble_source("print 1 + 2;\nvar a = \"s\";"),
+            "== <script> ==\n\
+             0000    1 Constant            0 1\n\
+             0003    | Constant            1 2\n\
+             0006    | Add\n\
+             0007    | Print\n\
+             0008    2 Constant            2 \"s\"\n\
+             0011    | DefineGlobal        3 \"a\"\n\
+             0014    | Nil\n\
+             0015    | Return\n"
+        );
+    }
+
+    #[test]
+    fn jumps_and_closures() {
+        let text =
+            disassemble_source("fun f(x) {\n  fun g() { return x; }\n  while (x) x = nil;\n}");
+
+        assert!(text.contains("== <fn f> =="));
+        assert!(text.contains("== <fn g> =="));
+        assert!(text.contains("Closure             0 <fn g>\n0003    |   local 1\n"));
+        assert!(text.contains("JumpIfFalse"));
+        assert!(text.contains("Loop"));
+        assert!(text.contains("GetUpvalue          0"));
+    }
+
+    // Each 'tests/disasm/*.lox' file is compared against the disassembly in
+    // the '.disasm' file next to it.
+    #[test]
+    fn golden_files() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/disasm");
+        let mut files: Vec<PathBuf> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "lox"))
+            .collect();
+        files.sort();
+        assert!(!files.is_empty());
+
+        for file in files {
+            let source = fs::read_to_string(&file).unwrap();
+            let expected = fs::read_to_string(file.with_extension("disasm")).unwrap();
+
+            assert_eq!(
+                disassemble_source(&source),
+                expected,
+                "disassembly differs for {}",
+                file.display()
+            );
+        }
+    }
+}
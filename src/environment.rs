@@ -0,0 +1,168 @@
+use crate::lexer::OwnedToken;
+use crate::result::{Error, Result};
+use crate::symbol::Symbol;
+use crate::value::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// A scope mapping variable names to values. Scopes are chained through
+// 'enclosing', ending at the global scope.
+#[derive(Clone, Default)]
+pub struct Environment {
+    values: HashMap<Symbol, Value>,
+    enclosing: Option<Rc<RefCell<Environment>>>,
+}
+
+impl Environment {
+    pub fn new() -> Environment {
+        Environment::default()
+    }
+
+    // Create a scope nested within the specified 'enclosing' scope.
+    pub fn with_enclosing(enclosing: Rc<RefCell<Environment>>) -> Environment {
+        Environment {
+            values: HashMap::new(),
+            enclosing: Some(enclosing),
+        }
+    }
+
+    // Bind 'name' to 'value' in this scope, replacing any existing binding.
+    pub fn define(&mut self, name: Symbol, value: Value) {
+        self.values.insert(name, value);
+    }
+
+    // Look up the variable 'name' in this scope or the closest enclosing scope
+    // that defines it.
+    pub fn get(&self, name: &OwnedToken) -> Result<Value> {
+        if let Some(value) = self.values.get(&name.lexeme) {
+            return Ok(value.clone());
+        }
+
+        match self.enclosing {
+            Some(ref enclosing) => enclosing.borrow().get(name),
+            None => Err(undefined_variable(name)),
+        }
+    }
+
+    // Assign 'value' to the existing variable 'name' in this scope or the
+    // closest enclosing scope that defines it.
+    pub fn assign(&mut self, name: &OwnedToken, value: Value) -> Result<()> {
+        if let Some(slot) = self.values.get_mut(&name.lexeme) {
+            *slot = value;
+            return Ok(());
+        }
+
+        match self.enclosing {
+            Some(ref enclosing) => enclosing.borrow_mut().assign(name, value),
+            None => Err(undefined_variable(name)),
+        }
+    }
+
+    // Look up 'name' in the scope 'distance' levels up the chain. The resolver
+    // guarantees that the variable is defined there.
+    pub fn get_at(&self, distance: usize, name: Symbol) -> Option<Value> {
+        if distance == 0 {
+            return self.values.get(&name).cloned();
+        }
+
+        self.enclosing.as_ref()?.borrow().get_at(distance - 1, name)
+    }
+
+    // Assign 'value' to 'name' in the scope 'distance' levels up the chain.
+    pub fn assign_at(&mut self, distance: usize, name: Symbol, value: Value) {
+        if distance == 0 {
+            self.values.insert(name, value);
+        } else if let Some(ref enclosing) = self.enclosing {
+            enclosing.borrow_mut().assign_at(distance - 1, name, value);
+        }
+    }
+
+    // The bindings of this scope, ordered by name.
+    pub fn bindings(&self) -> Vec<(String, Value)> {
+        let mut bindings: Vec<(String, Value)> = self
+            .values
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect();
+        bindings.sort_by(|a, b| a.0.cmp(&b.0));
+
+        bindings
+    }
+}
+
+fn undefined_variable(name: &OwnedToken) -> Error {
+    Error::RuntimeError {
+        message: format!("undefined variable '{}'", name.lexeme),
+        source_position: name.source_position,
+        trace: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::TokenType;
+
+    fn identifier(lexeme: &str) -> OwnedToken {
+        OwnedToken {
+            token_type: TokenType::Identifier,
+            lexeme: Symbol::intern(lexeme),
+            source_position: (1, 1),
+        }
+    }
+
+    #[test]
+    fn nested_scopes() {
+        let globals = Rc::new(RefCell::new(Environment::new()));
+        globals
+            .borrow_mut()
+            .define(Symbol::intern("a"), Value::Number(1.0));
+
+        let mut local = Environment::with_enclosing(Rc::clone(&globals));
+        local.define(Symbol::intern("b"), Value::Number(2.0));
+
+        assert_eq!(local.get(&identifier("a")).unwrap(), Value::Number(1.0));
+        assert_eq!(local.get(&identifier("b")).unwrap(), Value::Number(2.0));
+
+        local.assign(&identifier("a"), Value::Nil).unwrap();
+        assert_eq!(globals.borrow().get(&identifier("a")).unwrap(), Value::Nil);
+        assert!(globals.borrow().get(&identifier("b")).is_err());
+    }
+
+    #[test]
+    fn resolved_access() {
+        let globals = Rc::new(RefCell::new(Environment::new()));
+        globals
+            .borrow_mut()
+            .define(Symbol::intern("a"), Value::Number(1.0));
+        let mut local = Environment::with_enclosing(Rc::clone(&globals));
+        local.define(Symbol::intern("a"), Value::Number(2.0));
+
+        assert_eq!(
+            local.get_at(0, Symbol::intern("a")),
+            Some(Value::Number(2.0))
+        );
+        assert_eq!(
+            local.get_at(1, Symbol::intern("a")),
+            Some(Value::Number(1.0))
+        );
+
+        local.assign_at(1, Symbol::intern("a"), Value::Nil);
+        assert_eq!(
+            globals.borrow().get_at(0, Symbol::intern("a")),
+            Some(Value::Nil)
+        );
+        assert_eq!(
+            local.get_at(0, Symbol::intern("a")),
+            Some(Value::Number(2.0))
+        );
+    }
+
+    #[test]
+    fn assign_undefined() {
+        let mut environment = Environment::new();
+
+        assert!(environment.assign(&identifier("a"), Value::Nil).is_err());
+    }
+}
@@ -0,0 +1,1339 @@
+use crate::binary_tree::BinaryTree;
+use crate::chunk::{Constant, FunctionProto};
+use crate::inline_cache::InlineCache;
+use crate::list;
+use crate::map;
+use crate::sorted;
+use crate::symbol::Symbol;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+// A value on the VM's stack. Objects live in the 'Heap' and are referred to by
+// handle, which keeps values 'Copy'.
+//
+// Values are NaN-boxed into 64 bits with the 'nan-boxing' feature and are
+// otherwise a tagged enum, which is twice the size but easier to inspect in a
+// debugger. Either way they are built with the constants and 'From'
+// conversions below and taken apart with 'unbox'.
+#[cfg(not(feature = "nan-boxing"))]
+#[derive(Debug, Clone, Copy)]
+pub struct Value(Unboxed);
+
+#[cfg(not(feature = "nan-boxing"))]
+impl Value {
+    pub const NIL: Value = Value(Unboxed::Nil);
+    pub const TRUE: Value = Value(Unboxed::Bool(true));
+    pub const FALSE: Value = Value(Unboxed::Bool(false));
+
+    pub fn unbox(self) -> Unboxed {
+        self.0
+    }
+}
+
+#[cfg(not(feature = "nan-boxing"))]
+impl From<f64> for Value {
+    fn from(value: f64) -> Value {
+        Value(Unboxed::Number(value))
+    }
+}
+
+#[cfg(not(feature = "nan-boxing"))]
+impl From<ObjRef> for Value {
+    fn from(reference: ObjRef) -> Value {
+        Value(Unboxed::Object(reference))
+    }
+}
+
+// A NaN-boxed value is a number unless all of the 'QUIET_NAN' bits are set.
+// Hardware only produces NaNs without the highest payload bit set, and other
+// NaNs are replaced with one that does not have it, so no number is mistaken
+// for another kind of value. With the sign bit set the low 32 bits are an
+// object handle; otherwise they are one of the tags for nil and booleans.
+#[cfg(feature = "nan-boxing")]
+#[derive(Clone, Copy)]
+pub struct Value(u64);
+
+#[cfg(feature = "nan-boxing")]
+const QUIET_NAN: u64 = 0x7ffc_0000_0000_0000;
+#[cfg(feature = "nan-boxing")]
+const OBJECT: u64 = 0x8000_0000_0000_0000 | QUIET_NAN;
+
+#[cfg(feature = "nan-boxing")]
+impl Value {
+    pub const NIL: Value = Value(QUIET_NAN | 1);
+    pub const FALSE: Value = Value(QUIET_NAN | 2);
+    pub const TRUE: Value = Value(QUIET_NAN | 3);
+
+    pub fn unbox(self) -> Unboxed {
+        if self.0 & QUIET_NAN != QUIET_NAN {
+            Unboxed::Number(f64::from_bits(self.0))
+        } else if self.0 & OBJECT == OBJECT {
+            Unboxed::Object(ObjRef(self.0 as u32))
+        } else if self.0 == Value::NIL.0 {
+            Unboxed::Nil
+        } else {
+            Unboxed::Bool(self.0 == Value::TRUE.0)
+        }
+    }
+}
+
+#[cfg(feature = "nan-boxing")]
+impl From<f64> for Value {
+    fn from(value: f64) -> Value {
+        if value.is_nan() {
+            Value(f64::NAN.to_bits())
+        } else {
+            Value(value.to_bits())
+        }
+    }
+}
+
+#[cfg(feature = "nan-boxing")]
+impl From<ObjRef> for Value {
+    fn from(reference: ObjRef) -> Value {
+        Value(OBJECT | reference.0 as u64)
+    }
+}
+
+#[cfg(feature = "nan-boxing")]
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.unbox())
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Value {
+        if value {
+            Value::TRUE
+        } else {
+            Value::FALSE
+        }
+    }
+}
+
+impl Value {
+    // 'nil' and 'false' are falsey, everything else is truthy.
+    pub fn is_truthy(self) -> bool {
+        !matches!(self.unbox(), Unboxed::Nil | Unboxed::Bool(false))
+    }
+
+    pub fn as_number(self) -> Option<f64> {
+        match self.unbox() {
+            Unboxed::Number(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_object(self) -> Option<ObjRef> {
+        match self.unbox() {
+            Unboxed::Object(reference) => Some(reference),
+            _ => None,
+        }
+    }
+}
+
+// The contents of a 'Value'.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unboxed {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Object(ObjRef),
+}
+
+// A handle to an object in the 'Heap'.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObjRef(u32);
+
+pub enum Object {
+    String(Rc<str>),
+    Function(Function),
+    Native(Native),
+    Closure(Closure),
+    Upvalue(Upvalue),
+    Class(Class),
+    Instance(Instance),
+    BoundMethod(BoundMethod),
+    List(Vec<Value>),
+    ListMethod(ListMethod),
+    Map(Map),
+    MapMethod(MapMethod),
+    SortedClass(sorted::Kind),
+    Sorted(Sorted),
+    SortedMethod(SortedMethod),
+}
+
+impl Object {
+    // Call 'visit' with each value this object refers to, which are kept
+    // alive as long as it is.
+    fn trace(&self, mut visit: impl FnMut(Value)) {
+        match self {
+            Object::String(_)
+            | Object::Native(_)
+            | Object::Upvalue(Upvalue::Open(_))
+            | Object::SortedClass(_)
+            | Object::Sorted(sorted::Sorted::Set(_)) => (),
+            Object::Function(function) => function.constants.iter().copied().for_each(visit),
+            Object::Closure(closure) => {
+                visit(Value::from(closure.function));
+                closure
+                    .upvalues
+                    .iter()
+                    .for_each(|&upvalue| visit(Value::from(upvalue)));
+            }
+            Object::Upvalue(Upvalue::Closed(value)) => visit(*value),
+            Object::Class(class) => class
+                .methods
+                .values()
+                .for_each(|&method| visit(Value::from(method))),
+            Object::Instance(instance) => {
+                visit(Value::from(instance.class));
+                instance.fields.iter().copied().for_each(visit);
+            }
+            Object::BoundMethod(bound) => {
+                visit(bound.receiver);
+                visit(Value::from(bound.method));
+            }
+            Object::List(elements) => elements.iter().copied().for_each(visit),
+            Object::ListMethod(method) => visit(Value::from(method.list)),
+            // A key an instance's 'hash' method returned is only referred to
+            // by the map.
+            Object::Map(map) => {
+                for (key, &(original, value)) in map.iter() {
+                    visit(original);
+                    visit(value);
+                    if let map::Key::String(string) = *key {
+                        visit(Value::from(string));
+                    }
+                }
+            }
+            Object::MapMethod(method) => visit(Value::from(method.map)),
+            Object::Sorted(sorted::Sorted::Map(map)) => map.values().copied().for_each(visit),
+            Object::SortedMethod(method) => visit(Value::from(method.sorted)),
+        }
+    }
+
+    // An estimate of the memory held by this object, which decides when to
+    // collect. It is counted when the object is allocated and recounted by
+    // 'Heap::resize' whenever the object grows or shrinks.
+    fn size(&self) -> usize {
+        mem::size_of::<Object>()
+            + match self {
+                Object::String(string) => string.len(),
+                Object::Function(function) => {
+                    function.constants.len() * mem::size_of::<Value>()
+                        + function.caches.len() * mem::size_of::<RefCell<InlineCache>>()
+                }
+                Object::Closure(closure) => closure.upvalues.len() * mem::size_of::<ObjRef>(),
+                Object::Class(class) => class.methods.len() * mem::size_of::<(Symbol, ObjRef)>(),
+                Object::Instance(instance) => instance.fields.len() * mem::size_of::<Value>(),
+                Object::List(elements) => elements.len() * mem::size_of::<Value>(),
+                Object::Map(map) => {
+                    map.len() * mem::size_of::<(map::Key<ObjRef>, (Value, Value))>()
+                }
+                Object::Sorted(sorted) => {
+                    sorted.len() * mem::size_of::<BinaryTree<(sorted::Key<Rc<str>>, Value)>>()
+                }
+                Object::Native(_)
+                | Object::Upvalue(_)
+                | Object::BoundMethod(_)
+                | Object::ListMethod(_)
+                | Object::MapMethod(_)
+                | Object::SortedClass(_)
+                | Object::SortedMethod(_) => 0,
+            }
+    }
+}
+
+// A compiled function whose constants have been loaded into the heap, along
+// with the inline caches of its property instructions.
+pub struct Function {
+    pub proto: Rc<FunctionProto>,
+    pub constants: Rc<[Value]>,
+    pub caches: Rc<[RefCell<InlineCache>]>,
+}
+
+// A function implemented in Rust. Errors are reported as a message, which the
+// VM positions at the call.
+pub struct Native {
+    pub name: &'static str,
+    pub arity: usize,
+    pub function: fn(&[Value]) -> Result<Value, String>,
+}
+
+pub struct Closure {
+    pub function: ObjRef,
+    pub upvalues: Vec<ObjRef>,
+}
+
+// A variable captured by a closure. It refers to a stack slot while the
+// variable is in scope and holds the value itself once the scope has ended.
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+pub struct Class {
+    pub name: Symbol,
+    pub methods: HashMap<Symbol, ObjRef>,
+    // The shape of the class's instances before any fields are added.
+    pub shape: ShapeId,
+}
+
+// The fields of an instance are stored in the slots given by its shape.
+pub struct Instance {
+    pub class: ObjRef,
+    pub shape: ShapeId,
+    pub fields: Vec<Value>,
+}
+
+// A handle to a 'Shape' in the 'Heap'.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ShapeId(u64);
+
+// The names and slots of an instance's fields, shared by every instance of a
+// class that had the same fields added in the same order. Each class has its
+// own shapes, so an instance's shape also determines its class.
+struct Shape {
+    slots: HashMap<Symbol, usize>,
+    // The shapes reached from this one by adding a field, created as needed.
+    transitions: HashMap<Symbol, ShapeId>,
+}
+
+// A method closure together with the instance it was accessed on.
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+// A method of a list, together with the list it was accessed on.
+pub struct ListMethod {
+    pub list: ObjRef,
+    pub method: list::Method,
+}
+
+// The entries of a map, each holding the key it was first inserted with
+// alongside its value. Strings are interned, so a string key is its handle.
+pub type Map = map::Map<map::Key<ObjRef>, (Value, Value)>;
+
+// A method of a map, together with the map it was accessed on.
+pub struct MapMethod {
+    pub map: ObjRef,
+    pub method: map::Method,
+}
+
+// The entries of a sorted map or set. String keys are copies of the strings'
+// contents, so that they are ordered without looking them up, and the
+// strings are interned again when a key is turned back into a value.
+pub type Sorted = sorted::Sorted<Rc<str>, Value>;
+
+// A method of a sorted map or set, together with the collection it was
+// accessed on.
+pub struct SortedMethod {
+    pub sorted: ObjRef,
+    pub method: sorted::Method,
+}
+
+// The first full collection happens once this many bytes have been
+// allocated.
+const INITIAL_THRESHOLD: usize = 1024 * 1024;
+
+// After a full collection, the next one happens once the heap has grown by
+// this factor over what survived.
+const GROWTH_FACTOR: usize = 2;
+
+// In generational mode, a young collection happens once this many bytes have
+// been allocated since the last collection.
+const NURSERY_SIZE: usize = 256 * 1024;
+
+// How the heap reclaims unreachable objects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GcMode {
+    // Trace and sweep the whole heap in every collection.
+    MarkSweep,
+    // Frequently collect only the objects allocated since the last
+    // collection, which is fast as most objects die young, and only
+    // occasionally the whole heap.
+    Generational,
+}
+
+impl FromStr for GcMode {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<GcMode, String> {
+        match name {
+            "mark-sweep" => Ok(GcMode::MarkSweep),
+            "generational" => Ok(GcMode::Generational),
+            _ => Err(format!(
+                "unknown garbage collector '{}', expected 'mark-sweep' or 'generational'",
+                name
+            )),
+        }
+    }
+}
+
+// The kinds of collection. A full collection traces the whole heap, a young
+// collection only the objects allocated since the last collection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Generation {
+    Young,
+    Full,
+}
+
+// Statistics for one kind of collection.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CollectionStats {
+    pub collections: usize,
+    pub objects_freed: usize,
+    pub bytes_freed: usize,
+    pub total_pause: Duration,
+    pub max_pause: Duration,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct GcStats {
+    pub young: CollectionStats,
+    pub full: CollectionStats,
+}
+
+impl fmt::Display for GcStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, stats) in [("young", &self.young), ("full", &self.full)] {
+            writeln!(
+                f,
+                "{:<5} {} collections, {} objects ({} bytes) freed, pauses total {:?}, max {:?}",
+                name,
+                stats.collections,
+                stats.objects_freed,
+                stats.bytes_freed,
+                stats.total_pause,
+                stats.max_pause
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// The objects allocated by the VM, reclaimed by a tracing garbage collector.
+//
+// The heap does not know the VM's roots, so it never collects by itself: the
+// VM checks 'should_collect' before allocating, calls 'begin_collection',
+// marks its roots with 'mark_value' and 'mark_object', then calls 'collect'.
+// Marking is tri-colour: unmarked objects are white, marked objects waiting
+// in the 'gray' worklist are gray, and objects whose references have been
+// marked are black. Whatever is still white once the worklist is empty is
+// unreachable and is freed, and its slot reused by a later allocation.
+//
+// In generational mode, objects that survive a collection become old and a
+// young collection neither traces nor frees them. The only references from
+// old objects to young ones are those stored since the last collection, which
+// the VM reports through 'write_barrier'; the old objects involved are kept in
+// the 'remembered' set and treated as roots by the next young collection.
+// Every object that survives is promoted, so after any collection no old
+// object refers to a young one.
+//
+// Strings are interned: there is at most one string object with any given
+// contents, so strings can be compared by handle. 'strings' does not keep
+// them alive, and a string is removed from it when it is freed.
+//
+// The shapes of a class are those reached from its 'shape', and are freed
+// along with the class, which each of its instances keeps alive. A 'ShapeId'
+// is never reused, so an inline cache entry for a freed shape can't match an
+// instance again.
+pub struct Heap {
+    slots: Vec<Slot>,
+    free: Vec<u32>,
+    strings: HashMap<Rc<str>, ObjRef>,
+    shapes: HashMap<ShapeId, Shape>,
+    next_shape: u64,
+    gray: Vec<ObjRef>,
+    mode: GcMode,
+    // The collection in progress, between 'begin_collection' and 'collect'.
+    collecting: Option<Generation>,
+    // In generational mode, the objects allocated since the last collection
+    // and the old objects that may refer to them.
+    young: Vec<u32>,
+    remembered: Vec<ObjRef>,
+    // An estimate of the memory held by live objects, and by young objects.
+    bytes_allocated: usize,
+    young_bytes: usize,
+    next_gc: usize,
+    // Collect before every allocation, to find values that are not rooted.
+    stress: bool,
+    stats: GcStats,
+}
+
+// An object, or 'None' once it has been freed. 'size' is the estimate counted
+// for it in 'Heap::bytes_allocated'.
+struct Slot {
+    object: Option<Object>,
+    marked: bool,
+    old: bool,
+    remembered: bool,
+    size: usize,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap::with_mode(GcMode::MarkSweep)
+    }
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap::default()
+    }
+
+    pub fn with_mode(mode: GcMode) -> Heap {
+        Heap {
+            slots: Vec::new(),
+            free: Vec::new(),
+            strings: HashMap::new(),
+            shapes: HashMap::new(),
+            next_shape: 0,
+            gray: Vec::new(),
+            mode,
+            collecting: None,
+            young: Vec::new(),
+            remembered: Vec::new(),
+            bytes_allocated: 0,
+            young_bytes: 0,
+            next_gc: INITIAL_THRESHOLD,
+            stress: false,
+            stats: GcStats::default(),
+        }
+    }
+
+    pub fn set_stress(&mut self, stress: bool) {
+        self.stress = stress;
+    }
+
+    pub fn stats(&self) -> &GcStats {
+        &self.stats
+    }
+
+    // Allocate 'object'. A string must not already be interned, which
+    // 'find_string' checks.
+    pub fn allocate(&mut self, object: Object) -> ObjRef {
+        let size = object.size();
+        self.bytes_allocated += size;
+        let string = match &object {
+            Object::String(string) => Some(Rc::clone(string)),
+            _ => None,
+        };
+
+        let slot = Slot {
+            object: Some(object),
+            marked: false,
+            old: false,
+            remembered: false,
+            size,
+        };
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.slots[index as usize] = slot;
+                index
+            }
+            None => {
+                let index = u32::try_from(self.slots.len()).expect("heap is full");
+                self.slots.push(slot);
+                index
+            }
+        };
+
+        if self.mode == GcMode::Generational {
+            self.young.push(index);
+            self.young_bytes += size;
+        }
+        if let Some(string) = string {
+            self.strings.insert(string, ObjRef(index));
+        }
+        ObjRef(index)
+    }
+
+    // The interned string with these contents, if there is one.
+    pub fn find_string(&self, value: &str) -> Option<ObjRef> {
+        self.strings.get(value).copied()
+    }
+
+    pub fn string(&mut self, value: &str) -> Value {
+        Value::from(match self.find_string(value) {
+            Some(reference) => reference,
+            None => self.allocate(Object::String(Rc::from(value))),
+        })
+    }
+
+    // A shape without fields, for the instances of a new class.
+    pub fn new_shape(&mut self) -> ShapeId {
+        let shape = ShapeId(self.next_shape);
+        self.next_shape += 1;
+        self.shapes.insert(
+            shape,
+            Shape {
+                slots: HashMap::new(),
+                transitions: HashMap::new(),
+            },
+        );
+        shape
+    }
+
+    // The slot of the field 'name' in instances of 'shape'.
+    pub fn field_slot(&self, shape: ShapeId, name: Symbol) -> Option<usize> {
+        self.shapes[&shape].slots.get(&name).copied()
+    }
+
+    // The number of shapes that have not been freed.
+    pub fn shape_count(&self) -> usize {
+        self.shapes.len()
+    }
+
+    // The shape of an instance of 'shape' after adding the field 'name',
+    // which goes in the slot after the existing fields.
+    pub fn add_field(&mut self, shape: ShapeId, name: Symbol) -> ShapeId {
+        if let Some(&next) = self.shapes[&shape].transitions.get(&name) {
+            return next;
+        }
+
+        let mut slots = self.shapes[&shape].slots.clone();
+        slots.insert(name, slots.len());
+        let next = self.new_shape();
+        self.shapes.get_mut(&next).unwrap().slots = slots;
+        self.shapes
+            .get_mut(&shape)
+            .unwrap()
+            .transitions
+            .insert(name, next);
+        next
+    }
+
+    // Free 'root' and the shapes reached from it, once the class they belong
+    // to has been freed.
+    fn free_shapes(&mut self, root: ShapeId) {
+        let mut shapes = vec![root];
+        while let Some(shape) = shapes.pop() {
+            if let Some(shape) = self.shapes.remove(&shape) {
+                shapes.extend(shape.transitions.into_values());
+            }
+        }
+    }
+
+    pub fn get(&self, reference: ObjRef) -> &Object {
+        self.slots[reference.0 as usize]
+            .object
+            .as_ref()
+            .expect("object has been freed")
+    }
+
+    // Objects that refer to others must only be changed through this after
+    // they have been allocated if 'write_barrier' is called with the new
+    // references, and objects that grow or shrink must be 'resize'd.
+    pub fn get_mut(&mut self, reference: ObjRef) -> &mut Object {
+        self.slots[reference.0 as usize]
+            .object
+            .as_mut()
+            .expect("object has been freed")
+    }
+
+    // Recount the size of 'reference' after its elements, entries, fields or
+    // methods changed, so that growing objects bring the next collection
+    // closer just as allocating new ones does.
+    pub fn resize(&mut self, reference: ObjRef) {
+        let slot = &mut self.slots[reference.0 as usize];
+        let size = slot.object.as_ref().expect("object has been freed").size();
+        let old_size = mem::replace(&mut slot.size, size);
+
+        self.bytes_allocated = self.bytes_allocated + size - old_size;
+        if self.mode == GcMode::Generational && !slot.old {
+            self.young_bytes = (self.young_bytes + size).saturating_sub(old_size);
+        }
+    }
+
+    // Record that a reference to 'value' has been stored in 'object'.
+    pub fn write_barrier(&mut self, object: ObjRef, value: Value) {
+        let Some(target) = value.as_object() else {
+            return;
+        };
+
+        let slot = &self.slots[object.0 as usize];
+        if slot.old && !slot.remembered && !self.slots[target.0 as usize].old {
+            self.slots[object.0 as usize].remembered = true;
+            self.remembered.push(object);
+        }
+    }
+
+    // The number of live objects.
+    pub fn len(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.stress
+            || self.bytes_allocated > self.next_gc
+            || (self.mode == GcMode::Generational && self.young_bytes > NURSERY_SIZE)
+    }
+
+    // Start a collection, choosing whether it is young or full. The roots
+    // must then be marked before calling 'collect'.
+    pub fn begin_collection(&mut self) -> Generation {
+        let generation =
+            if self.mode == GcMode::Generational && self.bytes_allocated <= self.next_gc {
+                Generation::Young
+            } else {
+                Generation::Full
+            };
+        self.collecting = Some(generation);
+
+        if generation == Generation::Young {
+            for reference in mem::take(&mut self.remembered) {
+                self.slots[reference.0 as usize].remembered = false;
+                self.blacken(reference);
+            }
+        }
+
+        generation
+    }
+
+    pub fn mark_value(&mut self, value: Value) {
+        if let Some(reference) = value.as_object() {
+            self.mark_object(reference);
+        }
+    }
+
+    // Mark 'reference' gray, unless it has already been marked or is old and
+    // the collection is young.
+    pub fn mark_object(&mut self, reference: ObjRef) {
+        let slot = &mut self.slots[reference.0 as usize];
+        if slot.marked || (slot.old && self.collecting == Some(Generation::Young)) {
+            return;
+        }
+
+        slot.marked = true;
+        self.gray.push(reference);
+    }
+
+    // Mark the objects 'object' refers to. Used for an object that is about
+    // to be allocated, whose references are not yet reachable from a root.
+    pub fn mark_references(&mut self, object: &Object) {
+        object.trace(|value| self.mark_value(value));
+    }
+
+    // Mark the objects 'reference' refers to. The object is taken out of its
+    // slot while they are, which leaves the slots free to be marked.
+    fn blacken(&mut self, reference: ObjRef) {
+        let object = self.slots[reference.0 as usize]
+            .object
+            .take()
+            .expect("object has been freed");
+        object.trace(|value| self.mark_value(value));
+        self.slots[reference.0 as usize].object = Some(object);
+    }
+
+    // Finish the collection started by 'begin_collection', freeing every
+    // object not reachable from the objects marked since. 'started' is when
+    // the collection began, for the pause statistics.
+    pub fn collect(&mut self, started: Instant) {
+        let generation = self.collecting.take().expect("collection in progress");
+
+        while let Some(reference) = self.gray.pop() {
+            self.blacken(reference);
+        }
+
+        let (bytes, objects) = (self.bytes_allocated, self.len());
+        match generation {
+            Generation::Young => {
+                for index in mem::take(&mut self.young) {
+                    self.sweep(index);
+                }
+            }
+            Generation::Full => {
+                for index in 0..self.slots.len() as u32 {
+                    self.sweep(index);
+                }
+                for reference in mem::take(&mut self.remembered) {
+                    self.slots[reference.0 as usize].remembered = false;
+                }
+                self.young.clear();
+                self.next_gc = (self.bytes_allocated * GROWTH_FACTOR).max(INITIAL_THRESHOLD);
+            }
+        }
+        self.young_bytes = 0;
+
+        let pause = started.elapsed();
+        let (bytes_freed, objects_freed) = (bytes - self.bytes_allocated, objects - self.len());
+        let stats = match generation {
+            Generation::Young => &mut self.stats.young,
+            Generation::Full => &mut self.stats.full,
+        };
+        stats.collections += 1;
+        stats.objects_freed += objects_freed;
+        stats.bytes_freed += bytes_freed;
+        stats.total_pause += pause;
+        stats.max_pause = stats.max_pause.max(pause);
+    }
+
+    // Free the object at 'index' if it was not marked, otherwise unmark it
+    // for the next collection. Survivors become old in generational mode.
+    fn sweep(&mut self, index: u32) {
+        let slot = &mut self.slots[index as usize];
+        if slot.object.is_none() {
+            return;
+        }
+
+        if mem::take(&mut slot.marked) {
+            slot.old = self.mode == GcMode::Generational;
+        } else {
+            self.bytes_allocated -= slot.size;
+            self.free.push(index);
+            match slot.object.take() {
+                Some(Object::String(string)) => {
+                    self.strings.remove(&string);
+                }
+                Some(Object::Class(class)) => self.free_shapes(class.shape),
+                _ => (),
+            }
+        }
+    }
+
+    // Load a compiled function and, recursively, the functions among its
+    // constants into the heap.
+    pub fn load_function(&mut self, proto: Rc<FunctionProto>) -> ObjRef {
+        let constants: Vec<Value> = proto
+            .chunk
+            .constants
+            .iter()
+            .map(|constant| match constant {
+                Constant::Number(value) => Value::from(*value),
+                Constant::String(value) => self.string(value.as_str()),
+                Constant::Function(function) => {
+                    Value::from(self.load_function(Rc::clone(function)))
+                }
+            })
+            .collect();
+
+        let caches = (0..proto.cache_count).map(|_| RefCell::default()).collect();
+        self.allocate(Object::Function(Function {
+            proto,
+            constants: constants.into(),
+            caches,
+        }))
+    }
+
+    // The contents of 'value' if it is a string.
+    pub fn as_string(&self, value: Value) -> Option<&Rc<str>> {
+        match self.get(value.as_object()?) {
+            Object::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    pub fn function(&self, reference: ObjRef) -> &Function {
+        match self.get(reference) {
+            Object::Function(function) => function,
+            _ => panic!("expected a function"),
+        }
+    }
+
+    pub fn closure(&self, reference: ObjRef) -> &Closure {
+        match self.get(reference) {
+            Object::Closure(closure) => closure,
+            _ => panic!("expected a closure"),
+        }
+    }
+
+    pub fn upvalue(&self, reference: ObjRef) -> &Upvalue {
+        match self.get(reference) {
+            Object::Upvalue(upvalue) => upvalue,
+            _ => panic!("expected an upvalue"),
+        }
+    }
+
+    pub fn upvalue_mut(&mut self, reference: ObjRef) -> &mut Upvalue {
+        match self.get_mut(reference) {
+            Object::Upvalue(upvalue) => upvalue,
+            _ => panic!("expected an upvalue"),
+        }
+    }
+
+    pub fn class(&self, reference: ObjRef) -> &Class {
+        match self.get(reference) {
+            Object::Class(class) => class,
+            _ => panic!("expected a class"),
+        }
+    }
+
+    pub fn instance(&self, reference: ObjRef) -> &Instance {
+        match self.get(reference) {
+            Object::Instance(instance) => instance,
+            _ => panic!("expected an instance"),
+        }
+    }
+
+    pub fn instance_mut(&mut self, reference: ObjRef) -> &mut Instance {
+        match self.get_mut(reference) {
+            Object::Instance(instance) => instance,
+            _ => panic!("expected an instance"),
+        }
+    }
+
+    // The list 'value' refers to, if it is one.
+    pub fn as_list(&self, value: Value) -> Option<ObjRef> {
+        let reference = value.as_object()?;
+        matches!(self.get(reference), Object::List(_)).then_some(reference)
+    }
+
+    pub fn list(&self, reference: ObjRef) -> &Vec<Value> {
+        match self.get(reference) {
+            Object::List(elements) => elements,
+            _ => panic!("expected a list"),
+        }
+    }
+
+    pub fn list_mut(&mut self, reference: ObjRef) -> &mut Vec<Value> {
+        match self.get_mut(reference) {
+            Object::List(elements) => elements,
+            _ => panic!("expected a list"),
+        }
+    }
+
+    // The map 'value' refers to, if it is one.
+    pub fn as_map(&self, value: Value) -> Option<ObjRef> {
+        let reference = value.as_object()?;
+        matches!(self.get(reference), Object::Map(_)).then_some(reference)
+    }
+
+    pub fn map(&self, reference: ObjRef) -> &Map {
+        match self.get(reference) {
+            Object::Map(map) => map,
+            _ => panic!("expected a map"),
+        }
+    }
+
+    pub fn map_mut(&mut self, reference: ObjRef) -> &mut Map {
+        match self.get_mut(reference) {
+            Object::Map(map) => map,
+            _ => panic!("expected a map"),
+        }
+    }
+
+    // The sorted map or set 'value' refers to, if it is one.
+    pub fn as_sorted(&self, value: Value) -> Option<ObjRef> {
+        let reference = value.as_object()?;
+        matches!(self.get(reference), Object::Sorted(_)).then_some(reference)
+    }
+
+    pub fn sorted(&self, reference: ObjRef) -> &Sorted {
+        match self.get(reference) {
+            Object::Sorted(sorted) => sorted,
+            _ => panic!("expected a sorted map or set"),
+        }
+    }
+
+    pub fn sorted_mut(&mut self, reference: ObjRef) -> &mut Sorted {
+        match self.get_mut(reference) {
+            Object::Sorted(sorted) => sorted,
+            _ => panic!("expected a sorted map or set"),
+        }
+    }
+
+    // The value of a key of a sorted map or set.
+    pub fn key_value(&mut self, key: &sorted::Key<Rc<str>>) -> Value {
+        match key {
+            sorted::Key::Number(number) => Value::from(*number),
+            sorted::Key::String(string) => self.string(string),
+        }
+    }
+
+    pub fn class_mut(&mut self, reference: ObjRef) -> &mut Class {
+        match self.get_mut(reference) {
+            Object::Class(class) => class,
+            _ => panic!("expected a class"),
+        }
+    }
+
+    // Numbers and booleans compare by value, everything else by identity.
+    // Strings are interned, so comparing them by identity compares their
+    // contents.
+    pub fn values_equal(&self, a: Value, b: Value) -> bool {
+        a.unbox() == b.unbox()
+    }
+
+    // Format 'value' the same way the tree-walking interpreter does.
+    pub fn display(&self, value: Value) -> Display<'_> {
+        Display { heap: self, value }
+    }
+
+    fn function_name(&self, closure: ObjRef) -> &str {
+        &self.function(self.closure(closure).function).proto.name
+    }
+}
+
+pub struct Display<'a> {
+    heap: &'a Heap,
+    value: Value,
+}
+
+impl fmt::Display for Display<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, self.value, &mut Vec::new())
+    }
+}
+
+impl Display<'_> {
+    // Write 'value', printing a list or map that contains itself, directly or
+    // through the others in 'open', as '[...]' or '{...}'.
+    fn write(&self, f: &mut fmt::Formatter, value: Value, open: &mut Vec<ObjRef>) -> fmt::Result {
+        let reference = match value.unbox() {
+            Unboxed::Nil => return write!(f, "nil"),
+            Unboxed::Bool(value) => return write!(f, "{value}"),
+            Unboxed::Number(value) => return write!(f, "{value}"),
+            Unboxed::Object(reference) => reference,
+        };
+
+        match self.heap.get(reference) {
+            Object::String(value) => write!(f, "{value}"),
+            Object::Function(function) => write_function(f, &function.proto.name),
+            Object::Native(_) => write!(f, "<native fn>"),
+            Object::Closure(_) => write_function(f, self.heap.function_name(reference)),
+            Object::Upvalue(_) => write!(f, "<upvalue>"),
+            Object::Class(class) => write!(f, "{}", class.name),
+            Object::Instance(instance) => {
+                write!(f, "{} instance", self.heap.class(instance.class).name)
+            }
+            Object::BoundMethod(bound) => write_function(f, self.heap.function_name(bound.method)),
+            Object::List(_) if open.contains(&reference) => write!(f, "[...]"),
+            Object::List(elements) => {
+                open.push(reference);
+                write!(f, "[")?;
+                for (i, &element) in elements.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    self.write(f, element, open)?;
+                }
+                open.pop();
+                write!(f, "]")
+            }
+            Object::Map(_) if open.contains(&reference) => write!(f, "{{...}}"),
+            Object::Map(map) => {
+                open.push(reference);
+                write!(f, "{{")?;
+                for (i, &(key, value)) in map.values().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    self.write(f, key, open)?;
+                    write!(f, ": ")?;
+                    self.write(f, value, open)?;
+                }
+                open.pop();
+                write!(f, "}}")
+            }
+            Object::SortedClass(kind) => write!(f, "{}", kind.name()),
+            Object::Sorted(_) if open.contains(&reference) => write!(f, "{{...}}"),
+            Object::Sorted(sorted) => {
+                open.push(reference);
+                write!(f, "{{")?;
+                match sorted {
+                    sorted::Sorted::Map(map) => {
+                        for (i, (key, &value)) in map.iter().enumerate() {
+                            if i > 0 {
+                                write!(f, ", ")?;
+                            }
+                            write_key(f, key)?;
+                            write!(f, ": ")?;
+                            self.write(f, value, open)?;
+                        }
+                    }
+                    sorted::Sorted::Set(set) => {
+                        for (i, key) in set.iter().enumerate() {
+                            if i > 0 {
+                                write!(f, ", ")?;
+                            }
+                            write_key(f, key)?;
+                        }
+                    }
+                }
+                open.pop();
+                write!(f, "}}")
+            }
+            Object::ListMethod(_) | Object::MapMethod(_) | Object::SortedMethod(_) => {
+                write!(f, "<native fn>")
+            }
+        }
+    }
+}
+
+fn write_key(f: &mut fmt::Formatter, key: &sorted::Key<Rc<str>>) -> fmt::Result {
+    match key {
+        sorted::Key::Number(number) => write!(f, "{number}"),
+        sorted::Key::String(string) => write!(f, "{string}"),
+    }
+}
+
+// The top-level script is the only function without a name.
+fn write_function(f: &mut fmt::Formatter, name: &str) -> fmt::Result {
+    if name.is_empty() {
+        write!(f, "<script>")
+    } else {
+        write!(f, "<fn {name}>")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values() {
+        let reference = ObjRef(7);
+        for (value, unboxed) in [
+            (Value::NIL, Unboxed::Nil),
+            (Value::TRUE, Unboxed::Bool(true)),
+            (Value::from(false), Unboxed::Bool(false)),
+            (Value::from(-0.5), Unboxed::Number(-0.5)),
+            (Value::from(f64::INFINITY), Unboxed::Number(f64::INFINITY)),
+            (Value::from(reference), Unboxed::Object(reference)),
+        ] {
+            assert_eq!(value.unbox(), unboxed);
+        }
+
+        // A NaN whose bits would otherwise decode as another kind of value.
+        let nan = f64::from_bits(0xfffc_0000_0000_0001);
+        assert!(Value::from(nan).as_number().unwrap().is_nan());
+        assert!(Value::from(f64::NAN).is_truthy());
+        assert!(!Value::NIL.is_truthy());
+    }
+
+    #[cfg(feature = "nan-boxing")]
+    #[test]
+    fn nan_boxed_size() {
+        assert_eq!(mem::size_of::<Value>(), 8);
+    }
+
+    #[test]
+    fn equality() {
+        let mut heap = Heap::new();
+        let a = heap.string("lox");
+        let b = heap.string("lox");
+        let c = heap.string("other");
+
+        assert_eq!(a.as_object(), b.as_object());
+        assert_eq!(heap.len(), 2);
+        assert!(heap.values_equal(a, b));
+        assert!(!heap.values_equal(a, c));
+        assert!(heap.values_equal(Value::from(1.0), Value::from(1.0)));
+        assert!(!heap.values_equal(Value::from(f64::NAN), Value::from(f64::NAN)));
+        assert!(!heap.values_equal(Value::NIL, Value::FALSE));
+    }
+
+    #[test]
+    fn collects_unreachable_cycles() {
+        let mut heap = Heap::new();
+        let kept = heap.string("kept");
+
+        // A closure whose upvalue holds the closure itself, the cycle a
+        // recursive local function creates.
+        let function = heap.allocate(Object::Function(Function {
+            proto: Rc::new(FunctionProto {
+                name: Rc::from("f"),
+                arity: 0,
+                upvalue_count: 1,
+                cache_count: 0,
+                chunk: Default::default(),
+            }),
+            constants: Rc::from([kept]),
+            caches: Rc::from([]),
+        }));
+        let upvalue = heap.allocate(Object::Upvalue(Upvalue::Closed(Value::NIL)));
+        let closure = heap.allocate(Object::Closure(Closure {
+            function,
+            upvalues: vec![upvalue],
+        }));
+        *heap.upvalue_mut(upvalue) = Upvalue::Closed(Value::from(closure));
+        heap.string("garbage");
+        assert_eq!(heap.len(), 5);
+
+        heap.begin_collection();
+        heap.mark_object(closure);
+        heap.collect(Instant::now());
+        assert_eq!(heap.len(), 4);
+        assert_eq!(heap.display(kept).to_string(), "kept");
+        assert_eq!(heap.find_string("garbage"), None);
+
+        heap.begin_collection();
+        heap.collect(Instant::now());
+        assert!(heap.is_empty());
+        assert_eq!(heap.bytes_allocated, 0);
+
+        // Freed slots are reused.
+        heap.string("new");
+        assert_eq!(heap.slots.len(), 5);
+    }
+
+    #[test]
+    fn young_collections() {
+        let mut heap = Heap::with_mode(GcMode::Generational);
+        let shape = heap.new_shape();
+        let class = heap.allocate(Object::Class(Class {
+            name: Symbol::intern("A"),
+            methods: HashMap::new(),
+            shape,
+        }));
+        let instance = heap.allocate(Object::Instance(Instance {
+            class,
+            shape,
+            fields: Vec::new(),
+        }));
+        heap.string("garbage");
+
+        assert_eq!(heap.begin_collection(), Generation::Young);
+        heap.mark_object(instance);
+        heap.collect(Instant::now());
+        assert_eq!(heap.len(), 2);
+        assert!(heap.slots[instance.0 as usize].old);
+
+        // A young string stored in the now old instance is only kept alive by
+        // the write barrier.
+        let field = heap.string("field");
+        if let Object::Instance(instance) = heap.get_mut(instance) {
+            instance.fields.push(field);
+        }
+        heap.write_barrier(instance, field);
+
+        // Old objects are neither traced nor freed by a young collection,
+        // even when unreachable.
+        heap.begin_collection();
+        heap.collect(Instant::now());
+        assert_eq!(heap.len(), 3);
+        assert_eq!(heap.display(field).to_string(), "field");
+
+        heap.bytes_allocated = heap.next_gc + 1;
+        assert_eq!(heap.begin_collection(), Generation::Full);
+        heap.collect(Instant::now());
+        assert!(heap.is_empty());
+
+        assert_eq!(heap.stats().young.collections, 2);
+        assert_eq!(heap.stats().young.objects_freed, 1);
+        assert_eq!(heap.stats().full.collections, 1);
+        assert_eq!(heap.stats().full.objects_freed, 3);
+    }
+
+    #[test]
+    fn resizing() {
+        let mut heap = Heap::new();
+        let list = heap.allocate(Object::List(Vec::new()));
+        let allocated = heap.bytes_allocated;
+
+        heap.list_mut(list).extend([Value::NIL; 100]);
+        heap.resize(list);
+        assert_eq!(
+            heap.bytes_allocated,
+            allocated + 100 * mem::size_of::<Value>()
+        );
+
+        heap.list_mut(list).truncate(10);
+        heap.resize(list);
+        assert_eq!(
+            heap.bytes_allocated,
+            allocated + 10 * mem::size_of::<Value>()
+        );
+
+        heap.begin_collection();
+        heap.collect(Instant::now());
+        assert_eq!(heap.bytes_allocated, 0);
+    }
+
+    #[test]
+    fn shapes() {
+        let mut heap = Heap::new();
+        let (x, y) = (Symbol::intern("x"), Symbol::intern("y"));
+        let empty = heap.new_shape();
+
+        let xy = heap.add_field(empty, x);
+        let xy = heap.add_field(xy, y);
+        assert_eq!(heap.field_slot(xy, x), Some(0));
+        assert_eq!(heap.field_slot(xy, y), Some(1));
+        assert_eq!(heap.field_slot(empty, x), None);
+
+        // Adding the same fields in the same order reaches the same shape, and
+        // in another order a different one.
+        let again = heap.add_field(empty, x);
+        assert_eq!(heap.add_field(again, y), xy);
+        let yx = heap.add_field(empty, y);
+        let yx = heap.add_field(yx, x);
+        assert_ne!(yx, xy);
+        assert_eq!(heap.field_slot(yx, x), Some(1));
+    }
+
+    // A class's shapes are freed with it, and their IDs aren't reused.
+    #[test]
+    fn shapes_are_freed_with_their_class() {
+        let mut heap = Heap::new();
+        let shape = heap.new_shape();
+        let class = heap.allocate(Object::Class(Class {
+            name: Symbol::intern("A"),
+            methods: HashMap::new(),
+            shape,
+        }));
+        let x = heap.add_field(shape, Symbol::intern("x"));
+        heap.add_field(x, Symbol::intern("y"));
+        heap.add_field(shape, Symbol::intern("y"));
+        assert_eq!(heap.shape_count(), 4);
+
+        heap.begin_collection();
+        heap.mark_object(class);
+        heap.collect(Instant::now());
+        assert_eq!(heap.shape_count(), 4);
+
+        heap.begin_collection();
+        heap.collect(Instant::now());
+        assert_eq!(heap.shape_count(), 0);
+        assert_ne!(heap.new_shape(), shape);
+    }
+
+    #[test]
+    fn gc_mode_names() {
+        assert_eq!("mark-sweep".parse(), Ok(GcMode::MarkSweep));
+        assert_eq!("generational".parse(), Ok(GcMode::Generational));
+        assert!("incremental".parse::<GcMode>().is_err());
+    }
+
+    #[test]
+    fn display() {
+        let mut heap = Heap::new();
+        let string = heap.string("abc");
+
+        assert_eq!(heap.display(Value::from(3.0)).to_string(), "3");
+        assert_eq!(heap.display(Value::from(2.5)).to_string(), "2.5");
+        assert_eq!(heap.display(Value::NIL).to_string(), "nil");
+        assert_eq!(heap.display(string).to_string(), "abc");
+
+        let list = heap.allocate(Object::List(vec![Value::from(1.0), string]));
+        heap.list_mut(list).push(Value::from(list));
+        let outer = heap.allocate(Object::List(vec![Value::from(list), Value::NIL]));
+        assert_eq!(
+            heap.display(Value::from(outer)).to_string(),
+            "[[1, abc, [...]], nil]"
+        );
+
+        let map = heap.allocate(Object::Map(Map::default()));
+        let key = map::Key::String(string.as_object().unwrap());
+        heap.map_mut(map).insert(key, (string, Value::from(map)));
+        heap.map_mut(map)
+            .insert(map::Key::Nil, (Value::NIL, Value::from(outer)));
+        assert_eq!(
+            heap.display(Value::from(map)).to_string(),
+            "{abc: {...}, nil: [[1, abc, [...]], nil]}"
+        );
+    }
+}
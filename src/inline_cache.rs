@@ -0,0 +1,70 @@
+use crate::heap::{ObjRef, ShapeId};
+
+// A site stops recording shapes once it has seen this many, and from then on
+// always looks properties up by name.
+const POLYMORPHIC_LIMIT: usize = 4;
+
+// What a property instruction found on instances of one shape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    // The field stored in this slot.
+    Field(usize),
+    // The shape has no such field, so the property is the class's method.
+    // Only valid while 'epoch' is the VM's current method epoch.
+    Method { method: ObjRef, epoch: u32 },
+    // Setting the property adds a field, giving the instance this shape.
+    Transition(ShapeId),
+}
+
+// The result of the lookups made by one property instruction, keyed by the
+// shape of the instance they were made on. Instances of the same shape have
+// the same fields in the same slots, so the cached target is valid for any
+// instance of that shape. Adding a field changes an instance's shape rather
+// than the shape itself, so entries never need to be invalidated when fields
+// are added.
+#[derive(Debug, Default)]
+pub struct InlineCache {
+    entries: Vec<(ShapeId, Target)>,
+}
+
+impl InlineCache {
+    pub fn lookup(&self, shape: ShapeId) -> Option<Target> {
+        self.entries
+            .iter()
+            .find(|(cached, _)| *cached == shape)
+            .map(|&(_, target)| target)
+    }
+
+    // Record the target found for 'shape', replacing an older entry for it.
+    pub fn update(&mut self, shape: ShapeId, target: Target) {
+        if let Some(entry) = self.entries.iter_mut().find(|(cached, _)| *cached == shape) {
+            entry.1 = target;
+        } else if self.entries.len() < POLYMORPHIC_LIMIT {
+            self.entries.push((shape, target));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heap::Heap;
+
+    #[test]
+    fn polymorphic() {
+        let mut heap = Heap::new();
+        let shapes: Vec<ShapeId> = (0..=POLYMORPHIC_LIMIT).map(|_| heap.new_shape()).collect();
+        let mut cache = InlineCache::default();
+
+        for (slot, &shape) in shapes.iter().enumerate() {
+            assert_eq!(cache.lookup(shape), None);
+            cache.update(shape, Target::Field(slot));
+        }
+
+        assert_eq!(cache.lookup(shapes[0]), Some(Target::Field(0)));
+        assert_eq!(cache.lookup(shapes[POLYMORPHIC_LIMIT]), None);
+
+        cache.update(shapes[1], Target::Transition(shapes[2]));
+        assert_eq!(cache.lookup(shapes[1]), Some(Target::Transition(shapes[2])));
+    }
+}
@@ -0,0 +1,1513 @@
+use crate::ast::{Expression, FunctionDeclaration, Statement};
+use crate::environment::Environment;
+use crate::lexer::{OwnedToken, TokenType};
+use crate::list;
+use crate::map;
+use crate::parser::DEFAULT_MAX_DEPTH;
+use crate::result::{Error, Result, TraceFrame};
+use crate::sorted;
+use crate::symbol::{self, Symbol};
+use crate::value::{Class, Function, Instance, Map, NativeFunction, Sorted, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Tree-walking interpreter. Global bindings persist across calls to
+// 'interpret', so the same interpreter can run a sequence of REPL entries.
+// Statements must have been resolved with 'resolver::resolve' first.
+pub struct Interpreter {
+    globals: Rc<RefCell<Environment>>,
+    environment: Rc<RefCell<Environment>>,
+    output: Box<dyn Write>,
+    // The calls in progress, innermost last, and how many the script may nest
+    // before a stack overflow. Calls made in tail position replace their
+    // caller and aren't counted.
+    frames: Vec<Frame>,
+    max_depth: usize,
+}
+
+// A call in progress: the function running, the line of the call that
+// started it, and the number of calls in tail position it has made in place
+// of returning.
+struct Frame {
+    function: Symbol,
+    line: usize,
+    tail_calls: usize,
+}
+
+// The result of executing a statement: 'Some' carries a 'return' statement up
+// to the enclosing call.
+type Completion = Option<Return>;
+
+// How a function returns. A call in tail position is made by the caller of
+// the returning function in its place, so that tail calls run in constant
+// space however deep they recurse.
+enum Return {
+    Value(Value),
+    Call(Rc<Function>, Vec<Value>),
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Interpreter::new()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Interpreter {
+        Interpreter::with_output(Box::new(io::stdout()))
+    }
+
+    // Create an interpreter that writes the output of 'print' statements to
+    // 'output'.
+    pub fn with_output(output: Box<dyn Write>) -> Interpreter {
+        let globals = Rc::new(RefCell::new(native_globals()));
+
+        Interpreter {
+            environment: Rc::clone(&globals),
+            globals,
+            output,
+            frames: Vec::new(),
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+
+    // Report a stack overflow once a call would nest more than 'max_depth'
+    // calls, counting the script itself as one of them.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    // Execute the given 'statements' in order, stopping at the first runtime
+    // error.
+    pub fn interpret(&mut self, statements: &[Statement<OwnedToken>]) -> Result<()> {
+        for statement in statements {
+            self.execute(statement)
+                .map_err(|error| self.traced(error))?;
+        }
+
+        Ok(())
+    }
+
+    // Remove every global binding other than the native functions.
+    pub fn reset(&mut self) {
+        self.globals = Rc::new(RefCell::new(native_globals()));
+        self.environment = Rc::clone(&self.globals);
+    }
+
+    // A copy of the global bindings, which 'restore_globals' puts back.
+    pub fn save_globals(&self) -> Environment {
+        self.globals.borrow().clone()
+    }
+
+    // Put back the global bindings from 'save_globals'. Functions declared
+    // since still refer to the global scope, which holds the saved bindings
+    // again.
+    pub fn restore_globals(&mut self, globals: Environment) {
+        *self.globals.borrow_mut() = globals;
+    }
+
+    // Write 'value' to the output as a 'print' statement does.
+    pub fn print(&mut self, value: &Value) {
+        writeln!(self.output, "{value}").expect("failed to write output");
+    }
+
+    // The global bindings, ordered by name.
+    pub fn globals(&self) -> Vec<(String, Value)> {
+        self.globals.borrow().bindings()
+    }
+
+    fn execute(&mut self, statement: &Statement<OwnedToken>) -> Result<Completion> {
+        match statement {
+            Statement::Expression(expression) => {
+                self.evaluate(expression)?;
+            }
+            Statement::Print { expression, .. } => {
+                let value = self.evaluate(expression)?;
+                self.print(&value);
+            }
+            Statement::Var {
+                name, initializer, ..
+            } => {
+                let value = match initializer {
+                    Some(initializer) => self.evaluate(initializer)?,
+                    None => Value::Nil,
+                };
+                self.environment.borrow_mut().define(name.lexeme, value);
+            }
+            Statement::Block(statements) => {
+                let environment = Environment::with_enclosing(Rc::clone(&self.environment));
+                return self.execute_block(statements, Rc::new(RefCell::new(environment)));
+            }
+            Statement::If {
+                condition,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                if self.evaluate(condition)?.is_truthy() {
+                    return self.execute(then_branch);
+                } else if let Some(else_branch) = else_branch {
+                    return self.execute(else_branch);
+                }
+            }
+            Statement::While {
+                condition, body, ..
+            } => {
+                while self.evaluate(condition)?.is_truthy() {
+                    if let Some(value) = self.execute(body)? {
+                        return Ok(Some(value));
+                    }
+                }
+            }
+            Statement::Function { declaration, .. } => {
+                let function = self.function(declaration, false);
+                self.environment
+                    .borrow_mut()
+                    .define(declaration.name.lexeme, Value::Function(Rc::new(function)));
+            }
+            Statement::Return { value, .. } => {
+                let value = match value.as_deref() {
+                    Some(Expression::Call {
+                        callee,
+                        paren,
+                        arguments,
+                    }) => return self.tail_call(callee, paren, arguments),
+                    Some(value) => self.evaluate(value)?,
+                    None => Value::Nil,
+                };
+                return Ok(Some(Return::Value(value)));
+            }
+            Statement::Class {
+                name,
+                superclass,
+                methods,
+                ..
+            } => self.class(name, superclass.as_deref(), methods)?,
+        }
+
+        Ok(None)
+    }
+
+    // Execute 'statements' within the given 'environment', restoring the
+    // current environment afterwards even if an error occurs.
+    fn execute_block(
+        &mut self,
+        statements: &[Statement<OwnedToken>],
+        environment: Rc<RefCell<Environment>>,
+    ) -> Result<Completion> {
+        let previous = std::mem::replace(&mut self.environment, environment);
+        let result = self.execute_statements(statements);
+        self.environment = previous;
+
+        result
+    }
+
+    fn execute_statements(&mut self, statements: &[Statement<OwnedToken>]) -> Result<Completion> {
+        for statement in statements {
+            if let Some(value) = self.execute(statement)? {
+                return Ok(Some(value));
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Create a function closing over the current environment.
+    fn function(
+        &self,
+        declaration: &Rc<FunctionDeclaration<OwnedToken>>,
+        is_initializer: bool,
+    ) -> Function {
+        Function {
+            declaration: Rc::clone(declaration),
+            closure: Rc::clone(&self.environment),
+            is_initializer,
+        }
+    }
+
+    fn class(
+        &mut self,
+        name: &OwnedToken,
+        superclass: Option<&Expression<OwnedToken>>,
+        methods: &[Rc<FunctionDeclaration<OwnedToken>>],
+    ) -> Result<()> {
+        let superclass = match superclass {
+            Some(expression) => match self.evaluate(expression)? {
+                Value::Class(class) => Some(class),
+                _ => {
+                    let position = match expression {
+                        Expression::Variable { name, .. } => name.source_position,
+                        _ => name.source_position,
+                    };
+                    return Err(Error::RuntimeError {
+                        message: "superclass must be a class".to_string(),
+                        source_position: position,
+                        trace: Vec::new(),
+                    });
+                }
+            },
+            None => None,
+        };
+
+        self.environment
+            .borrow_mut()
+            .define(name.lexeme, Value::Nil);
+
+        // Methods of a subclass close over an extra scope binding 'super'.
+        let previous = Rc::clone(&self.environment);
+        if let Some(ref superclass) = superclass {
+            let mut environment = Environment::with_enclosing(Rc::clone(&self.environment));
+            environment.define(symbol::SUPER, Value::Class(Rc::clone(superclass)));
+            self.environment = Rc::new(RefCell::new(environment));
+        }
+
+        let methods: HashMap<Symbol, Rc<Function>> = methods
+            .iter()
+            .map(|method| {
+                let is_initializer = method.name.lexeme == symbol::INIT;
+                let function = self.function(method, is_initializer);
+                (method.name.lexeme, Rc::new(function))
+            })
+            .collect();
+
+        self.environment = previous;
+
+        let class = Class {
+            name: name.lexeme.to_string(),
+            superclass,
+            methods,
+        };
+        self.environment
+            .borrow_mut()
+            .assign(name, Value::Class(Rc::new(class)))
+    }
+
+    pub fn evaluate(&mut self, expression: &Expression<OwnedToken>) -> Result<Value> {
+        match expression {
+            Expression::Literal { value, .. } => Ok(Value::from(value)),
+            Expression::Grouping(expression) => self.evaluate(expression),
+            Expression::Unary { operator, right } => {
+                let right = self.evaluate(right)?;
+
+                match operator.token_type {
+                    TokenType::Minus => Ok(Value::Number(-number_operand(operator, &right)?)),
+                    TokenType::Bang => Ok(Value::Bool(!right.is_truthy())),
+                    _ => Err(unexpected_operator(operator)),
+                }
+            }
+            Expression::Binary {
+                operator,
+                left,
+                right,
+            } => {
+                let left = self.evaluate(left)?;
+                let right = self.evaluate(right)?;
+
+                binary(operator, left, right)
+            }
+            Expression::Ternary {
+                left,
+                middle,
+                right,
+                ..
+            } => {
+                if self.evaluate(left)?.is_truthy() {
+                    self.evaluate(middle)
+                } else {
+                    self.evaluate(right)
+                }
+            }
+            Expression::Logical {
+                operator,
+                left,
+                right,
+            } => {
+                let left = self.evaluate(left)?;
+
+                match operator.token_type {
+                    TokenType::Or if left.is_truthy() => Ok(left),
+                    TokenType::And if !left.is_truthy() => Ok(left),
+                    _ => self.evaluate(right),
+                }
+            }
+            Expression::Variable { name, depth } => self.look_up_variable(name, depth.get()),
+            Expression::Assign { name, value, depth } => {
+                let value = self.evaluate(value)?;
+
+                match depth.get() {
+                    Some(distance) => self.environment.borrow_mut().assign_at(
+                        distance,
+                        name.lexeme,
+                        value.clone(),
+                    ),
+                    None => self.globals.borrow_mut().assign(name, value.clone())?,
+                }
+
+                Ok(value)
+            }
+            Expression::Call {
+                callee,
+                paren,
+                arguments,
+            } => {
+                let callee = self.evaluate(callee)?;
+                let arguments = arguments
+                    .iter()
+                    .map(|argument| self.evaluate(argument))
+                    .collect::<Result<Vec<Value>>>()?;
+
+                self.call(callee, arguments, paren)
+            }
+            Expression::Get { object, name } => match self.evaluate(object)? {
+                Value::Instance(instance) => get_property(&instance, name),
+                Value::List(list) => match list::Method::from_name(name.lexeme.as_str()) {
+                    Some(method) => Ok(Value::ListMethod(list, method)),
+                    None => Err(undefined_property(name)),
+                },
+                Value::Map(map) => match map::Method::from_name(name.lexeme.as_str()) {
+                    Some(method) => Ok(Value::MapMethod(map, method)),
+                    None => Err(undefined_property(name)),
+                },
+                Value::Sorted(sorted) => {
+                    let kind = sorted.borrow().kind();
+                    match sorted::Method::from_name(kind, name.lexeme.as_str()) {
+                        Some(method) => Ok(Value::SortedMethod(sorted, method)),
+                        None => Err(undefined_property(name)),
+                    }
+                }
+                _ => Err(Error::RuntimeError {
+                    message: "only instances have properties".to_string(),
+                    source_position: name.source_position,
+                    trace: Vec::new(),
+                }),
+            },
+            Expression::Set {
+                object,
+                name,
+                value,
+            } => {
+                // Both operands are evaluated before the object is checked,
+                // matching the order of the bytecode VM.
+                let object = self.evaluate(object)?;
+                let value = self.evaluate(value)?;
+
+                match object {
+                    Value::Instance(instance) => {
+                        instance
+                            .borrow_mut()
+                            .fields
+                            .insert(name.lexeme, value.clone());
+
+                        Ok(value)
+                    }
+                    _ => Err(Error::RuntimeError {
+                        message: "only instances have fields".to_string(),
+                        source_position: name.source_position,
+                        trace: Vec::new(),
+                    }),
+                }
+            }
+            Expression::List { elements, .. } => {
+                let elements = elements
+                    .iter()
+                    .map(|element| self.evaluate(element))
+                    .collect::<Result<Vec<Value>>>()?;
+
+                Ok(Value::List(Rc::new(RefCell::new(elements))))
+            }
+            Expression::Map { brace, entries } => self.map_literal(brace, entries),
+            Expression::Index {
+                object,
+                bracket,
+                index,
+            } => {
+                let object = self.evaluate(object)?;
+                let index = self.evaluate(index)?;
+
+                self.get_index(object, index, bracket)
+            }
+            Expression::Slice {
+                object,
+                bracket,
+                start,
+                end,
+            } => {
+                let object = self.evaluate(object)?;
+                let start = self.evaluate(start)?;
+                let end = self.evaluate(end)?;
+
+                let list = match &object {
+                    Value::List(list) => list.borrow(),
+                    _ => return Err(error_at(bracket, "can only slice lists")),
+                };
+                let range = slice_bound(&start)
+                    .and_then(|start| list::slice(start, slice_bound(&end)?, list.len()))
+                    .map_err(|message| error_at(bracket, message))?;
+                Ok(Value::List(Rc::new(RefCell::new(list[range].to_vec()))))
+            }
+            Expression::SetIndex {
+                object,
+                bracket,
+                index,
+                value,
+            } => {
+                let object = self.evaluate(object)?;
+                let index = self.evaluate(index)?;
+                let value = self.evaluate(value)?;
+
+                self.set_index(object, index, value, bracket)
+            }
+            Expression::This { keyword, depth } => self.look_up_variable(keyword, depth.get()),
+            Expression::Super {
+                keyword,
+                method,
+                depth,
+            } => {
+                // The resolver places 'this' in the scope just inside the one
+                // binding 'super'.
+                let distance = depth.get().unwrap_or(0);
+                let superclass = self.environment.borrow().get_at(distance, symbol::SUPER);
+                let object = distance
+                    .checked_sub(1)
+                    .and_then(|d| self.environment.borrow().get_at(d, symbol::THIS));
+
+                match (superclass, object) {
+                    (Some(Value::Class(superclass)), Some(object)) => {
+                        match superclass.find_method(method.lexeme) {
+                            Some(function) => Ok(Value::Function(Rc::new(function.bind(object)))),
+                            None => Err(undefined_property(method)),
+                        }
+                    }
+                    _ => Err(Error::RuntimeError {
+                        message: "invalid use of 'super'".to_string(),
+                        source_position: keyword.source_position,
+                        trace: Vec::new(),
+                    }),
+                }
+            }
+        }
+    }
+
+    fn look_up_variable(&self, name: &OwnedToken, depth: Option<usize>) -> Result<Value> {
+        match depth {
+            Some(distance) => self
+                .environment
+                .borrow()
+                .get_at(distance, name.lexeme)
+                .ok_or_else(|| Error::RuntimeError {
+                    message: format!("undefined variable '{}'", name.lexeme),
+                    source_position: name.source_position,
+                    trace: Vec::new(),
+                }),
+            None => self.globals.borrow().get(name),
+        }
+    }
+
+    fn call(&mut self, callee: Value, arguments: Vec<Value>, paren: &OwnedToken) -> Result<Value> {
+        let arity = match callee {
+            Value::Function(ref function) => function.arity(),
+            Value::NativeFunction(ref native) => native.arity,
+            Value::Class(ref class) => class.arity(),
+            Value::ListMethod(_, method) => method.arity(),
+            Value::MapMethod(_, method) => method.arity(),
+            Value::SortedClass(_) => 0,
+            Value::SortedMethod(_, method) => method.arity(),
+            _ => {
+                return Err(Error::RuntimeError {
+                    message: "can only call functions and classes".to_string(),
+                    source_position: paren.source_position,
+                    trace: Vec::new(),
+                })
+            }
+        };
+
+        check_arity(arity, arguments.len(), paren)?;
+
+        match callee {
+            Value::Function(function) => self.call_function(function, arguments, paren),
+            Value::NativeFunction(native) => {
+                (native.function)(&arguments).map_err(|message| Error::RuntimeError {
+                    message,
+                    source_position: paren.source_position,
+                    trace: Vec::new(),
+                })
+            }
+            Value::Class(class) => {
+                let instance =
+                    Value::Instance(Rc::new(RefCell::new(Instance::new(Rc::clone(&class)))));
+
+                if let Some(init) = class.find_method(symbol::INIT) {
+                    self.call_function(Rc::new(init.bind(instance.clone())), arguments, paren)?;
+                }
+
+                Ok(instance)
+            }
+            Value::ListMethod(list, method) => {
+                self.call_list_method(&list, method, arguments, paren)
+            }
+            Value::MapMethod(map, method) => self.call_map_method(&map, method, arguments, paren),
+            Value::SortedClass(kind) => Ok(Value::Sorted(Rc::new(RefCell::new(Sorted::new(kind))))),
+            Value::SortedMethod(sorted, method) => {
+                call_sorted_method(&sorted, method, &arguments, paren)
+            }
+            _ => unreachable!("arity was checked above"),
+        }
+    }
+
+    // Call 'method' on 'list'. Methods taking a function call it from the call
+    // at 'paren', on the elements the list had when the method was called.
+    fn call_list_method(
+        &mut self,
+        list: &Rc<RefCell<Vec<Value>>>,
+        method: list::Method,
+        mut arguments: Vec<Value>,
+        paren: &OwnedToken,
+    ) -> Result<Value> {
+        let error = |message| error_at(paren, message);
+
+        match method {
+            list::Method::Push => {
+                list.borrow_mut().push(arguments.remove(0));
+                Ok(Value::Nil)
+            }
+            list::Method::Pop => list
+                .borrow_mut()
+                .pop()
+                .ok_or_else(|| error(list::POP_EMPTY)),
+            list::Method::Insert => {
+                let mut list = list.borrow_mut();
+                let index =
+                    list_index(&arguments[0], list.len(), list::insert_index).map_err(error)?;
+                list.insert(index, arguments.remove(1));
+                Ok(Value::Nil)
+            }
+            list::Method::Remove => {
+                let mut list = list.borrow_mut();
+                let index = list_index(&arguments[0], list.len(), list::index).map_err(error)?;
+                Ok(list.remove(index))
+            }
+            list::Method::Len => Ok(Value::Number(list.borrow().len() as f64)),
+            list::Method::Map => {
+                let elements = list.borrow().clone();
+                let mapped = elements
+                    .into_iter()
+                    .map(|element| self.call(arguments[0].clone(), vec![element], paren))
+                    .collect::<Result<Vec<Value>>>()?;
+
+                Ok(Value::List(Rc::new(RefCell::new(mapped))))
+            }
+            list::Method::Filter => {
+                let elements = list.borrow().clone();
+                let mut kept = Vec::new();
+                for element in elements {
+                    let keep = self.call(arguments[0].clone(), vec![element.clone()], paren)?;
+                    if keep.is_truthy() {
+                        kept.push(element);
+                    }
+                }
+
+                Ok(Value::List(Rc::new(RefCell::new(kept))))
+            }
+            list::Method::Reduce => {
+                let elements = list.borrow().clone();
+                let mut accumulator = arguments.remove(1);
+                for element in elements {
+                    accumulator =
+                        self.call(arguments[0].clone(), vec![accumulator, element], paren)?;
+                }
+
+                Ok(accumulator)
+            }
+            list::Method::Sort => {
+                let mut elements = list.borrow().clone();
+                list::sort(&mut elements, |a, b| {
+                    match self.call(arguments[0].clone(), vec![a.clone(), b.clone()], paren)? {
+                        Value::Number(order) => Ok(order > 0.0),
+                        _ => Err(error(list::NOT_A_COMPARISON)),
+                    }
+                })?;
+                *list.borrow_mut() = elements;
+
+                Ok(Value::Nil)
+            }
+        }
+    }
+
+    // Call 'method' on 'map'. Keys are hashed before the map is looked at, so
+    // a 'hash' method may change the map.
+    fn call_map_method(
+        &mut self,
+        map: &Rc<RefCell<Map>>,
+        method: map::Method,
+        arguments: Vec<Value>,
+        paren: &OwnedToken,
+    ) -> Result<Value> {
+        match method {
+            map::Method::Has => {
+                let key = self.map_key(&arguments[0], paren)?;
+                Ok(Value::Bool(map.borrow().contains_key(&key)))
+            }
+            map::Method::Remove => {
+                let key = self.map_key(&arguments[0], paren)?;
+                match map.borrow_mut().remove(&key) {
+                    Some((_, value)) => Ok(value),
+                    None => Err(error_at(paren, map::KEY_NOT_FOUND)),
+                }
+            }
+            map::Method::Keys => {
+                let keys = map.borrow().values().map(|(key, _)| key.clone()).collect();
+                Ok(Value::List(Rc::new(RefCell::new(keys))))
+            }
+            map::Method::Values => {
+                let values = map
+                    .borrow()
+                    .values()
+                    .map(|(_, value)| value.clone())
+                    .collect();
+                Ok(Value::List(Rc::new(RefCell::new(values))))
+            }
+            map::Method::Len => Ok(Value::Number(map.borrow().len() as f64)),
+        }
+    }
+
+    // Keys are hashed once every entry has been evaluated, matching the order
+    // of the bytecode VM.
+    fn map_literal(
+        &mut self,
+        brace: &OwnedToken,
+        entries: &[(Expression<OwnedToken>, Expression<OwnedToken>)],
+    ) -> Result<Value> {
+        let mut values = Vec::with_capacity(entries.len());
+        for (key, value) in entries {
+            values.push((self.evaluate(key)?, self.evaluate(value)?));
+        }
+
+        let map = Rc::new(RefCell::new(Map::default()));
+        for (key, value) in values {
+            let hashed = self.map_key(&key, brace)?;
+            set_entry(&mut map.borrow_mut(), hashed, key, value);
+        }
+        Ok(Value::Map(map))
+    }
+
+    fn get_index(&mut self, object: Value, index: Value, bracket: &OwnedToken) -> Result<Value> {
+        match object {
+            Value::List(list) => {
+                let list = list.borrow();
+                let index = list_index(&index, list.len(), list::index)
+                    .map_err(|message| error_at(bracket, message))?;
+                Ok(list[index].clone())
+            }
+            Value::Map(map) => {
+                let key = self.map_key(&index, bracket)?;
+                let map = map.borrow();
+                match map.get(&key) {
+                    Some((_, value)) => Ok(value.clone()),
+                    None => Err(error_at(bracket, map::KEY_NOT_FOUND)),
+                }
+            }
+            Value::Sorted(sorted) => match &*sorted.borrow() {
+                sorted::Sorted::Map(map) => {
+                    let key = sorted_key(&index, bracket)?;
+                    match map.get(&key) {
+                        Some(value) => Ok(value.clone()),
+                        None => Err(error_at(bracket, map::KEY_NOT_FOUND)),
+                    }
+                }
+                sorted::Sorted::Set(_) => Err(error_at(bracket, "can only index lists and maps")),
+            },
+            _ => Err(error_at(bracket, "can only index lists and maps")),
+        }
+    }
+
+    fn set_index(
+        &mut self,
+        object: Value,
+        index: Value,
+        value: Value,
+        bracket: &OwnedToken,
+    ) -> Result<Value> {
+        match object {
+            Value::List(list) => {
+                let mut list = list.borrow_mut();
+                let index = list_index(&index, list.len(), list::index)
+                    .map_err(|message| error_at(bracket, message))?;
+                list[index] = value.clone();
+            }
+            Value::Map(map) => {
+                let key = self.map_key(&index, bracket)?;
+                set_entry(&mut map.borrow_mut(), key, index, value.clone());
+            }
+            Value::Sorted(sorted) => match &mut *sorted.borrow_mut() {
+                sorted::Sorted::Map(map) => {
+                    map.insert(sorted_key(&index, bracket)?, value.clone());
+                }
+                sorted::Sorted::Set(_) => {
+                    return Err(error_at(bracket, "can only index lists and maps"))
+                }
+            },
+            _ => return Err(error_at(bracket, "can only index lists and maps")),
+        }
+        Ok(value)
+    }
+
+    // The key 'value' is looked up by in a map. An instance is looked up by
+    // what its 'hash' method, called from 'token', returns.
+    fn map_key(&mut self, value: &Value, token: &OwnedToken) -> Result<map::Key<String>> {
+        let Value::Instance(instance) = value else {
+            return hashable(value).ok_or_else(|| error_at(token, map::UNHASHABLE));
+        };
+
+        let method = instance.borrow().class.find_method(symbol::HASH);
+        let Some(method) = method else {
+            return Err(error_at(token, map::UNHASHABLE));
+        };
+        let bound = Value::Function(Rc::new(method.bind(value.clone())));
+        let hash = self.call(bound, Vec::new(), token)?;
+        hashable(&hash).ok_or_else(|| error_at(token, map::NOT_A_HASH))
+    }
+
+    // Evaluate a call in tail position, leaving a call to a Lox function to
+    // the caller of the current one. Other callees return without nesting
+    // any deeper.
+    fn tail_call(
+        &mut self,
+        callee: &Expression<OwnedToken>,
+        paren: &OwnedToken,
+        arguments: &[Expression<OwnedToken>],
+    ) -> Result<Completion> {
+        let callee = self.evaluate(callee)?;
+        let arguments = arguments
+            .iter()
+            .map(|argument| self.evaluate(argument))
+            .collect::<Result<Vec<Value>>>()?;
+
+        match callee {
+            Value::Function(function) => {
+                check_arity(function.arity(), arguments.len(), paren)?;
+                Ok(Some(Return::Call(function, arguments)))
+            }
+            // An initializer returns the new instance, so it can be left to the
+            // caller in the same way.
+            Value::Class(class) if class.find_method(symbol::INIT).is_some() => {
+                check_arity(class.arity(), arguments.len(), paren)?;
+                let instance =
+                    Value::Instance(Rc::new(RefCell::new(Instance::new(Rc::clone(&class)))));
+                let init = class.find_method(symbol::INIT).unwrap().bind(instance);
+                Ok(Some(Return::Call(Rc::new(init), arguments)))
+            }
+            callee => Ok(Some(Return::Value(self.call(callee, arguments, paren)?))),
+        }
+    }
+
+    // Call 'function' from the call at 'paren', failing if that nests too
+    // deeply.
+    fn call_function(
+        &mut self,
+        function: Rc<Function>,
+        arguments: Vec<Value>,
+        paren: &OwnedToken,
+    ) -> Result<Value> {
+        if self.frames.len() + 1 >= self.max_depth {
+            return Err(Error::RuntimeError {
+                message: "stack overflow".to_string(),
+                source_position: paren.source_position,
+                trace: Vec::new(),
+            });
+        }
+
+        self.frames.push(Frame {
+            function: function.declaration.name.lexeme,
+            line: paren.source_position.0,
+            tail_calls: 0,
+        });
+        let result = self
+            .run_function(function, arguments)
+            .map_err(|error| self.traced(error));
+        self.frames.pop();
+        result
+    }
+
+    // Record the calls in progress in the trace of a runtime error that
+    // doesn't have one yet, the innermost at the line the error happened.
+    fn traced(&self, mut error: Error) -> Error {
+        if let Error::RuntimeError {
+            source_position: (line, _),
+            trace,
+            ..
+        } = &mut error
+        {
+            if trace.is_empty() {
+                let mut line = *line;
+                for frame in self.frames.iter().rev() {
+                    trace.push(TraceFrame {
+                        function: Some(frame.function.to_string()),
+                        file: None,
+                        line,
+                        tail_calls: frame.tail_calls,
+                    });
+                    line = frame.line;
+                }
+                trace.push(TraceFrame {
+                    function: None,
+                    file: None,
+                    line,
+                    tail_calls: 0,
+                });
+            }
+        }
+        error
+    }
+
+    // Run 'function', and then the functions it calls in tail position in
+    // turn, returning the value the last of them returns.
+    fn run_function(
+        &mut self,
+        mut function: Rc<Function>,
+        mut arguments: Vec<Value>,
+    ) -> Result<Value> {
+        loop {
+            let mut environment = Environment::with_enclosing(Rc::clone(&function.closure));
+            for (param, argument) in function.declaration.params.iter().zip(arguments) {
+                environment.define(param.lexeme, argument);
+            }
+
+            let completion = self.execute_block(
+                &function.declaration.body,
+                Rc::new(RefCell::new(environment)),
+            )?;
+
+            let value = match completion {
+                Some(Return::Call(callee, callee_arguments)) => {
+                    if let Some(frame) = self.frames.last_mut() {
+                        frame.function = callee.declaration.name.lexeme;
+                        frame.tail_calls += 1;
+                    }
+                    function = callee;
+                    arguments = callee_arguments;
+                    continue;
+                }
+                Some(Return::Value(value)) => value,
+                None => Value::Nil,
+            };
+
+            if function.is_initializer {
+                return Ok(function
+                    .closure
+                    .borrow()
+                    .get_at(0, symbol::THIS)
+                    .unwrap_or(Value::Nil));
+            }
+
+            return Ok(value);
+        }
+    }
+}
+
+fn check_arity(arity: usize, count: usize, paren: &OwnedToken) -> Result<()> {
+    if count != arity {
+        return Err(Error::RuntimeError {
+            message: format!("expected {} arguments but got {}", arity, count),
+            source_position: paren.source_position,
+            trace: Vec::new(),
+        });
+    }
+    Ok(())
+}
+
+// A global environment containing the native functions.
+fn native_globals() -> Environment {
+    let mut globals = Environment::new();
+
+    let natives = [NativeFunction {
+        name: "clock",
+        arity: 0,
+        function: |_| {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_err(|e| e.to_string())?;
+            Ok(Value::Number(now.as_secs_f64()))
+        },
+    }];
+
+    for native in natives {
+        globals.define(
+            Symbol::intern(native.name),
+            Value::NativeFunction(Rc::new(native)),
+        );
+    }
+    for kind in [sorted::Kind::Map, sorted::Kind::Set] {
+        globals.define(Symbol::intern(kind.name()), Value::SortedClass(kind));
+    }
+
+    globals
+}
+
+// Look up the field or method 'name' on 'instance'. Fields shadow methods.
+fn get_property(instance: &Rc<RefCell<Instance>>, name: &OwnedToken) -> Result<Value> {
+    if let Some(value) = instance.borrow().fields.get(&name.lexeme) {
+        return Ok(value.clone());
+    }
+
+    let method = instance.borrow().class.find_method(name.lexeme);
+    match method {
+        Some(method) => Ok(Value::Function(Rc::new(
+            method.bind(Value::Instance(Rc::clone(instance))),
+        ))),
+        None => Err(undefined_property(name)),
+    }
+}
+
+// The key of a value that is looked up by itself rather than by a 'hash'
+// method.
+fn hashable(value: &Value) -> Option<map::Key<String>> {
+    match value {
+        Value::Nil => Some(map::Key::Nil),
+        Value::Bool(value) => Some(map::Key::Bool(*value)),
+        Value::Number(value) => Some(map::Key::number(*value)),
+        Value::String(value) => Some(map::Key::String(value.clone())),
+        _ => None,
+    }
+}
+
+// Set the value of 'key', keeping the key it was first inserted with if it is
+// already in 'map'.
+fn set_entry(map: &mut Map, key: map::Key<String>, original: Value, value: Value) {
+    match map.get_mut(&key) {
+        Some(entry) => entry.1 = value,
+        None => map.insert(key, (original, value)),
+    }
+}
+
+// Call 'method' on 'sorted'. Keys that can't be ordered are reported at
+// 'paren'.
+fn call_sorted_method(
+    sorted: &Rc<RefCell<Sorted>>,
+    method: sorted::Method,
+    arguments: &[Value],
+    paren: &OwnedToken,
+) -> Result<Value> {
+    let list = |keys: &mut dyn Iterator<Item = &sorted::Key<String>>| {
+        Value::List(Rc::new(RefCell::new(keys.map(Value::from).collect())))
+    };
+
+    let mut sorted = sorted.borrow_mut();
+    match method {
+        sorted::Method::Add => match &mut *sorted {
+            sorted::Sorted::Set(set) => {
+                Ok(Value::Bool(set.insert(sorted_key(&arguments[0], paren)?)))
+            }
+            sorted::Sorted::Map(_) => unreachable!("maps have no 'add' method"),
+        },
+        sorted::Method::Has => Ok(Value::Bool(
+            sorted.contains(&sorted_key(&arguments[0], paren)?),
+        )),
+        sorted::Method::Remove => {
+            let key = sorted_key(&arguments[0], paren)?;
+            match &mut *sorted {
+                sorted::Sorted::Map(map) => map
+                    .remove(&key)
+                    .ok_or_else(|| error_at(paren, map::KEY_NOT_FOUND)),
+                sorted::Sorted::Set(set) => Ok(Value::Bool(set.remove(&key))),
+            }
+        }
+        sorted::Method::Keys => Ok(list(&mut sorted.keys())),
+        sorted::Method::Values => match &*sorted {
+            sorted::Sorted::Map(map) => Ok(Value::List(Rc::new(RefCell::new(
+                map.values().cloned().collect(),
+            )))),
+            sorted::Sorted::Set(set) => Ok(list(&mut set.iter())),
+        },
+        sorted::Method::Len => Ok(Value::Number(sorted.len() as f64)),
+        sorted::Method::Range => {
+            let start = range_bound(&arguments[0], paren)?;
+            let end = range_bound(&arguments[1], paren)?;
+            Ok(list(&mut sorted.range(start, end)))
+        }
+    }
+}
+
+fn sorted_key(value: &Value, token: &OwnedToken) -> Result<sorted::Key<String>> {
+    let key = match value {
+        Value::Number(number) => sorted::Key::number(*number),
+        Value::String(string) => Some(sorted::Key::String(string.clone())),
+        _ => None,
+    };
+    key.ok_or_else(|| error_at(token, sorted::NOT_A_KEY))
+}
+
+// A bound of 'range', where 'nil' leaves that end of the range open.
+fn range_bound(bound: &Value, token: &OwnedToken) -> Result<Option<sorted::Key<String>>> {
+    match bound {
+        Value::Nil => Ok(None),
+        _ => sorted_key(bound, token).map(Some),
+    }
+}
+
+// The position 'index' refers to in a list of 'len' elements, as 'position'
+// counts it.
+fn list_index(
+    index: &Value,
+    len: usize,
+    position: fn(f64, usize) -> std::result::Result<usize, &'static str>,
+) -> std::result::Result<usize, &'static str> {
+    match index {
+        Value::Number(index) => position(*index, len),
+        _ => Err(list::INDEX_NOT_INTEGER),
+    }
+}
+
+// A slice bound, where 'nil' stands for the start or end of the list.
+fn slice_bound(bound: &Value) -> std::result::Result<Option<f64>, &'static str> {
+    match bound {
+        Value::Nil => Ok(None),
+        Value::Number(bound) => Ok(Some(*bound)),
+        _ => Err(list::BOUND_NOT_INTEGER),
+    }
+}
+
+fn error_at(token: &OwnedToken, message: &str) -> Error {
+    Error::RuntimeError {
+        message: message.to_string(),
+        source_position: token.source_position,
+        trace: Vec::new(),
+    }
+}
+
+fn binary(operator: &OwnedToken, left: Value, right: Value) -> Result<Value> {
+    let value = match operator.token_type {
+        TokenType::Comma => right,
+        TokenType::EqualEqual => Value::Bool(left == right),
+        TokenType::BangEqual => Value::Bool(left != right),
+        TokenType::Plus => match (left, right) {
+            (Value::Number(l), Value::Number(r)) => Value::Number(l + r),
+            (Value::String(l), Value::String(r)) => Value::String(l + &r),
+            _ => {
+                return Err(Error::RuntimeError {
+                    message: "operands must be two numbers or two strings".to_string(),
+                    source_position: operator.source_position,
+                    trace: Vec::new(),
+                })
+            }
+        },
+        _ => {
+            let (l, r) = number_operands(operator, &left, &right)?;
+
+            match operator.token_type {
+                TokenType::Minus => Value::Number(l - r),
+                TokenType::Asterisk => Value::Number(l * r),
+                TokenType::Slash => Value::Number(l / r),
+                TokenType::GreaterThan => Value::Bool(l > r),
+                TokenType::GreaterThanOrEqual => Value::Bool(l >= r),
+                TokenType::LessThan => Value::Bool(l < r),
+                TokenType::LessThanOrEqual => Value::Bool(l <= r),
+                _ => return Err(unexpected_operator(operator)),
+            }
+        }
+    };
+
+    Ok(value)
+}
+
+fn number_operand(operator: &OwnedToken, operand: &Value) -> Result<f64> {
+    match operand {
+        Value::Number(n) => Ok(*n),
+        _ => Err(Error::RuntimeError {
+            message: "operand must be a number".to_string(),
+            source_position: operator.source_position,
+            trace: Vec::new(),
+        }),
+    }
+}
+
+fn number_operands(operator: &OwnedToken, left: &Value, right: &Value) -> Result<(f64, f64)> {
+    match (left, right) {
+        (Value::Number(l), Value::Number(r)) => Ok((*l, *r)),
+        _ => Err(Error::RuntimeError {
+            message: "operands must be numbers".to_string(),
+            source_position: operator.source_position,
+            trace: Vec::new(),
+        }),
+    }
+}
+
+fn unexpected_operator(operator: &OwnedToken) -> Error {
+    Error::RuntimeError {
+        message: format!("unexpected operator '{}'", operator.lexeme),
+        source_position: operator.source_position,
+        trace: Vec::new(),
+    }
+}
+
+fn undefined_property(name: &OwnedToken) -> Error {
+    Error::RuntimeError {
+        message: format!("undefined property '{}'", name.lexeme),
+        source_position: name.source_position,
+        trace: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex;
+    use crate::parser::{parse, parse_expression};
+    use crate::resolver::{resolve, resolve_expression};
+
+    fn evaluate(interpreter: &mut Interpreter, source: &str) -> Result<Value> {
+        let tokens = lex(source)?;
+        let expr = parse_expression(&tokens)?;
+        resolve_expression(&expr)?;
+        interpreter.evaluate(&expr)
+    }
+
+    fn run(interpreter: &mut Interpreter, source: &str) -> Result<()> {
+        let tokens = lex(source)?;
+        let statements = parse(&tokens)?;
+        resolve(&statements)?;
+        interpreter.interpret(&statements)
+    }
+
+    fn global(interpreter: &Interpreter, name: &str) -> Value {
+        interpreter
+            .globals()
+            .into_iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value)
+            .unwrap()
+    }
+
+    #[test]
+    fn arithmetic() {
+        let mut interpreter = Interpreter::new();
+
+        assert_eq!(
+            evaluate(&mut interpreter, "1 + 2 * (3 - 1) / 4").unwrap(),
+            Value::Number(2.0)
+        );
+        assert_eq!(
+            evaluate(&mut interpreter, "\"a\" + \"b\"").unwrap(),
+            Value::String(String::from("ab"))
+        );
+        assert_eq!(
+            evaluate(&mut interpreter, "!(1 < 2) == false").unwrap(),
+            Value::Bool(true)
+        );
+    }
+
+    #[test]
+    fn comma_and_ternary() {
+        let mut interpreter = Interpreter::new();
+
+        assert_eq!(
+            evaluate(&mut interpreter, "1, nil ? 2 : 3").unwrap(),
+            Value::Number(3.0)
+        );
+    }
+
+    #[test]
+    fn type_errors() {
+        let mut interpreter = Interpreter::new();
+
+        match evaluate(&mut interpreter, "\"a\" - 1") {
+            Err(Error::RuntimeError {
+                source_position, ..
+            }) => assert_eq!(source_position, (1, 5)),
+            _ => panic!("Expected RuntimeError"),
+        }
+        assert!(evaluate(&mut interpreter, "-nil").is_err());
+        assert!(evaluate(&mut interpreter, "1 + \"a\"").is_err());
+    }
+
+    #[test]
+    fn variables_and_scopes() {
+        let mut interpreter = Interpreter::new();
+
+        run(
+            &mut interpreter,
+            "var a = 1; var b = 2; { var a = 10; b = a + b; }",
+        )
+        .unwrap();
+
+        assert_eq!(global(&interpreter, "a"), Value::Number(1.0));
+        assert_eq!(global(&interpreter, "b"), Value::Number(12.0));
+    }
+
+    #[test]
+    fn globals_persist_until_reset() {
+        let mut interpreter = Interpreter::new();
+
+        run(&mut interpreter, "var a = 1;").unwrap();
+        run(&mut interpreter, "a = a + 1;").unwrap();
+        assert_eq!(evaluate(&mut interpreter, "a").unwrap(), Value::Number(2.0));
+
+        interpreter.reset();
+        assert!(evaluate(&mut interpreter, "a").is_err());
+    }
+
+    #[test]
+    fn scope_restored_after_error() {
+        let mut interpreter = Interpreter::new();
+
+        assert!(run(&mut interpreter, "{ var a = 1; a = -nil; }").is_err());
+        assert!(evaluate(&mut interpreter, "a").is_err());
+    }
+
+    #[test]
+    fn control_flow() {
+        let mut interpreter = Interpreter::new();
+
+        run(
+            &mut interpreter,
+            "var total = 0;
+             for (var i = 0; i < 5; i = i + 1) {
+                 if (i == 2 or i == 4) total = total + 10; else total = total + i;
+             }
+             var n = nil and 1;
+             var m = nil or \"default\";",
+        )
+        .unwrap();
+
+        assert_eq!(global(&interpreter, "total"), Value::Number(24.0));
+        assert_eq!(global(&interpreter, "n"), Value::Nil);
+        assert_eq!(
+            global(&interpreter, "m"),
+            Value::String(String::from("default"))
+        );
+    }
+
+    #[test]
+    fn recursion_and_closures() {
+        let mut interpreter = Interpreter::new();
+
+        run(
+            &mut interpreter,
+            "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+             fun counter() { var i = 0; fun next() { i = i + 1; return i; } return next; }
+             var c = counter(); c(); c();
+             var count = c();
+             var f = fib(10);",
+        )
+        .unwrap();
+
+        assert_eq!(global(&interpreter, "count"), Value::Number(3.0));
+        assert_eq!(global(&interpreter, "f"), Value::Number(55.0));
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let mut interpreter = Interpreter::new();
+        let source = "fun down(n) { if (n == 0) return 0; return 1 + down(n - 1); }";
+        run(&mut interpreter, source).unwrap();
+
+        assert_eq!(
+            evaluate(&mut interpreter, "down(62)").unwrap(),
+            Value::Number(62.0)
+        );
+        match evaluate(&mut interpreter, "down(63)") {
+            Err(Error::RuntimeError { message, .. }) => assert_eq!(message, "stack overflow"),
+            result => panic!("Expected a stack overflow, got {result:?}"),
+        }
+
+        // The calls that were in progress don't count once the error unwinds.
+        interpreter.set_max_depth(65);
+        assert_eq!(
+            evaluate(&mut interpreter, "down(63)").unwrap(),
+            Value::Number(63.0)
+        );
+    }
+
+    #[test]
+    fn closures_capture_by_scope() {
+        let mut interpreter = Interpreter::new();
+
+        run(
+            &mut interpreter,
+            "var a = \"global\"; var first; var second;
+             { fun show() { return a; } first = show(); var a = \"block\"; second = show(); }",
+        )
+        .unwrap();
+
+        assert_eq!(
+            global(&interpreter, "first"),
+            global(&interpreter, "second")
+        );
+    }
+
+    #[test]
+    fn classes_and_inheritance() {
+        let mut interpreter = Interpreter::new();
+
+        run(
+            &mut interpreter,
+            "class A { init(x) { this.x = x; } get() { return this.x; } }
+             class B < A { get() { return super.get() * 2; } }
+             var b = B(21);
+             var result = b.get();
+             var method = b.get;
+             var bound = method();
+             var again = b.init(1).x;",
+        )
+        .unwrap();
+
+        assert_eq!(global(&interpreter, "result"), Value::Number(42.0));
+        assert_eq!(global(&interpreter, "bound"), Value::Number(42.0));
+        assert_eq!(global(&interpreter, "again"), Value::Number(1.0));
+    }
+
+    #[test]
+    fn lists() {
+        let mut interpreter = Interpreter::new();
+
+        run(
+            &mut interpreter,
+            "var xs = [3, 1, 2];
+             xs[0] = xs[-1] + xs[1];
+             fun twice(x) { return x * 2; }
+             fun order(a, b) { return a - b; }
+             var doubled = xs.map(twice);
+             doubled.sort(order);
+             var first = doubled[0];
+             var rest = doubled[1:].len();
+             var popped = xs.pop();",
+        )
+        .unwrap();
+
+        assert_eq!(global(&interpreter, "first"), Value::Number(2.0));
+        assert_eq!(global(&interpreter, "rest"), Value::Number(2.0));
+        assert_eq!(global(&interpreter, "popped"), Value::Number(2.0));
+        assert_eq!(global(&interpreter, "xs").to_string(), "[3, 1]");
+    }
+
+    #[test]
+    fn list_errors() {
+        let mut interpreter = Interpreter::new();
+
+        match run(&mut interpreter, "var xs = [1];\nxs[1];") {
+            Err(Error::RuntimeError {
+                message,
+                source_position,
+                ..
+            }) => {
+                assert_eq!(message, list::INDEX_OUT_OF_RANGE);
+                assert_eq!(source_position, (2, 3));
+            }
+            _ => panic!("Expected RuntimeError"),
+        }
+        assert!(run(&mut interpreter, "xs[0.5];").is_err());
+        assert!(run(&mut interpreter, "xs[\"a\":];").is_err());
+        assert!(run(&mut interpreter, "1[0];").is_err());
+        assert!(run(&mut interpreter, "[].pop();").is_err());
+        assert!(run(&mut interpreter, "xs.missing;").is_err());
+        assert!(run(&mut interpreter, "fun f(a, b) {} xs.push(2); xs.sort(f);").is_err());
+    }
+
+    #[test]
+    fn maps() {
+        let mut interpreter = Interpreter::new();
+
+        run(
+            &mut interpreter,
+            "class K { init(k) { this.k = k; } hash() { return this.k; } }
+             var m = {\"a\": 1, K(\"b\"): 2, -0: 3};
+             m[0] = m[\"a\"] + m[\"b\"];
+             var removed = m.remove(K(\"a\"));
+             var has = m.has(\"a\");
+             var keys = m.keys().len();",
+        )
+        .unwrap();
+
+        assert_eq!(global(&interpreter, "removed"), Value::Number(1.0));
+        assert_eq!(global(&interpreter, "has"), Value::Bool(false));
+        assert_eq!(global(&interpreter, "keys"), Value::Number(2.0));
+        assert_eq!(
+            global(&interpreter, "m").to_string(),
+            "{K instance: 2, -0: 3}"
+        );
+    }
+
+    #[test]
+    fn map_errors() {
+        let mut interpreter = Interpreter::new();
+
+        match run(&mut interpreter, "var m = {1: 2};\nm[2];") {
+            Err(Error::RuntimeError {
+                message,
+                source_position,
+                ..
+            }) => {
+                assert_eq!(message, map::KEY_NOT_FOUND);
+                assert_eq!(source_position, (2, 2));
+            }
+            _ => panic!("Expected RuntimeError"),
+        }
+        assert!(run(&mut interpreter, "m[[]] = 1;").is_err());
+        assert!(run(&mut interpreter, "class A {} var a = {A(): 1};").is_err());
+        assert!(run(
+            &mut interpreter,
+            "class B { hash() { return []; } } m.has(B());"
+        )
+        .is_err());
+        assert!(run(&mut interpreter, "m.remove(3);").is_err());
+        assert!(run(&mut interpreter, "m[1:];").is_err());
+        assert!(run(&mut interpreter, "m.missing;").is_err());
+    }
+
+    #[test]
+    fn sorted() {
+        let mut interpreter = Interpreter::new();
+
+        run(
+            &mut interpreter,
+            "var m = SortedMap();
+             m[\"b\"] = 1; m[3] = 2; m[-0] = 3; m[0] = 4;
+             var removed = m.remove(3);
+             var s = SortedSet();
+             var added = s.add(2) and s.add(1) and !s.add(2);
+             var range = s.range(nil, 2);",
+        )
+        .unwrap();
+
+        assert_eq!(global(&interpreter, "removed"), Value::Number(2.0));
+        assert_eq!(global(&interpreter, "added"), Value::Bool(true));
+        assert_eq!(global(&interpreter, "m").to_string(), "{0: 4, b: 1}");
+        assert_eq!(global(&interpreter, "range").to_string(), "[1]");
+
+        match run(&mut interpreter, "s.add(nil);") {
+            Err(Error::RuntimeError { message, .. }) => assert_eq!(message, sorted::NOT_A_KEY),
+            _ => panic!("Expected RuntimeError"),
+        }
+        assert!(run(&mut interpreter, "m[0 / 0];").is_err());
+        assert!(run(&mut interpreter, "m.remove(7);").is_err());
+        assert!(run(&mut interpreter, "s[1];").is_err());
+        assert!(run(&mut interpreter, "s.keys;").is_err());
+        assert!(run(&mut interpreter, "SortedMap(1);").is_err());
+    }
+
+    #[test]
+    fn call_errors() {
+        let mut interpreter = Interpreter::new();
+
+        assert!(run(&mut interpreter, "fun f(a) {} f();").is_err());
+        assert!(run(&mut interpreter, "\"text\"();").is_err());
+        assert!(run(&mut interpreter, "class A {} A().missing;").is_err());
+        assert!(run(&mut interpreter, "var x = 1; x.field = 2;").is_err());
+        assert!(run(&mut interpreter, "var NotClass = 1; class A < NotClass {}").is_err());
+    }
+
+    #[test]
+    fn definitions_persist_across_runs() {
+        let mut interpreter = Interpreter::new();
+
+        run(&mut interpreter, "fun square(x) { return x * x; }").unwrap();
+        run(&mut interpreter, "class P { area() { return square(3); } }").unwrap();
+
+        assert_eq!(
+            evaluate(&mut interpreter, "P().area()").unwrap(),
+            Value::Number(9.0)
+        );
+    }
+}
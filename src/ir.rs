@@ -0,0 +1,1865 @@
+use crate::ast::{Expression, FunctionDeclaration, LiteralValue, Statement};
+use crate::lexer::{OwnedToken, SourcePosition, TokenType};
+use crate::result::{Error, Result};
+use crate::symbol::{self, Symbol};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::rc::Rc;
+
+// Frame slots and upvalues are addressed with a single byte operand.
+const MAX_SLOTS: usize = 256;
+const MAX_UPVALUES: usize = 256;
+
+pub type ValueId = usize;
+pub type BlockId = usize;
+pub type FunctionId = usize;
+
+// A program lowered to a control-flow graph of basic blocks for each
+// function, whose instructions are in static single assignment form: every
+// value is defined by exactly one instruction, and a local variable assigned
+// on several paths is merged by a phi where the paths meet.
+//
+// Local variables captured by closures are shared with them, so they live in
+// frame slots that are read and written with 'Load' and 'Store' instead.
+// Globals, upvalues and properties are accessed as in the bytecode.
+pub struct Program {
+    // The top-level script comes first, followed by the functions in the
+    // order they appear in the source.
+    pub functions: Vec<Function>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FunctionKind {
+    Script,
+    Function,
+    Method,
+    Initializer,
+}
+
+// A variable of the enclosing function captured by a closure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capture {
+    pub index: u8,
+    // Whether 'index' is a frame slot of the enclosing function rather than
+    // one of its upvalues.
+    pub is_local: bool,
+}
+
+pub struct Function {
+    // Empty for the top-level script.
+    pub name: Rc<str>,
+    pub kind: FunctionKind,
+    pub arity: usize,
+    // The frame starts with the closure, or the receiver in methods, and the
+    // arguments, followed by 'cells' slots for captured variables.
+    pub cells: usize,
+    pub upvalues: Vec<Capture>,
+    // The entry block comes first.
+    pub blocks: Vec<Block>,
+    // Every instruction created for the function, indexed by its value. Only
+    // those listed in a block are part of the function.
+    pub instructions: Vec<Instruction>,
+}
+
+pub struct Block {
+    pub predecessors: Vec<BlockId>,
+    pub phis: Vec<ValueId>,
+    pub instructions: Vec<ValueId>,
+    pub terminator: Terminator,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Terminator {
+    Jump(BlockId),
+    Branch {
+        condition: ValueId,
+        then_block: BlockId,
+        else_block: BlockId,
+    },
+    Return(ValueId),
+}
+
+impl Terminator {
+    pub fn successors(&self) -> Vec<BlockId> {
+        match *self {
+            Terminator::Jump(target) => vec![target],
+            Terminator::Branch {
+                then_block,
+                else_block,
+                ..
+            } => vec![then_block, else_block],
+            Terminator::Return(_) => Vec::new(),
+        }
+    }
+
+    pub fn operand(&self) -> Option<ValueId> {
+        match *self {
+            Terminator::Jump(_) => None,
+            Terminator::Branch { condition, .. } => Some(condition),
+            Terminator::Return(value) => Some(value),
+        }
+    }
+
+    pub fn operand_mut(&mut self) -> Option<&mut ValueId> {
+        match self {
+            Terminator::Jump(_) => None,
+            Terminator::Branch { condition, .. } => Some(condition),
+            Terminator::Return(value) => Some(value),
+        }
+    }
+
+    fn successors_mut(&mut self) -> Vec<&mut BlockId> {
+        match self {
+            Terminator::Jump(target) => vec![target],
+            Terminator::Branch {
+                then_block,
+                else_block,
+                ..
+            } => vec![then_block, else_block],
+            Terminator::Return(_) => Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Instruction {
+    pub op: Op,
+    // Where runtime errors raised by the instruction are reported.
+    pub position: SourcePosition,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constant {
+    Number(f64),
+    String(Symbol),
+    True,
+    False,
+    Nil,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BinaryOp {
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    // One operand for each predecessor of the block, in the same order.
+    Phi(Vec<ValueId>),
+    // The value in the given frame slot when the function is called.
+    Parameter(u8),
+    Constant(Constant),
+    Copy(ValueId),
+    Not(ValueId),
+    Negate(ValueId),
+    Binary(BinaryOp, ValueId, ValueId),
+    GetGlobal(Symbol),
+    DefineGlobal(Symbol, ValueId),
+    SetGlobal(Symbol, ValueId),
+    GetUpvalue(u8),
+    SetUpvalue(u8, ValueId),
+    // Access the frame slot of a captured variable.
+    Load(u8),
+    Store(u8, ValueId),
+    // Detach a frame slot from the closures that captured it, as its
+    // variable goes out of scope.
+    Close(u8),
+    GetProperty(ValueId, Symbol),
+    SetProperty(ValueId, Symbol, ValueId),
+    // Look up a method of the superclass, the second operand, bound to the
+    // receiver, the first.
+    GetSuper(ValueId, ValueId, Symbol),
+    Call(ValueId, Vec<ValueId>),
+    // Call a method of the receiver. The position is that of the call's ')',
+    // see the 'Invoke' instruction.
+    Invoke(ValueId, Symbol, Vec<ValueId>, SourcePosition),
+    Closure(FunctionId),
+    Class(Symbol),
+    // Copy the methods of the superclass, the second operand, into the class.
+    Inherit(ValueId, ValueId),
+    Method(ValueId, Symbol, ValueId),
+    Print(ValueId),
+    BuildList(Vec<ValueId>),
+    // The keys and values of a map literal, each key followed by its value.
+    BuildMap(Vec<ValueId>),
+    // Index the list or map, the first operand, with the second.
+    GetIndex(ValueId, ValueId),
+    SetIndex(ValueId, ValueId, ValueId),
+    // Slice the list between the start and end bounds, which are 'nil' when
+    // left out.
+    GetSlice(ValueId, ValueId, ValueId),
+}
+
+impl Op {
+    // Whether the instruction produces a value. Assignments don't: the value
+    // of an assignment expression is the value assigned.
+    pub fn has_value(&self) -> bool {
+        !matches!(
+            self,
+            Op::DefineGlobal(..)
+                | Op::SetGlobal(..)
+                | Op::SetUpvalue(..)
+                | Op::Store(..)
+                | Op::Close(_)
+                | Op::SetProperty(..)
+                | Op::SetIndex(..)
+                | Op::Inherit(..)
+                | Op::Method(..)
+                | Op::Print(_)
+        )
+    }
+
+    // The values the instruction uses, in the order the bytecode expects them
+    // on the stack.
+    pub fn operands(&self) -> Vec<ValueId> {
+        let mut op = self.clone();
+        op.operands_mut()
+            .into_iter()
+            .map(|operand| *operand)
+            .collect()
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut ValueId> {
+        match self {
+            Op::Phi(operands) | Op::BuildList(operands) | Op::BuildMap(operands) => {
+                operands.iter_mut().collect()
+            }
+            Op::Parameter(_)
+            | Op::Constant(_)
+            | Op::GetGlobal(_)
+            | Op::GetUpvalue(_)
+            | Op::Load(_)
+            | Op::Close(_)
+            | Op::Closure(_)
+            | Op::Class(_) => Vec::new(),
+            Op::Copy(value)
+            | Op::Not(value)
+            | Op::Negate(value)
+            | Op::DefineGlobal(_, value)
+            | Op::SetGlobal(_, value)
+            | Op::SetUpvalue(_, value)
+            | Op::Store(_, value)
+            | Op::GetProperty(value, _)
+            | Op::Print(value) => vec![value],
+            Op::Binary(_, left, right)
+            | Op::SetProperty(left, _, right)
+            | Op::GetSuper(left, right, _)
+            | Op::Method(left, _, right)
+            | Op::GetIndex(left, right) => vec![left, right],
+            Op::SetIndex(first, second, third) | Op::GetSlice(first, second, third) => {
+                vec![first, second, third]
+            }
+            Op::Inherit(class, superclass) => vec![superclass, class],
+            Op::Call(callee, arguments) | Op::Invoke(callee, _, arguments, _) => {
+                std::iter::once(callee)
+                    .chain(arguments.iter_mut())
+                    .collect()
+            }
+        }
+    }
+}
+
+impl Function {
+    // The blocks reachable from the entry in reverse postorder, so that each
+    // block comes before the blocks it dominates.
+    pub fn reverse_postorder(&self) -> Vec<BlockId> {
+        let mut visited = vec![false; self.blocks.len()];
+        let mut order = Vec::new();
+        // Each block is pushed with the index of the next successor to visit.
+        let mut stack = vec![(0, 0)];
+        visited[0] = true;
+
+        while let Some((block, next)) = stack.pop() {
+            let successors = self.blocks[block].terminator.successors();
+            // Successors are visited last to first so that the first comes
+            // first in the order.
+            match successors.len().checked_sub(next + 1) {
+                Some(index) => {
+                    stack.push((block, next + 1));
+                    let successor = successors[index];
+                    if !visited[successor] {
+                        visited[successor] = true;
+                        stack.push((successor, 0));
+                    }
+                }
+                None => order.push(block),
+            }
+        }
+
+        order.reverse();
+        order
+    }
+
+    // The immediate dominator of each block, with the entry as its own. The
+    // blocks must all be reachable and numbered in reverse postorder, as
+    // 'lower' leaves them. See Cooper, Harvey and Kennedy, "A Simple, Fast
+    // Dominance Algorithm".
+    pub fn immediate_dominators(&self) -> Vec<BlockId> {
+        let mut dominators: Vec<Option<BlockId>> = vec![None; self.blocks.len()];
+        dominators[0] = Some(0);
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in 1..self.blocks.len() {
+                let mut dominator = None;
+                for &predecessor in &self.blocks[block].predecessors {
+                    if dominators[predecessor].is_none() {
+                        continue;
+                    }
+                    dominator = Some(match dominator {
+                        None => predecessor,
+                        Some(mut other) => {
+                            let mut finger = predecessor;
+                            while finger != other {
+                                while finger > other {
+                                    finger = dominators[finger].expect("processed block");
+                                }
+                                while other > finger {
+                                    other = dominators[other].expect("processed block");
+                                }
+                            }
+                            finger
+                        }
+                    });
+                }
+                if dominator != dominators[block] {
+                    dominators[block] = dominator;
+                    changed = true;
+                }
+            }
+        }
+
+        dominators
+            .into_iter()
+            .map(|dominator| dominator.expect("reachable block"))
+            .collect()
+    }
+
+    // The number of times each value is used by the instructions and
+    // terminators of the function.
+    pub fn use_counts(&self) -> Vec<usize> {
+        let mut counts = vec![0; self.instructions.len()];
+        for block in &self.blocks {
+            for &value in block.phis.iter().chain(&block.instructions) {
+                for operand in self.instructions[value].op.operands() {
+                    counts[operand] += 1;
+                }
+            }
+            if let Some(operand) = block.terminator.operand() {
+                counts[operand] += 1;
+            }
+        }
+        counts
+    }
+
+    // Replace every use of a value by 'replacements[value]', following chains
+    // of replacements.
+    pub fn replace_uses(&mut self, replacements: &[ValueId]) {
+        let resolve = |mut value: ValueId| {
+            while replacements[value] != value {
+                value = replacements[value];
+            }
+            value
+        };
+
+        for block in &mut self.blocks {
+            for &value in block.phis.iter().chain(&block.instructions) {
+                for operand in self.instructions[value].op.operands_mut() {
+                    *operand = resolve(*operand);
+                }
+            }
+            if let Some(operand) = block.terminator.operand_mut() {
+                *operand = resolve(*operand);
+            }
+        }
+    }
+}
+
+// Lower a resolved program to the IR.
+pub fn lower(statements: &[Statement<OwnedToken>]) -> Result<Program> {
+    let mut lowering = Lowering {
+        captured: captured_variables(statements),
+        functions: Vec::new(),
+        program: vec![None],
+        position: (1, 1),
+        errors: Vec::new(),
+    };
+
+    lowering
+        .functions
+        .push(Builder::new(0, Rc::from(""), FunctionKind::Script, 0));
+    for statement in statements {
+        lowering.statement(statement);
+    }
+    lowering.end_function();
+
+    match lowering.errors.len() {
+        0 => Ok(Program {
+            functions: lowering
+                .program
+                .into_iter()
+                .map(|function| function.expect("lowered function"))
+                .collect(),
+        }),
+        1 => Err(lowering.errors.remove(0)),
+        _ => Err(Error::MultipleErrors(lowering.errors)),
+    }
+}
+
+// A local variable is identified by the position and name of the token
+// declaring it. 'this' is declared by the name of its method and 'super' by
+// the name of its class.
+type Declaration = (SourcePosition, Symbol);
+
+// Find the local variables that are used by functions nested in the one
+// declaring them, resolving names the same way as the lowering does.
+fn captured_variables(statements: &[Statement<OwnedToken>]) -> HashSet<Declaration> {
+    let mut finder = CaptureFinder {
+        functions: vec![Vec::new()],
+        captured: HashSet::new(),
+    };
+    for statement in statements {
+        finder.statement(statement);
+    }
+    finder.captured
+}
+
+struct CaptureFinder {
+    // The local scopes of each function being walked, innermost last. The
+    // top-level script starts without any.
+    functions: Vec<Vec<Vec<(Symbol, Declaration)>>>,
+    captured: HashSet<Declaration>,
+}
+
+impl CaptureFinder {
+    fn declare(&mut self, name: Symbol, position: SourcePosition) {
+        let scopes = self.functions.last_mut().expect("function being walked");
+        if let Some(scope) = scopes.last_mut() {
+            scope.push((name, (position, name)));
+        }
+    }
+
+    fn reference(&mut self, name: Symbol) {
+        for (depth, scopes) in self.functions.iter().rev().enumerate() {
+            let declaration = scopes
+                .iter()
+                .rev()
+                .find_map(|scope| scope.iter().rev().find(|(n, _)| *n == name));
+            if let Some(&(_, declaration)) = declaration {
+                if depth > 0 {
+                    self.captured.insert(declaration);
+                }
+                return;
+            }
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement<OwnedToken>) {
+        match statement {
+            Statement::Expression(expression) | Statement::Print { expression, .. } => {
+                self.expression(expression)
+            }
+            Statement::Var {
+                name, initializer, ..
+            } => {
+                if let Some(initializer) = initializer {
+                    self.expression(initializer);
+                }
+                self.declare(name.lexeme, name.source_position);
+            }
+            Statement::Block(statements) => {
+                self.scopes().push(Vec::new());
+                for statement in statements {
+                    self.statement(statement);
+                }
+                self.scopes().pop();
+            }
+            Statement::If {
+                condition,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                self.expression(condition);
+                self.statement(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch);
+                }
+            }
+            Statement::While {
+                condition, body, ..
+            } => {
+                self.expression(condition);
+                self.statement(body);
+            }
+            Statement::Function { declaration, .. } => {
+                self.declare(declaration.name.lexeme, declaration.name.source_position);
+                self.function(declaration, false);
+            }
+            Statement::Return { value, .. } => {
+                if let Some(value) = value {
+                    self.expression(value);
+                }
+            }
+            Statement::Class {
+                name,
+                superclass,
+                methods,
+                ..
+            } => {
+                self.declare(name.lexeme, name.source_position);
+                if let Some(superclass) = superclass {
+                    self.expression(superclass);
+                    self.scopes().push(Vec::new());
+                    self.declare(symbol::SUPER, name.source_position);
+                }
+                for method in methods {
+                    self.function(method, true);
+                }
+                if superclass.is_some() {
+                    self.scopes().pop();
+                }
+            }
+        }
+    }
+
+    fn scopes(&mut self) -> &mut Vec<Vec<(Symbol, Declaration)>> {
+        self.functions.last_mut().expect("function being walked")
+    }
+
+    fn function(&mut self, declaration: &FunctionDeclaration<OwnedToken>, is_method: bool) {
+        self.functions.push(vec![Vec::new()]);
+        if is_method {
+            self.declare(symbol::THIS, declaration.name.source_position);
+        }
+        for param in &declaration.params {
+            self.declare(param.lexeme, param.source_position);
+        }
+        for statement in &declaration.body {
+            self.statement(statement);
+        }
+        self.functions.pop();
+    }
+
+    fn expression(&mut self, expression: &Expression<OwnedToken>) {
+        match expression {
+            Expression::Literal { .. } => {}
+            Expression::Grouping(expression)
+            | Expression::Unary {
+                right: expression, ..
+            }
+            | Expression::Get {
+                object: expression, ..
+            } => self.expression(expression),
+            Expression::Binary { left, right, .. } | Expression::Logical { left, right, .. } => {
+                self.expression(left);
+                self.expression(right);
+            }
+            Expression::Ternary {
+                left,
+                middle,
+                right,
+                ..
+            } => {
+                self.expression(left);
+                self.expression(middle);
+                self.expression(right);
+            }
+            Expression::Variable { name, .. } => self.reference(name.lexeme),
+            Expression::Assign { name, value, .. } => {
+                self.expression(value);
+                self.reference(name.lexeme);
+            }
+            Expression::Call {
+                callee, arguments, ..
+            } => {
+                self.expression(callee);
+                for argument in arguments {
+                    self.expression(argument);
+                }
+            }
+            Expression::Set { object, value, .. }
+            | Expression::Index {
+                object,
+                index: value,
+                ..
+            } => {
+                self.expression(object);
+                self.expression(value);
+            }
+            Expression::List { elements, .. } => {
+                for element in elements {
+                    self.expression(element);
+                }
+            }
+            Expression::Map { entries, .. } => {
+                for (key, value) in entries {
+                    self.expression(key);
+                    self.expression(value);
+                }
+            }
+            Expression::Slice {
+                object,
+                start: middle,
+                end: right,
+                ..
+            }
+            | Expression::SetIndex {
+                object,
+                index: middle,
+                value: right,
+                ..
+            } => {
+                self.expression(object);
+                self.expression(middle);
+                self.expression(right);
+            }
+            Expression::This { .. } => self.reference(symbol::THIS),
+            Expression::Super { .. } => {
+                self.reference(symbol::THIS);
+                self.reference(symbol::SUPER);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Variable {
+    // A variable in SSA form, numbering its definitions per block.
+    Value(usize),
+    // A captured variable in a frame slot.
+    Slot(u8),
+}
+
+struct Local {
+    name: Symbol,
+    depth: usize,
+    variable: Variable,
+}
+
+struct BlockState {
+    predecessors: Vec<BlockId>,
+    phis: Vec<ValueId>,
+    instructions: Vec<ValueId>,
+    terminator: Option<Terminator>,
+    // Whether all the predecessors of the block are known.
+    sealed: bool,
+    // Phis created for variables read before the block was sealed, whose
+    // operands are added when it is.
+    incomplete: Vec<(usize, ValueId)>,
+}
+
+// The state of a function being lowered. Variables are put in SSA form as
+// they are lowered, following Braun et al., "Simple and Efficient
+// Construction of Static Single Assignment Form": a read looks up the value
+// last written in the block, or else in its predecessors, creating a phi
+// where several of them meet.
+struct Builder {
+    id: FunctionId,
+    name: Rc<str>,
+    kind: FunctionKind,
+    arity: usize,
+    blocks: Vec<BlockState>,
+    instructions: Vec<Instruction>,
+    current: BlockId,
+    locals: Vec<Local>,
+    scope_depth: usize,
+    upvalues: Vec<Capture>,
+    // The number of slots for captured variables currently in scope, and
+    // the most there have been.
+    live_cells: usize,
+    cells: usize,
+    // The value of each SSA variable at the end of the blocks that define it.
+    definitions: Vec<HashMap<BlockId, ValueId>>,
+}
+
+impl Builder {
+    fn new(id: FunctionId, name: Rc<str>, kind: FunctionKind, arity: usize) -> Builder {
+        let mut builder = Builder {
+            id,
+            name,
+            kind,
+            arity,
+            blocks: Vec::new(),
+            instructions: Vec::new(),
+            current: 0,
+            locals: Vec::new(),
+            scope_depth: 0,
+            upvalues: Vec::new(),
+            live_cells: 0,
+            cells: 0,
+            definitions: Vec::new(),
+        };
+        builder.new_block();
+        builder.seal(0);
+        builder
+    }
+
+    fn resolve_local(&self, name: Symbol) -> Option<usize> {
+        self.locals.iter().rposition(|local| local.name == name)
+    }
+
+    fn new_block(&mut self) -> BlockId {
+        self.blocks.push(BlockState {
+            predecessors: Vec::new(),
+            phis: Vec::new(),
+            instructions: Vec::new(),
+            terminator: None,
+            sealed: false,
+            incomplete: Vec::new(),
+        });
+        self.blocks.len() - 1
+    }
+
+    fn instruction(&mut self, op: Op, position: SourcePosition) -> ValueId {
+        self.instructions.push(Instruction { op, position });
+        self.instructions.len() - 1
+    }
+
+    fn emit(&mut self, op: Op, position: SourcePosition) -> ValueId {
+        let value = self.instruction(op, position);
+        self.blocks[self.current].instructions.push(value);
+        value
+    }
+
+    fn phi(&mut self, block: BlockId, operands: Vec<ValueId>, position: SourcePosition) -> ValueId {
+        let value = self.instruction(Op::Phi(operands), position);
+        self.blocks[block].phis.push(value);
+        value
+    }
+
+    // End the current block with 'terminator'.
+    fn terminate(&mut self, terminator: Terminator) {
+        for successor in terminator.successors() {
+            self.blocks[successor].predecessors.push(self.current);
+        }
+        self.blocks[self.current].terminator = Some(terminator);
+    }
+
+    fn new_variable(&mut self) -> usize {
+        self.definitions.push(HashMap::new());
+        self.definitions.len() - 1
+    }
+
+    fn write_variable(&mut self, variable: usize, block: BlockId, value: ValueId) {
+        self.definitions[variable].insert(block, value);
+    }
+
+    fn read_variable(
+        &mut self,
+        variable: usize,
+        block: BlockId,
+        position: SourcePosition,
+    ) -> ValueId {
+        if let Some(&value) = self.definitions[variable].get(&block) {
+            return value;
+        }
+
+        let value = if !self.blocks[block].sealed {
+            let phi = self.phi(block, Vec::new(), position);
+            self.blocks[block].incomplete.push((variable, phi));
+            phi
+        } else if let [predecessor] = self.blocks[block].predecessors[..] {
+            self.read_variable(variable, predecessor, position)
+        } else {
+            // Writing the phi first ends the recursion around loops.
+            let phi = self.phi(block, Vec::new(), position);
+            self.write_variable(variable, block, phi);
+            self.add_phi_operands(variable, block, phi, position);
+            phi
+        };
+
+        self.write_variable(variable, block, value);
+        value
+    }
+
+    fn add_phi_operands(
+        &mut self,
+        variable: usize,
+        block: BlockId,
+        phi: ValueId,
+        position: SourcePosition,
+    ) {
+        for predecessor in self.blocks[block].predecessors.clone() {
+            let operand = self.read_variable(variable, predecessor, position);
+            if let Op::Phi(operands) = &mut self.instructions[phi].op {
+                operands.push(operand);
+            }
+        }
+    }
+
+    // Record that every predecessor of 'block' is known.
+    fn seal(&mut self, block: BlockId) {
+        for (variable, phi) in std::mem::take(&mut self.blocks[block].incomplete) {
+            let position = self.instructions[phi].position;
+            self.add_phi_operands(variable, block, phi, position);
+        }
+        self.blocks[block].sealed = true;
+    }
+
+    // Finish the function, dropping the blocks that can't be reached.
+    fn finish(self) -> Function {
+        let mut function = Function {
+            name: self.name,
+            kind: self.kind,
+            arity: self.arity,
+            cells: self.cells,
+            upvalues: self.upvalues,
+            blocks: self
+                .blocks
+                .into_iter()
+                .map(|block| Block {
+                    predecessors: block.predecessors,
+                    phis: block.phis,
+                    instructions: block.instructions,
+                    terminator: block.terminator.expect("terminated block"),
+                })
+                .collect(),
+            instructions: self.instructions,
+        };
+
+        let order = function.reverse_postorder();
+        let mut numbers = vec![None; function.blocks.len()];
+        for (number, &block) in order.iter().enumerate() {
+            numbers[block] = Some(number);
+        }
+
+        let mut blocks: Vec<Option<Block>> = function.blocks.drain(..).map(Some).collect();
+        for &block in &order {
+            let mut block = blocks[block].take().expect("block visited once");
+            let kept: Vec<bool> = block
+                .predecessors
+                .iter()
+                .map(|&predecessor| numbers[predecessor].is_some())
+                .collect();
+
+            for &phi in &block.phis {
+                if let Op::Phi(operands) = &mut function.instructions[phi].op {
+                    let mut kept = kept.iter();
+                    operands.retain(|_| *kept.next().expect("operand per predecessor"));
+                }
+            }
+            block.predecessors = block
+                .predecessors
+                .iter()
+                .filter_map(|&predecessor| numbers[predecessor])
+                .collect();
+            for successor in block.terminator.successors_mut() {
+                *successor = numbers[*successor].expect("successor of a reachable block");
+            }
+            function.blocks.push(block);
+        }
+
+        function
+    }
+}
+
+struct Lowering {
+    captured: HashSet<Declaration>,
+    // The functions being lowered, innermost last.
+    functions: Vec<Builder>,
+    // The lowered functions, filled in as they are finished.
+    program: Vec<Option<Function>>,
+    // The position of the token most recently lowered, recorded for each
+    // instruction as in 'compiler'.
+    position: SourcePosition,
+    errors: Vec<Error>,
+}
+
+impl Lowering {
+    fn error(&mut self, message: &str) {
+        self.errors.push(Error::ParseError {
+            message: message.to_string(),
+            source_position: self.position,
+        });
+    }
+
+    fn current(&mut self) -> &mut Builder {
+        self.functions.last_mut().expect("function being lowered")
+    }
+
+    fn emit(&mut self, op: Op) -> ValueId {
+        let position = self.position;
+        self.current().emit(op, position)
+    }
+
+    fn constant(&mut self, constant: Constant) -> ValueId {
+        self.emit(Op::Constant(constant))
+    }
+
+    fn terminate(&mut self, terminator: Terminator) {
+        self.current().terminate(terminator);
+    }
+
+    fn switch_to(&mut self, block: BlockId) {
+        self.current().current = block;
+    }
+
+    fn begin_scope(&mut self) {
+        self.current().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        let position = self.position;
+        let function = self.current();
+        function.scope_depth -= 1;
+
+        while let Some(local) = function.locals.last() {
+            if local.depth <= function.scope_depth {
+                break;
+            }
+            if let Variable::Slot(slot) = local.variable {
+                function.live_cells -= 1;
+                function.emit(Op::Close(slot), position);
+            }
+            function.locals.pop();
+        }
+    }
+
+    // Add a local variable to the current scope, in a slot if closures
+    // capture it.
+    fn declare_local(&mut self, name: Symbol, declaration: Declaration) -> Variable {
+        let captured = self.captured.contains(&declaration);
+        let function = self.current();
+
+        let variable = if captured {
+            let slot = function.arity + 1 + function.live_cells;
+            function.live_cells += 1;
+            function.cells = function.cells.max(function.live_cells);
+            if slot >= MAX_SLOTS {
+                self.error("too many local variables in function");
+                return Variable::Slot(0);
+            }
+            Variable::Slot(slot as u8)
+        } else {
+            Variable::Value(function.new_variable())
+        };
+
+        let function = self.current();
+        let depth = function.scope_depth;
+        function.locals.push(Local {
+            name,
+            depth,
+            variable,
+        });
+        variable
+    }
+
+    fn initialize(&mut self, variable: Variable, value: ValueId) {
+        match variable {
+            Variable::Value(variable) => {
+                let function = self.current();
+                let block = function.current;
+                function.write_variable(variable, block, value);
+            }
+            Variable::Slot(slot) => {
+                self.emit(Op::Store(slot, value));
+            }
+        }
+    }
+
+    // Define the variable declared by 'name' with 'value', as a global at the
+    // top level of the script.
+    fn define(&mut self, name: &OwnedToken, value: ValueId) {
+        if self.current().scope_depth == 0 {
+            self.emit(Op::DefineGlobal(name.lexeme, value));
+            return;
+        }
+
+        let variable = self.declare_local(name.lexeme, (name.source_position, name.lexeme));
+        self.initialize(variable, value);
+    }
+
+    fn add_upvalue(&mut self, function: usize, capture: Capture) -> u8 {
+        let upvalues = &mut self.functions[function].upvalues;
+        if let Some(index) = upvalues.iter().position(|&u| u == capture) {
+            return index as u8;
+        }
+
+        if upvalues.len() >= MAX_UPVALUES {
+            self.error("too many closure variables in function");
+            return 0;
+        }
+
+        upvalues.push(capture);
+        (upvalues.len() - 1) as u8
+    }
+
+    // Find 'name' among the locals of the functions enclosing
+    // 'self.functions[function]', adding upvalues to each function in between.
+    fn resolve_upvalue(&mut self, function: usize, name: Symbol) -> Option<u8> {
+        let enclosing = function.checked_sub(1)?;
+
+        if let Some(local) = self.functions[enclosing].resolve_local(name) {
+            let Variable::Slot(index) = self.functions[enclosing].locals[local].variable else {
+                unreachable!("captured variables live in slots");
+            };
+            let capture = Capture {
+                index,
+                is_local: true,
+            };
+            return Some(self.add_upvalue(function, capture));
+        }
+
+        let index = self.resolve_upvalue(enclosing, name)?;
+        let capture = Capture {
+            index,
+            is_local: false,
+        };
+        Some(self.add_upvalue(function, capture))
+    }
+
+    fn read(&mut self, name: Symbol) -> ValueId {
+        let function = self.functions.len() - 1;
+
+        if let Some(local) = self.functions[function].resolve_local(name) {
+            match self.functions[function].locals[local].variable {
+                Variable::Value(variable) => {
+                    let position = self.position;
+                    let function = self.current();
+                    let block = function.current;
+                    function.read_variable(variable, block, position)
+                }
+                Variable::Slot(slot) => self.emit(Op::Load(slot)),
+            }
+        } else if let Some(index) = self.resolve_upvalue(function, name) {
+            self.emit(Op::GetUpvalue(index))
+        } else {
+            self.emit(Op::GetGlobal(name))
+        }
+    }
+
+    // Assign 'value' to the variable 'name', returning the value of the
+    // assignment.
+    fn assign(&mut self, name: Symbol, value: ValueId) -> ValueId {
+        let function = self.functions.len() - 1;
+
+        if let Some(local) = self.functions[function].resolve_local(name) {
+            match self.functions[function].locals[local].variable {
+                Variable::Value(variable) => {
+                    let copy = self.emit(Op::Copy(value));
+                    self.initialize(Variable::Value(variable), copy);
+                    copy
+                }
+                Variable::Slot(slot) => {
+                    self.emit(Op::Store(slot, value));
+                    value
+                }
+            }
+        } else if let Some(index) = self.resolve_upvalue(function, name) {
+            self.emit(Op::SetUpvalue(index, value));
+            value
+        } else {
+            self.emit(Op::SetGlobal(name, value));
+            value
+        }
+    }
+
+    // The value returned by a 'return' without one, or at the end of the
+    // function.
+    fn implicit_return_value(&mut self) -> ValueId {
+        if self.current().kind == FunctionKind::Initializer {
+            self.read(symbol::THIS)
+        } else {
+            self.constant(Constant::Nil)
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement<OwnedToken>) {
+        match statement {
+            Statement::Expression(expression) => {
+                self.expression(expression);
+            }
+            Statement::Print { expression, .. } => {
+                let value = self.expression(expression);
+                self.emit(Op::Print(value));
+            }
+            Statement::Var {
+                name, initializer, ..
+            } => {
+                self.position = name.source_position;
+                let value = match initializer {
+                    Some(initializer) => self.expression(initializer),
+                    None => self.constant(Constant::Nil),
+                };
+                self.position = name.source_position;
+                // Each definition of an SSA variable is a copy, like an
+                // assignment, which copy propagation removes.
+                let value = match self.current().scope_depth {
+                    0 => value,
+                    _ => self.emit(Op::Copy(value)),
+                };
+                self.define(name, value);
+            }
+            Statement::Block(statements) => {
+                self.begin_scope();
+                for statement in statements {
+                    self.statement(statement);
+                }
+                self.end_scope();
+            }
+            Statement::If {
+                condition,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                let condition = self.expression(condition);
+                let then_block = self.current().new_block();
+                let else_block = else_branch.as_ref().map(|_| self.current().new_block());
+                let join = self.current().new_block();
+
+                self.terminate(Terminator::Branch {
+                    condition,
+                    then_block,
+                    else_block: else_block.unwrap_or(join),
+                });
+                self.current().seal(then_block);
+                self.switch_to(then_block);
+                self.statement(then_branch);
+                self.terminate(Terminator::Jump(join));
+
+                if let (Some(else_block), Some(else_branch)) = (else_block, else_branch) {
+                    self.current().seal(else_block);
+                    self.switch_to(else_block);
+                    self.statement(else_branch);
+                    self.terminate(Terminator::Jump(join));
+                }
+                self.current().seal(join);
+                self.switch_to(join);
+            }
+            Statement::While {
+                condition, body, ..
+            } => {
+                let header = self.current().new_block();
+                self.terminate(Terminator::Jump(header));
+                self.switch_to(header);
+
+                let condition = self.expression(condition);
+                let body_block = self.current().new_block();
+                let exit = self.current().new_block();
+                self.terminate(Terminator::Branch {
+                    condition,
+                    then_block: body_block,
+                    else_block: exit,
+                });
+
+                self.current().seal(body_block);
+                self.switch_to(body_block);
+                self.statement(body);
+                self.terminate(Terminator::Jump(header));
+
+                self.current().seal(header);
+                self.current().seal(exit);
+                self.switch_to(exit);
+            }
+            Statement::Function { declaration, .. } => {
+                self.position = declaration.name.source_position;
+                if self.current().scope_depth == 0 {
+                    let closure = self.function(declaration, FunctionKind::Function);
+                    self.emit(Op::DefineGlobal(declaration.name.lexeme, closure));
+                } else {
+                    // Declare a local function before lowering the body so
+                    // that it can refer to itself.
+                    let name = &declaration.name;
+                    let variable =
+                        self.declare_local(name.lexeme, (name.source_position, name.lexeme));
+                    let closure = self.function(declaration, FunctionKind::Function);
+                    self.initialize(variable, closure);
+                }
+            }
+            Statement::Return { keyword, value } => {
+                self.position = keyword.source_position;
+                let value = match value {
+                    Some(value) => self.expression(value),
+                    None => self.implicit_return_value(),
+                };
+                self.terminate(Terminator::Return(value));
+
+                // Anything after the return can't be reached and is dropped
+                // once the function is finished.
+                let unreachable = self.current().new_block();
+                self.current().seal(unreachable);
+                self.switch_to(unreachable);
+            }
+            Statement::Class {
+                name,
+                superclass,
+                methods,
+                ..
+            } => self.class(name, superclass.as_deref(), methods),
+        }
+    }
+
+    fn function(
+        &mut self,
+        declaration: &FunctionDeclaration<OwnedToken>,
+        kind: FunctionKind,
+    ) -> ValueId {
+        let id = self.program.len();
+        self.program.push(None);
+        self.functions.push(Builder::new(
+            id,
+            Rc::from(declaration.name.lexeme.as_str()),
+            kind,
+            declaration.params.len(),
+        ));
+        self.begin_scope();
+
+        // Slot zero holds the receiver in methods.
+        if let FunctionKind::Method | FunctionKind::Initializer = kind {
+            self.position = declaration.name.source_position;
+            self.parameter(symbol::THIS, declaration.name.source_position, 0);
+        }
+        for (index, param) in declaration.params.iter().enumerate() {
+            self.position = param.source_position;
+            self.parameter(param.lexeme, param.source_position, index + 1);
+        }
+
+        for statement in &declaration.body {
+            self.statement(statement);
+        }
+        self.end_function();
+
+        self.position = declaration.name.source_position;
+        self.emit(Op::Closure(id))
+    }
+
+    // Declare the parameter in 'slot'. A captured parameter stays in its
+    // slot, where closures can share it.
+    fn parameter(&mut self, name: Symbol, position: SourcePosition, slot: usize) {
+        let variable = if self.captured.contains(&(position, name)) {
+            Variable::Slot(slot as u8)
+        } else {
+            let variable = self.current().new_variable();
+            let value = self.emit(Op::Parameter(slot as u8));
+            self.initialize(Variable::Value(variable), value);
+            Variable::Value(variable)
+        };
+
+        let function = self.current();
+        let depth = function.scope_depth;
+        function.locals.push(Local {
+            name,
+            depth,
+            variable,
+        });
+    }
+
+    // Return from the function being lowered and add it to the program. Its
+    // locals are discarded along with its frame, so there is no need to end
+    // its scope.
+    fn end_function(&mut self) {
+        let value = self.implicit_return_value();
+        self.terminate(Terminator::Return(value));
+
+        let function = self.functions.pop().expect("function being lowered");
+        let id = function.id;
+        self.program[id] = Some(function.finish());
+    }
+
+    fn class(
+        &mut self,
+        name: &OwnedToken,
+        superclass: Option<&Expression<OwnedToken>>,
+        methods: &[Rc<FunctionDeclaration<OwnedToken>>],
+    ) {
+        self.position = name.source_position;
+        let class = self.emit(Op::Class(name.lexeme));
+        self.define(name, class);
+
+        // Methods of a subclass close over a local holding the superclass,
+        // which 'super' expressions refer to.
+        if let Some(superclass) = superclass {
+            let superclass = self.expression(superclass);
+            self.begin_scope();
+            let variable = self.declare_local(symbol::SUPER, (name.source_position, symbol::SUPER));
+            self.initialize(variable, superclass);
+            self.emit(Op::Inherit(class, superclass));
+        }
+
+        for method in methods {
+            self.position = method.name.source_position;
+            let kind = if method.name.lexeme == symbol::INIT {
+                FunctionKind::Initializer
+            } else {
+                FunctionKind::Method
+            };
+
+            let closure = self.function(method, kind);
+            self.emit(Op::Method(class, method.name.lexeme, closure));
+        }
+
+        if superclass.is_some() {
+            self.end_scope();
+        }
+    }
+
+    // Lower two branches of control flow that produce a value, merging it
+    // with a phi. 'branch' ends the current block with a branch to the two
+    // blocks it is given.
+    fn join(
+        &mut self,
+        branch: impl FnOnce(&mut Lowering, BlockId, BlockId),
+        then_value: impl FnOnce(&mut Lowering) -> ValueId,
+        else_value: impl FnOnce(&mut Lowering) -> ValueId,
+    ) -> ValueId {
+        let then_block = self.current().new_block();
+        let else_block = self.current().new_block();
+        let join = self.current().new_block();
+        branch(self, then_block, else_block);
+        self.current().seal(then_block);
+        self.current().seal(else_block);
+
+        self.switch_to(then_block);
+        let then_value = then_value(self);
+        self.terminate(Terminator::Jump(join));
+        self.switch_to(else_block);
+        let else_value = else_value(self);
+        self.terminate(Terminator::Jump(join));
+
+        self.current().seal(join);
+        self.switch_to(join);
+        let position = self.position;
+        self.current()
+            .phi(join, vec![then_value, else_value], position)
+    }
+
+    fn expression(&mut self, expression: &Expression<OwnedToken>) -> ValueId {
+        match expression {
+            Expression::Literal { value, token } => {
+                self.position = token.source_position;
+                let constant = match value {
+                    LiteralValue::Number(n) => Constant::Number(*n),
+                    LiteralValue::String(s) => Constant::String(Symbol::intern(s)),
+                    LiteralValue::True => Constant::True,
+                    LiteralValue::False => Constant::False,
+                    LiteralValue::Nil => Constant::Nil,
+                };
+                self.constant(constant)
+            }
+            Expression::Grouping(expression) => self.expression(expression),
+            Expression::Unary { operator, right } => {
+                let right = self.expression(right);
+                self.position = operator.source_position;
+                match operator.token_type {
+                    TokenType::Minus => self.emit(Op::Negate(right)),
+                    TokenType::Bang => self.emit(Op::Not(right)),
+                    _ => {
+                        self.error(&format!("unexpected operator '{}'", operator.lexeme));
+                        right
+                    }
+                }
+            }
+            Expression::Binary {
+                operator,
+                left,
+                right,
+            } => {
+                let left = self.expression(left);
+                if operator.token_type == TokenType::Comma {
+                    return self.expression(right);
+                }
+
+                let right = self.expression(right);
+                self.position = operator.source_position;
+                let op = match operator.token_type {
+                    TokenType::EqualEqual => BinaryOp::Equal,
+                    TokenType::BangEqual => BinaryOp::NotEqual,
+                    TokenType::GreaterThan => BinaryOp::Greater,
+                    TokenType::GreaterThanOrEqual => BinaryOp::GreaterEqual,
+                    TokenType::LessThan => BinaryOp::Less,
+                    TokenType::LessThanOrEqual => BinaryOp::LessEqual,
+                    TokenType::Plus => BinaryOp::Add,
+                    TokenType::Minus => BinaryOp::Subtract,
+                    TokenType::Asterisk => BinaryOp::Multiply,
+                    TokenType::Slash => BinaryOp::Divide,
+                    _ => {
+                        self.error(&format!("unexpected operator '{}'", operator.lexeme));
+                        return left;
+                    }
+                };
+                self.emit(Op::Binary(op, left, right))
+            }
+            Expression::Ternary {
+                left,
+                middle,
+                right,
+                ..
+            } => {
+                let condition = self.expression(left);
+                self.join(
+                    |lowering, then_block, else_block| {
+                        lowering.terminate(Terminator::Branch {
+                            condition,
+                            then_block,
+                            else_block,
+                        })
+                    },
+                    |lowering| lowering.expression(middle),
+                    |lowering| lowering.expression(right),
+                )
+            }
+            Expression::Logical {
+                operator,
+                left,
+                right,
+            } => {
+                let left = self.expression(left);
+                self.position = operator.source_position;
+
+                // The right operand is evaluated on one branch, and the left
+                // one is the value on the other.
+                let is_and = operator.token_type == TokenType::And;
+                self.join(
+                    |lowering, right_block, left_block| {
+                        let (then_block, else_block) = if is_and {
+                            (right_block, left_block)
+                        } else {
+                            (left_block, right_block)
+                        };
+                        lowering.terminate(Terminator::Branch {
+                            condition: left,
+                            then_block,
+                            else_block,
+                        })
+                    },
+                    |lowering| lowering.expression(right),
+                    |_| left,
+                )
+            }
+            Expression::Variable { name, .. } => {
+                self.position = name.source_position;
+                self.read(name.lexeme)
+            }
+            Expression::Assign { name, value, .. } => {
+                let value = self.expression(value);
+                self.position = name.source_position;
+                self.assign(name.lexeme, value)
+            }
+            Expression::Call {
+                callee,
+                paren,
+                arguments,
+            } => {
+                let value = if let Expression::Get { object, name } = &**callee {
+                    let object = self.expression(object);
+                    let arguments = arguments
+                        .iter()
+                        .map(|argument| self.expression(argument))
+                        .collect();
+                    self.position = name.source_position;
+                    self.emit(Op::Invoke(
+                        object,
+                        name.lexeme,
+                        arguments,
+                        paren.source_position,
+                    ))
+                } else {
+                    let callee = self.expression(callee);
+                    let arguments = arguments
+                        .iter()
+                        .map(|argument| self.expression(argument))
+                        .collect();
+                    self.position = paren.source_position;
+                    self.emit(Op::Call(callee, arguments))
+                };
+                self.position = paren.source_position;
+                value
+            }
+            Expression::Get { object, name } => {
+                let object = self.expression(object);
+                self.position = name.source_position;
+                self.emit(Op::GetProperty(object, name.lexeme))
+            }
+            Expression::Set {
+                object,
+                name,
+                value,
+            } => {
+                let object = self.expression(object);
+                let value = self.expression(value);
+                self.position = name.source_position;
+                self.emit(Op::SetProperty(object, name.lexeme, value));
+                value
+            }
+            Expression::List { bracket, elements } => {
+                let elements = elements
+                    .iter()
+                    .map(|element| self.expression(element))
+                    .collect();
+                self.position = bracket.source_position;
+                self.emit(Op::BuildList(elements))
+            }
+            Expression::Map { brace, entries } => {
+                let mut operands = Vec::with_capacity(2 * entries.len());
+                for (key, value) in entries {
+                    operands.push(self.expression(key));
+                    operands.push(self.expression(value));
+                }
+                self.position = brace.source_position;
+                self.emit(Op::BuildMap(operands))
+            }
+            Expression::Index {
+                object,
+                bracket,
+                index,
+            } => {
+                let object = self.expression(object);
+                let index = self.expression(index);
+                self.position = bracket.source_position;
+                self.emit(Op::GetIndex(object, index))
+            }
+            Expression::Slice {
+                object,
+                bracket,
+                start,
+                end,
+            } => {
+                let object = self.expression(object);
+                let start = self.expression(start);
+                let end = self.expression(end);
+                self.position = bracket.source_position;
+                self.emit(Op::GetSlice(object, start, end))
+            }
+            Expression::SetIndex {
+                object,
+                bracket,
+                index,
+                value,
+            } => {
+                let object = self.expression(object);
+                let index = self.expression(index);
+                let value = self.expression(value);
+                self.position = bracket.source_position;
+                self.emit(Op::SetIndex(object, index, value));
+                value
+            }
+            Expression::This { keyword, .. } => {
+                self.position = keyword.source_position;
+                self.read(symbol::THIS)
+            }
+            Expression::Super {
+                keyword, method, ..
+            } => {
+                self.position = keyword.source_position;
+                let this = self.read(symbol::THIS);
+                let superclass = self.read(symbol::SUPER);
+
+                self.position = method.source_position;
+                self.emit(Op::GetSuper(this, superclass, method.lexeme))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for id in 0..self.functions.len() {
+            if id > 0 {
+                writeln!(f)?;
+            }
+            self.write_function(f, id)?;
+        }
+        Ok(())
+    }
+}
+
+impl Program {
+    fn function_name(&self, id: FunctionId) -> String {
+        let name = &self.functions[id].name;
+        if name.is_empty() {
+            String::from("<script>")
+        } else {
+            format!("<fn {name}>")
+        }
+    }
+
+    // Write 'function' with its values numbered in the order they are
+    // defined, leaving out the instructions that have been removed.
+    fn write_function(&self, f: &mut fmt::Formatter, id: FunctionId) -> fmt::Result {
+        let function = &self.functions[id];
+        let mut numbers = HashMap::new();
+        for block in &function.blocks {
+            for &value in block.phis.iter().chain(&block.instructions) {
+                if function.instructions[value].op.has_value() {
+                    numbers.insert(value, numbers.len());
+                }
+            }
+        }
+        let name = |value: &ValueId| match numbers.get(value) {
+            Some(number) => format!("v{number}"),
+            None => String::from("v?"),
+        };
+        let list = |values: &[ValueId]| values.iter().map(name).collect::<Vec<_>>().join(", ");
+
+        writeln!(f, "== {} ==", self.function_name(id))?;
+
+        for (index, block) in function.blocks.iter().enumerate() {
+            write!(f, "b{index}:")?;
+            if !block.predecessors.is_empty() {
+                let predecessors: Vec<String> = block
+                    .predecessors
+                    .iter()
+                    .map(|block| format!("b{block}"))
+                    .collect();
+                write!(f, " <- {}", predecessors.join(", "))?;
+            }
+            writeln!(f)?;
+
+            for &value in block.phis.iter().chain(&block.instructions) {
+                let op = &function.instructions[value].op;
+                write!(f, "    ")?;
+                if op.has_value() {
+                    write!(f, "{} = ", name(&value))?;
+                }
+
+                match op {
+                    Op::Phi(operands) => write!(f, "phi {}", list(operands))?,
+                    Op::Parameter(slot) => write!(f, "param {slot}")?,
+                    Op::Constant(constant) => write!(f, "const {constant}")?,
+                    Op::Copy(value) => write!(f, "copy {}", name(value))?,
+                    Op::Not(value) => write!(f, "not {}", name(value))?,
+                    Op::Negate(value) => write!(f, "negate {}", name(value))?,
+                    Op::Binary(op, left, right) => {
+                        write!(f, "{} {}, {}", op.name(), name(left), name(right))?
+                    }
+                    Op::GetGlobal(global) => write!(f, "get_global {global}")?,
+                    Op::DefineGlobal(global, value) => {
+                        write!(f, "define_global {global}, {}", name(value))?
+                    }
+                    Op::SetGlobal(global, value) => {
+                        write!(f, "set_global {global}, {}", name(value))?
+                    }
+                    Op::GetUpvalue(index) => write!(f, "get_upvalue {index}")?,
+                    Op::SetUpvalue(index, value) => {
+                        write!(f, "set_upvalue {index}, {}", name(value))?
+                    }
+                    Op::Load(slot) => write!(f, "load {slot}")?,
+                    Op::Store(slot, value) => write!(f, "store {slot}, {}", name(value))?,
+                    Op::Close(slot) => write!(f, "close {slot}")?,
+                    Op::GetProperty(object, property) => {
+                        write!(f, "get_property {}, {property}", name(object))?
+                    }
+                    Op::SetProperty(object, property, value) => write!(
+                        f,
+                        "set_property {}, {property}, {}",
+                        name(object),
+                        name(value)
+                    )?,
+                    Op::GetSuper(this, superclass, method) => write!(
+                        f,
+                        "get_super {}, {}, {method}",
+                        name(this),
+                        name(superclass)
+                    )?,
+                    Op::Call(callee, arguments) => {
+                        write!(f, "call {}({})", name(callee), list(arguments))?
+                    }
+                    Op::Invoke(receiver, method, arguments, _) => {
+                        write!(f, "invoke {}.{method}({})", name(receiver), list(arguments))?
+                    }
+                    Op::Closure(id) => {
+                        write!(f, "closure {}", self.function_name(*id))?;
+                        let captures: Vec<String> = self.functions[*id]
+                            .upvalues
+                            .iter()
+                            .map(|capture| match capture.is_local {
+                                true => format!("local {}", capture.index),
+                                false => format!("upvalue {}", capture.index),
+                            })
+                            .collect();
+                        if !captures.is_empty() {
+                            write!(f, " [{}]", captures.join(", "))?;
+                        }
+                    }
+                    Op::Class(class) => write!(f, "class {class}")?,
+                    Op::Inherit(class, superclass) => {
+                        write!(f, "inherit {}, {}", name(class), name(superclass))?
+                    }
+                    Op::Method(class, method, closure) => {
+                        write!(f, "method {}, {method}, {}", name(class), name(closure))?
+                    }
+                    Op::Print(value) => write!(f, "print {}", name(value))?,
+                    Op::BuildList(elements) => write!(f, "list [{}]", list(elements))?,
+                    Op::BuildMap(entries) => write!(f, "map [{}]", list(entries))?,
+                    Op::GetIndex(object, index) => {
+                        write!(f, "get_index {}, {}", name(object), name(index))?
+                    }
+                    Op::SetIndex(object, index, value) => write!(
+                        f,
+                        "set_index {}, {}, {}",
+                        name(object),
+                        name(index),
+                        name(value)
+                    )?,
+                    Op::GetSlice(object, start, end) => write!(
+                        f,
+                        "get_slice {}, {}, {}",
+                        name(object),
+                        name(start),
+                        name(end)
+                    )?,
+                }
+                writeln!(f)?;
+            }
+
+            match &block.terminator {
+                Terminator::Jump(target) => writeln!(f, "    jump b{target}")?,
+                Terminator::Branch {
+                    condition,
+                    then_block,
+                    else_block,
+                } => writeln!(
+                    f,
+                    "    branch {}, b{then_block}, b{else_block}",
+                    name(condition)
+                )?,
+                Terminator::Return(value) => writeln!(f, "    return {}", name(value))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Constant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Constant::Number(value) => write!(f, "{value}"),
+            Constant::String(value) => write!(f, "{:?}", value.as_str()),
+            Constant::True => write!(f, "true"),
+            Constant::False => write!(f, "false"),
+            Constant::Nil => write!(f, "nil"),
+        }
+    }
+}
+
+impl BinaryOp {
+    fn name(self) -> &'static str {
+        match self {
+            BinaryOp::Equal => "equal",
+            BinaryOp::NotEqual => "not_equal",
+            BinaryOp::Greater => "greater",
+            BinaryOp::GreaterEqual => "greater_equal",
+            BinaryOp::Less => "less",
+            BinaryOp::LessEqual => "less_equal",
+            BinaryOp::Add => "add",
+            BinaryOp::Subtract => "subtract",
+            BinaryOp::Multiply => "multiply",
+            BinaryOp::Divide => "divide",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex;
+    use crate::parser::parse;
+    use crate::resolver::resolve;
+
+    fn lower_source(source: &str) -> Program {
+        let tokens = lex(source).unwrap();
+        let statements = parse(&tokens).unwrap();
+        resolve(&statements).unwrap();
+        lower(&statements).unwrap()
+    }
+
+    // The printed function named 'name', without its header.
+    fn function_text(program: &Program, name: &str) -> String {
+        let text = program.to_string();
+        let header = format!("== {name} ==\n");
+        let start = text.find(&header).unwrap() + header.len();
+        let end = text[start..]
+            .find("\n\n")
+            .map_or(text.len(), |end| start + end);
+        text[start..end].trim_end().to_string()
+    }
+
+    // The phi for 'n', which is never assigned in the loop, is only removed
+    // by the optimizer.
+    #[test]
+    fn merges_assignments_with_phis() {
+        let program = lower_source("fun f(n) { var i = 0; while (i < n) i = i + 1; return i; }");
+
+        assert_eq!(
+            function_text(&program, "<fn f>"),
+            "\
+b0:
+    v0 = param 1
+    v1 = const 0
+    v2 = copy v1
+    jump b1
+b1: <- b0, b2
+    v3 = phi v2, v8
+    v4 = phi v0, v4
+    v5 = less v3, v4
+    branch v5, b2, b3
+b2: <- b1
+    v6 = const 1
+    v7 = add v3, v6
+    v8 = copy v7
+    jump b1
+b3: <- b1
+    return v3"
+        );
+    }
+
+    // 'c' is given the first slot after the parameters, while the captured
+    // parameter 'n' stays in its own.
+    #[test]
+    fn keeps_captured_variables_in_slots() {
+        let program = lower_source(
+            "fun f(n) { { var c = n; fun g() { return c + n; } c = c + 1; } return n; }",
+        );
+
+        assert_eq!(
+            function_text(&program, "<fn f>"),
+            "\
+b0:
+    v0 = load 1
+    v1 = copy v0
+    store 2, v1
+    v2 = closure <fn g> [local 2, local 1]
+    v3 = load 2
+    v4 = const 1
+    v5 = add v3, v4
+    store 2, v5
+    close 2
+    v6 = load 1
+    return v6"
+        );
+        assert_eq!(program.functions[1].cells, 1);
+    }
+
+    #[test]
+    fn joins_values_of_logical_operators() {
+        let program = lower_source("fun f(a, b) { return a and b; }");
+
+        assert_eq!(
+            function_text(&program, "<fn f>"),
+            "\
+b0:
+    v0 = param 1
+    v1 = param 2
+    branch v0, b1, b2
+b1: <- b0
+    jump b3
+b2: <- b0
+    jump b3
+b3: <- b1, b2
+    v2 = phi v1, v0
+    return v2"
+        );
+    }
+}
@@ -0,0 +1,808 @@
+use crate::chunk::{self, Chunk, FunctionProto, OpCode};
+use crate::ir::{BinaryOp, BlockId, Constant, Function, Op, Program, Terminator, ValueId};
+use crate::lexer::SourcePosition;
+use crate::result::{Error, Result};
+use crate::symbol::Symbol;
+use std::collections::{BTreeSet, HashMap};
+use std::rc::Rc;
+
+// Frame slots are addressed with a single byte operand, inline caches with
+// two.
+const MAX_SLOTS: usize = 256;
+const MAX_CACHES: usize = 65536;
+
+// Compile a program in the IR to bytecode, returning the function for its
+// top-level script. The result runs on 'vm::Vm' like the output of
+// 'compiler::compile'.
+//
+// Values live on the VM's stack where they can: a value used once, by a
+// later instruction of the same block, is left on the stack for it when the
+// values pushed in between have been used up by then. Constants are pushed
+// again wherever they are used. Every other value is kept in a frame slot,
+// reserved when the function is called, and slots are shared by values that
+// are never live at the same time. Phis are resolved by copying their
+// operands into their slots at the end of each predecessor.
+pub fn compile(program: &Program) -> Result<Rc<FunctionProto>> {
+    let mut errors = Vec::new();
+    let script = FunctionCompiler::new(program, 0, &mut errors).compile();
+
+    match errors.len() {
+        0 => Ok(Rc::new(script)),
+        1 => Err(errors.remove(0)),
+        _ => Err(Error::MultipleErrors(errors)),
+    }
+}
+
+// Where a value is kept between the instruction defining it and its uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Location {
+    // The value is never used.
+    Nowhere,
+    // On top of the stack when it is used.
+    Stack,
+    // Pushed by each use.
+    Constant,
+    Slot(u8),
+}
+
+struct FunctionCompiler<'a> {
+    program: &'a Program,
+    function: &'a Function,
+    locations: Vec<Location>,
+    // The number of frame slots in use, including the closure and arguments.
+    slots: usize,
+    chunk: Chunk,
+    // Indices of the string constants already in 'chunk'.
+    strings: HashMap<Symbol, u16>,
+    cache_count: usize,
+    // The offset of each block in the code once it has been emitted, and the
+    // operands of the jumps to it before then.
+    offsets: Vec<Option<usize>>,
+    jumps: Vec<Vec<usize>>,
+    position: SourcePosition,
+    errors: &'a mut Vec<Error>,
+}
+
+impl<'a> FunctionCompiler<'a> {
+    fn new(program: &'a Program, id: usize, errors: &'a mut Vec<Error>) -> FunctionCompiler<'a> {
+        let function = &program.functions[id];
+        FunctionCompiler {
+            program,
+            function,
+            locations: vec![Location::Nowhere; function.instructions.len()],
+            slots: 1 + function.arity + function.cells,
+            chunk: Chunk::default(),
+            strings: HashMap::new(),
+            cache_count: 0,
+            offsets: vec![None; function.blocks.len()],
+            jumps: vec![Vec::new(); function.blocks.len()],
+            position: function
+                .instructions
+                .first()
+                .map_or((1, 1), |instruction| instruction.position),
+            errors,
+        }
+    }
+
+    fn error(&mut self, message: &str) {
+        self.errors.push(Error::ParseError {
+            message: message.to_string(),
+            source_position: self.position,
+        });
+    }
+
+    fn compile(mut self) -> FunctionProto {
+        self.locate_values();
+
+        // Reserve the slots for captured variables and values.
+        let reserved = self.slots - 1 - self.function.arity;
+        for _ in 0..reserved {
+            self.emit(OpCode::Nil);
+        }
+
+        // Blocks are numbered in reverse postorder, so jumps to an earlier
+        // block are loops.
+        let mut stubs = Vec::new();
+        for block in 0..self.function.blocks.len() {
+            self.offsets[block] = Some(self.chunk.code.len());
+            for operand in std::mem::take(&mut self.jumps[block]) {
+                self.patch_jump(operand);
+            }
+            self.block(block, &mut stubs);
+        }
+        for (operand, from, to) in stubs {
+            self.patch_jump(operand);
+            self.emit(OpCode::Pop);
+            self.edge(from, to, false);
+        }
+
+        FunctionProto {
+            name: Rc::clone(&self.function.name),
+            arity: self.function.arity,
+            upvalue_count: self.function.upvalues.len(),
+            cache_count: self.cache_count,
+            chunk: self.chunk,
+        }
+    }
+
+    // Decide where each value is kept, see 'compile'.
+    fn locate_values(&mut self) {
+        let function = self.function;
+        let uses = function.use_counts();
+
+        for block in &function.blocks {
+            for &value in block.phis.iter().chain(&block.instructions) {
+                self.locations[value] = match function.instructions[value].op {
+                    Op::Constant(_) => Location::Constant,
+                    Op::Parameter(slot) => Location::Slot(slot),
+                    _ if uses[value] == 0 => Location::Nowhere,
+                    // Decided below.
+                    _ => Location::Slot(0),
+                };
+            }
+        }
+        for block in 0..function.blocks.len() {
+            self.stack_values(block, &uses);
+        }
+        self.allocate_slots();
+    }
+
+    // The operands of the instructions of 'block', followed by those of its
+    // terminator.
+    fn block_operands(&self, block: BlockId) -> Vec<(Option<ValueId>, Vec<ValueId>)> {
+        let block = &self.function.blocks[block];
+        block
+            .instructions
+            .iter()
+            .map(|&value| (Some(value), self.function.instructions[value].op.operands()))
+            .chain(std::iter::once((
+                None,
+                block.terminator.operand().into_iter().collect(),
+            )))
+            .collect()
+    }
+
+    // Find the values of 'block' that can be left on the stack for their
+    // only use. An instruction finds the first of its operands that are left
+    // on the stack on top of it, in order, and pushes the rest.
+    fn stack_values(&mut self, block: BlockId, uses: &[usize]) {
+        let items = self.block_operands(block);
+
+        let mut used_in_block = vec![false; self.function.instructions.len()];
+        for (_, operands) in &items {
+            for &operand in operands {
+                used_in_block[operand] = true;
+            }
+        }
+        let mut candidates = vec![false; self.function.instructions.len()];
+        for &value in &self.function.blocks[block].instructions {
+            candidates[value] = uses[value] == 1 && used_in_block[value] && self.in_slot(value);
+        }
+
+        // Drop the candidates that would be in the way until the rest fit.
+        loop {
+            let mut stack = Vec::new();
+            let mut conflicts: Vec<ValueId> = Vec::new();
+
+            for (value, operands) in &items {
+                let leading = operands
+                    .iter()
+                    .take_while(|&&operand| candidates[operand])
+                    .count();
+                conflicts.extend(
+                    operands[leading..]
+                        .iter()
+                        .filter(|&&operand| candidates[operand]),
+                );
+                if stack.len() < leading || stack[stack.len() - leading..] != operands[..leading] {
+                    conflicts.extend(&operands[..leading]);
+                }
+                if !conflicts.is_empty() {
+                    break;
+                }
+
+                stack.truncate(stack.len() - leading);
+                if let Some(value) = value.filter(|&value| candidates[value]) {
+                    stack.push(value);
+                }
+            }
+
+            if conflicts.is_empty() {
+                break;
+            }
+            for conflict in conflicts {
+                candidates[conflict] = false;
+            }
+        }
+
+        for &value in &self.function.blocks[block].instructions {
+            if candidates[value] {
+                self.locations[value] = Location::Stack;
+            }
+        }
+    }
+
+    // Whether 'value' is kept in a slot of its own, rather than in that of a
+    // parameter. Until slots are allocated, it is 'Slot(0)'.
+    fn in_slot(&self, value: ValueId) -> bool {
+        matches!(self.locations[value], Location::Slot(_))
+            && !matches!(self.function.instructions[value].op, Op::Parameter(_))
+    }
+
+    // Give each value kept in a slot one that no other value live at the
+    // same time has, preferring the slot of a phi for its operands and the
+    // other way around, which saves copying.
+    fn allocate_slots(&mut self) {
+        let function = self.function;
+        let count = function.instructions.len();
+
+        // Liveness of the values in slots at the start and end of each block,
+        // where the live values at the start exclude its phis.
+        let mut live_in: Vec<BTreeSet<ValueId>> = vec![BTreeSet::new(); function.blocks.len()];
+        let mut live_out: Vec<BTreeSet<ValueId>> = vec![BTreeSet::new(); function.blocks.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in (0..function.blocks.len()).rev() {
+                let mut live = BTreeSet::new();
+                for successor in function.blocks[block].terminator.successors() {
+                    live.extend(self.phi_operands(block, successor));
+                    live.extend(&live_in[successor]);
+                }
+                live_out[block] = live.clone();
+
+                for (value, operands) in self.block_operands(block).into_iter().rev() {
+                    if let Some(value) = value {
+                        live.remove(&value);
+                    }
+                    live.extend(
+                        operands
+                            .into_iter()
+                            .filter(|&operand| self.in_slot(operand)),
+                    );
+                }
+                for phi in &function.blocks[block].phis {
+                    live.remove(phi);
+                }
+                if live != live_in[block] {
+                    live_in[block] = live;
+                    changed = true;
+                }
+            }
+        }
+
+        let mut interference: Vec<BTreeSet<ValueId>> = vec![BTreeSet::new(); count];
+        let mut interfere = |a: ValueId, b: ValueId| {
+            if a != b {
+                interference[a].insert(b);
+                interference[b].insert(a);
+            }
+        };
+        for (block, live_out) in live_out.into_iter().enumerate() {
+            let mut live = live_out;
+            for (value, operands) in self.block_operands(block).into_iter().rev() {
+                if let Some(value) = value.filter(|&value| self.in_slot(value)) {
+                    live.remove(&value);
+                    for &other in &live {
+                        interfere(value, other);
+                    }
+                }
+                live.extend(
+                    operands
+                        .into_iter()
+                        .filter(|&operand| self.in_slot(operand)),
+                );
+            }
+
+            // Phis are all defined as the block starts.
+            let phis: Vec<ValueId> = function.blocks[block]
+                .phis
+                .iter()
+                .copied()
+                .filter(|&phi| self.in_slot(phi))
+                .collect();
+            for &phi in &phis {
+                live.remove(&phi);
+            }
+            for &phi in &phis {
+                for &other in live.iter().chain(&phis) {
+                    interfere(phi, other);
+                }
+            }
+        }
+
+        let mut hints: Vec<Vec<ValueId>> = vec![Vec::new(); count];
+        for block in &function.blocks {
+            for &phi in &block.phis {
+                for operand in function.instructions[phi].op.operands() {
+                    hints[phi].push(operand);
+                    hints[operand].push(phi);
+                }
+            }
+        }
+
+        let first = self.slots;
+        let mut slots: Vec<Option<usize>> = vec![None; count];
+        for block in &function.blocks {
+            for &value in block.phis.iter().chain(&block.instructions) {
+                if !self.in_slot(value) {
+                    continue;
+                }
+
+                let taken: BTreeSet<usize> = interference[value]
+                    .iter()
+                    .filter_map(|&other| slots[other])
+                    .collect();
+                let hint = hints[value]
+                    .iter()
+                    .filter_map(|&other| slots[other])
+                    .find(|slot| !taken.contains(slot));
+                let slot = hint.unwrap_or_else(|| {
+                    (first..)
+                        .find(|slot| !taken.contains(slot))
+                        .expect("free slot")
+                });
+
+                if slot >= MAX_SLOTS {
+                    self.position = function.instructions[value].position;
+                    self.error("too many local variables in function");
+                    return;
+                }
+                slots[value] = Some(slot);
+                self.slots = self.slots.max(slot + 1);
+                self.locations[value] = Location::Slot(slot as u8);
+            }
+        }
+    }
+
+    // The operands of the phis of 'successor' coming from 'block'.
+    fn phi_operands(&self, block: BlockId, successor: BlockId) -> Vec<ValueId> {
+        let successor = &self.function.blocks[successor];
+        let Some(index) = successor
+            .predecessors
+            .iter()
+            .position(|&predecessor| predecessor == block)
+        else {
+            return Vec::new();
+        };
+
+        successor
+            .phis
+            .iter()
+            .filter(|&&phi| self.in_slot(phi))
+            .filter_map(|&phi| match &self.function.instructions[phi].op {
+                Op::Phi(operands) => Some(operands[index]),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn emit(&mut self, op: OpCode) {
+        self.chunk.write_op(op, self.position);
+    }
+
+    fn emit_byte(&mut self, byte: u8) {
+        self.chunk.write(byte, self.position);
+    }
+
+    fn emit_with_u16(&mut self, op: OpCode, operand: u16) {
+        self.emit(op);
+        self.chunk.write_u16(operand, self.position);
+    }
+
+    fn make_constant(&mut self, constant: chunk::Constant) -> u16 {
+        match self.chunk.add_constant(constant) {
+            Some(index) => index,
+            None => {
+                self.error("too many constants in one chunk");
+                0
+            }
+        }
+    }
+
+    fn identifier_constant(&mut self, name: Symbol) -> u16 {
+        if let Some(&index) = self.strings.get(&name) {
+            return index;
+        }
+
+        let index = self.make_constant(chunk::Constant::String(name));
+        self.strings.insert(name, index);
+        index
+    }
+
+    // Emit 'op' on the property 'name' with a new inline cache.
+    fn emit_property(&mut self, op: OpCode, name: Symbol) {
+        let constant = self.identifier_constant(name);
+        self.emit_with_u16(op, constant);
+
+        if self.cache_count >= MAX_CACHES {
+            self.error("too many property accesses in one function");
+        }
+        self.chunk.write_u16(self.cache_count as u16, self.position);
+        self.cache_count += 1;
+    }
+
+    fn patch_jump(&mut self, operand: usize) {
+        let jump = self.chunk.code.len() - operand - 2;
+        let Ok(jump) = u16::try_from(jump) else {
+            self.error("too much code to jump over");
+            return;
+        };
+
+        let [high, low] = jump.to_be_bytes();
+        self.chunk.code[operand] = high;
+        self.chunk.code[operand + 1] = low;
+    }
+
+    // Emit a forward jump with a placeholder offset, returning the offset of
+    // the operand to patch.
+    fn emit_jump(&mut self, op: OpCode) -> usize {
+        self.emit_with_u16(op, u16::MAX);
+        self.chunk.code.len() - 2
+    }
+
+    // Jump to the start of 'block', unless it comes next and 'fall_through'
+    // is set.
+    fn jump_to(&mut self, block: BlockId, fall_through: bool) {
+        match self.offsets[block] {
+            Some(start) => {
+                self.emit(OpCode::Loop);
+                let offset = self.chunk.code.len() - start + 2;
+                match u16::try_from(offset) {
+                    Ok(offset) => self.chunk.write_u16(offset, self.position),
+                    Err(_) => self.error("loop body too large"),
+                }
+            }
+            // Blocks are emitted in order, so this one comes next.
+            None if fall_through && self.offsets[block - 1].is_some() => {}
+            None => {
+                let operand = self.emit_jump(OpCode::Jump);
+                self.jumps[block].push(operand);
+            }
+        }
+    }
+
+    // Follow the edge from 'from' to 'to', copying the operands of the phis
+    // of 'to' into their slots. The copies are made through the stack, so
+    // that every operand is read before any slot is written.
+    fn edge(&mut self, from: BlockId, to: BlockId, fall_through: bool) {
+        let phis: Vec<ValueId> = self.function.blocks[to]
+            .phis
+            .iter()
+            .copied()
+            .filter(|&phi| self.in_slot(phi))
+            .collect();
+        let copies: Vec<(ValueId, Location)> = phis
+            .iter()
+            .zip(self.phi_operands(from, to))
+            .map(|(&phi, operand)| (operand, self.locations[phi]))
+            .filter(|&(operand, location)| self.locations[operand] != location)
+            .collect();
+
+        for &(operand, _) in &copies {
+            self.load(operand);
+        }
+        for &(_, location) in copies.iter().rev() {
+            let Location::Slot(slot) = location else {
+                unreachable!("phis are kept in slots");
+            };
+            self.emit(OpCode::SetLocal);
+            self.emit_byte(slot);
+            self.emit(OpCode::Pop);
+        }
+
+        self.jump_to(to, fall_through);
+    }
+
+    // Push 'value' unless it is on the stack already.
+    fn load(&mut self, value: ValueId) {
+        match self.locations[value] {
+            Location::Stack => {}
+            Location::Slot(slot) => {
+                self.emit(OpCode::GetLocal);
+                self.emit_byte(slot);
+            }
+            Location::Constant => {
+                let Op::Constant(constant) = &self.function.instructions[value].op else {
+                    unreachable!("constant location");
+                };
+                match constant {
+                    Constant::Number(n) => {
+                        let constant = self.make_constant(chunk::Constant::Number(*n));
+                        self.emit_with_u16(OpCode::Constant, constant);
+                    }
+                    Constant::String(s) => {
+                        let constant = self.identifier_constant(*s);
+                        self.emit_with_u16(OpCode::Constant, constant);
+                    }
+                    Constant::True => self.emit(OpCode::True),
+                    Constant::False => self.emit(OpCode::False),
+                    Constant::Nil => self.emit(OpCode::Nil),
+                }
+            }
+            Location::Nowhere => unreachable!("used value"),
+        }
+    }
+
+    fn block(&mut self, block: BlockId, stubs: &mut Vec<(usize, BlockId, BlockId)>) {
+        let function = self.function;
+        for &value in &function.blocks[block].instructions {
+            self.instruction(value);
+        }
+
+        let terminator = &function.blocks[block].terminator;
+        if let Some(operand) = terminator.operand() {
+            self.load(operand);
+        }
+        match *terminator {
+            Terminator::Jump(target) => self.edge(block, target, true),
+            Terminator::Branch {
+                then_block,
+                else_block,
+                ..
+            } => {
+                let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit(OpCode::Pop);
+                // When the then block comes next, the else edge is emitted
+                // after the last block so that the then edge can fall
+                // through to it.
+                if then_block == block + 1 {
+                    self.edge(block, then_block, true);
+                    stubs.push((else_jump, block, else_block));
+                } else {
+                    self.edge(block, then_block, false);
+                    self.patch_jump(else_jump);
+                    self.emit(OpCode::Pop);
+                    self.edge(block, else_block, true);
+                }
+            }
+            Terminator::Return(_) => self.emit(OpCode::Return),
+        }
+    }
+
+    fn instruction(&mut self, value: ValueId) {
+        let function = self.function;
+        let instruction = &function.instructions[value];
+        let op = &instruction.op;
+        if let Op::Parameter(_) | Op::Constant(_) = op {
+            return;
+        }
+
+        for operand in op.operands() {
+            self.load(operand);
+        }
+        self.position = instruction.position;
+
+        // Whether the bytecode leaves a value on the stack.
+        let mut pushes = true;
+        match *op {
+            Op::Phi(_) | Op::Parameter(_) | Op::Constant(_) => unreachable!("not emitted"),
+            Op::Copy(_) => {}
+            Op::Not(_) => self.emit(OpCode::Not),
+            Op::Negate(_) => self.emit(OpCode::Negate),
+            Op::Binary(op, _, _) => self.emit(match op {
+                BinaryOp::Equal => OpCode::Equal,
+                BinaryOp::NotEqual => OpCode::NotEqual,
+                BinaryOp::Greater => OpCode::Greater,
+                BinaryOp::GreaterEqual => OpCode::GreaterEqual,
+                BinaryOp::Less => OpCode::Less,
+                BinaryOp::LessEqual => OpCode::LessEqual,
+                BinaryOp::Add => OpCode::Add,
+                BinaryOp::Subtract => OpCode::Subtract,
+                BinaryOp::Multiply => OpCode::Multiply,
+                BinaryOp::Divide => OpCode::Divide,
+            }),
+            Op::GetGlobal(name) => {
+                let constant = self.identifier_constant(name);
+                self.emit_with_u16(OpCode::GetGlobal, constant);
+            }
+            Op::DefineGlobal(name, _) => {
+                let constant = self.identifier_constant(name);
+                self.emit_with_u16(OpCode::DefineGlobal, constant);
+                pushes = false;
+            }
+            Op::SetGlobal(name, _) => {
+                let constant = self.identifier_constant(name);
+                self.emit_with_u16(OpCode::SetGlobal, constant);
+            }
+            Op::GetUpvalue(index) | Op::SetUpvalue(index, _) => {
+                let op = match op {
+                    Op::GetUpvalue(_) => OpCode::GetUpvalue,
+                    _ => OpCode::SetUpvalue,
+                };
+                self.emit(op);
+                self.emit_byte(index);
+            }
+            Op::Load(slot) | Op::Store(slot, _) => {
+                let op = match op {
+                    Op::Load(_) => OpCode::GetLocal,
+                    _ => OpCode::SetLocal,
+                };
+                self.emit(op);
+                self.emit_byte(slot);
+            }
+            Op::Close(slot) => {
+                self.emit(OpCode::CloseLocal);
+                self.emit_byte(slot);
+                pushes = false;
+            }
+            Op::GetProperty(_, name) => self.emit_property(OpCode::GetProperty, name),
+            Op::SetProperty(_, name, _) => self.emit_property(OpCode::SetProperty, name),
+            Op::GetSuper(_, _, name) => {
+                let constant = self.identifier_constant(name);
+                self.emit_with_u16(OpCode::GetSuper, constant);
+            }
+            Op::Call(_, ref arguments) => {
+                self.emit(OpCode::Call);
+                self.emit_byte(arguments.len() as u8);
+            }
+            // The argument count carries the position of the call, see
+            // 'compiler'.
+            Op::Invoke(_, name, ref arguments, call) => {
+                self.emit_property(OpCode::Invoke, name);
+                self.position = call;
+                self.emit_byte(arguments.len() as u8);
+            }
+            Op::Closure(id) => {
+                let proto = FunctionCompiler::new(self.program, id, self.errors).compile();
+                let constant = self.make_constant(chunk::Constant::Function(Rc::new(proto)));
+                self.emit_with_u16(OpCode::Closure, constant);
+                for capture in &self.program.functions[id].upvalues {
+                    self.emit_byte(capture.is_local as u8);
+                    self.emit_byte(capture.index);
+                }
+            }
+            Op::Class(name) => {
+                let constant = self.identifier_constant(name);
+                self.emit_with_u16(OpCode::Class, constant);
+            }
+            Op::Inherit(..) => self.emit(OpCode::Inherit),
+            Op::Method(_, name, _) => {
+                let constant = self.identifier_constant(name);
+                self.emit_with_u16(OpCode::Method, constant);
+            }
+            Op::Print(_) => {
+                self.emit(OpCode::Print);
+                pushes = false;
+            }
+            Op::BuildList(ref elements) => {
+                let count = u16::try_from(elements.len()).unwrap_or_else(|_| {
+                    self.error("too many elements in a list");
+                    0
+                });
+                self.emit_with_u16(OpCode::BuildList, count);
+            }
+            Op::BuildMap(ref entries) => {
+                let count = u16::try_from(entries.len() / 2).unwrap_or_else(|_| {
+                    self.error("too many entries in a map");
+                    0
+                });
+                self.emit_with_u16(OpCode::BuildMap, count);
+            }
+            Op::GetIndex(..) => self.emit(OpCode::GetIndex),
+            Op::SetIndex(..) => self.emit(OpCode::SetIndex),
+            Op::GetSlice(..) => self.emit(OpCode::GetSlice),
+        }
+
+        if !pushes {
+            return;
+        }
+        match self.locations[value] {
+            Location::Stack => {}
+            Location::Slot(slot) => {
+                self.emit(OpCode::SetLocal);
+                self.emit_byte(slot);
+                self.emit(OpCode::Pop);
+            }
+            // Instructions without a value leave their operand or the class
+            // on the stack.
+            Location::Nowhere | Location::Constant => self.emit(OpCode::Pop),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir;
+    use crate::ir_optimizer;
+    use crate::lexer::lex;
+    use crate::parser::parse;
+    use crate::resolver::resolve;
+    use crate::testing::Output;
+    use crate::verifier::verify;
+    use crate::vm::Vm;
+    use std::fs;
+    use std::path::Path;
+
+    fn compile_source(source: &str, optimize: bool) -> Result<Rc<FunctionProto>> {
+        let tokens = lex(source)?;
+        let statements = parse(&tokens)?;
+        resolve(&statements)?;
+        let mut program = ir::lower(&statements)?;
+        if optimize {
+            ir_optimizer::optimize(&mut program);
+        }
+        compile(&program)
+    }
+
+    fn output(source: &str) -> String {
+        let output = Output::default();
+        let script = compile_source(source, true).unwrap();
+        Vm::with_output(Box::new(output.clone()))
+            .interpret(script)
+            .unwrap();
+        output.contents()
+    }
+
+    #[test]
+    fn accepts_compiled_programs() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+        for subdir in ["corpus", "disasm"] {
+            for entry in fs::read_dir(dir.join(subdir)).unwrap() {
+                let path = entry.unwrap().path();
+                if path.extension().is_none_or(|ext| ext != "lox") {
+                    continue;
+                }
+
+                let source = fs::read_to_string(&path).unwrap();
+                for optimize in [false, true] {
+                    let Ok(script) = compile_source(&source, optimize) else {
+                        continue;
+                    };
+                    if let Err(error) = verify(&script) {
+                        panic!("rejected {}: {}", path.display(), error);
+                    }
+                }
+            }
+        }
+    }
+
+    // The phis of the loop header take each other's values, which must all
+    // be read before any of them is written.
+    #[test]
+    fn copies_phis_in_parallel() {
+        assert_eq!(
+            output(
+                "fun f() { var a = 1; var b = 2; var c = 3; \
+                 for (var i = 0; i < 4; i = i + 1) { var t = a; a = b; b = c; c = t; } \
+                 print a; print b; print c; } f();"
+            ),
+            "2\n3\n1\n"
+        );
+    }
+
+    #[test]
+    fn leaves_single_uses_on_the_stack() {
+        let script = compile_source("fun f(a, b) { return -(a * b + 1); }", true).unwrap();
+        let Some(chunk::Constant::Function(function)) = script.chunk.constants.first() else {
+            panic!("expected a function");
+        };
+
+        // No slots are reserved, and the values are never stored.
+        assert_eq!(function.chunk.code[0], OpCode::GetLocal as u8);
+        assert!(!function.chunk.code.contains(&(OpCode::SetLocal as u8)));
+        assert_eq!(
+            output("fun f(a, b) { return -(a * b + 1); } print f(2, 3);"),
+            "-7\n"
+        );
+    }
+
+    #[test]
+    fn too_many_live_values() {
+        let mut source = String::from("fun f(a) {");
+        for i in 0..300 {
+            source.push_str(&format!(" var x{i} = a + {i};"));
+        }
+        source.push_str(" return x0");
+        for i in 1..300 {
+            source.push_str(&format!(" + x{i}"));
+        }
+        source.push_str("; }");
+
+        assert_eq!(
+            compile_source(&source, true).unwrap_err().to_string(),
+            "Parse Error [ln: 1, col: 4885]: too many local variables in function"
+        );
+    }
+}
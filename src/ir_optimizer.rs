@@ -0,0 +1,493 @@
+use crate::ir::{BinaryOp, BlockId, Constant, Function, Op, Program, Terminator, ValueId};
+use crate::symbol::Symbol;
+use std::collections::HashMap;
+
+// Optimize each function of 'program' in place: copy propagation, common
+// subexpression elimination and loop-invariant code motion, followed by the
+// removal of the instructions left unused.
+//
+// The program behaves exactly as before. Instructions that may raise a
+// runtime error are never removed or moved out of a loop, since the error
+// would then be reported differently, if at all. They may be merged with an
+// identical instruction that dominates them, which has already succeeded on
+// the same operands.
+pub fn optimize(program: &mut Program) {
+    for function in &mut program.functions {
+        propagate_copies(function);
+        eliminate_common_subexpressions(function);
+        hoist_loop_invariants(function);
+        remove_dead_code(function);
+    }
+}
+
+// Replace the uses of copies, and of phis whose operands are all the same
+// value apart from the phi itself, by that value.
+fn propagate_copies(function: &mut Function) {
+    let mut replacements: Vec<ValueId> = (0..function.instructions.len()).collect();
+    let resolve = |replacements: &[ValueId], mut value: ValueId| {
+        while replacements[value] != value {
+            value = replacements[value];
+        }
+        value
+    };
+
+    // Removing a phi can make the phis using it trivial in turn.
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in &mut function.blocks {
+            block.phis.retain(|&phi| {
+                let Op::Phi(operands) = &function.instructions[phi].op else {
+                    return true;
+                };
+                let mut same = None;
+                for &operand in operands {
+                    let operand = resolve(&replacements, operand);
+                    if operand == phi || same == Some(operand) {
+                        continue;
+                    }
+                    if same.is_some() {
+                        return true;
+                    }
+                    same = Some(operand);
+                }
+
+                match same {
+                    Some(value) => {
+                        replacements[phi] = value;
+                        changed = true;
+                        false
+                    }
+                    None => true,
+                }
+            });
+
+            block
+                .instructions
+                .retain(|&value| match function.instructions[value].op {
+                    Op::Copy(source) => {
+                        replacements[value] = resolve(&replacements, source);
+                        changed = true;
+                        false
+                    }
+                    _ => true,
+                });
+        }
+    }
+
+    function.replace_uses(&replacements);
+}
+
+// The operation and operands of an instruction that gives the same value
+// whenever they are the same.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Expression {
+    // Numbers are compared by their bits, so that 0 and -0 differ.
+    Number(u64),
+    String(Symbol),
+    True,
+    False,
+    Nil,
+    Not(ValueId),
+    Negate(ValueId),
+    Binary(BinaryOp, ValueId, ValueId),
+}
+
+impl Expression {
+    fn of(op: &Op) -> Option<Expression> {
+        Some(match *op {
+            Op::Constant(Constant::Number(value)) => Expression::Number(value.to_bits()),
+            Op::Constant(Constant::String(value)) => Expression::String(value),
+            Op::Constant(Constant::True) => Expression::True,
+            Op::Constant(Constant::False) => Expression::False,
+            Op::Constant(Constant::Nil) => Expression::Nil,
+            Op::Not(value) => Expression::Not(value),
+            Op::Negate(value) => Expression::Negate(value),
+            // Operands of commutative operations are put in order, except
+            // for '+', which concatenates strings.
+            Op::Binary(
+                op @ (BinaryOp::Equal | BinaryOp::NotEqual | BinaryOp::Multiply),
+                left,
+                right,
+            ) => Expression::Binary(op, left.min(right), left.max(right)),
+            Op::Binary(op, left, right) => Expression::Binary(op, left, right),
+            _ => return None,
+        })
+    }
+}
+
+// Replace each computation by an identical one that dominates it, walking
+// the dominator tree with the computations available in each block.
+fn eliminate_common_subexpressions(function: &mut Function) {
+    let dominators = function.immediate_dominators();
+    let mut children = vec![Vec::new(); function.blocks.len()];
+    for block in 1..function.blocks.len() {
+        children[dominators[block]].push(block);
+    }
+
+    let mut replacements: Vec<ValueId> = (0..function.instructions.len()).collect();
+    let mut available: HashMap<Expression, ValueId> = HashMap::new();
+    // Each block is visited on the way down the tree, and on the way back up
+    // the computations it made available are forgotten.
+    let mut stack = vec![(0, false)];
+    let mut added: Vec<Vec<Expression>> = vec![Vec::new(); function.blocks.len()];
+
+    while let Some((block, leaving)) = stack.pop() {
+        if leaving {
+            for expression in added[block].drain(..) {
+                available.remove(&expression);
+            }
+            continue;
+        }
+
+        let mut kept = Vec::new();
+        for &value in &function.blocks[block].instructions {
+            let instruction = &mut function.instructions[value];
+            for operand in instruction.op.operands_mut() {
+                *operand = replacements[*operand];
+            }
+
+            match Expression::of(&instruction.op) {
+                Some(expression) => match available.get(&expression) {
+                    Some(&existing) => replacements[value] = existing,
+                    None => {
+                        available.insert(expression.clone(), value);
+                        added[block].push(expression);
+                        kept.push(value);
+                    }
+                },
+                None => kept.push(value),
+            }
+        }
+        function.blocks[block].instructions = kept;
+
+        stack.push((block, true));
+        for &child in children[block].iter().rev() {
+            stack.push((child, false));
+        }
+    }
+
+    function.replace_uses(&replacements);
+}
+
+// What is known about the type of a value.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Type {
+    Number,
+    String,
+    Unknown,
+}
+
+// The type of each value, which is known for constants and for the results
+// of arithmetic.
+fn types(function: &Function) -> Vec<Type> {
+    let mut types = vec![Type::Unknown; function.instructions.len()];
+    // Operands other than those of phis are defined in an earlier block in
+    // reverse postorder, or earlier in the same block.
+    for block in &function.blocks {
+        for &value in &block.instructions {
+            types[value] = match function.instructions[value].op {
+                Op::Constant(Constant::Number(_)) => Type::Number,
+                Op::Constant(Constant::String(_)) => Type::String,
+                Op::Negate(_)
+                | Op::Binary(BinaryOp::Subtract | BinaryOp::Multiply | BinaryOp::Divide, _, _) => {
+                    Type::Number
+                }
+                Op::Binary(BinaryOp::Add, left, right) if types[left] == types[right] => {
+                    types[left]
+                }
+                _ => Type::Unknown,
+            };
+        }
+    }
+    types
+}
+
+// Whether 'op' computes a value from its operands alone, without any effect
+// or the possibility of an error.
+fn is_pure(op: &Op, types: &[Type]) -> bool {
+    match *op {
+        Op::Constant(_) | Op::Not(_) | Op::Binary(BinaryOp::Equal | BinaryOp::NotEqual, _, _) => {
+            true
+        }
+        Op::Negate(value) => types[value] == Type::Number,
+        Op::Binary(BinaryOp::Add, left, right) => {
+            types[left] != Type::Unknown && types[left] == types[right]
+        }
+        Op::Binary(_, left, right) => types[left] == Type::Number && types[right] == Type::Number,
+        _ => false,
+    }
+}
+
+// Move the pure computations in a loop whose operands are defined outside it
+// to the end of the block before the loop, so that they are computed once.
+fn hoist_loop_invariants(function: &mut Function) {
+    let dominators = function.immediate_dominators();
+    let dominates = |dominator: BlockId, mut block: BlockId| loop {
+        if block == dominator {
+            return true;
+        }
+        if block == 0 {
+            return false;
+        }
+        block = dominators[block];
+    };
+
+    let types = types(function);
+    let mut definitions = vec![0; function.instructions.len()];
+    for (index, block) in function.blocks.iter().enumerate() {
+        for &value in block.phis.iter().chain(&block.instructions) {
+            definitions[value] = index;
+        }
+    }
+
+    // Loops are found by their back edges, to a header dominating the block
+    // the edge leaves. Inner loops have later headers, so they are hoisted
+    // from first and what they hoist can leave the outer loop too.
+    let mut latches: Vec<Vec<BlockId>> = vec![Vec::new(); function.blocks.len()];
+    for (block, successors) in function
+        .blocks
+        .iter()
+        .map(|block| block.terminator.successors())
+        .enumerate()
+    {
+        for successor in successors {
+            if dominates(successor, block) {
+                latches[successor].push(block);
+            }
+        }
+    }
+
+    for header in (0..function.blocks.len()).rev() {
+        if latches[header].is_empty() {
+            continue;
+        }
+
+        let mut in_loop = vec![false; function.blocks.len()];
+        in_loop[header] = true;
+        let mut worklist = latches[header].clone();
+        while let Some(block) = worklist.pop() {
+            if !in_loop[block] {
+                in_loop[block] = true;
+                worklist.extend(&function.blocks[block].predecessors);
+            }
+        }
+
+        // The loop is entered from a single block that only jumps to it.
+        let entries: Vec<BlockId> = function.blocks[header]
+            .predecessors
+            .iter()
+            .copied()
+            .filter(|&block| !in_loop[block])
+            .collect();
+        let [preheader] = entries[..] else {
+            continue;
+        };
+        if function.blocks[preheader].terminator != Terminator::Jump(header) {
+            continue;
+        }
+
+        let mut hoisted = Vec::new();
+        for block in (0..function.blocks.len()).filter(|&block| in_loop[block]) {
+            let instructions = std::mem::take(&mut function.blocks[block].instructions);
+            for value in instructions {
+                let op = &function.instructions[value].op;
+                let invariant = op
+                    .operands()
+                    .iter()
+                    .all(|&operand| !in_loop[definitions[operand]]);
+
+                if invariant && is_pure(op, &types) {
+                    definitions[value] = preheader;
+                    hoisted.push(value);
+                } else {
+                    function.blocks[block].instructions.push(value);
+                }
+            }
+        }
+        function.blocks[preheader].instructions.extend(hoisted);
+    }
+}
+
+// Remove the instructions whose values are unused and which have no effect.
+fn remove_dead_code(function: &mut Function) {
+    let types = types(function);
+
+    // Removing an instruction can leave its operands unused in turn.
+    let mut changed = true;
+    while changed {
+        changed = false;
+        let uses = function.use_counts();
+        let instructions = &function.instructions;
+        let is_dead = |value: &ValueId| {
+            let op = &instructions[*value].op;
+            uses[*value] == 0
+                && (is_pure(op, &types)
+                    || matches!(
+                        op,
+                        Op::Phi(_)
+                            | Op::Parameter(_)
+                            | Op::Copy(_)
+                            | Op::GetUpvalue(_)
+                            | Op::Load(_)
+                            | Op::Closure(_)
+                    ))
+        };
+
+        for block in &mut function.blocks {
+            let before = block.phis.len() + block.instructions.len();
+            block.phis.retain(|value| !is_dead(value));
+            block.instructions.retain(|value| !is_dead(value));
+            changed |= block.phis.len() + block.instructions.len() != before;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::lower;
+    use crate::lexer::lex;
+    use crate::parser::parse;
+    use crate::resolver::resolve;
+
+    // Lower 'source', which declares a single function, and apply 'pass' to
+    // it, returning the printed result without its header.
+    fn apply(source: &str, pass: fn(&mut Function)) -> String {
+        let tokens = lex(source).unwrap();
+        let statements = parse(&tokens).unwrap();
+        resolve(&statements).unwrap();
+        let mut program = lower(&statements).unwrap();
+        program.functions.truncate(2);
+        pass(&mut program.functions[1]);
+
+        let text = program.to_string();
+        let start = text.find(" ==\n").unwrap() + 4;
+        let start = start + text[start..].find(" ==\n").unwrap() + 4;
+        text[start..].trim_end().to_string()
+    }
+
+    #[test]
+    fn propagates_copies() {
+        let optimized = apply(
+            "fun f(n) { var i = 0; while (i < n) i = i + 1; return i; }",
+            propagate_copies,
+        );
+
+        assert_eq!(
+            optimized,
+            "\
+b0:
+    v0 = param 1
+    v1 = const 0
+    jump b1
+b1: <- b0, b2
+    v2 = phi v1, v5
+    v3 = less v2, v0
+    branch v3, b2, b3
+b2: <- b1
+    v4 = const 1
+    v5 = add v2, v4
+    jump b1
+b3: <- b1
+    return v2"
+        );
+    }
+
+    #[test]
+    fn eliminates_common_subexpressions() {
+        let optimized = apply(
+            "fun f(a, b) { var x = a + b; if (a) return b * x; return (a + b) * b; }",
+            |function| {
+                propagate_copies(function);
+                eliminate_common_subexpressions(function);
+            },
+        );
+
+        assert_eq!(
+            optimized,
+            "\
+b0:
+    v0 = param 1
+    v1 = param 2
+    v2 = add v0, v1
+    branch v0, b1, b2
+b1: <- b0
+    v3 = multiply v1, v2
+    return v3
+b2: <- b0
+    v4 = multiply v2, v1
+    return v4"
+        );
+    }
+
+    #[test]
+    fn hoists_loop_invariants() {
+        let optimized = apply(
+            "fun f(t) { var n = 4; while (t < 10) t = t + n * n; return t; }",
+            |function| {
+                propagate_copies(function);
+                hoist_loop_invariants(function);
+            },
+        );
+
+        assert_eq!(
+            optimized,
+            "\
+b0:
+    v0 = param 1
+    v1 = const 4
+    v2 = const 10
+    v3 = multiply v1, v1
+    jump b1
+b1: <- b0, b2
+    v4 = phi v0, v6
+    v5 = less v4, v2
+    branch v5, b2, b3
+b2: <- b1
+    v6 = add v4, v3
+    jump b1
+b3: <- b1
+    return v4"
+        );
+    }
+
+    // Negating a parameter fails unless it is a number, which must only
+    // happen once the loop runs.
+    #[test]
+    fn keeps_failing_computations_in_loops() {
+        let optimized = apply(
+            "fun f(o) { for (var i = 0; i < 3; i = i + 1) -o; }",
+            |function| {
+                propagate_copies(function);
+                hoist_loop_invariants(function);
+                remove_dead_code(function);
+            },
+        );
+
+        assert!(
+            optimized.contains("b2: <- b1\n    v6 = negate v0\n"),
+            "{optimized}"
+        );
+    }
+
+    #[test]
+    fn removes_dead_code() {
+        let optimized = apply(
+            "fun f(a) { var x = a; var y = 1 + 2; var z = -a; return a; }",
+            |function| {
+                propagate_copies(function);
+                remove_dead_code(function);
+            },
+        );
+
+        assert_eq!(
+            optimized,
+            "\
+b0:
+    v0 = param 1
+    v1 = negate v0
+    return v0"
+        );
+    }
+}
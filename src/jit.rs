@@ -0,0 +1,1207 @@
+use crate::chunk::{Constant, FunctionProto, OpCode};
+use crate::heap::{Heap, Object, Value};
+use crate::symbol::Symbol;
+use cranelift_codegen::ir::condcodes::{FloatCC, IntCC};
+use cranelift_codegen::ir::{
+    types, AbiParam, Block, FuncRef, InstBuilder, MemFlags, Signature, StackSlot, StackSlotData,
+    StackSlotKind, Type,
+};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, FuncId, Linkage, Module};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::mem::ManuallyDrop;
+use std::rc::Rc;
+
+// Compiled functions that deoptimize this many times are left to the
+// interpreter from then on.
+const MAX_DEOPTS: u32 = 16;
+
+// Compiled code deoptimizes rather than recursing deeper than this, which
+// bounds the native stack it uses.
+pub const MAX_NATIVE_DEPTH: usize = 1024;
+
+// A value as compiled code writes it to memory: one of the tags below and the
+// bits of the number or boolean.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    tag: u64,
+    bits: u64,
+}
+
+const NIL_TAG: u64 = 0;
+const BOOL_TAG: u64 = 1;
+const NUMBER_TAG: u64 = 2;
+// The closure being run, which is only ever in the callee slot of a frame.
+const FUNCTION_TAG: u64 = 3;
+
+const SLOT_SIZE: i32 = std::mem::size_of::<Slot>() as i32;
+
+// What 'loxi_jit_global' found in a global variable.
+const GLOBAL_OTHER: u32 = 0;
+const GLOBAL_NUMBER: u32 = 1;
+const GLOBAL_FUNCTION: u32 = 2;
+
+// Compiled functions are called with the context, a pointer to the
+// arguments, the number of frames they may still use and where to write the
+// result. They return zero once the result is written or one if they
+// deoptimized, in which case their frames have been added to the context.
+type NativeFunction = unsafe extern "C" fn(*mut Context, *const f64, i64, *mut Slot) -> u32;
+
+// The state of the VM that compiled code reads, and the frames it leaves for
+// the interpreter when it deoptimizes, innermost first.
+struct Context<'a> {
+    heap: &'a Heap,
+    globals: &'a HashMap<Symbol, Value>,
+    proto: &'a Rc<FunctionProto>,
+    frames: Vec<(usize, Vec<Slot>, usize)>,
+}
+
+// An interpreter frame rebuilt after deoptimizing: the instruction to resume
+// at, the contents of its stack slots, starting with the callee, and the
+// number of calls in tail position it has made in place of returning.
+pub struct Frame {
+    pub ip: usize,
+    pub values: Vec<Value>,
+    pub tail_calls: usize,
+}
+
+pub enum Outcome {
+    Returned(Value),
+    // The frames to resume in the interpreter, outermost first.
+    Deoptimized(Vec<Frame>),
+}
+
+// Compiles functions to native code once they have been called often enough.
+//
+// Only functions using numbers, booleans and nil in local variables, calling
+// nothing but themselves and reading no globals except numbers and their own
+// name are compiled; anything else stays in the interpreter. Compiled code
+// assumes its arguments are numbers and guards the globals it reads. When a
+// guard fails it deoptimizes, handing its frames back to the interpreter,
+// which carries on from the instruction that failed.
+pub struct Jit {
+    module: ManuallyDrop<JITModule>,
+    functions: HashMap<*const FunctionProto, Entry>,
+    threshold: u32,
+    // Compiled functions, which also numbers their symbols.
+    compiled: usize,
+}
+
+// What the JIT knows about one function. Holding on to 'proto' keeps the
+// address the entry is keyed by from being reused.
+struct Entry {
+    proto: Rc<FunctionProto>,
+    calls: u32,
+    deopts: u32,
+    code: Code,
+}
+
+#[derive(Clone, Copy)]
+enum Code {
+    Interpreted,
+    Native(NativeFunction),
+    Unsupported,
+}
+
+impl Jit {
+    // Create a JIT compiling functions once they have been called 'threshold'
+    // times. Returns 'None' if Cranelift doesn't support the host.
+    pub fn new(threshold: u32) -> Option<Jit> {
+        let mut flags = settings::builder();
+        flags.set("use_colocated_libcalls", "false").ok()?;
+        flags.set("is_pic", "false").ok()?;
+        flags.set("opt_level", "speed").ok()?;
+        // Only the features the host reports are used, so the code runs on
+        // any CPU of the host's architecture.
+        let isa = cranelift_native::builder()
+            .ok()?
+            .finish(settings::Flags::new(flags))
+            .ok()?;
+
+        let mut builder = JITBuilder::with_isa(isa, default_libcall_names());
+        builder.symbol("loxi_jit_global", loxi_jit_global as *const u8);
+        builder.symbol("loxi_jit_deopt", loxi_jit_deopt as *const u8);
+
+        Some(Jit {
+            module: ManuallyDrop::new(JITModule::new(builder)),
+            functions: HashMap::new(),
+            threshold,
+            compiled: 0,
+        })
+    }
+
+    pub fn compiled_functions(&self) -> usize {
+        self.compiled
+    }
+
+    // Count a call of a closure over 'proto', returning its native code once
+    // the function is hot and could be compiled.
+    fn hot_code(&mut self, proto: &Rc<FunctionProto>) -> Option<NativeFunction> {
+        let entry = self
+            .functions
+            .entry(Rc::as_ptr(proto))
+            .or_insert_with(|| Entry {
+                proto: Rc::clone(proto),
+                calls: 0,
+                deopts: 0,
+                code: Code::Interpreted,
+            });
+
+        match entry.code {
+            Code::Native(code) => return Some(code),
+            Code::Unsupported => return None,
+            Code::Interpreted => {}
+        }
+
+        entry.calls += 1;
+        if entry.calls < self.threshold {
+            return None;
+        }
+
+        let proto = Rc::clone(&entry.proto);
+        let code = match self.compile(&proto) {
+            Some(code) => Code::Native(code),
+            None => Code::Unsupported,
+        };
+        self.functions.get_mut(&Rc::as_ptr(&proto)).unwrap().code = code;
+        match code {
+            Code::Native(code) => Some(code),
+            _ => None,
+        }
+    }
+
+    fn deoptimized(&mut self, proto: &Rc<FunctionProto>) {
+        if let Some(entry) = self.functions.get_mut(&Rc::as_ptr(proto)) {
+            entry.deopts += 1;
+            if entry.deopts >= MAX_DEOPTS {
+                entry.code = Code::Unsupported;
+            }
+        }
+    }
+
+    // Run a call of 'closure', a closure over 'proto', in native code if it is
+    // hot. 'stack' holds the callee followed by the arguments, and 'frames'
+    // is the number of frames the call may use.
+    pub fn call(
+        &mut self,
+        heap: &Heap,
+        globals: &HashMap<Symbol, Value>,
+        proto: &Rc<FunctionProto>,
+        closure: Value,
+        stack: &[Value],
+        frames: usize,
+    ) -> Option<Outcome> {
+        if proto.name.is_empty() || stack.len() != proto.arity + 1 || frames == 0 {
+            return None;
+        }
+        // Compiled code expects numbers, so calls with anything else are
+        // interpreted without counting towards the threshold.
+        let arguments = stack[1..]
+            .iter()
+            .map(|argument| argument.as_number())
+            .collect::<Option<Vec<f64>>>()?;
+        let code = self.hot_code(proto)?;
+
+        let mut context = Context {
+            heap,
+            globals,
+            proto,
+            frames: Vec::new(),
+        };
+        let mut result = Slot { tag: 0, bits: 0 };
+        let depth = frames.min(MAX_NATIVE_DEPTH) as i64;
+        let status = unsafe { code(&mut context, arguments.as_ptr(), depth, &mut result) };
+
+        if status == 0 {
+            return Some(Outcome::Returned(from_slot(result, closure)));
+        }
+
+        self.deoptimized(proto);
+        let frames = context
+            .frames
+            .into_iter()
+            .rev()
+            .map(|(ip, values, tail_calls)| Frame {
+                ip,
+                values: values
+                    .into_iter()
+                    .map(|slot| from_slot(slot, closure))
+                    .collect(),
+                tail_calls,
+            })
+            .collect();
+        Some(Outcome::Deoptimized(frames))
+    }
+
+    fn compile(&mut self, proto: &FunctionProto) -> Option<NativeFunction> {
+        let module = &mut *self.module;
+        let name = format!("loxi_jit_{}", self.compiled);
+        let id = define(module, &name, Linkage::Local, proto)?;
+        module.finalize_definitions().ok()?;
+        self.compiled += 1;
+
+        let code = module.get_finalized_function(id);
+        Some(unsafe { std::mem::transmute::<*const u8, NativeFunction>(code) })
+    }
+}
+
+// Compile 'proto' into 'module' as the function 'name', returning 'None' if
+// it uses anything compiled code doesn't support.
+fn define(
+    module: &mut JITModule,
+    name: &str,
+    linkage: Linkage,
+    proto: &FunctionProto,
+) -> Option<FuncId> {
+    let analysis = Analysis::of(proto)?;
+
+    let pointer = module.target_config().pointer_type();
+    let mut signature = module.make_signature();
+    signature.params.extend([
+        AbiParam::new(pointer),
+        AbiParam::new(pointer),
+        AbiParam::new(types::I64),
+        AbiParam::new(pointer),
+    ]);
+    signature.returns.push(AbiParam::new(types::I32));
+
+    let mut global_signature = module.make_signature();
+    global_signature.params.extend([
+        AbiParam::new(pointer),
+        AbiParam::new(types::I64),
+        AbiParam::new(pointer),
+    ]);
+    global_signature.returns.push(AbiParam::new(types::I32));
+
+    let mut deopt_signature = module.make_signature();
+    deopt_signature.params.extend([
+        AbiParam::new(pointer),
+        AbiParam::new(types::I64),
+        AbiParam::new(pointer),
+        AbiParam::new(types::I64),
+        AbiParam::new(types::I64),
+    ]);
+
+    let mut declare = |name: &str, linkage: Linkage, signature: &Signature| {
+        module.declare_function(name, linkage, signature).ok()
+    };
+    let id = declare(name, linkage, &signature)?;
+    let global = declare("loxi_jit_global", Linkage::Import, &global_signature)?;
+    let deopt = declare("loxi_jit_deopt", Linkage::Import, &deopt_signature)?;
+
+    let mut context = module.make_context();
+    context.func.signature = signature;
+    let mut builder_context = FunctionBuilderContext::new();
+    let mut builder = FunctionBuilder::new(&mut context.func, &mut builder_context);
+
+    let functions = [id, global, deopt].map(|id| module.declare_func_in_func(id, builder.func));
+    let translator = Translator::new(&mut builder, proto, &analysis, pointer, functions);
+    translator.translate();
+    builder.finalize();
+
+    let defined = module.define_function(id, &mut context);
+    module.clear_context(&mut context);
+    defined.ok()?;
+    Some(id)
+}
+
+impl Drop for Jit {
+    fn drop(&mut self) {
+        // The code is only called through 'self', so nothing refers to it
+        // any more.
+        unsafe { ManuallyDrop::take(&mut self.module).free_memory() };
+    }
+}
+
+fn from_slot(slot: Slot, closure: Value) -> Value {
+    match slot.tag {
+        BOOL_TAG => Value::from(slot.bits != 0),
+        NUMBER_TAG => Value::from(f64::from_bits(slot.bits)),
+        FUNCTION_TAG => closure,
+        _ => Value::NIL,
+    }
+}
+
+// Look up the global named by the string constant 'constant' of the running
+// function, writing its value to 'number' if it is a number.
+unsafe extern "C" fn loxi_jit_global(
+    context: *mut Context,
+    constant: u64,
+    number: *mut f64,
+) -> u32 {
+    let context = &*context;
+    let Constant::String(name) = &context.proto.chunk.constants[constant as usize] else {
+        return GLOBAL_OTHER;
+    };
+    let Some(&value) = context.globals.get(name) else {
+        return GLOBAL_OTHER;
+    };
+
+    if let Some(value) = value.as_number() {
+        *number = value;
+        return GLOBAL_NUMBER;
+    }
+    match value.as_object().map(|object| context.heap.get(object)) {
+        Some(Object::Closure(closure))
+            if Rc::ptr_eq(
+                &context.heap.function(closure.function).proto,
+                context.proto,
+            ) =>
+        {
+            GLOBAL_FUNCTION
+        }
+        _ => GLOBAL_OTHER,
+    }
+}
+
+// Record the frame of compiled code that is deoptimizing.
+unsafe extern "C" fn loxi_jit_deopt(
+    context: *mut Context,
+    ip: u64,
+    values: *const Slot,
+    count: u64,
+    tail_calls: u64,
+) {
+    let values = std::slice::from_raw_parts(values, count as usize).to_vec();
+    (*context)
+        .frames
+        .push((ip as usize, values, tail_calls as usize));
+}
+
+// The static type of a stack slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Ty {
+    Nil,
+    Bool,
+    Number,
+    // The closure being run.
+    Function,
+    // The value of the global read at this offset, which is guarded to be the
+    // function itself since it is only called.
+    Global(usize),
+}
+
+impl Ty {
+    fn tag(self) -> u64 {
+        match self {
+            Ty::Nil => NIL_TAG,
+            Ty::Bool => BOOL_TAG,
+            Ty::Number => NUMBER_TAG,
+            Ty::Function | Ty::Global(_) => FUNCTION_TAG,
+        }
+    }
+}
+
+// Why an analysis had to be redone with different assumptions.
+enum Retry {
+    // The global read at this offset is used as a value, so must be guarded
+    // to be a number.
+    Global(usize),
+    // A function returns a value of this type.
+    Return(Ty),
+    Unsupported,
+}
+
+// The types of the stack slots before each reachable instruction of a
+// function the JIT can compile.
+struct Analysis {
+    types: Vec<Option<Vec<Ty>>>,
+    // Offsets of the instructions starting a basic block.
+    blocks: BTreeSet<usize>,
+    // Offsets of the global reads guarded to be numbers. The others are
+    // guarded to be the function itself.
+    numbers: HashSet<usize>,
+    return_type: Ty,
+    max_depth: usize,
+    max_arguments: usize,
+}
+
+impl Analysis {
+    fn of(proto: &FunctionProto) -> Option<Analysis> {
+        if proto.upvalue_count != 0 {
+            return None;
+        }
+
+        let mut numbers = HashSet::new();
+        let mut return_type = Ty::Number;
+        let mut retried_return = false;
+        loop {
+            match Analysis::with(proto, &numbers, return_type) {
+                Ok(analysis) => return Some(analysis),
+                Err(Retry::Global(offset)) if numbers.insert(offset) => {}
+                Err(Retry::Return(ty)) if !retried_return => {
+                    retried_return = true;
+                    return_type = ty;
+                }
+                Err(_) => return None,
+            }
+        }
+    }
+
+    // Analyze 'proto' assuming the globals read at 'numbers' are numbers and
+    // that it returns values of 'return_type'.
+    fn with(
+        proto: &FunctionProto,
+        numbers: &HashSet<usize>,
+        return_type: Ty,
+    ) -> Result<Analysis, Retry> {
+        let chunk = &proto.chunk;
+        let code = &chunk.code;
+        let mut types: Vec<Option<Vec<Ty>>> = vec![None; code.len()];
+        let mut blocks = BTreeSet::from([0]);
+        let mut max_depth = 0;
+        let mut max_arguments = 0;
+
+        let mut entry = vec![Ty::Function];
+        entry.extend(std::iter::repeat_n(Ty::Number, proto.arity));
+        let mut worklist = vec![(0, entry)];
+
+        while let Some((offset, mut stack)) = worklist.pop() {
+            match &types[offset] {
+                Some(seen) if *seen == stack => continue,
+                Some(_) => return Err(Retry::Unsupported),
+                None => {}
+            }
+            types[offset] = Some(stack.clone());
+            max_depth = max_depth.max(stack.len() + 1);
+
+            let op = OpCode::from_byte(code[offset]).ok_or(Retry::Unsupported)?;
+            let mut next = offset + 1;
+            match op {
+                OpCode::Constant => {
+                    match chunk.constants[chunk.read_u16(offset + 1) as usize] {
+                        Constant::Number(_) => stack.push(Ty::Number),
+                        _ => return Err(Retry::Unsupported),
+                    }
+                    next += 2;
+                }
+                OpCode::Nil => stack.push(Ty::Nil),
+                OpCode::True | OpCode::False => stack.push(Ty::Bool),
+                OpCode::Pop => {
+                    pop(&mut stack)?;
+                }
+                OpCode::GetLocal => {
+                    let slot = local(code[offset + 1])?;
+                    stack.push(stack[slot]);
+                    next += 1;
+                }
+                OpCode::SetLocal => {
+                    let slot = local(code[offset + 1])?;
+                    let value = pop(&mut stack)?;
+                    stack[slot] = value;
+                    stack.push(value);
+                    next += 1;
+                }
+                OpCode::GetGlobal => {
+                    if numbers.contains(&offset) {
+                        stack.push(Ty::Number);
+                    } else {
+                        stack.push(Ty::Global(offset));
+                    }
+                    next += 2;
+                }
+                OpCode::Equal | OpCode::NotEqual => {
+                    let (a, b) = (pop(&mut stack)?, pop(&mut stack)?);
+                    if a == Ty::Function || b == Ty::Function {
+                        return Err(Retry::Unsupported);
+                    }
+                    stack.push(Ty::Bool);
+                }
+                OpCode::Greater
+                | OpCode::GreaterEqual
+                | OpCode::Less
+                | OpCode::LessEqual
+                | OpCode::Add
+                | OpCode::Subtract
+                | OpCode::Multiply
+                | OpCode::Divide => {
+                    let (a, b) = (pop(&mut stack)?, pop(&mut stack)?);
+                    if a != Ty::Number || b != Ty::Number {
+                        return Err(Retry::Unsupported);
+                    }
+                    let comparison = matches!(
+                        op,
+                        OpCode::Greater | OpCode::GreaterEqual | OpCode::Less | OpCode::LessEqual
+                    );
+                    stack.push(if comparison { Ty::Bool } else { Ty::Number });
+                }
+                OpCode::Not => {
+                    if pop(&mut stack)? == Ty::Function {
+                        return Err(Retry::Unsupported);
+                    }
+                    stack.push(Ty::Bool);
+                }
+                OpCode::Negate => {
+                    if pop(&mut stack)? != Ty::Number {
+                        return Err(Retry::Unsupported);
+                    }
+                    stack.push(Ty::Number);
+                }
+                OpCode::Jump | OpCode::Loop => {
+                    let jump = chunk.read_u16(offset + 1) as usize;
+                    let target = if op == OpCode::Jump {
+                        offset + 3 + jump
+                    } else {
+                        offset + 3 - jump
+                    };
+                    blocks.insert(target);
+                    blocks.insert(offset + 3);
+                    worklist.push((target, stack));
+                    continue;
+                }
+                OpCode::JumpIfFalse => {
+                    let condition = pop(&mut stack)?;
+                    if condition == Ty::Function {
+                        return Err(Retry::Unsupported);
+                    }
+                    stack.push(condition);
+
+                    let target = offset + 3 + chunk.read_u16(offset + 1) as usize;
+                    blocks.insert(target);
+                    blocks.insert(offset + 3);
+                    worklist.push((target, stack.clone()));
+                    next += 2;
+                }
+                OpCode::Call => {
+                    let count = code[offset + 1] as usize;
+                    if count != proto.arity {
+                        return Err(Retry::Unsupported);
+                    }
+                    for _ in 0..count {
+                        if pop(&mut stack)? != Ty::Number {
+                            return Err(Retry::Unsupported);
+                        }
+                    }
+                    let Some(Ty::Global(_)) = stack.pop() else {
+                        return Err(Retry::Unsupported);
+                    };
+                    max_arguments = max_arguments.max(count);
+                    next += 1;
+                    // A call in tail position jumps back to the start of the
+                    // function instead of returning, see 'Translator::call'.
+                    if code.get(next) == Some(&(OpCode::Return as u8)) {
+                        continue;
+                    }
+                    stack.push(return_type);
+                }
+                OpCode::Return => {
+                    let value = pop(&mut stack)?;
+                    if value == Ty::Function {
+                        return Err(Retry::Unsupported);
+                    }
+                    if value != return_type {
+                        return Err(Retry::Return(value));
+                    }
+                    blocks.insert(offset + 1);
+                    continue;
+                }
+                _ => return Err(Retry::Unsupported),
+            }
+            worklist.push((next, stack));
+        }
+
+        blocks.retain(|&offset| offset < code.len() && types[offset].is_some());
+        Ok(Analysis {
+            types,
+            blocks,
+            numbers: numbers.clone(),
+            return_type,
+            max_depth,
+            max_arguments,
+        })
+    }
+}
+
+// Pop a value that an instruction uses. A global used this way is read as a
+// number, which needs the analysis to be redone.
+fn pop(stack: &mut Vec<Ty>) -> Result<Ty, Retry> {
+    match stack.pop() {
+        Some(Ty::Global(offset)) => Err(Retry::Global(offset)),
+        Some(ty) => Ok(ty),
+        None => Err(Retry::Unsupported),
+    }
+}
+
+// Slot zero holds the callee, or the receiver in methods, neither of which
+// compiled code can use.
+fn local(slot: u8) -> Result<usize, Retry> {
+    match slot {
+        0 => Err(Retry::Unsupported),
+        slot => Ok(slot as usize),
+    }
+}
+
+// Translates the bytecode of a function into Cranelift IR. Each stack slot is
+// a variable holding a number and another holding a boolean, of which the
+// slot's type at each instruction says which to use.
+struct Translator<'a, 'b> {
+    builder: &'a mut FunctionBuilder<'b>,
+    proto: &'a FunctionProto,
+    analysis: &'a Analysis,
+    pointer: Type,
+    this: FuncRef,
+    global: FuncRef,
+    deopt: FuncRef,
+    context: cranelift_codegen::ir::Value,
+    depth: cranelift_codegen::ir::Value,
+    result: cranelift_codegen::ir::Value,
+    blocks: HashMap<usize, Block>,
+    // Where frames are written when deoptimizing.
+    frame: StackSlot,
+    // Arguments to calls, and results of calls and global reads.
+    arguments: StackSlot,
+    returned: StackSlot,
+}
+
+impl<'a, 'b> Translator<'a, 'b> {
+    fn new(
+        builder: &'a mut FunctionBuilder<'b>,
+        proto: &'a FunctionProto,
+        analysis: &'a Analysis,
+        pointer: Type,
+        [this, global, deopt]: [FuncRef; 3],
+    ) -> Translator<'a, 'b> {
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        let params = builder.block_params(entry).to_vec();
+
+        for slot in 0..analysis.max_depth {
+            builder.declare_var(number(slot), types::F64);
+            builder.declare_var(boolean(slot, analysis), types::I8);
+        }
+        builder.declare_var(tail_calls(analysis), types::I64);
+        let zero = builder.ins().iconst(types::I64, 0);
+        builder.def_var(tail_calls(analysis), zero);
+        for parameter in 0..proto.arity {
+            let value = builder.ins().load(
+                types::F64,
+                MemFlags::trusted(),
+                params[1],
+                (parameter * 8) as i32,
+            );
+            builder.def_var(number(parameter + 1), value);
+        }
+
+        let mut slot = |size: usize| {
+            builder.create_sized_stack_slot(StackSlotData::new(
+                StackSlotKind::ExplicitSlot,
+                size.max(1) as u32 * SLOT_SIZE as u32,
+                3,
+            ))
+        };
+        let frame = slot(analysis.max_depth);
+        let arguments = slot(analysis.max_arguments);
+        let returned = slot(1);
+
+        let blocks: HashMap<usize, Block> = analysis
+            .blocks
+            .iter()
+            .map(|&offset| (offset, builder.create_block()))
+            .collect();
+        builder.ins().jump(blocks[&0], &[]);
+
+        Translator {
+            builder,
+            proto,
+            analysis,
+            pointer,
+            this,
+            global,
+            deopt,
+            context: params[0],
+            depth: params[2],
+            result: params[3],
+            blocks,
+            frame,
+            arguments,
+            returned,
+        }
+    }
+
+    fn translate(mut self) {
+        let code = &self.proto.chunk.code;
+        let mut terminated = true;
+
+        for offset in 0..code.len() {
+            let Some(stack) = &self.analysis.types[offset] else {
+                continue;
+            };
+            if let Some(&block) = self.blocks.get(&offset) {
+                if !terminated {
+                    self.builder.ins().jump(block, &[]);
+                }
+                self.builder.switch_to_block(block);
+            }
+            terminated = self.instruction(offset, stack);
+        }
+
+        self.builder.seal_all_blocks();
+    }
+
+    // Translate the instruction at 'offset', returning whether it ends its
+    // block.
+    fn instruction(&mut self, offset: usize, stack: &[Ty]) -> bool {
+        let chunk = &self.proto.chunk;
+        let op = OpCode::from_byte(chunk.code[offset]).expect("analyzed opcode");
+        let top = stack.len();
+
+        match op {
+            OpCode::Constant => {
+                let Constant::Number(value) = chunk.constants[chunk.read_u16(offset + 1) as usize]
+                else {
+                    unreachable!("analyzed constant");
+                };
+                let value = self.builder.ins().f64const(value);
+                self.builder.def_var(number(top), value);
+            }
+            OpCode::Nil | OpCode::Pop => {}
+            OpCode::True | OpCode::False => {
+                let value = self
+                    .builder
+                    .ins()
+                    .iconst(types::I8, (op == OpCode::True) as i64);
+                self.builder.def_var(self.boolean(top), value);
+            }
+            OpCode::GetLocal => self.copy(chunk.code[offset + 1] as usize, top, stack),
+            OpCode::SetLocal => self.copy(top - 1, chunk.code[offset + 1] as usize, stack),
+            OpCode::GetGlobal => self.get_global(offset, stack),
+            OpCode::Equal | OpCode::NotEqual => {
+                let equal = op == OpCode::Equal;
+                let (a, b) = (top - 2, top - 1);
+                let value = match (stack[a], stack[b]) {
+                    (Ty::Number, Ty::Number) => {
+                        let cc = if equal {
+                            FloatCC::Equal
+                        } else {
+                            FloatCC::NotEqual
+                        };
+                        let (x, y) = (self.number(a), self.number(b));
+                        self.builder.ins().fcmp(cc, x, y)
+                    }
+                    (Ty::Bool, Ty::Bool) => {
+                        let cc = if equal { IntCC::Equal } else { IntCC::NotEqual };
+                        let (x, y) = (self.bool(a), self.bool(b));
+                        self.builder.ins().icmp(cc, x, y)
+                    }
+                    (Ty::Nil, Ty::Nil) => self.builder.ins().iconst(types::I8, equal as i64),
+                    _ => self.builder.ins().iconst(types::I8, !equal as i64),
+                };
+                self.builder.def_var(self.boolean(a), value);
+            }
+            OpCode::Greater | OpCode::GreaterEqual | OpCode::Less | OpCode::LessEqual => {
+                let cc = match op {
+                    OpCode::Greater => FloatCC::GreaterThan,
+                    OpCode::GreaterEqual => FloatCC::GreaterThanOrEqual,
+                    OpCode::Less => FloatCC::LessThan,
+                    _ => FloatCC::LessThanOrEqual,
+                };
+                let (x, y) = (self.number(top - 2), self.number(top - 1));
+                let value = self.builder.ins().fcmp(cc, x, y);
+                self.builder.def_var(self.boolean(top - 2), value);
+            }
+            OpCode::Add | OpCode::Subtract | OpCode::Multiply | OpCode::Divide => {
+                let (x, y) = (self.number(top - 2), self.number(top - 1));
+                let value = match op {
+                    OpCode::Add => self.builder.ins().fadd(x, y),
+                    OpCode::Subtract => self.builder.ins().fsub(x, y),
+                    OpCode::Multiply => self.builder.ins().fmul(x, y),
+                    _ => self.builder.ins().fdiv(x, y),
+                };
+                self.builder.def_var(number(top - 2), value);
+            }
+            OpCode::Not => {
+                let value = match stack[top - 1] {
+                    Ty::Bool => {
+                        let value = self.bool(top - 1);
+                        self.builder.ins().icmp_imm(IntCC::Equal, value, 0)
+                    }
+                    Ty::Nil => self.builder.ins().iconst(types::I8, 1),
+                    _ => self.builder.ins().iconst(types::I8, 0),
+                };
+                self.builder.def_var(self.boolean(top - 1), value);
+            }
+            OpCode::Negate => {
+                let value = self.number(top - 1);
+                let value = self.builder.ins().fneg(value);
+                self.builder.def_var(number(top - 1), value);
+            }
+            OpCode::Jump => {
+                let target = offset + 3 + chunk.read_u16(offset + 1) as usize;
+                self.builder.ins().jump(self.blocks[&target], &[]);
+                return true;
+            }
+            OpCode::Loop => {
+                let target = offset + 3 - chunk.read_u16(offset + 1) as usize;
+                self.builder.ins().jump(self.blocks[&target], &[]);
+                return true;
+            }
+            OpCode::JumpIfFalse => {
+                let target = self.blocks[&(offset + 3 + chunk.read_u16(offset + 1) as usize)];
+                let next = self.blocks[&(offset + 3)];
+                match stack[top - 1] {
+                    Ty::Bool => {
+                        let condition = self.bool(top - 1);
+                        self.builder.ins().brif(condition, next, &[], target, &[]);
+                    }
+                    Ty::Nil => {
+                        self.builder.ins().jump(target, &[]);
+                    }
+                    _ => {
+                        self.builder.ins().jump(next, &[]);
+                    }
+                }
+                return true;
+            }
+            OpCode::Call => return self.call(offset, stack),
+            OpCode::Return => {
+                let ty = stack[top - 1];
+                let bits = match ty {
+                    Ty::Number => {
+                        let value = self.number(top - 1);
+                        self.builder
+                            .ins()
+                            .bitcast(types::I64, MemFlags::new(), value)
+                    }
+                    Ty::Bool => {
+                        let value = self.bool(top - 1);
+                        self.builder.ins().uextend(types::I64, value)
+                    }
+                    _ => self.builder.ins().iconst(types::I64, 0),
+                };
+                let tag = self.builder.ins().iconst(types::I64, ty.tag() as i64);
+                let result = self.result;
+                self.builder
+                    .ins()
+                    .store(MemFlags::trusted(), tag, result, 0);
+                self.builder
+                    .ins()
+                    .store(MemFlags::trusted(), bits, result, 8);
+                let status = self.builder.ins().iconst(types::I32, 0);
+                self.builder.ins().return_(&[status]);
+                return true;
+            }
+            _ => unreachable!("analyzed opcode {op:?}"),
+        }
+        false
+    }
+
+    fn number(&mut self, slot: usize) -> cranelift_codegen::ir::Value {
+        self.builder.use_var(number(slot))
+    }
+
+    fn bool(&mut self, slot: usize) -> cranelift_codegen::ir::Value {
+        self.builder.use_var(self.boolean(slot))
+    }
+
+    fn boolean(&self, slot: usize) -> Variable {
+        boolean(slot, self.analysis)
+    }
+
+    fn copy(&mut self, from: usize, to: usize, stack: &[Ty]) {
+        match stack[from] {
+            Ty::Number => {
+                let value = self.number(from);
+                self.builder.def_var(number(to), value);
+            }
+            Ty::Bool => {
+                let value = self.bool(from);
+                self.builder.def_var(self.boolean(to), value);
+            }
+            _ => {}
+        }
+    }
+
+    // Read a global, deoptimizing unless it has the type the analysis
+    // assumed.
+    fn get_global(&mut self, offset: usize, stack: &[Ty]) {
+        let is_number = self.analysis.numbers.contains(&offset);
+        let constant = self.proto.chunk.read_u16(offset + 1) as i64;
+        let constant = self.builder.ins().iconst(types::I64, constant);
+        let returned = self
+            .builder
+            .ins()
+            .stack_addr(self.pointer, self.returned, 0);
+        let call = self
+            .builder
+            .ins()
+            .call(self.global, &[self.context, constant, returned]);
+        let found = self.builder.inst_results(call)[0];
+
+        let expected = if is_number {
+            GLOBAL_NUMBER
+        } else {
+            GLOBAL_FUNCTION
+        };
+        let guard = self
+            .builder
+            .ins()
+            .icmp_imm(IntCC::Equal, found, expected as i64);
+        self.guard(guard, offset, stack);
+
+        if is_number {
+            let value = self.builder.ins().stack_load(types::F64, self.returned, 0);
+            self.builder.def_var(number(stack.len()), value);
+        }
+    }
+
+    // Call the function itself, deoptimizing if it would use more frames than
+    // it is allowed or if the call deoptimizes. A call whose result is
+    // returned straight away reuses the frame instead, jumping back to the
+    // start of the function with the arguments as its parameters. Returns
+    // whether the call ends its block.
+    fn call(&mut self, offset: usize, stack: &[Ty]) -> bool {
+        let count = self.proto.chunk.code[offset + 1] as usize;
+        let callee = stack.len() - count - 1;
+
+        if self.proto.chunk.code.get(offset + 2) == Some(&(OpCode::Return as u8)) {
+            let arguments: Vec<_> = (0..count)
+                .map(|argument| self.number(callee + 1 + argument))
+                .collect();
+            for (parameter, value) in arguments.into_iter().enumerate() {
+                self.builder.def_var(number(parameter + 1), value);
+            }
+            let made = self.builder.use_var(tail_calls(self.analysis));
+            let made = self.builder.ins().iadd_imm(made, 1);
+            self.builder.def_var(tail_calls(self.analysis), made);
+            self.builder.ins().jump(self.blocks[&0], &[]);
+            return true;
+        }
+
+        let deeper = self
+            .builder
+            .ins()
+            .icmp_imm(IntCC::SignedGreaterThan, self.depth, 1);
+        self.guard(deeper, offset, stack);
+
+        for argument in 0..count {
+            let value = self.number(callee + 1 + argument);
+            self.builder
+                .ins()
+                .stack_store(value, self.arguments, (argument * 8) as i32);
+        }
+        let arguments = self
+            .builder
+            .ins()
+            .stack_addr(self.pointer, self.arguments, 0);
+        let returned = self
+            .builder
+            .ins()
+            .stack_addr(self.pointer, self.returned, 0);
+        let depth = self.builder.ins().iadd_imm(self.depth, -1);
+        let call = self
+            .builder
+            .ins()
+            .call(self.this, &[self.context, arguments, depth, returned]);
+        let status = self.builder.inst_results(call)[0];
+
+        // If the callee deoptimized, this frame resumes once it returns.
+        let returned_normally = self.builder.ins().icmp_imm(IntCC::Equal, status, 0);
+        self.guard(returned_normally, offset + 2, &stack[..callee]);
+
+        match self.analysis.return_type {
+            Ty::Number => {
+                let value = self.builder.ins().stack_load(types::F64, self.returned, 8);
+                self.builder.def_var(number(callee), value);
+            }
+            Ty::Bool => {
+                let value = self.builder.ins().stack_load(types::I8, self.returned, 8);
+                self.builder.def_var(self.boolean(callee), value);
+            }
+            _ => {}
+        }
+        false
+    }
+
+    // Continue if 'condition' holds, and otherwise deoptimize to the
+    // instruction at 'ip' with 'stack' as the contents of the frame.
+    fn guard(&mut self, condition: cranelift_codegen::ir::Value, ip: usize, stack: &[Ty]) {
+        let pass = self.builder.create_block();
+        let fail = self.builder.create_block();
+        self.builder.ins().brif(condition, pass, &[], fail, &[]);
+
+        self.builder.switch_to_block(fail);
+        self.builder.set_cold_block(fail);
+        for (slot, &ty) in stack.iter().enumerate() {
+            let bits = match ty {
+                Ty::Number => {
+                    let value = self.number(slot);
+                    self.builder
+                        .ins()
+                        .bitcast(types::I64, MemFlags::new(), value)
+                }
+                Ty::Bool => {
+                    let value = self.bool(slot);
+                    self.builder.ins().uextend(types::I64, value)
+                }
+                _ => self.builder.ins().iconst(types::I64, 0),
+            };
+            let tag = self.builder.ins().iconst(types::I64, ty.tag() as i64);
+            let offset = slot as i32 * SLOT_SIZE;
+            self.builder.ins().stack_store(tag, self.frame, offset);
+            self.builder.ins().stack_store(bits, self.frame, offset + 8);
+        }
+        let ip = self.builder.ins().iconst(types::I64, ip as i64);
+        let values = self.builder.ins().stack_addr(self.pointer, self.frame, 0);
+        let count = self.builder.ins().iconst(types::I64, stack.len() as i64);
+        let made = self.builder.use_var(tail_calls(self.analysis));
+        self.builder
+            .ins()
+            .call(self.deopt, &[self.context, ip, values, count, made]);
+        let status = self.builder.ins().iconst(types::I32, 1);
+        self.builder.ins().return_(&[status]);
+
+        self.builder.switch_to_block(pass);
+    }
+}
+
+fn number(slot: usize) -> Variable {
+    Variable::from_u32(slot as u32)
+}
+
+fn boolean(slot: usize, analysis: &Analysis) -> Variable {
+    Variable::from_u32((analysis.max_depth + slot) as u32)
+}
+
+// The number of calls in tail position the function has made by jumping back
+// to its start.
+fn tail_calls(analysis: &Analysis) -> Variable {
+    Variable::from_u32((2 * analysis.max_depth) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::lexer::lex;
+    use crate::parser::parse;
+    use crate::resolver::resolve;
+    use crate::testing::Output;
+    use crate::vm::Vm;
+
+    fn compile_source(source: &str) -> Rc<FunctionProto> {
+        let tokens = lex(source).unwrap();
+        let statements = parse(&tokens).unwrap();
+        resolve(&statements).unwrap();
+        compile(&statements).unwrap()
+    }
+
+    // The function 'name' declared in 'source'.
+    fn function(source: &str, name: &str) -> Rc<FunctionProto> {
+        let script = compile_source(source);
+        let found = script
+            .chunk
+            .constants
+            .iter()
+            .find_map(|constant| match constant {
+                Constant::Function(function) if &*function.name == name => {
+                    Some(Rc::clone(function))
+                }
+                _ => None,
+            });
+        found.unwrap()
+    }
+
+    // Run 'source', compiling every function on its first call if 'jit' is
+    // set, returning the output and error and the number of functions
+    // compiled.
+    fn run(source: &str, jit: bool) -> (String, usize) {
+        let output = Output::default();
+        let mut vm = Vm::with_output(Box::new(output.clone()));
+        // Recurse deeper than native code can, see 'MAX_NATIVE_DEPTH'.
+        vm.set_max_depth(64 * 1024);
+        if jit {
+            assert!(vm.enable_jit(1));
+        }
+
+        let result = vm.interpret(compile_source(source));
+        (output.transcript(result), vm.compiled_functions())
+    }
+
+    fn assert_same(source: &str) -> String {
+        let (expected, _) = run(source, false);
+        let (actual, compiled) = run(source, true);
+        assert_eq!(actual, expected);
+        assert_ne!(compiled, 0);
+        actual
+    }
+
+    #[test]
+    fn supported_functions() {
+        let compiles = |source: &str, name: &str| Analysis::of(&function(source, name)).is_some();
+
+        assert!(compiles(
+            "fun f(n) { if (n < 2) return n; return f(n - 1); }",
+            "f"
+        ));
+        assert!(compiles("var k = 2; fun f(x) { return x * k; }", "f"));
+        assert!(compiles("fun f(x) { return !x or x == nil; }", "f"));
+
+        assert!(!compiles("fun f(x) { print x; }", "f"));
+        assert!(!compiles("fun f(x) { return \"s\"; }", "f"));
+        assert!(!compiles("fun g() {} fun f(x) { return g(); }", "f"));
+        assert!(!compiles("fun f(x) { return x(); }", "f"));
+        assert!(!compiles("fun f(x) { if (x) return 1; return true; }", "f"));
+        assert!(!compiles("fun f(x) { return -true; }", "f"));
+        assert!(!compiles(
+            "fun f(x) { var y = 1; if (x) y = nil; return y; }",
+            "f"
+        ));
+    }
+
+    #[test]
+    fn compiles_hot_functions() {
+        let (output, compiled) = run(
+            "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+             print fib(15);
+             fun greet(name) { print \"hi \" + name; }
+             greet(\"a\");",
+            true,
+        );
+        assert_eq!(output, "610\nhi a\n");
+        assert_eq!(compiled, 1);
+    }
+
+    // A guard failing deep in a recursion hands every frame back to the
+    // interpreter, which then reports the error from the right place.
+    #[test]
+    fn deoptimizes_when_guards_fail() {
+        let output = assert_same(
+            "var k = 1;
+             fun f(n) { if (n == 0) return k; return f(n - 1) + 1; }
+             print f(10);
+             k = 0.5;
+             print f(10);
+             k = \"s\";
+             print f(10);",
+        );
+        assert!(output.starts_with("11\n10.5\nerror: "));
+    }
+
+    #[test]
+    fn deep_recursion() {
+        let output = assert_same(
+            "fun down(n) { if (n == 0) return 0; return 1 + down(n - 1); }
+             print down(3000);
+             down(100000);",
+        );
+        assert!(output.contains("stack overflow\n"));
+    }
+
+    // Calls in tail position loop in native code, and a guard failing in the
+    // loop hands a single frame back to the interpreter.
+    #[test]
+    fn tail_calls() {
+        let output = assert_same(
+            "var k = 1;
+             fun count(n, total) { if (n == 0) return total + k; return count(n - 1, total + 2); }
+             print count(1000000, 0);
+             k = \"s\";
+             print count(10, 0);",
+        );
+        assert!(output.starts_with("2000001\nerror: "));
+    }
+}
@@ -1,138 +1,169 @@
 use crate::result::{Error, Result};
+use crate::symbol::Symbol;
 use itertools::{multipeek, MultiPeek};
 use std::fmt;
 
 const RADIX: u32 = 10;
 
-pub fn lex(source: &str) -> Result<Vec<Token>> {
-    let mut tokens = Vec::new();
-    let mut in_block_comment = false;
-
-    'line_loop: for (i, line) in source.lines().enumerate() {
-        let char_indices = line.char_indices();
-        let mut iter = multipeek(char_indices);
-
-        while in_block_comment {
-            let found_end = scan_for_block_comment_end(&mut iter);
-
-            if found_end {
-                in_block_comment = false;
-            } else {
-                continue 'line_loop;
-            }
-        }
+// Reserved words, as recognized by 'map_lexeme_to_keyword'.
+pub const KEYWORDS: [&str; 16] = [
+    "and", "class", "else", "false", "for", "fun", "if", "nil", "or", "print", "return", "super",
+    "this", "true", "var", "while",
+];
 
-        'char_loop: while let Some((j, c)) = iter.next() {
-            let source_position = (i + 1, j + 1);
-            let (token_type, lexeme) = match c {
-                '(' => (TokenType::LeftParen, &line[j..j + 1]),
-                ')' => (TokenType::RightParen, &line[j..j + 1]),
-                '{' => (TokenType::LeftBrace, &line[j..j + 1]),
-                '}' => (TokenType::RightBrace, &line[j..j + 1]),
-                ',' => (TokenType::Comma, &line[j..j + 1]),
-                '.' => (TokenType::Dot, &line[j..j + 1]),
-                '-' => (TokenType::Minus, &line[j..j + 1]),
-                '+' => (TokenType::Plus, &line[j..j + 1]),
-                ';' => (TokenType::Semicolon, &line[j..j + 1]),
-                '*' => (TokenType::Asterisk, &line[j..j + 1]),
-                '?' => (TokenType::QuestionMark, &line[j..j + 1]),
-                ':' => (TokenType::Colon, &line[j..j + 1]),
-                '!' => match iter.peek() {
-                    Some(&(_, '=')) => {
-                        iter.next();
-                        (TokenType::BangEqual, &line[j..j + 2])
-                    }
-                    _ => (TokenType::Bang, &line[j..j + 1]),
-                },
-                '=' => match iter.peek() {
-                    Some(&(_, '=')) => {
-                        iter.next();
-                        (TokenType::EqualEqual, &line[j..j + 2])
-                    }
-                    _ => (TokenType::Equal, &line[j..j + 1]),
-                },
-                '<' => match iter.peek() {
-                    Some(&(_, '=')) => {
-                        iter.next();
-                        (TokenType::LessThanOrEqual, &line[j..j + 2])
+pub fn lex(source: &str) -> Result<Vec<Token<'_>>> {
+    let mut tokens = Vec::new();
+    let mut iter = multipeek(source.char_indices());
+    let mut cursor = LineCursor::default();
+
+    while let Some((j, c)) = iter.next() {
+        let source_position = cursor.position(j);
+        let (token_type, lexeme) = match c {
+            '(' => (TokenType::LeftParen, &source[j..j + 1]),
+            ')' => (TokenType::RightParen, &source[j..j + 1]),
+            '{' => (TokenType::LeftBrace, &source[j..j + 1]),
+            '}' => (TokenType::RightBrace, &source[j..j + 1]),
+            '[' => (TokenType::LeftBracket, &source[j..j + 1]),
+            ']' => (TokenType::RightBracket, &source[j..j + 1]),
+            ',' => (TokenType::Comma, &source[j..j + 1]),
+            '.' => (TokenType::Dot, &source[j..j + 1]),
+            '-' => (TokenType::Minus, &source[j..j + 1]),
+            '+' => (TokenType::Plus, &source[j..j + 1]),
+            ';' => (TokenType::Semicolon, &source[j..j + 1]),
+            '*' => (TokenType::Asterisk, &source[j..j + 1]),
+            '?' => (TokenType::QuestionMark, &source[j..j + 1]),
+            ':' => (TokenType::Colon, &source[j..j + 1]),
+            '!' => match iter.peek() {
+                Some(&(_, '=')) => {
+                    iter.next();
+                    (TokenType::BangEqual, &source[j..j + 2])
+                }
+                _ => (TokenType::Bang, &source[j..j + 1]),
+            },
+            '=' => match iter.peek() {
+                Some(&(_, '=')) => {
+                    iter.next();
+                    (TokenType::EqualEqual, &source[j..j + 2])
+                }
+                _ => (TokenType::Equal, &source[j..j + 1]),
+            },
+            '<' => match iter.peek() {
+                Some(&(_, '=')) => {
+                    iter.next();
+                    (TokenType::LessThanOrEqual, &source[j..j + 2])
+                }
+                _ => (TokenType::LessThan, &source[j..j + 1]),
+            },
+            '>' => match iter.peek() {
+                Some(&(_, '=')) => {
+                    iter.next();
+                    (TokenType::GreaterThanOrEqual, &source[j..j + 2])
+                }
+                _ => (TokenType::GreaterThan, &source[j..j + 1]),
+            },
+            '/' => {
+                match iter.peek() {
+                    Some(&(_, '/')) => {
+                        // Encountered a line comment, ignore the rest of the line.
+                        skip_line_comment(&mut iter);
+                        continue;
                     }
-                    _ => (TokenType::LessThan, &line[j..j + 1]),
-                },
-                '>' => match iter.peek() {
-                    Some(&(_, '=')) => {
+                    Some(&(_, '*')) => {
                         iter.next();
-                        (TokenType::GreaterThanOrEqual, &line[j..j + 2])
-                    }
-                    _ => (TokenType::GreaterThan, &line[j..j + 1]),
-                },
-                '/' => {
-                    match iter.peek() {
-                        Some(&(_, '/')) => {
-                            // Encountered a comment, ignore the rest and continue to next line.
-                            continue 'line_loop;
+                        if !scan_for_block_comment_end(&mut iter, &mut cursor) {
+                            return Err(Error::UnexpectedEndOfInput {
+                                message: String::from("Block comment missing closing '*/'"),
+                                source_position,
+                            });
                         }
-                        Some(&(_, '*')) => {
-                            in_block_comment = true;
-                            iter.next();
-                            if scan_for_block_comment_end(&mut iter) {
-                                in_block_comment = false;
-                            }
-                            continue 'char_loop;
-                        }
-                        _ => (TokenType::Slash, &line[j..j + 1]),
-                    }
-                }
-                '"' => match string(&mut iter, line, j) {
-                    Some((lexeme, literal)) => (TokenType::Str(literal), lexeme),
-                    None => {
-                        return Err(Error::SyntaxError {
-                            message: String::from("String literal missing closing '\"'"),
-                            source_position,
-                        });
+                        continue;
                     }
-                },
-                // Number
-                c if c.is_digit(RADIX) => {
-                    let (lexeme, literal) = number(&mut iter, line, j);
-                    (TokenType::Number(literal), lexeme)
+                    _ => (TokenType::Slash, &source[j..j + 1]),
                 }
-                // Identifier
-                c if c.is_alphabetic() || c == '_' => {
-                    let lexeme = identifier(&mut iter, line, j);
-                    (map_lexeme_to_keyword(lexeme), lexeme)
-                }
-                // Ignore whitespace
-                c if c.is_whitespace() => continue,
-
-                // Default case
-                c => {
-                    return Err(Error::SyntaxError {
-                        message: format!("Unrecognized character '{}'", c),
+            }
+            '"' => match string(&mut iter, &mut cursor, source, j) {
+                Some((lexeme, literal)) => (TokenType::Str(literal), lexeme),
+                None => {
+                    return Err(Error::UnexpectedEndOfInput {
+                        message: String::from("String literal missing closing '\"'"),
                         source_position,
                     });
                 }
-            };
+            },
+            // Number
+            c if c.is_digit(RADIX) => {
+                let (lexeme, literal) = number(&mut iter, source, j);
+                (TokenType::Number(literal), lexeme)
+            }
+            // Identifier
+            c if c.is_alphabetic() || c == '_' => {
+                let lexeme = identifier(&mut iter, source, j);
+                (map_lexeme_to_keyword(lexeme), lexeme)
+            }
+            '\n' => {
+                cursor.new_line(j);
+                continue;
+            }
+            // Ignore whitespace
+            c if c.is_whitespace() => continue,
+
+            // Default case
+            c => {
+                return Err(Error::SyntaxError {
+                    message: format!("Unrecognized character '{}'", c),
+                    source_position,
+                });
+            }
+        };
 
-            tokens.push(Token {
-                token_type,
-                lexeme,
-                source_position,
-            });
-        }
+        tokens.push(Token {
+            token_type,
+            lexeme,
+            source_position,
+        });
     }
 
     // Append EOF token once we hit the end.
     tokens.push(Token {
         token_type: TokenType::Eof,
         lexeme: "EOF",
-        source_position: (0, 0),
+        source_position: cursor.position(source.len()),
     });
 
     Ok(tokens)
 }
 
+// Tracks the current line number and the byte offset at which it starts, so
+// that byte offsets into the source can be mapped to a 'SourcePosition'.
+#[derive(Debug, Clone, Copy)]
+struct LineCursor {
+    line: usize,
+    line_start: usize,
+}
+
+impl Default for LineCursor {
+    fn default() -> Self {
+        LineCursor {
+            line: 1,
+            line_start: 0,
+        }
+    }
+}
+
+impl LineCursor {
+    // Record that the newline character at byte offset 'newline' was consumed.
+    fn new_line(&mut self, newline: usize) {
+        self.line += 1;
+        self.line_start = newline + 1;
+    }
+
+    fn position(&self, offset: usize) -> SourcePosition {
+        (self.line, offset - self.line_start + 1)
+    }
+}
+
 // Maps the given 'lexeme' to the corresponding TokenType.
-fn map_lexeme_to_keyword(lexeme: &str) -> TokenType {
+fn map_lexeme_to_keyword(lexeme: &str) -> TokenType<'_> {
     match lexeme {
         "and" => TokenType::And,
         "class" => TokenType::Class,
@@ -154,28 +185,31 @@ fn map_lexeme_to_keyword(lexeme: &str) -> TokenType {
     }
 }
 
-fn string<'a, I>(iter: &mut MultiPeek<I>, line: &'a str, start: usize) -> Option<(&'a str, &'a str)>
+// Scan the remainder of a string literal whose opening quote is at 'start'.
+// String literals may span multiple lines. Returns 'None' if the source ends
+// before the closing quote.
+fn string<'a, I>(
+    iter: &mut MultiPeek<I>,
+    cursor: &mut LineCursor,
+    source: &'a str,
+    start: usize,
+) -> Option<(&'a str, &'a str)>
 where
     I: Iterator<Item = (usize, char)>,
 {
-    while let Some(&(_, c)) = iter.peek() {
+    for (i, c) in iter.by_ref() {
         match c {
-            '"' => break,
-            _ => {
-                iter.next();
-            }
+            '"' => return Some((&source[start..i + 1], &source[start + 1..i])),
+            '\n' => cursor.new_line(i),
+            _ => (),
         }
     }
 
-    // Either we found the closing double quote, or we have an untermintated string.
-    if let Some((i, '"')) = iter.next() {
-        Some((&line[start..i + 1], &line[start + 1..i]))
-    } else {
-        None
-    }
+    // Untermintated string.
+    None
 }
 
-fn number<'a, I>(iter: &mut MultiPeek<I>, line: &'a str, start: usize) -> (&'a str, f64)
+fn number<'a, I>(iter: &mut MultiPeek<I>, source: &'a str, start: usize) -> (&'a str, f64)
 where
     I: Iterator<Item = (usize, char)>,
 {
@@ -202,7 +236,7 @@ where
         0
     };
 
-    let lexeme = &line[start..start + integer_length + fraction_length + 1];
+    let lexeme = &source[start..start + integer_length + fraction_length + 1];
     (lexeme, lexeme.parse().unwrap())
 }
 
@@ -227,36 +261,60 @@ where
     result
 }
 
-fn identifier<'a, I>(iter: &mut MultiPeek<I>, line: &'a str, start: usize) -> &'a str
+fn identifier<'a, I>(iter: &mut MultiPeek<I>, source: &'a str, start: usize) -> &'a str
 where
     I: Iterator<Item = (usize, char)>,
 {
-    let mut length = 0;
+    let mut end = start + 1;
 
-    while let Some((_, c)) = iter.next() {
-        if !c.is_alphanumeric() {
+    while let Some(&(i, c)) = iter.peek() {
+        if !(c.is_alphanumeric() || c == '_') {
+            iter.reset_peek();
             break;
         }
 
-        length += 1;
+        iter.next();
+        end = i + c.len_utf8();
     }
 
-    &line[start..start + length + 1]
+    &source[start..end]
 }
 
-fn scan_for_block_comment_end<I>(iter: &mut MultiPeek<I>) -> bool
+// Advance the iterator up to, but not including, the next newline.
+fn skip_line_comment<I>(iter: &mut MultiPeek<I>)
 where
     I: Iterator<Item = (usize, char)>,
 {
-    loop {
-        let _ = iter.by_ref().skip_while(|(_, x)| *x != '*');
+    while let Some(&(_, c)) = iter.peek() {
+        if c == '\n' {
+            iter.reset_peek();
+            break;
+        }
 
-        match iter.next() {
-            Some((_, '/')) => return true, // Found end of block comment
-            None => return false,          // End of line without end of block comment
+        iter.next();
+    }
+}
+
+// Consume characters up to and including the closing '*/' of a block comment.
+// Returns false if the source ends before the block comment is closed.
+fn scan_for_block_comment_end<I>(iter: &mut MultiPeek<I>, cursor: &mut LineCursor) -> bool
+where
+    I: Iterator<Item = (usize, char)>,
+{
+    while let Some((i, c)) = iter.next() {
+        match c {
+            '*' => {
+                if let Some(&(_, '/')) = iter.peek() {
+                    iter.next();
+                    return true;
+                }
+            }
+            '\n' => cursor.new_line(i),
             _ => (),
         }
     }
+
+    false
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -272,6 +330,8 @@ pub enum TokenType<'a> {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
     Dot,
     Minus,
@@ -311,6 +371,85 @@ pub enum TokenType<'a> {
     Eof,
 }
 
+// An owned copy of a 'Token' that does not borrow from the source it was lexed
+// from, so that syntax trees can outlive their source. The lexeme is interned,
+// so that names can be compared and looked up without comparing strings.
+#[derive(Debug, PartialEq, Clone)]
+pub struct OwnedToken {
+    pub token_type: TokenType<'static>,
+    pub lexeme: Symbol,
+    pub source_position: SourcePosition,
+}
+
+impl From<&Token<'_>> for OwnedToken {
+    fn from(token: &Token) -> Self {
+        OwnedToken {
+            token_type: token.token_type.to_static(),
+            lexeme: Symbol::intern(token.lexeme),
+            source_position: token.source_position,
+        }
+    }
+}
+
+impl fmt::Display for OwnedToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.lexeme)
+    }
+}
+
+impl TokenType<'_> {
+    // Copy this token type without borrowing from the source. The value of a
+    // string literal is dropped, since the parser stores literals as
+    // 'ast::LiteralValue' rather than as tokens.
+    pub fn to_static(self) -> TokenType<'static> {
+        match self {
+            TokenType::LeftParen => TokenType::LeftParen,
+            TokenType::RightParen => TokenType::RightParen,
+            TokenType::LeftBrace => TokenType::LeftBrace,
+            TokenType::RightBrace => TokenType::RightBrace,
+            TokenType::LeftBracket => TokenType::LeftBracket,
+            TokenType::RightBracket => TokenType::RightBracket,
+            TokenType::Comma => TokenType::Comma,
+            TokenType::Dot => TokenType::Dot,
+            TokenType::Minus => TokenType::Minus,
+            TokenType::Plus => TokenType::Plus,
+            TokenType::Semicolon => TokenType::Semicolon,
+            TokenType::Slash => TokenType::Slash,
+            TokenType::Asterisk => TokenType::Asterisk,
+            TokenType::Colon => TokenType::Colon,
+            TokenType::QuestionMark => TokenType::QuestionMark,
+            TokenType::Bang => TokenType::Bang,
+            TokenType::BangEqual => TokenType::BangEqual,
+            TokenType::Equal => TokenType::Equal,
+            TokenType::EqualEqual => TokenType::EqualEqual,
+            TokenType::GreaterThan => TokenType::GreaterThan,
+            TokenType::GreaterThanOrEqual => TokenType::GreaterThanOrEqual,
+            TokenType::LessThan => TokenType::LessThan,
+            TokenType::LessThanOrEqual => TokenType::LessThanOrEqual,
+            TokenType::Identifier => TokenType::Identifier,
+            TokenType::Str(_) => TokenType::Str(""),
+            TokenType::Number(n) => TokenType::Number(n),
+            TokenType::And => TokenType::And,
+            TokenType::Class => TokenType::Class,
+            TokenType::Else => TokenType::Else,
+            TokenType::False => TokenType::False,
+            TokenType::Fun => TokenType::Fun,
+            TokenType::For => TokenType::For,
+            TokenType::If => TokenType::If,
+            TokenType::Nil => TokenType::Nil,
+            TokenType::Or => TokenType::Or,
+            TokenType::Print => TokenType::Print,
+            TokenType::Return => TokenType::Return,
+            TokenType::Super => TokenType::Super,
+            TokenType::This => TokenType::This,
+            TokenType::True => TokenType::True,
+            TokenType::Var => TokenType::Var,
+            TokenType::While => TokenType::While,
+            TokenType::Eof => TokenType::Eof,
+        }
+    }
+}
+
 // Source position is defined by a tuple containing the line number and
 // character index.
 pub type SourcePosition = (usize, usize);
@@ -383,8 +522,61 @@ mod tests {
     fn str_with_missing_quote() {
         let source = "\"Missing closing quote";
 
-        if let Result::Ok(_) = lex(source) {
-            panic!("Expected Err");
+        match lex(source) {
+            Err(Error::UnexpectedEndOfInput { .. }) => (),
+            _ => panic!("Expected UnexpectedEndOfInput"),
+        }
+    }
+
+    #[test]
+    fn multi_line_str() {
+        let source = "\"first\nsecond\" third";
+
+        if let Result::Ok(tokens) = lex(source) {
+            assert_eq!(tokens.len(), 3);
+            assert_eq!(tokens[0].token_type, TokenType::Str("first\nsecond"));
+            assert_eq!(tokens[1].lexeme, "third");
+            assert_eq!(tokens[1].source_position, (2, 9));
+        } else {
+            panic!("Expected Ok");
+        }
+    }
+
+    #[test]
+    fn block_comment() {
+        let source = "a /* b/c *\n d */ e";
+
+        if let Result::Ok(tokens) = lex(source) {
+            assert_eq!(tokens.len(), 3);
+            assert_eq!(tokens[0].lexeme, "a");
+            assert_eq!(tokens[1].lexeme, "e");
+            assert_eq!(tokens[1].source_position, (2, 7));
+        } else {
+            panic!("Expected Ok");
+        }
+    }
+
+    #[test]
+    fn unterminated_block_comment() {
+        let source = "a /* b";
+
+        match lex(source) {
+            Err(Error::UnexpectedEndOfInput {
+                source_position, ..
+            }) => assert_eq!(source_position, (1, 3)),
+            _ => panic!("Expected UnexpectedEndOfInput"),
+        }
+    }
+
+    #[test]
+    fn identifier_followed_by_operator() {
+        let source = "snake_case+b";
+
+        if let Result::Ok(tokens) = lex(source) {
+            let lexemes: Vec<&str> = tokens.iter().map(|t| t.lexeme).collect();
+            assert_eq!(lexemes, ["snake_case", "+", "b", "EOF"]);
+        } else {
+            panic!("Expected Ok");
         }
     }
 
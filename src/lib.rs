@@ -3,11 +3,43 @@
 
 pub mod loxi;
 
+#[cfg(feature = "aot")]
+mod aot;
 mod ast;
 mod binary_tree;
+mod bytecode;
+mod chunk;
+mod compiler;
+#[cfg(feature = "repl")]
+mod config;
+mod disassembler;
+mod environment;
+mod heap;
+mod inline_cache;
+mod interpreter;
+mod ir;
+mod ir_compiler;
+mod ir_optimizer;
+#[cfg(feature = "jit")]
+mod jit;
 mod lexer;
+mod list;
+mod map;
+mod native;
+mod optimizer;
 mod parser;
+#[cfg(feature = "repl")]
+mod repl;
+mod resolver;
 mod result;
+mod sorted;
+mod symbol;
+#[cfg(test)]
+mod testing;
+mod transpiler;
+mod value;
+mod verifier;
+mod vm;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,202 @@
+// Behaviour shared by the lists of every backend: the methods a list has, how
+// indices and slice bounds count, and the order in which 'sort' compares
+// elements. The C runtime in 'c/loxi.h' follows the same rules.
+use std::ops::Range;
+
+pub const INDEX_NOT_INTEGER: &str = "list index must be an integer";
+pub const BOUND_NOT_INTEGER: &str = "slice bounds must be integers";
+pub const INDEX_OUT_OF_RANGE: &str = "list index out of range";
+pub const POP_EMPTY: &str = "can't pop from an empty list";
+pub const NOT_A_COMPARISON: &str = "comparator must return a number";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    Push,
+    Pop,
+    Insert,
+    Remove,
+    Len,
+    Map,
+    Filter,
+    Reduce,
+    Sort,
+}
+
+pub const METHODS: [Method; 9] = [
+    Method::Push,
+    Method::Pop,
+    Method::Insert,
+    Method::Remove,
+    Method::Len,
+    Method::Map,
+    Method::Filter,
+    Method::Reduce,
+    Method::Sort,
+];
+
+impl Method {
+    pub fn from_name(name: &str) -> Option<Method> {
+        METHODS.into_iter().find(|method| method.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Method::Push => "push",
+            Method::Pop => "pop",
+            Method::Insert => "insert",
+            Method::Remove => "remove",
+            Method::Len => "len",
+            Method::Map => "map",
+            Method::Filter => "filter",
+            Method::Reduce => "reduce",
+            Method::Sort => "sort",
+        }
+    }
+
+    pub fn arity(self) -> usize {
+        match self {
+            Method::Pop | Method::Len => 0,
+            Method::Push | Method::Remove | Method::Map | Method::Filter | Method::Sort => 1,
+            Method::Insert | Method::Reduce => 2,
+        }
+    }
+}
+
+// The element at 'index' of a list of 'len' elements. Negative indices count
+// back from the end, so '-1' is the last element.
+pub fn index(index: f64, len: usize) -> Result<usize, &'static str> {
+    if index.fract() != 0.0 {
+        return Err(INDEX_NOT_INTEGER);
+    }
+
+    let index = if index < 0.0 {
+        index + len as f64
+    } else {
+        index
+    };
+    if index < 0.0 || index >= len as f64 {
+        return Err(INDEX_OUT_OF_RANGE);
+    }
+    Ok(index as usize)
+}
+
+// Where 'insert' puts an element at 'index', which may also be the end of the
+// list.
+pub fn insert_index(index: f64, len: usize) -> Result<usize, &'static str> {
+    if index == len as f64 {
+        Ok(len)
+    } else {
+        self::index(index, len)
+    }
+}
+
+// The elements from 'start' up to but not including 'end'. A missing bound is
+// the start or the end of the list, and bounds outside it are clamped, so a
+// slice is never out of range.
+pub fn slice(
+    start: Option<f64>,
+    end: Option<f64>,
+    len: usize,
+) -> Result<Range<usize>, &'static str> {
+    let bound = |bound: Option<f64>, default: usize| match bound {
+        None => Ok(default),
+        Some(bound) if bound.fract() != 0.0 => Err(BOUND_NOT_INTEGER),
+        Some(bound) => {
+            let bound = if bound < 0.0 {
+                bound + len as f64
+            } else {
+                bound
+            };
+            Ok(bound.clamp(0.0, len as f64) as usize)
+        }
+    };
+
+    let start = bound(start, 0)?;
+    let end = bound(end, len)?.max(start);
+    Ok(start..end)
+}
+
+// Sort 'items' with a stable, bottom-up merge sort. 'after(a, b)' tells
+// whether 'a' belongs after 'b', and is asked about the same pairs in the
+// same order on every backend, since it may be a Lox function with effects.
+// If it fails, 'items' is left partly sorted.
+pub fn sort<T: Clone, E>(
+    items: &mut [T],
+    mut after: impl FnMut(&T, &T) -> Result<bool, E>,
+) -> Result<(), E> {
+    let len = items.len();
+    let mut merged = Vec::with_capacity(len);
+    let mut width = 1;
+
+    while width < len {
+        let mut start = 0;
+        while start + width < len {
+            let middle = start + width;
+            let end = (middle + width).min(len);
+            let (mut i, mut j) = (start, middle);
+
+            merged.clear();
+            while i < middle && j < end {
+                if after(&items[i], &items[j])? {
+                    merged.push(items[j].clone());
+                    j += 1;
+                } else {
+                    merged.push(items[i].clone());
+                    i += 1;
+                }
+            }
+            merged.extend_from_slice(&items[i..middle]);
+            merged.extend_from_slice(&items[j..end]);
+            items[start..end].clone_from_slice(&merged);
+
+            start = end;
+        }
+        width *= 2;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn indices() {
+        assert_eq!(index(0.0, 3), Ok(0));
+        assert_eq!(index(-1.0, 3), Ok(2));
+        assert_eq!(index(-3.0, 3), Ok(0));
+        assert_eq!(index(3.0, 3), Err(INDEX_OUT_OF_RANGE));
+        assert_eq!(index(-4.0, 3), Err(INDEX_OUT_OF_RANGE));
+        assert_eq!(index(0.5, 3), Err(INDEX_NOT_INTEGER));
+        assert_eq!(index(f64::NAN, 3), Err(INDEX_NOT_INTEGER));
+        assert_eq!(index(f64::INFINITY, 3), Err(INDEX_NOT_INTEGER));
+        assert_eq!(insert_index(3.0, 3), Ok(3));
+        assert_eq!(insert_index(4.0, 3), Err(INDEX_OUT_OF_RANGE));
+    }
+
+    #[test]
+    fn slices() {
+        assert_eq!(slice(Some(1.0), Some(3.0), 5), Ok(1..3));
+        assert_eq!(slice(None, Some(-1.0), 5), Ok(0..4));
+        assert_eq!(slice(Some(-2.0), None, 5), Ok(3..5));
+        assert_eq!(slice(Some(-10.0), Some(10.0), 5), Ok(0..5));
+        assert_eq!(slice(Some(4.0), Some(2.0), 5), Ok(4..4));
+        assert_eq!(slice(Some(0.5), None, 5), Err(BOUND_NOT_INTEGER));
+    }
+
+    #[test]
+    fn sort_is_stable() {
+        let mut items = [(3, 'a'), (1, 'b'), (3, 'c'), (2, 'd'), (1, 'e')];
+        let mut comparisons = 0;
+        sort(&mut items, |a, b| {
+            comparisons += 1;
+            Ok::<_, ()>(a.0 > b.0)
+        })
+        .unwrap();
+
+        assert_eq!(items, [(1, 'b'), (1, 'e'), (2, 'd'), (3, 'a'), (3, 'c')]);
+        assert_eq!(comparisons, 7);
+        assert_eq!(sort(&mut items, |_, _| Err("failed")), Err("failed"));
+    }
+}
@@ -1,64 +1,1369 @@
-use crate::lexer;
+use crate::ast::Statement;
+use crate::bytecode;
+use crate::chunk::FunctionProto;
+use crate::compiler;
+#[cfg(feature = "repl")]
+use crate::config::{Config, EditMode};
+use crate::disassembler;
+use crate::interpreter::Interpreter;
+use crate::ir;
+use crate::ir_compiler;
+use crate::ir_optimizer;
+use crate::lexer::{self, OwnedToken};
+use crate::optimizer;
 use crate::parser;
-use dirs;
-use rustyline::Editor;
+#[cfg(feature = "repl")]
+use crate::repl::{self, Command, InputState, ReplHelper};
+use crate::resolver;
+use crate::transpiler;
+use crate::vm::Vm;
+#[cfg(feature = "repl")]
 use rustyline::error::ReadlineError;
-use std::error::Error;
+#[cfg(feature = "repl")]
+use rustyline::{ColorMode, Editor};
 use std::fs;
+use std::io::{self, Write};
 use std::path::Path;
-use std::path::PathBuf;
+use std::rc::Rc;
+use std::str::FromStr;
+#[cfg(feature = "repl")]
+use std::time::Instant;
 
-pub type Result = std::result::Result<(), Box<dyn Error>>;
+pub use crate::heap::GcMode;
+pub use crate::parser::{max_nesting, DEFAULT_MAX_DEPTH};
+pub use crate::result::{Error, Severity, TraceFrame};
+pub use crate::symbol::Symbol;
 
-fn run(source: &str) -> Result {
+pub type Result = std::result::Result<(), Box<dyn std::error::Error>>;
+
+// The number of calls after which '--jit' compiles a function.
+pub const DEFAULT_JIT_THRESHOLD: u32 = 1000;
+
+// The native stack reserved for each level of nesting allowed, see
+// 'max_nesting', and for each call, measured on the tree-walking interpreter
+// with room to spare. A call takes more, as it runs through the statements and
+// expressions leading to the next call, and unoptimized builds take several
+// times as much for either.
+#[cfg(debug_assertions)]
+const STACK_PER_LEVEL: usize = 32 * 1024;
+#[cfg(debug_assertions)]
+const STACK_PER_CALL: usize = 128 * 1024;
+#[cfg(not(debug_assertions))]
+const STACK_PER_LEVEL: usize = 8 * 1024;
+#[cfg(not(debug_assertions))]
+const STACK_PER_CALL: usize = 16 * 1024;
+const MIN_STACK_SIZE: usize = 8 * 1024 * 1024;
+
+// The native stack to run a script allowed to nest calls 'max_depth' deep on.
+// Parsing, the passes over the syntax tree and the tree-walking interpreter
+// recurse natively, so this leaves room for the deepest nesting and calls
+// allowed, and for the 'else if' branches leading to a statement, which take
+// far less each.
+pub fn stack_size(max_depth: usize) -> usize {
+    max_nesting(max_depth)
+        .saturating_mul(STACK_PER_LEVEL)
+        .saturating_add(max_depth.saturating_mul(STACK_PER_CALL))
+        .max(MIN_STACK_SIZE)
+}
+
+// The warnings reported while optimizing a program, see 'optimizer::optimize'.
+// All of them are enabled by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Warnings {
+    // Warn about statements that can never run.
+    pub unreachable: bool,
+}
+
+impl Default for Warnings {
+    fn default() -> Self {
+        Warnings { unreachable: true }
+    }
+}
+
+// Lex, parse and resolve 'source', reporting any static errors, and optimize
+// the result, see 'optimizer::optimize'. Expressions and statements may be
+// nested as deeply as 'parser::max_nesting' allows for 'max_depth'.
+fn parse_program(
+    source: &str,
+    warnings: Warnings,
+    max_depth: usize,
+) -> std::result::Result<Vec<Statement<OwnedToken>>, Box<dyn std::error::Error>> {
     let tokens = lexer::lex(source)?;
-    let ast = parser::parse(&tokens)?;
-    println!("{ast}");
+    let statements = parser::parse_with_max_nesting(&tokens, parser::max_nesting(max_depth))?;
+    resolver::resolve(&statements)?;
+    Ok(optimize(statements, warnings))
+}
+
+// Optimize resolved 'statements', printing the enabled warnings to stderr.
+fn optimize(
+    statements: Vec<Statement<OwnedToken>>,
+    warnings: Warnings,
+) -> Vec<Statement<OwnedToken>> {
+    let (statements, found) = optimizer::optimize(statements);
+    if warnings.unreachable {
+        for warning in found {
+            eprintln!("{}", warning);
+        }
+    }
+    statements
+}
+
+fn run(
+    interpreter: &mut Interpreter,
+    source: &str,
+    warnings: Warnings,
+    max_depth: usize,
+) -> Result {
+    interpreter.interpret(&parse_program(source, warnings, max_depth)?)?;
     Ok(())
 }
 
+// The implementation used to execute a script.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    // Evaluate the syntax tree directly.
+    TreeWalker,
+    // Compile to bytecode and run it on a stack-based virtual machine.
+    Vm,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(name: &str) -> std::result::Result<Backend, String> {
+        match name {
+            "tree" => Ok(Backend::TreeWalker),
+            "vm" => Ok(Backend::Vm),
+            _ => Err(format!(
+                "unknown backend '{}', expected 'tree' or 'vm'",
+                name
+            )),
+        }
+    }
+}
+
+// Options for running a script.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Options {
+    pub backend: Backend,
+    // Print the VM's stack and each instruction to stderr as it executes.
+    pub trace: bool,
+    // How the VM's heap is collected.
+    pub gc: GcMode,
+    // Collect garbage on every VM allocation, see 'Vm::set_gc_stress'.
+    pub gc_stress: bool,
+    // Print garbage collection statistics to stderr once the script ends.
+    pub gc_stats: bool,
+    // Compile functions to native code once they have been called this many
+    // times. Only available when built with the 'jit' feature.
+    pub jit: Option<u32>,
+    // Compile for the VM through the SSA IR and optimize it, see
+    // 'ir_optimizer::optimize'.
+    pub optimize: bool,
+    // The warnings reported before the script runs.
+    pub warnings: Warnings,
+    // The number of calls the script can nest, counting the script itself,
+    // before a stack overflow. Expressions and statements can't be nested any
+    // deeper either.
+    pub max_depth: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            backend: Backend::TreeWalker,
+            trace: false,
+            gc: GcMode::MarkSweep,
+            gc_stress: false,
+            gc_stats: false,
+            jit: None,
+            optimize: false,
+            warnings: Warnings::default(),
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+}
+
+// Run 'source' to completion, writing the output of 'print' statements to
+// 'output'.
+pub fn run_with_options(source: &str, options: Options, output: Box<dyn Write>) -> Result {
+    let statements = parse_program(source, options.warnings, options.max_depth)?;
+
+    match options.backend {
+        Backend::TreeWalker => {
+            let mut interpreter = Interpreter::with_output(output);
+            interpreter.set_max_depth(options.max_depth);
+            interpreter.interpret(&statements)?
+        }
+        Backend::Vm if options.optimize => {
+            run_vm(compile_optimized(&statements)?, options, output)?
+        }
+        Backend::Vm => run_vm(compiler::compile(&statements)?, options, output)?,
+    }
+
+    Ok(())
+}
+
+// Run a compiled script on the VM.
+fn run_vm(script: Rc<FunctionProto>, options: Options, output: Box<dyn Write>) -> Result {
+    let mut vm = Vm::with_gc_mode(output, options.gc);
+    if options.trace {
+        vm.set_trace(Box::new(io::stderr()));
+    }
+    vm.set_gc_stress(options.gc_stress);
+    vm.set_max_depth(options.max_depth);
+    #[cfg(feature = "jit")]
+    if let Some(threshold) = options.jit {
+        vm.enable_jit(threshold);
+    }
+
+    let result = vm.interpret(script);
+    if options.gc_stats {
+        eprint!("{}", vm.gc_stats());
+    }
+    Ok(result?)
+}
+
 // Run the given source file.
-pub fn run_file(filename: &str) -> Result {
+pub fn run_file(filename: &str, options: Options) -> Result {
     let path = Path::new(filename);
-    let source = fs::read_to_string(&path)?;
+    let source = fs::read_to_string(path)?;
+
+    run_with_options(&source, options, Box::new(io::stdout())).map_err(|error| {
+        match error.downcast::<Error>() {
+            Ok(error) => Box::new(error.in_file(filename)),
+            Err(error) => error,
+        }
+    })
+}
+
+// Compile 'source' to bytecode, reporting the same static errors as the
+// tree-walking interpreter.
+fn compile(
+    source: &str,
+    warnings: Warnings,
+    max_depth: usize,
+) -> std::result::Result<Rc<FunctionProto>, Box<dyn std::error::Error>> {
+    Ok(compiler::compile(&parse_program(
+        source, warnings, max_depth,
+    )?)?)
+}
+
+// Compile resolved 'statements' to bytecode through the SSA IR, optimizing
+// it on the way.
+fn compile_optimized(
+    statements: &[Statement<OwnedToken>],
+) -> std::result::Result<Rc<FunctionProto>, Box<dyn std::error::Error>> {
+    let mut program = ir::lower(statements)?;
+    ir_optimizer::optimize(&mut program);
+    Ok(ir_compiler::compile(&program)?)
+}
+
+// Print the syntax tree of the given source file, one statement per line,
+// optimized if 'optimized' is set. A file holding a single expression without
+// a trailing ';' is accepted too, as in the REPL.
+pub fn print_ast(filename: &str, optimized: bool, warnings: Warnings, max_depth: usize) -> Result {
+    let source = fs::read_to_string(Path::new(filename))?;
+    let tokens = lexer::lex(&source)?;
+    let max_nesting = parser::max_nesting(max_depth);
+    let statements = match parser::parse_with_max_nesting(&tokens, max_nesting) {
+        Ok(statements) => statements,
+        Err(error) => match parser::parse_expression_with_max_nesting(&tokens, max_nesting) {
+            Ok(expression) => vec![Statement::Expression(expression)],
+            Err(_) => return Err(error.into()),
+        },
+    };
+    resolver::resolve(&statements)?;
+
+    let statements = if optimized {
+        optimize(statements, warnings)
+    } else {
+        statements
+    };
+    for statement in statements {
+        println!("{statement}");
+    }
+    Ok(())
+}
+
+// Print the SSA IR lowered from the given source file, optimized if
+// 'optimized' is set, see 'ir::Program'.
+pub fn print_ir(filename: &str, optimized: bool, warnings: Warnings, max_depth: usize) -> Result {
+    let source = fs::read_to_string(Path::new(filename))?;
+    let mut program = ir::lower(&parse_program(&source, warnings, max_depth)?)?;
+    if optimized {
+        ir_optimizer::optimize(&mut program);
+    }
+    print!("{program}");
+    Ok(())
+}
+
+// Print the bytecode compiled from the given source file.
+pub fn disassemble_file(filename: &str, warnings: Warnings, max_depth: usize) -> Result {
+    let source = fs::read_to_string(Path::new(filename))?;
+    print!(
+        "{}",
+        disassembler::disassemble(&*compile(&source, warnings, max_depth)?)
+    );
+    Ok(())
+}
+
+// The file 'compile', 'build' and 'emit-c' write to when no output is given:
+// 'script' with its extension replaced by 'extension'. 'None' if that is the
+// script itself, which writing would destroy.
+pub fn default_output(script: &str, extension: &str) -> Option<String> {
+    let output = Path::new(script).with_extension(extension);
+    (output != Path::new(script)).then(|| output.to_string_lossy().into_owned())
+}
+
+// Compile the given source file to bytecode and write it to 'output', see
+// 'bytecode' for the format.
+pub fn compile_file(filename: &str, output: &str, warnings: Warnings, max_depth: usize) -> Result {
+    let source = fs::read_to_string(Path::new(filename))?;
+    let script = compile(&source, warnings, max_depth)?;
+    fs::write(Path::new(output), bytecode::serialize(&script))?;
+    Ok(())
+}
+
+// Build a standalone executable at 'output' running the given source file,
+// see 'aot::build'.
+pub fn build_file(filename: &str, output: &str, warnings: Warnings, max_depth: usize) -> Result {
+    #[cfg(feature = "aot")]
+    {
+        let source = fs::read_to_string(Path::new(filename))?;
+        let runtime = crate::aot::runtime_library()?;
+        let script = compile(&source, warnings, max_depth)?;
+        crate::aot::build(&script, filename, max_depth, &runtime, Path::new(output))
+    }
+    #[cfg(not(feature = "aot"))]
+    {
+        let _ = (filename, output, warnings, max_depth);
+        Err("loxi was built without the 'aot' feature".into())
+    }
+}
+
+// Run a script built by 'build_file' from its serialized bytecode and the
+// native code compiled for each of its functions, allowing calls to nest
+// 'max_depth' deep and tracing runtime errors to 'file', see 'aot::build'.
+// This is what the runtime in 'runtime' does when a built executable starts.
+//
+// # Safety
+//
+// 'code' must hold the code 'build_file' compiled for each function of
+// 'program', in order.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn run_built(
+    program: &[u8],
+    code: &[*const u8],
+    file: &str,
+    max_depth: usize,
+) -> Result {
+    let script = bytecode::deserialize(program)?;
+    let compiled = bytecode::functions(&script)
+        .into_iter()
+        .zip(code.iter().copied())
+        .collect();
+
+    let mut vm = Vm::new();
+    vm.set_max_depth(max_depth);
+    vm.set_native_code(compiled);
+    vm.interpret(script).map_err(|error| error.in_file(file))?;
+    Ok(())
+}
+
+// Translate the given source file to C and write it to 'output', together
+// with the runtime header it includes, which is written to the same
+// directory. See 'transpiler::transpile'.
+pub fn emit_c_file(filename: &str, output: &str, warnings: Warnings, max_depth: usize) -> Result {
+    let source = fs::read_to_string(Path::new(filename))?;
+    let statements = parse_program(&source, warnings, max_depth)?;
+
+    let output = Path::new(output);
+    fs::write(
+        output,
+        transpiler::transpile(&statements, Some(filename), max_depth),
+    )?;
+    fs::write(
+        output.with_file_name(transpiler::RUNTIME_NAME),
+        transpiler::RUNTIME,
+    )?;
+    Ok(())
+}
 
-    run(&source)
+// Run a file written by 'compile_file' on the VM. The file is verified before
+// it runs. The backend in 'options' is ignored.
+pub fn run_compiled_file(filename: &str, options: Options) -> Result {
+    let script = bytecode::deserialize(&fs::read(Path::new(filename))?)?;
+    run_vm(script, options, Box::new(io::stdout()))
 }
 
-// Receive input from stdin and run each line.
-pub fn run_repl() -> Result {
-    let home_dir = dirs::home_dir().unwrap_or(PathBuf::from("."));
-    let history_file = home_dir.join(".loxi.history");
+// Receive input from stdin and run each complete entry. Entries may span
+// multiple lines, in which case a continuation prompt is shown until the
+// delimiters, string literals and block comments are closed. Entries starting
+// with ':' are meta-commands, see 'repl::Command'. Settings are read from the
+// config file and environment, see 'config::Config'.
+#[cfg(feature = "repl")]
+pub fn run_repl(warnings: Warnings, max_depth: usize) -> Result {
+    let (config, ignored) = Config::load();
+    for message in ignored {
+        eprintln!("{}", message);
+    }
+
+    let editor_config = rustyline::Config::builder()
+        .max_history_size(config.history_size)
+        .history_ignore_dups(config.history_ignore_dups)
+        .edit_mode(match config.edit_mode {
+            EditMode::Emacs => rustyline::EditMode::Emacs,
+            EditMode::Vi => rustyline::EditMode::Vi,
+        })
+        .color_mode(if config.color {
+            ColorMode::Enabled
+        } else {
+            ColorMode::Disabled
+        })
+        .build();
 
-    let mut rl = Editor::<()>::new()?;
-    if rl.load_history(&history_file).is_err() {
-        eprintln!("No history loaded");
+    let mut rl = Editor::<ReplHelper>::with_config(editor_config)?;
+    rl.set_helper(Some(ReplHelper::with_prompts(
+        &config.prompt,
+        &config.continuation_prompt,
+    )));
+
+    // A missing history file is expected on the first run.
+    if let Some(history_file) = &config.history_file {
+        if history_file.exists() {
+            if let Err(error) = rl.load_history(history_file) {
+                eprintln!("Could not load history: {}", error);
+            }
+        }
     }
 
+    let startup = match &config.startup_file {
+        Some(path) => match fs::read_to_string(path) {
+            Ok(source) => Some(source),
+            Err(error) => {
+                eprintln!("Could not read {}: {}", path.display(), error);
+                None
+            }
+        },
+        None => None,
+    };
+
+    let mut session = Session::new(startup, warnings, max_depth);
+    if let Err(error) = session.start() {
+        eprintln!("{}", error);
+    }
+    helper(&mut rl).set_global_names(session.global_names());
+
     loop {
-        let line = rl.readline("> ");
+        let prompt = helper(&mut rl).prompt().to_string();
+        let line = rl.readline(&prompt);
 
         match line {
             Ok(line) => {
-                rl.add_history_entry(line.as_str());
+                if helper(&mut rl).pending().is_empty() && repl::is_command(&line) {
+                    rl.add_history_entry(line.trim());
+
+                    match repl::parse_command(&line) {
+                        Ok(Command::Quit) => break,
+                        Ok(command) => {
+                            if let Err(error) = session.run_command(command) {
+                                eprintln!("{}", error);
+                            }
+                        }
+                        Err(message) => eprintln!("{}", message),
+                    }
+                } else {
+                    if helper(&mut rl).push_line(&line) == InputState::Incomplete {
+                        continue;
+                    }
+
+                    let input = helper(&mut rl).take_pending();
+                    rl.add_history_entry(input.trim_end());
 
-                if let Err(error) = run(&line) {
-                    eprintln!("{}", error);
+                    if let Err(error) = session.run(input) {
+                        eprintln!("{}", error);
+                    }
                 }
+
+                let names = session.global_names();
+                helper(&mut rl).set_global_names(names);
+            }
+            // Ctrl-C discards any pending input and starts a new entry.
+            Err(ReadlineError::Interrupted) => {
+                helper(&mut rl).take_pending();
             }
-            Err(ReadlineError::Interrupted) => break,
             Err(ReadlineError::Eof) => break,
             Err(error) => eprintln!("Error: {}", error),
         }
     }
 
-    rl.save_history(&history_file)?;
+    if let Some(history_file) = &config.history_file {
+        rl.save_history(history_file)?;
+    }
     Ok(())
 }
 
+#[cfg(feature = "repl")]
+fn helper(rl: &mut Editor<ReplHelper>) -> &mut ReplHelper {
+    rl.helper_mut().expect("REPL helper is always set")
+}
+
+// The state of a REPL session: the interpreter and the inputs that have run
+// successfully since it last started afresh, which ':save' writes out. The
+// 'startup' script is run whenever the session starts afresh but is not
+// saved.
+#[cfg(feature = "repl")]
+struct Session {
+    interpreter: Interpreter,
+    inputs: Vec<String>,
+    startup: Option<String>,
+    warnings: Warnings,
+    max_depth: usize,
+}
+
+#[cfg(feature = "repl")]
+impl Session {
+    fn new(startup: Option<String>, warnings: Warnings, max_depth: usize) -> Session {
+        Session::with_output(startup, warnings, max_depth, Box::new(io::stdout()))
+    }
+
+    // Create a session that writes what it prints, including the values it
+    // echoes, to 'output'.
+    fn with_output(
+        startup: Option<String>,
+        warnings: Warnings,
+        max_depth: usize,
+        output: Box<dyn Write>,
+    ) -> Session {
+        let mut interpreter = Interpreter::with_output(output);
+        interpreter.set_max_depth(max_depth);
+
+        Session {
+            interpreter,
+            inputs: Vec::new(),
+            startup,
+            warnings,
+            max_depth,
+        }
+    }
+
+    // Run the startup script, if any.
+    fn start(&mut self) -> Result {
+        match &self.startup {
+            Some(source) => run(&mut self.interpreter, source, self.warnings, self.max_depth),
+            None => Ok(()),
+        }
+    }
+
+    // Run a REPL entry. A bare expression without a trailing ';' is accepted
+    // too, and saved with one, and the value of each top-level expression
+    // statement is echoed. An entry that fails is undone as far as the
+    // globals go, since it isn't saved.
+    fn run(&mut self, input: String) -> Result {
+        let tokens = lexer::lex(&input)?;
+        let max_nesting = parser::max_nesting(self.max_depth);
+        let (statements, saved) = match parser::parse_with_max_nesting(&tokens, max_nesting) {
+            Ok(statements) => (statements, None),
+            Err(error) => match parser::parse_expression_with_max_nesting(&tokens, max_nesting) {
+                Ok(expression) => (
+                    vec![Statement::Expression(expression)],
+                    Some(terminate_expression(&input, &tokens)),
+                ),
+                Err(_) => return Err(error.into()),
+            },
+        };
+        resolver::resolve(&statements)?;
+        let statements = optimize(statements, self.warnings);
+
+        self.undo_on_error(|interpreter| {
+            for statement in &statements {
+                match statement {
+                    Statement::Expression(expression) => {
+                        let value = interpreter.evaluate(expression)?;
+                        interpreter.print(&value);
+                    }
+                    _ => interpreter.interpret(std::slice::from_ref(statement))?,
+                }
+            }
+            Ok(())
+        })?;
+
+        self.inputs.push(saved.unwrap_or(input));
+        Ok(())
+    }
+
+    // Run 'entry' on the interpreter, putting the globals back as they were
+    // if it fails, so that the session only holds what ':save' writes.
+    fn undo_on_error(
+        &mut self,
+        entry: impl FnOnce(&mut Interpreter) -> crate::result::Result<()>,
+    ) -> crate::result::Result<()> {
+        let globals = self.interpreter.save_globals();
+        let result = entry(&mut self.interpreter);
+        if result.is_err() {
+            self.interpreter.restore_globals(globals);
+        }
+        result
+    }
+
+    fn global_names(&self) -> Vec<String> {
+        self.interpreter
+            .globals()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    fn run_command(&mut self, command: Command) -> Result {
+        match command {
+            Command::Help => println!("{}", repl::COMMAND_HELP),
+            Command::Quit => (),
+            Command::Tokens(source) => {
+                for token in lexer::lex(&source)? {
+                    let (line, column) = token.source_position;
+                    println!(
+                        "{}:{} {:?} '{}'",
+                        line, column, token.token_type, token.lexeme
+                    );
+                }
+            }
+            Command::Ast(source) => {
+                let tokens = lexer::lex(&source)?;
+                let expr = parser::parse_expression_with_max_nesting(
+                    &tokens,
+                    parser::max_nesting(self.max_depth),
+                )?;
+                println!("{expr}");
+            }
+            // A loaded file runs as it would on its own, without the echo.
+            Command::Load(filename) => {
+                let source = fs::read_to_string(Path::new(&filename))?;
+                let statements = parse_program(&source, self.warnings, self.max_depth)?;
+                self.undo_on_error(|interpreter| interpreter.interpret(&statements))?;
+                self.inputs.push(source);
+            }
+            Command::Reset => {
+                self.interpreter.reset();
+                self.inputs.clear();
+                self.start()?;
+            }
+            Command::Env => {
+                for (name, value) in self.interpreter.globals() {
+                    println!("{name} = {value}");
+                }
+            }
+            Command::Time(source) => {
+                let tokens = lexer::lex(&source)?;
+                let expr = parser::parse_expression_with_max_nesting(
+                    &tokens,
+                    parser::max_nesting(self.max_depth),
+                )?;
+                resolver::resolve_expression(&expr)?;
+
+                let start = Instant::now();
+                let value = self.interpreter.evaluate(&expr)?;
+                let elapsed = start.elapsed();
+
+                println!("{value}");
+                println!("took {:?}", elapsed);
+            }
+            Command::Save(filename) => {
+                let mut script = String::new();
+                for input in &self.inputs {
+                    script.push_str(input.trim_end());
+                    script.push('\n');
+                }
+
+                fs::write(Path::new(&filename), script)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// The bare expression 'input', lexed into 'tokens', as an expression
+// statement, with a ';' right after its last token so that it goes before any
+// comment following it.
+#[cfg(feature = "repl")]
+fn terminate_expression(input: &str, tokens: &[lexer::Token]) -> String {
+    let end = tokens
+        .iter()
+        .rfind(|token| token.token_type != lexer::TokenType::Eof)
+        .map_or(0, |token| {
+            token.lexeme.as_ptr() as usize - input.as_ptr() as usize + token.lexeme.len()
+        });
+    format!("{};{}", &input[..end], &input[end..])
+}
+
+#[cfg(test)]
 mod tests {
-    //use super::*;
+    use super::*;
+    use crate::testing::{corpus, Output};
+
+    // Run 'source' on 'backend', returning everything it printed followed by
+    // the error it stopped with, if any.
+    fn transcript(source: &str, backend: Backend) -> String {
+        transcript_with_options(
+            source,
+            Options {
+                backend,
+                ..Options::default()
+            },
+        )
+    }
+
+    fn transcript_with_options(source: &str, options: Options) -> String {
+        let output = Output::default();
+        let result = run_with_options(source, options, Box::new(output.clone()));
+        output.transcript(result)
+    }
+
+    // The 'transcript' of the script at 'file', tracing runtime errors to the
+    // file as 'run_file' does.
+    fn file_transcript(file: &Path, backend: Backend) -> String {
+        let output = Output::default();
+        let source = fs::read_to_string(file).unwrap();
+        let options = Options {
+            backend,
+            ..Options::default()
+        };
+        let result = run_with_options(&source, options, Box::new(output.clone()));
+
+        let mut transcript = output.contents();
+        if let Err(error) = result {
+            let error = error.downcast::<Error>().unwrap();
+            let error = error.in_file(file.to_str().unwrap());
+            transcript.push_str(&format!("error: {error}\n"));
+        }
+        transcript
+    }
+
+    #[test]
+    fn backends_agree_on_corpus() {
+        let files = corpus();
+        assert!(!files.is_empty());
+
+        for file in files {
+            let source = fs::read_to_string(&file).unwrap();
+            let expected = transcript(&source, Backend::TreeWalker);
+            let actual = transcript(&source, Backend::Vm);
+
+            assert!(!expected.is_empty(), "{} printed nothing", file.display());
+            assert_eq!(actual, expected, "backends differ on {}", file.display());
+        }
+    }
+
+    // Collecting on every allocation frees any object the VM uses without
+    // rooting it, which shows up as a panic or different output.
+    #[test]
+    fn corpus_survives_gc_stress() {
+        for file in corpus() {
+            let source = fs::read_to_string(&file).unwrap();
+            let expected = transcript(&source, Backend::TreeWalker);
+
+            for gc in [GcMode::MarkSweep, GcMode::Generational] {
+                let options = Options {
+                    backend: Backend::Vm,
+                    gc,
+                    gc_stress: true,
+                    ..Options::default()
+                };
+
+                assert_eq!(
+                    transcript_with_options(&source, options),
+                    expected,
+                    "GC stress in {:?} mode changed the output of {}",
+                    gc,
+                    file.display()
+                );
+            }
+        }
+    }
+
+    // Compiling through the SSA IR, with and without optimizing it, must not
+    // change what a script does, including when garbage is collected on
+    // every allocation.
+    #[test]
+    fn optimized_corpus_runs_the_same() {
+        for file in corpus() {
+            let source = fs::read_to_string(&file).unwrap();
+            let expected = transcript(&source, Backend::Vm);
+
+            for gc_stress in [false, true] {
+                let options = Options {
+                    backend: Backend::Vm,
+                    optimize: true,
+                    gc_stress,
+                    ..Options::default()
+                };
+
+                assert_eq!(
+                    transcript_with_options(&source, options),
+                    expected,
+                    "optimizing changed the output of {}",
+                    file.display()
+                );
+            }
+
+            let statements = parse_program(&source, Warnings::default(), DEFAULT_MAX_DEPTH);
+            if let Ok(script) = statements
+                .and_then(|statements| Ok(ir_compiler::compile(&ir::lower(&statements)?)?))
+            {
+                let output = Output::default();
+                let result = Vm::with_output(Box::new(output.clone())).interpret(script);
+                let actual = output.transcript(result);
+                assert_eq!(
+                    actual,
+                    expected,
+                    "the unoptimized IR changed the output of {}",
+                    file.display()
+                );
+            }
+        }
+    }
+
+    // Calls in tail position run in constant space on every backend, far
+    // beyond the depth other calls are limited to.
+    #[test]
+    fn tail_calls_recurse_a_million_times() {
+        let source =
+            "fun count(n, total) { if (n == 0) return total; return count(n - 1, total + 1); }
+                      print count(1000000, 0);";
+
+        for (backend, optimize) in [
+            (Backend::TreeWalker, false),
+            (Backend::Vm, false),
+            (Backend::Vm, true),
+        ] {
+            let options = Options {
+                backend,
+                optimize,
+                ..Options::default()
+            };
+            assert_eq!(transcript_with_options(source, options), "1000000\n");
+        }
+    }
+
+    // A stack of 'stack_size' holds the deepest calls the tree-walking
+    // interpreter allows, even when each goes through a method, a loop and
+    // nested blocks and expressions, so running out of it is reported as a
+    // stack overflow rather than crashing.
+    #[test]
+    fn default_output_is_never_the_script() {
+        assert_eq!(
+            default_output("dir/a.lox", "loxc").as_deref(),
+            Some("dir/a.loxc")
+        );
+        assert_eq!(default_output("a.lox", "").as_deref(), Some("a"));
+        assert_eq!(default_output("prog", ""), None);
+        assert_eq!(default_output("a.loxc", "loxc"), None);
+        assert_eq!(default_output("a.c", "c"), None);
+    }
+
+    #[test]
+    fn stack_size_holds_the_max_depth() {
+        let source = |depth| {
+            format!(
+                "class A {{
+                   down(n) {{
+                     {{
+                       while (true) {{
+                         if (n == 0) {{ return 0; }} else {{
+                           var f = this.down;
+                           return 1 + f(n - 1) + 0 * (1 + (2 * (3 - n)));
+                         }}
+                       }}
+                     }}
+                   }}
+                 }}
+                 print A().down({depth});"
+            )
+        };
+        let options = Options {
+            max_depth: 2000,
+            ..Options::default()
+        };
+
+        let run = move |depth| {
+            std::thread::Builder::new()
+                .stack_size(stack_size(options.max_depth))
+                .spawn(move || transcript_with_options(&source(depth), options))
+                .unwrap()
+                .join()
+                .unwrap()
+        };
+        assert_eq!(run(1998), "1998\n");
+        assert!(run(1999).contains("stack overflow\n"));
+    }
+
+    #[test]
+    fn max_depth_limits_calls() {
+        let source = "fun down(n) { if (n == 0) return 0; return 1 + down(n - 1); }
+                      print down(1000);";
+
+        for optimize in [false, true] {
+            let options = Options {
+                backend: Backend::Vm,
+                optimize,
+                ..Options::default()
+            };
+            assert!(transcript_with_options(source, options).contains("stack overflow\n"));
+
+            let options = Options {
+                max_depth: 1002,
+                ..options
+            };
+            assert_eq!(transcript_with_options(source, options), "1000\n");
+        }
+    }
+
+    // Loading a compiled script must not change what it does.
+    #[test]
+    fn compiled_corpus_runs_the_same() {
+        for file in corpus() {
+            let source = fs::read_to_string(&file).unwrap();
+            let Ok(script) = compile(&source, Warnings::default(), DEFAULT_MAX_DEPTH) else {
+                continue;
+            };
+            let loaded = bytecode::deserialize(&bytecode::serialize(&script)).unwrap();
+
+            let output = Output::default();
+            let result = Vm::with_output(Box::new(output.clone())).interpret(loaded);
+            let actual = output.transcript(result);
+
+            assert_eq!(
+                actual,
+                transcript(&source, Backend::Vm),
+                "compiled script differs for {}",
+                file.display()
+            );
+        }
+    }
+
+    // Each script in the corpus is run with every function compiled on its
+    // first call, which must not change what the script does.
+    #[cfg(feature = "jit")]
+    #[test]
+    fn jit_agrees_with_interpreter() {
+        for file in corpus() {
+            let source = fs::read_to_string(&file).unwrap();
+            let options = |jit| Options {
+                backend: Backend::Vm,
+                jit,
+                ..Options::default()
+            };
+
+            assert_eq!(
+                transcript_with_options(&source, options(Some(1))),
+                transcript_with_options(&source, options(None)),
+                "the JIT changed the output of {}",
+                file.display()
+            );
+        }
+    }
+
+    // Run 'executable', returning what it printed in the same form as
+    // 'transcript'.
+    fn transcript_of(executable: &Path) -> String {
+        let run = std::process::Command::new(executable).output().unwrap();
+        let mut output = String::from_utf8(run.stdout).unwrap();
+        if !run.status.success() {
+            output.push_str("error: ");
+            output.push_str(&String::from_utf8(run.stderr).unwrap());
+        }
+        output
+    }
+
+    // Each script in the corpus translated to C and compiled with the system
+    // 'cc' prints the same output and errors as the interpreter, including
+    // when built to collect garbage on every allocation.
+    #[test]
+    fn c_programs_run_the_same() {
+        let dir = std::env::temp_dir().join(format!("loxi-emit-c-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        for file in corpus() {
+            let program = dir.join(file.file_stem().unwrap()).with_extension("c");
+            if emit_c_file(
+                file.to_str().unwrap(),
+                program.to_str().unwrap(),
+                Warnings::default(),
+                DEFAULT_MAX_DEPTH,
+            )
+            .is_err()
+            {
+                continue;
+            }
+            let expected = file_transcript(&file, Backend::TreeWalker);
+
+            for flags in [&[][..], &["-DLOX_GC_STRESS"]] {
+                let executable = program.with_extension("");
+                let compiled = std::process::Command::new("cc")
+                    .args(["-std=c11", "-Wall", "-Werror"])
+                    .args(flags)
+                    .arg(&program)
+                    .arg("-o")
+                    .arg(&executable)
+                    .output()
+                    .unwrap();
+                assert!(
+                    compiled.status.success(),
+                    "the C for {} doesn't compile:\n{}",
+                    file.display(),
+                    String::from_utf8_lossy(&compiled.stderr)
+                );
+
+                assert_eq!(
+                    transcript_of(&executable),
+                    expected,
+                    "the C program differs for {} with {:?}",
+                    file.display(),
+                    flags
+                );
+            }
+        }
 
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Recursing 150 calls deep, beyond the default maximum depth.
+    const DEEP_RECURSION: &str = "fun down(n) { if (n == 0) return 0; return 1 + down(n - 1); }
+                                  print down(150);";
+
+    // The C program allows calls to nest as deeply as the maximum depth the
+    // script was translated with.
+    #[test]
+    fn c_programs_use_the_max_depth() {
+        let dir = std::env::temp_dir().join(format!("loxi-emit-c-depth-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("deep.lox");
+        fs::write(&script, DEEP_RECURSION).unwrap();
+        let program = dir.join("deep.c");
+        let executable = dir.join("deep");
+
+        for (max_depth, expected) in [(DEFAULT_MAX_DEPTH, false), (200, true)] {
+            emit_c_file(
+                script.to_str().unwrap(),
+                program.to_str().unwrap(),
+                Warnings::default(),
+                max_depth,
+            )
+            .unwrap();
+            let compiled = std::process::Command::new("cc")
+                .args(["-std=c11", "-o"])
+                .arg(&executable)
+                .arg(&program)
+                .status()
+                .unwrap();
+            assert!(compiled.success());
+
+            let output = transcript_of(&executable);
+            assert_eq!(output == "150\n", expected, "{output}");
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Executables built from the corpus print the same output and errors as
+    // running the scripts on the VM, with errors traced to the script's file.
+    // Linking is slow, so the scripts are built
+    // in parallel, but a few at a time, since each linker needs a lot of
+    // memory.
+    #[cfg(feature = "aot")]
+    #[test]
+    fn built_executables_run_the_same() {
+        let dir = std::env::temp_dir().join(format!("loxi-build-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let runtime = crate::aot::runtime_library().unwrap();
+
+        let run_executable = |file: &Path| {
+            let source = fs::read_to_string(file).unwrap();
+            let script = compile(&source, Warnings::default(), DEFAULT_MAX_DEPTH).ok()?;
+            let executable = dir.join(file.file_stem().unwrap());
+            let name = file.to_str().unwrap();
+            crate::aot::build(&script, name, DEFAULT_MAX_DEPTH, &runtime, &executable).unwrap();
+            Some(transcript_of(&executable))
+        };
+
+        let files = corpus();
+        let outputs: Vec<Option<String>> = files
+            .chunks(8)
+            .flat_map(|chunk| {
+                std::thread::scope(|scope| {
+                    let builds: Vec<_> = chunk
+                        .iter()
+                        .map(|file| scope.spawn(|| run_executable(file)))
+                        .collect();
+                    builds
+                        .into_iter()
+                        .map(|build| build.join().unwrap())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        for (file, output) in files.iter().zip(outputs) {
+            let Some(output) = output else {
+                continue;
+            };
+            assert_eq!(
+                output,
+                file_transcript(file, Backend::Vm),
+                "executable differs for {}",
+                file.display()
+            );
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // A built executable allows calls to nest as deeply as the maximum depth
+    // the script was built with.
+    #[cfg(feature = "aot")]
     #[test]
-    fn dummy() {}
+    fn built_executables_use_the_max_depth() {
+        let dir = std::env::temp_dir().join(format!("loxi-build-depth-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let runtime = crate::aot::runtime_library().unwrap();
+        let script = compile(DEEP_RECURSION, Warnings::default(), DEFAULT_MAX_DEPTH).unwrap();
+        let executable = dir.join("deep");
+
+        for (max_depth, expected) in [(DEFAULT_MAX_DEPTH, false), (200, true)] {
+            crate::aot::build(&script, "deep.lox", max_depth, &runtime, &executable).unwrap();
+            let output = transcript_of(&executable);
+            assert_eq!(output == "150\n", expected, "{output}");
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Building writes its object file somewhere of its own, rather than next
+    // to the executable, where it could replace one of the user's.
+    #[cfg(feature = "aot")]
+    #[test]
+    fn building_keeps_other_files() {
+        let dir = std::env::temp_dir().join(format!("loxi-build-keep-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let runtime = crate::aot::runtime_library().unwrap();
+        let script = compile("print 1;", Warnings::default(), DEFAULT_MAX_DEPTH).unwrap();
+        let object = dir.join("keep.o");
+        fs::write(&object, "mine").unwrap();
+
+        let executable = dir.join("keep");
+        crate::aot::build(
+            &script,
+            "keep.lox",
+            DEFAULT_MAX_DEPTH,
+            &runtime,
+            &executable,
+        )
+        .unwrap();
+        assert_eq!(transcript_of(&executable), "1\n");
+        assert_eq!(fs::read_to_string(&object).unwrap(), "mine");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn backend_names() {
+        assert_eq!("tree".parse(), Ok(Backend::TreeWalker));
+        assert_eq!("vm".parse(), Ok(Backend::Vm));
+        assert!("jit".parse::<Backend>().is_err());
+    }
+
+    #[cfg(feature = "repl")]
+    #[test]
+    fn session_saves_successful_inputs() {
+        let filename = std::env::temp_dir().join(format!("loxi-save-{}.lox", std::process::id()));
+        let mut session = Session::new(None, Warnings::default(), DEFAULT_MAX_DEPTH);
+
+        session.run(String::from("var a = 1;\n")).unwrap();
+        assert!(session.run(String::from("a = -nil;")).is_err());
+        session.run(String::from("{\n  a = a + 1;\n}\n")).unwrap();
+        session
+            .run_command(Command::Save(filename.to_string_lossy().into_owned()))
+            .unwrap();
+
+        let script = fs::read_to_string(&filename).unwrap();
+        fs::remove_file(&filename).unwrap();
+        assert_eq!(script, "var a = 1;\n{\n  a = a + 1;\n}\n");
+    }
+
+    // A saved session runs as a script, including the bare expressions it
+    // echoed.
+    #[cfg(feature = "repl")]
+    #[test]
+    fn saved_session_runs() {
+        let filename =
+            std::env::temp_dir().join(format!("loxi-save-run-{}.lox", std::process::id()));
+        let mut session = Session::with_output(
+            None,
+            Warnings::default(),
+            DEFAULT_MAX_DEPTH,
+            Box::new(Output::default()),
+        );
+
+        session
+            .run(String::from("class P { init() { this.x = 1; } }"))
+            .unwrap();
+        session.run(String::from("var p = P();")).unwrap();
+        session.run(String::from("p.x // the field\n")).unwrap();
+        session.run(String::from("p.x = p.x + 1")).unwrap();
+        session.run(String::from("print p.x;")).unwrap();
+        session
+            .run_command(Command::Save(filename.to_string_lossy().into_owned()))
+            .unwrap();
+
+        let script = fs::read_to_string(&filename).unwrap();
+        fs::remove_file(&filename).unwrap();
+        assert_eq!(
+            script,
+            "class P { init() { this.x = 1; } }\nvar p = P();\np.x; // the field\n\
+             p.x = p.x + 1;\nprint p.x;\n"
+        );
+        assert_eq!(transcript(&script, Backend::TreeWalker), "2\n");
+    }
+
+    // An entry that fails part of the way through leaves nothing behind that
+    // the saved session would miss.
+    #[cfg(feature = "repl")]
+    #[test]
+    fn failed_entries_are_undone() {
+        let filename =
+            std::env::temp_dir().join(format!("loxi-save-failed-{}.lox", std::process::id()));
+        let output = Output::default();
+        let mut session = Session::with_output(
+            None,
+            Warnings::default(),
+            DEFAULT_MAX_DEPTH,
+            Box::new(output.clone()),
+        );
+
+        session.run(String::from("var y = 1;")).unwrap();
+        assert!(session
+            .run(String::from("var x = 1; y = 2; print x + nil;"))
+            .is_err());
+        assert!(session.run(String::from("print x;")).is_err());
+        session.run(String::from("print y;")).unwrap();
+        session
+            .run_command(Command::Save(filename.to_string_lossy().into_owned()))
+            .unwrap();
+
+        let script = fs::read_to_string(&filename).unwrap();
+        fs::remove_file(&filename).unwrap();
+        assert_eq!(script, "var y = 1;\nprint y;\n");
+        assert_eq!(output.contents(), "2\n1\n");
+        assert_eq!(transcript(&script, Backend::TreeWalker), "1\n");
+    }
+
+    // Only typed expression statements are echoed, not those in a file
+    // loaded with ':load'.
+    #[cfg(feature = "repl")]
+    #[test]
+    fn loaded_files_run_without_echo() {
+        let filename =
+            std::env::temp_dir().join(format!("loxi-load-echo-{}.lox", std::process::id()));
+        fs::write(&filename, "fun f() {}\nf();\n1 + 2;\nprint 4;\n").unwrap();
+        let output = Output::default();
+        let mut session = Session::with_output(
+            None,
+            Warnings::default(),
+            DEFAULT_MAX_DEPTH,
+            Box::new(output.clone()),
+        );
+
+        let loaded = session.run_command(Command::Load(filename.to_string_lossy().into_owned()));
+        fs::remove_file(&filename).unwrap();
+        loaded.unwrap();
+        session.run(String::from("f();")).unwrap();
+
+        assert_eq!(output.contents(), "4\nnil\n");
+    }
+
+    #[cfg(feature = "repl")]
+    #[test]
+    fn session_saves_inputs_since_reset() {
+        let filename =
+            std::env::temp_dir().join(format!("loxi-save-reset-{}.lox", std::process::id()));
+        let mut session = Session::new(None, Warnings::default(), DEFAULT_MAX_DEPTH);
+
+        session.run(String::from("var a = 1;")).unwrap();
+        session.run_command(Command::Reset).unwrap();
+        session.run(String::from("var b = 2;")).unwrap();
+        session
+            .run_command(Command::Save(filename.to_string_lossy().into_owned()))
+            .unwrap();
+
+        let script = fs::read_to_string(&filename).unwrap();
+        fs::remove_file(&filename).unwrap();
+        assert_eq!(script, "var b = 2;\n");
+    }
+
+    #[cfg(feature = "repl")]
+    #[test]
+    fn session_reset() {
+        let mut session = Session::new(None, Warnings::default(), DEFAULT_MAX_DEPTH);
+
+        session.run(String::from("var a = 1; var b = 2;")).unwrap();
+        assert_eq!(
+            session.global_names(),
+            ["SortedMap", "SortedSet", "a", "b", "clock"]
+        );
+
+        session.run_command(Command::Reset).unwrap();
+        assert_eq!(session.global_names(), ["SortedMap", "SortedSet", "clock"]);
+    }
+
+    #[cfg(feature = "repl")]
+    #[test]
+    fn session_keeps_definitions() {
+        let output = Output::default();
+        let mut session = Session::with_output(
+            None,
+            Warnings::default(),
+            DEFAULT_MAX_DEPTH,
+            Box::new(output.clone()),
+        );
+
+        session
+            .run(String::from("fun add(a, b) { return a + b; }"))
+            .unwrap();
+        session
+            .run(String::from("class Point { init(x) { this.x = x; } }"))
+            .unwrap();
+        session
+            .run(String::from("var p = Point(add(1, 2));"))
+            .unwrap();
+        session.run(String::from("p.x")).unwrap();
+
+        assert_eq!(output.contents(), "3\n");
+        assert_eq!(
+            session.global_names(),
+            ["Point", "SortedMap", "SortedSet", "add", "clock", "p"]
+        );
+    }
+
+    #[cfg(feature = "repl")]
+    #[test]
+    fn session_startup_script() {
+        let mut session = Session::new(
+            Some(String::from("fun double(x) { return 2 * x; }")),
+            Warnings::default(),
+            DEFAULT_MAX_DEPTH,
+        );
+        session.start().unwrap();
+        session.run(String::from("var a = double(2);")).unwrap();
+        assert_eq!(
+            session.global_names(),
+            ["SortedMap", "SortedSet", "a", "clock", "double"]
+        );
+
+        session.run_command(Command::Reset).unwrap();
+        assert_eq!(
+            session.global_names(),
+            ["SortedMap", "SortedSet", "clock", "double"]
+        );
+    }
 }
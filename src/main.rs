@@ -1,7 +1,33 @@
-use exitcode;
 use loxi::loxi;
-use std::cmp::Ordering;
 use std::env;
+use std::thread;
+
+const USAGE: &str = "\
+Usage: loxi [--backend=tree|vm] [--max-depth=<depth>] [VM options] [-W <warning>] [script]
+       loxi compile [--max-depth=<depth>] [-W <warning>] <script> [-o <output>]
+       loxi build [--max-depth=<depth>] [-W <warning>] <script> [-o <output>]
+       loxi emit-c [--max-depth=<depth>] [-W <warning>] <script> [-o <output>]
+       loxi run [--max-depth=<depth>] [VM options] <compiled script>
+       loxi disasm [--max-depth=<depth>] [-W <warning>] <script>
+       loxi ast [--optimized] [--max-depth=<depth>] [-W <warning>] <script>
+       loxi ir [--optimized] [--max-depth=<depth>] [-W <warning>] <script>
+
+The maximum depth is the number of calls a script can nest, counting the
+script itself, before a stack overflow (64 by default). Expressions and
+statements can be nested 1024 deep, or as deep as the maximum depth if that
+is more.
+
+Warnings, enabled by default and disabled with 'no-' in front:
+  unreachable                   statements that can never run
+
+VM options:
+  --trace                       print each instruction as it executes
+  --gc=mark-sweep|generational  choose the garbage collector
+  --gc-stress                   collect garbage on every allocation
+  --gc-stats                    print garbage collection statistics
+  --optimize                    compile through the SSA IR and optimize it
+  --jit[=calls]                 compile functions called this often to native
+                                code (1000 by default)";
 
 fn process_error_and_exit(result: &loxi::Result) {
     match result {
@@ -13,21 +39,212 @@ fn process_error_and_exit(result: &loxi::Result) {
     }
 }
 
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    eprintln!("{}", USAGE);
+    std::process::exit(exitcode::USAGE);
+}
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut backend = None;
+    let mut trace = false;
+    let mut gc = None;
+    let mut gc_stress = false;
+    let mut gc_stats = false;
+    let mut jit = None;
+    let mut optimize = false;
+    let mut max_depth = None;
+    let mut output = None;
+    let mut optimized = false;
+    let mut warnings = loxi::Warnings::default();
+    let mut args = Vec::new();
 
-    match args.len().cmp(&2) {
-        Ordering::Equal => {
-            let result = loxi::run_file(&args[1]);
-            process_error_and_exit(&result);
+    let mut arguments = env::args().skip(1);
+    while let Some(arg) = arguments.next() {
+        if arg == "-o" {
+            match arguments.next() {
+                Some(path) => output = Some(path),
+                None => usage_error("expected a file name after '-o'"),
+            }
+        } else if arg == "-W" {
+            let Some(warning) = arguments.next() else {
+                usage_error("expected a warning after '-W'");
+            };
+            let (name, enabled) = match warning.strip_prefix("no-") {
+                Some(name) => (name, false),
+                None => (warning.as_str(), true),
+            };
+            match name {
+                "unreachable" => warnings.unreachable = enabled,
+                _ => usage_error(&format!("unknown warning '{}'", warning)),
+            }
+        } else if let Some(name) = arg.strip_prefix("--backend=") {
+            match name.parse::<loxi::Backend>() {
+                Ok(name) => backend = Some(name),
+                Err(message) => usage_error(&message),
+            }
+        } else if let Some(depth) = arg.strip_prefix("--max-depth=") {
+            match depth.parse() {
+                Ok(depth) if depth > 0 => max_depth = Some(depth),
+                _ => usage_error(&format!("invalid depth '{}'", depth)),
+            }
+        } else if arg == "--optimized" {
+            optimized = true;
+        } else if arg == "--trace" {
+            trace = true;
+        } else if let Some(name) = arg.strip_prefix("--gc=") {
+            match name.parse::<loxi::GcMode>() {
+                Ok(mode) => gc = Some(mode),
+                Err(message) => usage_error(&message),
+            }
+        } else if arg == "--gc-stress" {
+            gc_stress = true;
+        } else if arg == "--gc-stats" {
+            gc_stats = true;
+        } else if arg == "--optimize" {
+            optimize = true;
+        } else if arg == "--jit" || arg.starts_with("--jit=") {
+            if !cfg!(feature = "jit") {
+                usage_error("loxi was built without the 'jit' feature");
+            }
+            jit = match arg.strip_prefix("--jit=") {
+                None => Some(loxi::DEFAULT_JIT_THRESHOLD),
+                Some(calls) => match calls.parse() {
+                    Ok(calls) => Some(calls),
+                    Err(_) => usage_error(&format!("invalid call count '{}'", calls)),
+                },
+            };
+        } else if arg.starts_with('-') {
+            usage_error(&format!("unknown option '{}'", arg));
+        } else {
+            args.push(arg);
         }
-        Ordering::Less => {
-            let result = loxi::run_repl();
-            process_error_and_exit(&result);
+    }
+
+    // The VM options and compiled scripts are only available on the VM, so
+    // they imply '--backend=vm'.
+    let compiled = args.first().is_some_and(|command| command == "run");
+    let vm_options = trace || gc.is_some() || gc_stress || gc_stats || jit.is_some() || optimize;
+    let backend = match backend {
+        Some(loxi::Backend::TreeWalker) if vm_options => {
+            usage_error("VM options require the 'vm' backend")
         }
-        Ordering::Greater => {
-            println!("Usage: loxi [script]");
-            std::process::exit(exitcode::USAGE);
+        Some(loxi::Backend::TreeWalker) if compiled => {
+            usage_error("compiled scripts require the 'vm' backend")
         }
+        Some(backend) => backend,
+        None if vm_options || compiled => loxi::Backend::Vm,
+        None => loxi::Backend::TreeWalker,
+    };
+    let options = loxi::Options {
+        backend,
+        trace,
+        gc: gc.unwrap_or(loxi::GcMode::MarkSweep),
+        gc_stress,
+        gc_stats,
+        jit,
+        optimize,
+        warnings,
+        max_depth: max_depth.unwrap_or(loxi::DEFAULT_MAX_DEPTH),
+    };
+
+    if output.is_some()
+        && args
+            .first()
+            .is_none_or(|command| !["compile", "build", "emit-c"].contains(&command.as_str()))
+    {
+        usage_error("'-o' is only used by 'compile', 'build' and 'emit-c'");
+    }
+
+    if optimized
+        && args
+            .first()
+            .is_none_or(|command| command != "ast" && command != "ir")
+    {
+        usage_error("'--optimized' is only used by 'ast' and 'ir'");
+    }
+
+    // Parsing, the passes over the syntax tree and the tree-walking
+    // interpreter recurse natively, so the stack is sized for the deepest
+    // nesting and calls allowed.
+    let spawned = thread::Builder::new()
+        .stack_size(loxi::stack_size(options.max_depth))
+        .spawn(move || process_error_and_exit(&run(&args, output, optimized, options)));
+    let result = match spawned {
+        Ok(thread) => thread.join().map_err(|_| "loxi panicked".into()),
+        Err(error) => Err(format!(
+            "can't reserve a stack for a depth of {}: {}",
+            options.max_depth, error
+        )
+        .into()),
     };
+    process_error_and_exit(&result);
+}
+
+// The output given with '-o', or else the default next to 'script', which
+// must not be the script itself.
+fn output_or_default(output: Option<String>, script: &str, extension: &str) -> String {
+    output
+        .or_else(|| loxi::default_output(script, extension))
+        .unwrap_or_else(|| {
+            usage_error(&format!(
+                "the output would overwrite '{}', give another with '-o'",
+                script
+            ))
+        })
+}
+
+// Run the command in 'args'.
+fn run(
+    args: &[String],
+    output: Option<String>,
+    optimized: bool,
+    options: loxi::Options,
+) -> loxi::Result {
+    let warnings = options.warnings;
+    let max_depth = options.max_depth;
+    match args {
+        [command, script] if command == "compile" => {
+            let output = output_or_default(output, script, "loxc");
+            loxi::compile_file(script, &output, warnings, max_depth)
+        }
+        [command, script] if command == "build" => {
+            let output = output_or_default(output, script, "");
+            loxi::build_file(script, &output, warnings, max_depth)
+        }
+        [command, script] if command == "emit-c" => {
+            let output = output_or_default(output, script, "c");
+            loxi::emit_c_file(script, &output, warnings, max_depth)
+        }
+        [command, script] if command == "run" => loxi::run_compiled_file(script, options),
+        [command, script] if command == "disasm" => {
+            loxi::disassemble_file(script, warnings, max_depth)
+        }
+        [command, script] if command == "ast" => {
+            loxi::print_ast(script, optimized, warnings, max_depth)
+        }
+        [command, script] if command == "ir" => {
+            loxi::print_ir(script, optimized, warnings, max_depth)
+        }
+        [command] if command == "compile" => usage_error("expected a script to compile"),
+        [command] if command == "build" => usage_error("expected a script to build"),
+        [command] if command == "emit-c" => usage_error("expected a script to translate"),
+        [command] if command == "run" => usage_error("expected a compiled script to run"),
+        [command] if command == "disasm" => usage_error("expected a script to disassemble"),
+        [command] if command == "ast" || command == "ir" => {
+            usage_error("expected a script to print")
+        }
+        [script] => loxi::run_file(script, options),
+        [] if options
+            != (loxi::Options {
+                warnings,
+                max_depth: options.max_depth,
+                ..loxi::Options::default()
+            }) =>
+        {
+            usage_error("the REPL only supports the 'tree' backend")
+        }
+        [] => loxi::run_repl(warnings, options.max_depth),
+        _ => usage_error("expected at most one script"),
+    }
 }
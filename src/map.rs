@@ -0,0 +1,190 @@
+// Behaviour shared by the maps of every backend: the methods a map has, which
+// values can be keys and when two keys are the same, and the insertion order
+// entries are kept in. The C runtime in 'c/loxi.h' follows the same rules.
+use std::collections::HashMap;
+use std::hash::Hash;
+
+pub const UNHASHABLE: &str =
+    "map keys must be nil, booleans, numbers, strings or instances with a 'hash' method";
+pub const NOT_A_HASH: &str = "'hash' must return nil, a boolean, a number or a string";
+pub const KEY_NOT_FOUND: &str = "key not found in map";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    Has,
+    Remove,
+    Keys,
+    Values,
+    Len,
+}
+
+pub const METHODS: [Method; 5] = [
+    Method::Has,
+    Method::Remove,
+    Method::Keys,
+    Method::Values,
+    Method::Len,
+];
+
+impl Method {
+    pub fn from_name(name: &str) -> Option<Method> {
+        METHODS.into_iter().find(|method| method.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Method::Has => "has",
+            Method::Remove => "remove",
+            Method::Keys => "keys",
+            Method::Values => "values",
+            Method::Len => "len",
+        }
+    }
+
+    pub fn arity(self) -> usize {
+        match self {
+            Method::Keys | Method::Values | Method::Len => 0,
+            Method::Has | Method::Remove => 1,
+        }
+    }
+}
+
+// What a map looks a key up by. Strings are whatever each backend represents
+// them with. An instance is looked up by the key its 'hash' method returns,
+// so instances whose 'hash' methods agree are the same key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Key<S> {
+    Nil,
+    Bool(bool),
+    Number(u64),
+    String(S),
+}
+
+impl<S> Key<S> {
+    // Numbers that compare equal are the same key, so '-0' is '0'. Unlike
+    // with '==', NaN is a key like any other and finds itself.
+    pub fn number(number: f64) -> Key<S> {
+        let number = if number == 0.0 {
+            0.0
+        } else if number.is_nan() {
+            f64::NAN
+        } else {
+            number
+        };
+        Key::Number(number.to_bits())
+    }
+}
+
+// Entries in the order they were first inserted. Removing an entry leaves a
+// hole, and the holes are squeezed out once they outnumber the entries.
+#[derive(Debug, Clone)]
+pub struct Map<K, V> {
+    entries: Vec<Option<(K, V)>>,
+    indices: HashMap<K, usize>,
+}
+
+impl<K, V> Default for Map<K, V> {
+    fn default() -> Self {
+        Map {
+            entries: Vec::new(),
+            indices: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash, V> Map<K, V> {
+    pub fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        let &index = self.indices.get(key)?;
+        self.entries[index].as_ref().map(|(_, value)| value)
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let &index = self.indices.get(key)?;
+        self.entries[index].as_mut().map(|(_, value)| value)
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.indices.contains_key(key)
+    }
+
+    // Set the value of 'key'. A key already in the map keeps its place.
+    pub fn insert(&mut self, key: K, value: V) {
+        match self.indices.get(&key) {
+            Some(&index) => self.entries[index] = Some((key, value)),
+            None => {
+                self.indices.insert(key.clone(), self.entries.len());
+                self.entries.push(Some((key, value)));
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let index = self.indices.remove(key)?;
+        let (_, value) = self.entries[index].take()?;
+
+        if self.entries.len() > 2 * self.indices.len() {
+            self.entries.retain(Option::is_some);
+            for (index, (key, _)) in self.entries.iter().flatten().enumerate() {
+                self.indices.insert(key.clone(), index);
+            }
+        }
+        Some(value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries
+            .iter()
+            .flatten()
+            .map(|(key, value)| (key, value))
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, value)| value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers() {
+        assert_eq!(Key::<()>::number(-0.0), Key::number(0.0));
+        assert_eq!(Key::<()>::number(f64::NAN), Key::number(-f64::NAN));
+        assert_ne!(Key::<()>::number(1.0), Key::number(1.5));
+    }
+
+    #[test]
+    fn insertion_order() {
+        let mut map = Map::default();
+        for key in 0..10 {
+            map.insert(key, key * 10);
+        }
+        map.insert(3, 33);
+        for key in 0..8 {
+            if key != 3 {
+                assert_eq!(map.remove(&key), Some(key * 10));
+            }
+        }
+        assert_eq!(map.remove(&0), None);
+        map.insert(0, 0);
+
+        assert_eq!(
+            map.iter()
+                .map(|(&key, &value)| (key, value))
+                .collect::<Vec<_>>(),
+            [(3, 33), (8, 80), (9, 90), (0, 0)]
+        );
+        assert_eq!(map.get(&9), Some(&90));
+        assert!(!map.contains_key(&1));
+        assert_eq!(map.len(), 4);
+    }
+}
@@ -0,0 +1,87 @@
+use crate::chunk::OpCode;
+use crate::result::{Error, Result};
+use crate::vm::{Flow, Vm};
+
+// Code compiled ahead of time for a function, see 'aot::build'. It is called
+// with the context, the table of helpers below and the offset of the
+// instruction to start at, and runs the function's instructions in order,
+// calling the helper for each opcode, until one of them returns something
+// other than 'NEXT', which the code returns in turn. Jumps are made natively,
+// with the 'FALSEY' helper deciding conditional ones.
+pub type NativeCode = unsafe extern "C" fn(*mut Context, *const Helper, usize) -> u32;
+
+// Execute the instruction at the given offset in the VM's current frame.
+type Helper = unsafe extern "C" fn(*mut Context, usize) -> u32;
+
+// What a helper returns: carry on with the next instruction, or return to
+// the VM because the current frame changed, the frame the VM was running has
+// returned, or an error was recorded in the context.
+pub const NEXT: u32 = 0;
+pub const SWITCH: u32 = 1;
+pub const EXIT: u32 = 2;
+pub const ERROR: u32 = 3;
+
+// The index of the helper returning one if the value on top of the stack is
+// falsey and zero otherwise. The helper of each opcode is at the index of
+// its byte.
+pub const FALSEY: usize = OpCode::ALL.len();
+
+pub struct Context {
+    vm: *mut Vm,
+    // The number of frames below the one 'Vm::run' is executing.
+    base: usize,
+    error: Option<Error>,
+}
+
+macro_rules! helpers {
+    ($($op:literal)*) => {
+        [$(execute::<$op> as Helper,)* falsey]
+    };
+}
+
+static HELPERS: [Helper; FALSEY + 1] = helpers!(
+    0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31 32 33
+    34 35 36 37 38 39 40 41 42 43 44
+);
+
+// Run the native 'code' of the frame on top of 'vm' from the instruction at
+// 'ip' until it makes a call or returns.
+//
+// # Safety
+//
+// 'code' must be the code compiled for the function of the frame and 'ip'
+// the start of one of its instructions.
+pub unsafe fn call(vm: &mut Vm, code: NativeCode, base: usize, ip: usize) -> Result<Flow> {
+    let mut context = Context {
+        vm,
+        base,
+        error: None,
+    };
+    match code(&mut context, HELPERS.as_ptr(), ip) {
+        EXIT => Ok(Flow::Exit),
+        ERROR => Err(context.error.expect("error recorded by a helper")),
+        _ => Ok(Flow::Switch),
+    }
+}
+
+unsafe extern "C" fn execute<const OP: usize>(context: *mut Context, ip: usize) -> u32 {
+    let context = &mut *context;
+    let vm = &mut *context.vm;
+    // The operands follow the opcode, where the interpreter would have read
+    // them from.
+    vm.jump_to(ip + 1);
+    match vm.execute(OpCode::ALL[OP], context.base) {
+        Ok(Flow::Next) => NEXT,
+        Ok(Flow::Switch) => SWITCH,
+        Ok(Flow::Exit) => EXIT,
+        Err(error) => {
+            context.error = Some(error);
+            ERROR
+        }
+    }
+}
+
+unsafe extern "C" fn falsey(context: *mut Context, _: usize) -> u32 {
+    let vm = &*(*context).vm;
+    u32::from(!vm.peek(0).is_truthy())
+}
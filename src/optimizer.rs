@@ -0,0 +1,690 @@
+use crate::ast::{Expression, FunctionDeclaration, LiteralValue, Statement};
+use crate::lexer::{OwnedToken, SourcePosition, TokenType};
+use crate::result::Error;
+use crate::symbol::Symbol;
+use std::rc::Rc;
+
+// Fold the expressions in 'statements' whose value is known without running
+// the program: arithmetic, comparisons and concatenation of literals, '!' and
+// '-' of literals, ternaries and logical operators with a literal condition,
+// and comma expressions whose left operand has no effect. The statements must
+// have been resolved, which tells local variables, whose reads can't fail,
+// from globals.
+//
+// Statements that can never run are then removed: those following a 'return'
+// or a 'while' loop with a truthy literal condition, which only a 'return'
+// can leave, and the branches an 'if' or 'while' with a literal condition
+// never takes. Each stretch of removed code is reported as a warning at the
+// first statement in it, which is returned along with the optimized
+// statements.
+//
+// The result behaves exactly like the original program. Operations that
+// would fail at runtime, such as '"a" - 1', are left in place so that the
+// error is still reported, at the same position. For the same reason no
+// algebraic identities such as 'x * 1' are applied: they don't hold when 'x'
+// isn't a number.
+pub fn optimize(
+    statements: Vec<Statement<OwnedToken>>,
+) -> (Vec<Statement<OwnedToken>>, Vec<Error>) {
+    let mut optimizer = Optimizer {
+        warnings: Vec::new(),
+    };
+    let statements = optimizer.statements(statements);
+    (statements, optimizer.warnings)
+}
+
+struct Optimizer {
+    warnings: Vec<Error>,
+}
+
+impl Optimizer {
+    fn statements(&mut self, statements: Vec<Statement<OwnedToken>>) -> Vec<Statement<OwnedToken>> {
+        let mut optimized = Vec::new();
+        let mut statements = statements.into_iter();
+
+        while let Some(statement) = statements.next() {
+            let Some(statement) = self.statement(statement) else {
+                continue;
+            };
+            let completes = completes(&statement);
+            optimized.push(statement);
+
+            if !completes {
+                self.unreachable(statements.as_slice());
+                break;
+            }
+        }
+
+        optimized
+    }
+
+    // Optimize 'statement', returning 'None' if nothing of it is left.
+    fn statement(&mut self, statement: Statement<OwnedToken>) -> Option<Statement<OwnedToken>> {
+        let statement = match statement {
+            Statement::Expression(expression) => Statement::Expression(Box::new(fold(*expression))),
+            Statement::Print {
+                keyword,
+                expression,
+            } => Statement::Print {
+                keyword,
+                expression: Box::new(fold(*expression)),
+            },
+            Statement::Var {
+                keyword,
+                name,
+                initializer,
+            } => Statement::Var {
+                keyword,
+                name,
+                initializer: initializer.map(|expression| Box::new(fold(*expression))),
+            },
+            Statement::Block(statements) => Statement::Block(self.statements(statements)),
+            Statement::If {
+                keyword,
+                condition,
+                then_branch,
+                else_branch,
+            } => match fold(*condition) {
+                Expression::Literal { value, .. } => {
+                    let (taken, skipped) = if is_truthy(&value) {
+                        (Some(then_branch), else_branch)
+                    } else {
+                        (else_branch, Some(then_branch))
+                    };
+                    if let Some(skipped) = skipped {
+                        self.unreachable(std::slice::from_ref(&*skipped));
+                    }
+                    return self.statement(*taken?);
+                }
+                condition => Statement::If {
+                    keyword,
+                    condition: Box::new(condition),
+                    then_branch: self.branch(*then_branch),
+                    else_branch: else_branch.map(|branch| self.branch(*branch)),
+                },
+            },
+            Statement::While {
+                keyword,
+                condition,
+                body,
+            } => match fold(*condition) {
+                Expression::Literal { value, .. } if !is_truthy(&value) => {
+                    self.unreachable(std::slice::from_ref(&*body));
+                    return None;
+                }
+                condition => Statement::While {
+                    keyword,
+                    condition: Box::new(condition),
+                    body: self.branch(*body),
+                },
+            },
+            Statement::Function {
+                keyword,
+                declaration,
+            } => Statement::Function {
+                keyword,
+                declaration: self.function(declaration),
+            },
+            Statement::Return { keyword, value } => Statement::Return {
+                keyword,
+                value: value.map(|expression| Box::new(fold(*expression))),
+            },
+            Statement::Class {
+                keyword,
+                name,
+                superclass,
+                methods,
+            } => Statement::Class {
+                keyword,
+                name,
+                superclass,
+                methods: methods
+                    .into_iter()
+                    .map(|method| self.function(method))
+                    .collect(),
+            },
+        };
+
+        Some(statement)
+    }
+
+    // Optimize the body of an 'if' or 'while', which has to be a statement
+    // even if nothing is left of it.
+    fn branch(&mut self, statement: Statement<OwnedToken>) -> Box<Statement<OwnedToken>> {
+        Box::new(
+            self.statement(statement)
+                .unwrap_or(Statement::Block(Vec::new())),
+        )
+    }
+
+    // Declarations are only shared once the program runs, so a declaration
+    // that is shared already is left as it is.
+    fn function(
+        &mut self,
+        declaration: Rc<FunctionDeclaration<OwnedToken>>,
+    ) -> Rc<FunctionDeclaration<OwnedToken>> {
+        match Rc::try_unwrap(declaration) {
+            Ok(declaration) => Rc::new(FunctionDeclaration {
+                name: declaration.name,
+                params: declaration.params,
+                body: self.statements(declaration.body),
+            }),
+            Err(shared) => shared,
+        }
+    }
+
+    // Warn that 'statements' can never run. Empty blocks have nothing to
+    // warn about.
+    fn unreachable(&mut self, statements: &[Statement<OwnedToken>]) {
+        if let Some(source_position) = statements.iter().find_map(position) {
+            self.warnings.push(Error::Warning {
+                message: "unreachable code".to_string(),
+                source_position,
+            });
+        }
+    }
+}
+
+// Whether running 'statement' can carry on to the statement after it. Lox
+// has no 'break', so only a 'return' leaves a loop whose condition is always
+// truthy.
+fn completes(statement: &Statement<OwnedToken>) -> bool {
+    match statement {
+        Statement::Return { .. } => false,
+        Statement::Block(statements) => statements.iter().all(completes),
+        Statement::If {
+            then_branch,
+            else_branch,
+            ..
+        } => completes(then_branch) || else_branch.as_deref().is_none_or(completes),
+        Statement::While { condition, .. } => !matches!(
+            &**condition,
+            Expression::Literal { value, .. } if is_truthy(value)
+        ),
+        _ => true,
+    }
+}
+
+// The position of the token 'statement' starts with. A block is reported at
+// its first statement, and an empty block has none.
+fn position(statement: &Statement<OwnedToken>) -> Option<SourcePosition> {
+    match statement {
+        Statement::Expression(expression) => Some(expression_position(expression)),
+        Statement::Print { keyword, .. }
+        | Statement::Var { keyword, .. }
+        | Statement::If { keyword, .. }
+        | Statement::While { keyword, .. }
+        | Statement::Function { keyword, .. }
+        | Statement::Return { keyword, .. }
+        | Statement::Class { keyword, .. } => Some(keyword.source_position),
+        Statement::Block(statements) => statements.iter().find_map(position),
+    }
+}
+
+// The position of the leftmost token of 'expression'.
+fn expression_position(expression: &Expression<OwnedToken>) -> SourcePosition {
+    match expression {
+        Expression::Literal { token, .. } => token.source_position,
+        Expression::Unary { operator, .. } => operator.source_position,
+        Expression::Binary { left, .. }
+        | Expression::Ternary { left, .. }
+        | Expression::Logical { left, .. } => expression_position(left),
+        Expression::Grouping(expression) => expression_position(expression),
+        Expression::Variable { name, .. } | Expression::Assign { name, .. } => name.source_position,
+        Expression::Call { callee, .. } => expression_position(callee),
+        Expression::Get { object, .. }
+        | Expression::Set { object, .. }
+        | Expression::Index { object, .. }
+        | Expression::Slice { object, .. }
+        | Expression::SetIndex { object, .. } => expression_position(object),
+        Expression::List { bracket, .. } => bracket.source_position,
+        Expression::Map { brace, .. } => brace.source_position,
+        Expression::This { keyword, .. } | Expression::Super { keyword, .. } => {
+            keyword.source_position
+        }
+    }
+}
+
+fn fold(expression: Expression<OwnedToken>) -> Expression<OwnedToken> {
+    match expression {
+        Expression::Grouping(inner) => match fold(*inner) {
+            literal @ Expression::Literal { .. } => literal,
+            inner => Expression::Grouping(Box::new(inner)),
+        },
+        Expression::Unary { operator, right } => {
+            let right = fold(*right);
+            let value = match (&operator.token_type, &right) {
+                (TokenType::Bang, Expression::Literal { value, .. }) => {
+                    Some(boolean(!is_truthy(value)))
+                }
+                (
+                    TokenType::Minus,
+                    Expression::Literal {
+                        value: LiteralValue::Number(n),
+                        ..
+                    },
+                ) => Some(LiteralValue::Number(-n)),
+                _ => None,
+            };
+
+            match value {
+                Some(value) => literal(value, &operator),
+                None => Expression::Unary {
+                    operator,
+                    right: Box::new(right),
+                },
+            }
+        }
+        Expression::Binary {
+            operator,
+            left,
+            right,
+        } => {
+            let left = fold(*left);
+            let right = fold(*right);
+
+            if operator.token_type == TokenType::Comma && is_pure(&left) {
+                return right;
+            }
+
+            let value = match (&left, &right) {
+                (
+                    Expression::Literal { value: left, .. },
+                    Expression::Literal { value: right, .. },
+                ) => binary(&operator.token_type, left, right),
+                _ => None,
+            };
+
+            match value {
+                Some(value) => literal(value, &operator),
+                None => Expression::Binary {
+                    operator,
+                    left: Box::new(left),
+                    right: Box::new(right),
+                },
+            }
+        }
+        Expression::Ternary {
+            operator,
+            left,
+            middle,
+            right,
+        } => match fold(*left) {
+            Expression::Literal { value, .. } if is_truthy(&value) => fold(*middle),
+            Expression::Literal { .. } => fold(*right),
+            left => Expression::Ternary {
+                operator,
+                left: Box::new(left),
+                middle: Box::new(fold(*middle)),
+                right: Box::new(fold(*right)),
+            },
+        },
+        Expression::Logical {
+            operator,
+            left,
+            right,
+        } => match fold(*left) {
+            // The result is the left operand if it decides the outcome and
+            // the right one otherwise.
+            Expression::Literal { value, token }
+                if is_truthy(&value) == (operator.token_type == TokenType::Or) =>
+            {
+                Expression::Literal { value, token }
+            }
+            Expression::Literal { .. } => fold(*right),
+            left => Expression::Logical {
+                operator,
+                left: Box::new(left),
+                right: Box::new(fold(*right)),
+            },
+        },
+        Expression::Assign { name, value, depth } => Expression::Assign {
+            name,
+            value: Box::new(fold(*value)),
+            depth,
+        },
+        Expression::Call {
+            callee,
+            paren,
+            arguments,
+        } => Expression::Call {
+            callee: Box::new(fold(*callee)),
+            paren,
+            arguments: arguments.into_iter().map(fold).collect(),
+        },
+        Expression::Get { object, name } => Expression::Get {
+            object: Box::new(fold(*object)),
+            name,
+        },
+        Expression::Set {
+            object,
+            name,
+            value,
+        } => Expression::Set {
+            object: Box::new(fold(*object)),
+            name,
+            value: Box::new(fold(*value)),
+        },
+        Expression::List { bracket, elements } => Expression::List {
+            bracket,
+            elements: elements.into_iter().map(fold).collect(),
+        },
+        Expression::Map { brace, entries } => Expression::Map {
+            brace,
+            entries: entries
+                .into_iter()
+                .map(|(key, value)| (fold(key), fold(value)))
+                .collect(),
+        },
+        Expression::Index {
+            object,
+            bracket,
+            index,
+        } => Expression::Index {
+            object: Box::new(fold(*object)),
+            bracket,
+            index: Box::new(fold(*index)),
+        },
+        Expression::Slice {
+            object,
+            bracket,
+            start,
+            end,
+        } => Expression::Slice {
+            object: Box::new(fold(*object)),
+            bracket,
+            start: Box::new(fold(*start)),
+            end: Box::new(fold(*end)),
+        },
+        Expression::SetIndex {
+            object,
+            bracket,
+            index,
+            value,
+        } => Expression::SetIndex {
+            object: Box::new(fold(*object)),
+            bracket,
+            index: Box::new(fold(*index)),
+            value: Box::new(fold(*value)),
+        },
+        expression @ (Expression::Literal { .. }
+        | Expression::Variable { .. }
+        | Expression::This { .. }
+        | Expression::Super { .. }) => expression,
+    }
+}
+
+// The value of 'left operator right' for literal operands, or 'None' if the
+// operation fails at runtime.
+fn binary(operator: &TokenType, left: &LiteralValue, right: &LiteralValue) -> Option<LiteralValue> {
+    use LiteralValue::{Number, String};
+
+    let value = match (operator, left, right) {
+        (TokenType::EqualEqual, _, _) => boolean(equal(left, right)),
+        (TokenType::BangEqual, _, _) => boolean(!equal(left, right)),
+        (TokenType::Plus, String(l), String(r)) => String(format!("{l}{r}")),
+        (TokenType::Plus, Number(l), Number(r)) => Number(l + r),
+        (TokenType::Minus, Number(l), Number(r)) => Number(l - r),
+        (TokenType::Asterisk, Number(l), Number(r)) => Number(l * r),
+        (TokenType::Slash, Number(l), Number(r)) => Number(l / r),
+        (TokenType::GreaterThan, Number(l), Number(r)) => boolean(l > r),
+        (TokenType::GreaterThanOrEqual, Number(l), Number(r)) => boolean(l >= r),
+        (TokenType::LessThan, Number(l), Number(r)) => boolean(l < r),
+        (TokenType::LessThanOrEqual, Number(l), Number(r)) => boolean(l <= r),
+        _ => return None,
+    };
+
+    Some(value)
+}
+
+// Evaluating 'expression' has no effect and can't fail.
+fn is_pure(expression: &Expression<OwnedToken>) -> bool {
+    match expression {
+        Expression::Literal { .. } | Expression::This { .. } => true,
+        Expression::Variable { depth, .. } => depth.get().is_some(),
+        Expression::Grouping(inner) => is_pure(inner),
+        _ => false,
+    }
+}
+
+// The same rules as 'Value::is_truthy'.
+fn is_truthy(value: &LiteralValue) -> bool {
+    !matches!(value, LiteralValue::Nil | LiteralValue::False)
+}
+
+// The same rules as 'Value::eq'.
+fn equal(left: &LiteralValue, right: &LiteralValue) -> bool {
+    match (left, right) {
+        (LiteralValue::Number(l), LiteralValue::Number(r)) => l == r,
+        (LiteralValue::String(l), LiteralValue::String(r)) => l == r,
+        (LiteralValue::True, LiteralValue::True)
+        | (LiteralValue::False, LiteralValue::False)
+        | (LiteralValue::Nil, LiteralValue::Nil) => true,
+        _ => false,
+    }
+}
+
+fn boolean(value: bool) -> LiteralValue {
+    if value {
+        LiteralValue::True
+    } else {
+        LiteralValue::False
+    }
+}
+
+// A literal replacing the operation at 'operator', which keeps the source
+// position of the instructions compiled for it.
+fn literal(value: LiteralValue, operator: &OwnedToken) -> Expression<OwnedToken> {
+    let token_type = match value {
+        LiteralValue::Number(n) => TokenType::Number(n),
+        LiteralValue::String(_) => TokenType::Str(""),
+        LiteralValue::True => TokenType::True,
+        LiteralValue::False => TokenType::False,
+        LiteralValue::Nil => TokenType::Nil,
+    };
+    let token = OwnedToken {
+        token_type,
+        lexeme: Symbol::intern(&value.to_string()),
+        source_position: operator.source_position,
+    };
+
+    Expression::Literal { value, token }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::Interpreter;
+    use crate::lexer::lex;
+    use crate::parser::{parse, parse_expression};
+    use crate::resolver::{resolve, resolve_expression};
+    use crate::testing::{corpus, Output};
+    use std::fs;
+
+    fn optimized(source: &str) -> String {
+        let tokens = lex(source).unwrap();
+        let expression = parse_expression(&tokens).unwrap();
+        resolve_expression(&expression).unwrap();
+        fold(*expression).to_string()
+    }
+
+    fn optimized_program(source: &str) -> String {
+        optimized_with_warnings(source).0
+    }
+
+    // The optimized program and the positions of the warnings reported.
+    fn optimized_with_warnings(source: &str) -> (String, Vec<SourcePosition>) {
+        let tokens = lex(source).unwrap();
+        let statements = parse(&tokens).unwrap();
+        resolve(&statements).unwrap();
+        let (statements, warnings) = optimize(statements);
+        let program = statements
+            .iter()
+            .map(|statement| statement.to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let positions = warnings
+            .into_iter()
+            .map(|warning| match warning {
+                Error::Warning {
+                    source_position, ..
+                } => source_position,
+                _ => panic!("Expected Warning"),
+            })
+            .collect();
+        (program, positions)
+    }
+
+    #[test]
+    fn folds_constants() {
+        assert_eq!(optimized("1 + 2 * (3 - 1) / 4"), "2");
+        assert_eq!(optimized("\"a\" + \"b\" + \"c\""), "abc");
+        assert_eq!(optimized("!(1 < 2) == false"), "true");
+        assert_eq!(optimized("-(1 + 1)"), "-2");
+        assert_eq!(optimized("!nil"), "true");
+        assert_eq!(optimized("1 == \"1\""), "false");
+        assert_eq!(optimized("1 / 0"), "inf");
+    }
+
+    #[test]
+    fn folds_control_flow() {
+        assert_eq!(optimized("true ? a : b"), "a");
+        assert_eq!(optimized("nil ? a : 1 + 1"), "2");
+        assert_eq!(optimized("x ? 1 + 1 : 2"), "(? x 2 2)");
+        assert_eq!(optimized("nil or \"default\""), "default");
+        assert_eq!(optimized("1 and f()"), "(call f)");
+        assert_eq!(optimized("false and f()"), "false");
+        assert_eq!(optimized("1, 2, 3"), "3");
+    }
+
+    #[test]
+    fn keeps_effects_and_errors() {
+        assert_eq!(optimized("f(), 2"), "(, (call f) 2)");
+        // Reading a global fails if it isn't defined.
+        assert_eq!(optimized("a, 2"), "(, a 2)");
+        assert_eq!(
+            optimized_program("fun f(a) { return (a, 1 + 1); }"),
+            "(fun f (a) (return 2))"
+        );
+        assert_eq!(optimized("\"a\" - 1"), "(- a 1)");
+        assert_eq!(optimized("-\"a\" + 1"), "(+ (- a) 1)");
+        assert_eq!(optimized("x * 1"), "(* x 1)");
+    }
+
+    #[test]
+    fn removes_unreachable_code() {
+        assert_eq!(
+            optimized_with_warnings("fun f() {\n  return 1;\n  print 2;\n  print 3;\n}"),
+            ("(fun f () (return 1))".to_string(), vec![(3, 3)])
+        );
+        assert_eq!(
+            optimized_with_warnings("if (false) print 1; else print 2;\nprint 3;"),
+            ("(print 2) (print 3)".to_string(), vec![(1, 12)])
+        );
+        assert_eq!(
+            optimized_with_warnings("if (1 < 2) { print 1; } else { x = 2; }"),
+            ("(block (print 1))".to_string(), vec![(1, 32)])
+        );
+        assert_eq!(
+            optimized_with_warnings("while (nil) { var a = 1; }\nprint 2;"),
+            ("(print 2)".to_string(), vec![(1, 15)])
+        );
+        // Only a 'return' leaves a loop whose condition is always truthy.
+        assert_eq!(
+            optimized_with_warnings("fun f() { while (true) { return 1; } print 2; }"),
+            (
+                "(fun f () (while true (block (return 1))))".to_string(),
+                vec![(1, 38)]
+            )
+        );
+        // Empty blocks don't need a warning.
+        assert_eq!(
+            optimized_with_warnings("if (false) {} else print 1;"),
+            ("(print 1)".to_string(), vec![])
+        );
+        assert_eq!(
+            optimized_with_warnings("fun f() { if (x) { return; } else return; print 1; }"),
+            (
+                "(fun f () (if x (block (return)) (return)))".to_string(),
+                vec![(1, 43)]
+            )
+        );
+        // Statements are reported at the keyword they start with.
+        assert_eq!(
+            optimized_with_warnings("fun f() { return; while (x) {} }"),
+            ("(fun f () (return))".to_string(), vec![(1, 19)])
+        );
+    }
+
+    #[test]
+    fn keeps_reachable_code() {
+        assert_eq!(
+            optimized_with_warnings("fun f() { if (x) return; print 1; }"),
+            ("(fun f () (if x (return)) (print 1))".to_string(), vec![])
+        );
+        assert_eq!(
+            optimized_with_warnings("fun f() { while (x) if (true) return; print 1; }"),
+            (
+                "(fun f () (while x (return)) (print 1))".to_string(),
+                vec![]
+            )
+        );
+        assert_eq!(
+            optimized_with_warnings("if (x) if (false) print 1;"),
+            ("(if x (block))".to_string(), vec![(1, 19)])
+        );
+    }
+
+    #[test]
+    fn errors_keep_their_position() {
+        let tokens = lex("print 1 + 2;\nprint (1 + 1) - \"a\";").unwrap();
+        let statements = parse(&tokens).unwrap();
+        resolve(&statements).unwrap();
+
+        let output = Output::default();
+        let mut interpreter = Interpreter::with_output(Box::new(output.clone()));
+        match interpreter.interpret(&optimize(statements).0) {
+            Err(Error::RuntimeError {
+                message,
+                source_position,
+                ..
+            }) => {
+                assert_eq!(message, "operands must be numbers");
+                assert_eq!(source_position, (2, 15));
+            }
+            _ => panic!("Expected RuntimeError"),
+        }
+        assert_eq!(output.contents(), "3\n");
+    }
+
+    // Optimizing the scripts in the corpus doesn't change what they print.
+    #[test]
+    fn corpus_runs_the_same() {
+        for file in corpus() {
+            let source = fs::read_to_string(&file).unwrap();
+            let Ok(tokens) = lex(&source) else {
+                continue;
+            };
+            let Ok(statements) = parse(&tokens) else {
+                continue;
+            };
+            if resolve(&statements).is_err() {
+                continue;
+            }
+            let expected = transcript(&statements);
+
+            assert_eq!(
+                transcript(&optimize(statements).0),
+                expected,
+                "optimizing changed the output of {}",
+                file.display()
+            );
+        }
+    }
+
+    fn transcript(statements: &[Statement<OwnedToken>]) -> String {
+        let output = Output::default();
+        let result = Interpreter::with_output(Box::new(output.clone())).interpret(statements);
+        output.transcript(result)
+    }
+}
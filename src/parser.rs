@@ -1,8 +1,30 @@
 // GRAMMAR
+// program     → declaration* EOF
+//
+// declaration → classDecl | funDecl | varDecl | statement
+// classDecl   → "class" IDENTIFIER ( "<" IDENTIFIER )? "{" function* "}"
+// funDecl     → "fun" function
+// function    → IDENTIFIER "(" parameters? ")" block
+// parameters  → IDENTIFIER ( "," IDENTIFIER )*
+// varDecl     → "var" IDENTIFIER ( "=" expression )? ";"
+//
+// statement   → exprStmt | forStmt | ifStmt | printStmt | returnStmt
+//             | whileStmt | block
+// exprStmt    → expression ";"
+// forStmt     → "for" "(" ( varDecl | exprStmt | ";" ) expression? ";"
+//               expression? ")" statement
+// ifStmt      → "if" "(" expression ")" statement ( "else" statement )?
+// printStmt   → "print" expression ";"
+// returnStmt  → "return" expression? ";"
+// whileStmt   → "while" "(" expression ")" statement
+// block       → "{" declaration* "}"
+//
 // expression → literal
 //            | unary
 //            | binary
 //            | grouping
+//            | assignment
+//            | call
 //
 // literal    → NUMBER | STRING | "true" | "false" | "nil"
 // grouping   → "(" expression ")"
@@ -10,112 +32,684 @@
 // binary     → expression operator expression
 // operator   → "==" | "!=" | "<" | "<=" | ">" | ">="
 //            | "+" | "-"  | "*" | "/" | "," | "?"
-//            | ":"
+//            | ":" | "and" | "or"
+// assignment → ( call "." )? IDENTIFIER "=" expression
+// call       → expression ( "(" arguments? ")" | "." IDENTIFIER )
 //
 // PRECEDENCE (Lowest to highest)
 // Name         Operators   Associates
 // ----         ---------   ----------
 // Comma        ,           Left
+// Assignment   =           Right
 // Ternary      ? :         Right
+// Or           or          Left
+// And          and         Left
 // Equality     == !=       Left
 // Comparison   > >= < <=   Left
 // Term         - +         Left
 // Factor       / *         Left
 // Unary        ! -         Right
+// Call         () .        Left
 //
 // STRATIFIED GRAMMAR
 // expression → comma
-// comma      → ternary ( "," ternary )*
-// ternary    → ( equality "?" ternary ":" ternary ) | equality
+// comma      → assignment ( "," assignment )*
+// assignment → ( call "." )? IDENTIFIER "=" assignment | ternary
+// ternary    → ( or "?" ternary ":" ternary ) | or
+// or         → and ( "or" and )*
+// and        → equality ( "and" equality )*
 // equality   → comparison ( ( "==" | "!=" ) comparison )*
 // comparison → term ( ( ">" | ">=" | "<" | "<=" ) term )*
 // term       → factor ( ( "+" | "-" ) factor )*
 // factor     → unary ( ( "*" | "/" ) unary )*
-// unary      → ( "-" | "!" ) unary | primary
-// primary    → NUMBER | STRING | "true" | "false" | "nil" | "(" expression ")"
+// unary      → ( "-" | "!" ) unary | call
+// call       → primary ( "(" arguments? ")" | "." IDENTIFIER )*
+// arguments  → assignment ( "," assignment )*
+// primary    → NUMBER | STRING | "true" | "false" | "nil" | "this"
+//            | IDENTIFIER | "(" expression ")" | "super" "." IDENTIFIER
 
-use crate::ast::{Expression, LiteralValue};
-use crate::lexer::{Token, TokenType};
+use crate::ast::{Depth, Expression, FunctionDeclaration, LiteralValue, Statement};
+use crate::lexer::{OwnedToken, Token, TokenType};
 use crate::result::Error;
 use std::iter::Peekable;
+use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
+
+// The maximum number of parameters a function can declare, and arguments a
+// call can pass.
+pub const MAX_ARGUMENTS: usize = 255;
+
+// The maximum depth of nested calls a script can make unless configured
+// otherwise. This matches the number of call frames of clox.
+pub const DEFAULT_MAX_DEPTH: usize = 64;
+
+// How deeply expressions and statements can be nested. Parsing and the passes
+// over the syntax tree recurse natively, so this is bounded by the stack that
+// 'loxi::stack_size' reserves for each level rather than by the call depth.
+pub const MAX_NESTING: usize = 1024;
+
+// How deeply expressions and statements can be nested in a script allowed to
+// nest calls 'max_depth' deep, which is never less than 'MAX_NESTING'.
+pub fn max_nesting(max_depth: usize) -> usize {
+    max_depth.max(MAX_NESTING)
+}
+
+pub type Result = crate::result::Result<Box<Expression<OwnedToken>>>;
+pub type StatementResult = crate::result::Result<Statement<OwnedToken>>;
+
+// The tokens being parsed, how deeply the construct being parsed is nested,
+// counting the chains of operators and calls leading to it, see 'link', and
+// how many 'else if' branches lead to it. Both are bounded so that a
+// deeply nested program is reported as an error instead of overflowing the
+// native stack. Once it has been, 'too_deep' is set and parsing stops, as the
+// tokens left can't be made sense of.
+struct Parser<I: Iterator> {
+    iter: Peekable<I>,
+    nesting: usize,
+    else_ifs: usize,
+    max_nesting: usize,
+    too_deep: bool,
+}
+
+impl<I: Iterator> Parser<I> {
+    fn new(iter: I, max_nesting: usize) -> Self {
+        Parser {
+            iter: iter.peekable(),
+            nesting: 0,
+            else_ifs: 0,
+            max_nesting,
+            too_deep: false,
+        }
+    }
+}
+
+impl<I: Iterator> Deref for Parser<I> {
+    type Target = Peekable<I>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.iter
+    }
+}
+
+impl<I: Iterator> DerefMut for Parser<I> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.iter
+    }
+}
+
+// Parse a whole program, see 'parse_with_max_nesting'.
+pub fn parse<'a>(tokens: &'a [Token<'a>]) -> crate::result::Result<Vec<Statement<OwnedToken>>> {
+    parse_with_max_nesting(tokens, MAX_NESTING)
+}
+
+// Parse a whole program whose expressions and statements are nested at most
+// 'max_nesting' deep. Parsing continues past errors other than too deep
+// nesting so that all of them can be reported together.
+pub fn parse_with_max_nesting<'a>(
+    tokens: &'a [Token<'a>],
+    max_nesting: usize,
+) -> crate::result::Result<Vec<Statement<OwnedToken>>> {
+    let mut iter = Parser::new(tokens.iter(), max_nesting);
+    let mut statements = Vec::new();
+    let mut errors = Vec::new();
+
+    while !is_at_end(&mut iter) {
+        match declaration(&mut iter) {
+            Ok(statement) => statements.push(statement),
+            Err(error) => {
+                errors.push(error);
+                if iter.too_deep {
+                    break;
+                }
+                // A chain the error cut short may not have restored the
+                // nesting.
+                iter.nesting = 0;
+                synchronize(&mut iter);
+            }
+        }
+    }
+
+    match errors.len() {
+        0 => Ok(statements),
+        1 => Err(errors.remove(0)),
+        _ => Err(Error::MultipleErrors(errors)),
+    }
+}
+
+// Parse a single expression spanning all of the given 'tokens', see
+// 'parse_expression_with_max_nesting'.
+pub fn parse_expression<'a>(tokens: &'a [Token<'a>]) -> Result {
+    parse_expression_with_max_nesting(tokens, MAX_NESTING)
+}
+
+// Parse a single expression spanning all of the given 'tokens', nested at most
+// 'max_nesting' deep.
+pub fn parse_expression_with_max_nesting<'a>(
+    tokens: &'a [Token<'a>],
+    max_nesting: usize,
+) -> Result {
+    let mut iter = Parser::new(tokens.iter(), max_nesting);
+    let expr = expression(&mut iter)?;
+
+    match iter.peek() {
+        Some(&token) if token.token_type != TokenType::Eof => Err(Error::ParseError {
+            message: format!("unexpected '{}' after expression", token.lexeme),
+            source_position: token.source_position,
+        }),
+        _ => Ok(expr),
+    }
+}
+
+fn declaration<'a, I>(iter: &mut Parser<I>) -> StatementResult
+where
+    I: Iterator<Item = &'a Token<'a>> + Clone,
+{
+    if let Some(keyword) = match_token(iter, TokenType::Class) {
+        class_declaration(iter, keyword)
+    } else if let Some(keyword) = match_token(iter, TokenType::Fun) {
+        Ok(Statement::Function {
+            keyword: OwnedToken::from(keyword),
+            declaration: Rc::new(function(iter, "function")?),
+        })
+    } else if let Some(keyword) = match_token(iter, TokenType::Var) {
+        var_declaration(iter, keyword)
+    } else {
+        statement(iter)
+    }
+}
+
+fn class_declaration<'a, I>(iter: &mut Parser<I>, keyword: &Token) -> StatementResult
+where
+    I: Iterator<Item = &'a Token<'a>> + Clone,
+{
+    let name = consume(iter, TokenType::Identifier, "expected class name")?;
 
-pub type Result<'a> = crate::result::Result<Box<Expression<Token<'a>>>>;
+    let superclass = if match_token(iter, TokenType::LessThan).is_some() {
+        let superclass = consume(iter, TokenType::Identifier, "expected superclass name")?;
+        Some(Box::new(Expression::Variable {
+            name: OwnedToken::from(superclass),
+            depth: Depth::default(),
+        }))
+    } else {
+        None
+    };
+
+    consume(iter, TokenType::LeftBrace, "expected '{' before class body")?;
+
+    let mut methods = Vec::new();
+    while !is_at_end(iter) && !check(iter, TokenType::RightBrace) {
+        methods.push(Rc::new(function(iter, "method")?));
+    }
 
-pub fn parse<'a>(tokens: &'a [Token]) -> Result<'a> {
-    let mut iter = tokens.iter().peekable();
+    consume(iter, TokenType::RightBrace, "expected '}' after class body")?;
 
-    expression(&mut iter)
+    Ok(Statement::Class {
+        keyword: OwnedToken::from(keyword),
+        name: OwnedToken::from(name),
+        superclass,
+        methods,
+    })
 }
 
-fn expression<'a, I>(iter: &mut Peekable<I>) -> Result<'a>
+// Parse the name, parameters and body of a function or method of the given
+// 'kind'.
+fn function<'a, I>(
+    iter: &mut Parser<I>,
+    kind: &str,
+) -> crate::result::Result<FunctionDeclaration<OwnedToken>>
+where
+    I: Iterator<Item = &'a Token<'a>> + Clone,
+{
+    let name = consume(
+        iter,
+        TokenType::Identifier,
+        &format!("expected {kind} name"),
+    )?;
+    consume(
+        iter,
+        TokenType::LeftParen,
+        &format!("expected '(' after {kind} name"),
+    )?;
+
+    let mut params = Vec::new();
+    if !check(iter, TokenType::RightParen) {
+        loop {
+            let param = consume(iter, TokenType::Identifier, "expected parameter name")?;
+            if params.len() >= MAX_ARGUMENTS {
+                return Err(Error::ParseError {
+                    message: format!("can't have more than {MAX_ARGUMENTS} parameters"),
+                    source_position: param.source_position,
+                });
+            }
+            params.push(OwnedToken::from(param));
+
+            if match_token(iter, TokenType::Comma).is_none() {
+                break;
+            }
+        }
+    }
+    consume(iter, TokenType::RightParen, "expected ')' after parameters")?;
+
+    consume(
+        iter,
+        TokenType::LeftBrace,
+        &format!("expected '{{' before {kind} body"),
+    )?;
+    let body = block(iter)?;
+
+    Ok(FunctionDeclaration {
+        name: OwnedToken::from(name),
+        params,
+        body,
+    })
+}
+
+fn var_declaration<'a, I>(iter: &mut Parser<I>, keyword: &Token) -> StatementResult
+where
+    I: Iterator<Item = &'a Token<'a>>,
+{
+    let name = consume(iter, TokenType::Identifier, "expected variable name")?;
+
+    let initializer = if match_token(iter, TokenType::Equal).is_some() {
+        Some(expression(iter)?)
+    } else {
+        None
+    };
+
+    consume(
+        iter,
+        TokenType::Semicolon,
+        "expected ';' after variable declaration",
+    )?;
+
+    Ok(Statement::Var {
+        keyword: OwnedToken::from(keyword),
+        name: OwnedToken::from(name),
+        initializer,
+    })
+}
+
+fn statement<'a, I>(iter: &mut Parser<I>) -> StatementResult
+where
+    I: Iterator<Item = &'a Token<'a>> + Clone,
+{
+    if let Some(keyword) = match_token(iter, TokenType::For) {
+        for_statement(iter, keyword)
+    } else if let Some(keyword) = match_token(iter, TokenType::If) {
+        if_statement(iter, keyword)
+    } else if let Some(keyword) = match_token(iter, TokenType::Print) {
+        let value = expression(iter)?;
+        consume(iter, TokenType::Semicolon, "expected ';' after value")?;
+
+        Ok(Statement::Print {
+            keyword: OwnedToken::from(keyword),
+            expression: value,
+        })
+    } else if let Some(keyword) = match_token(iter, TokenType::Return) {
+        let value = if check(iter, TokenType::Semicolon) {
+            None
+        } else {
+            Some(expression(iter)?)
+        };
+        consume(
+            iter,
+            TokenType::Semicolon,
+            "expected ';' after return value",
+        )?;
+
+        Ok(Statement::Return {
+            keyword: OwnedToken::from(keyword),
+            value,
+        })
+    } else if let Some(keyword) = match_token(iter, TokenType::While) {
+        consume(iter, TokenType::LeftParen, "expected '(' after 'while'")?;
+        let condition = expression(iter)?;
+        consume(iter, TokenType::RightParen, "expected ')' after condition")?;
+
+        Ok(Statement::While {
+            keyword: OwnedToken::from(keyword),
+            condition,
+            body: Box::new(nested(iter, statement)?),
+        })
+    } else if check(iter, TokenType::LeftBrace) && !starts_map(iter) {
+        iter.next();
+        Ok(Statement::Block(block(iter)?))
+    } else {
+        let expr = expression(iter)?;
+        consume(iter, TokenType::Semicolon, "expected ';' after expression")?;
+
+        Ok(Statement::Expression(expr))
+    }
+}
+
+// A for loop is desugared into a while loop starting with its 'for', wrapped
+// in a block that scopes the initializer.
+fn for_statement<'a, I>(iter: &mut Parser<I>, keyword: &Token) -> StatementResult
+where
+    I: Iterator<Item = &'a Token<'a>> + Clone,
+{
+    consume(iter, TokenType::LeftParen, "expected '(' after 'for'")?;
+
+    let initializer = if match_token(iter, TokenType::Semicolon).is_some() {
+        None
+    } else if let Some(keyword) = match_token(iter, TokenType::Var) {
+        Some(var_declaration(iter, keyword)?)
+    } else {
+        let expr = expression(iter)?;
+        consume(iter, TokenType::Semicolon, "expected ';' after expression")?;
+        Some(Statement::Expression(expr))
+    };
+
+    // A missing condition is always true.
+    let condition = if let Some(&semicolon) = iter
+        .peek()
+        .filter(|token| token.token_type == TokenType::Semicolon)
+    {
+        literal(LiteralValue::True, semicolon)
+    } else {
+        expression(iter)?
+    };
+    consume(
+        iter,
+        TokenType::Semicolon,
+        "expected ';' after loop condition",
+    )?;
+
+    let increment = if check(iter, TokenType::RightParen) {
+        None
+    } else {
+        Some(expression(iter)?)
+    };
+    consume(
+        iter,
+        TokenType::RightParen,
+        "expected ')' after for clauses",
+    )?;
+
+    let mut body = nested(iter, statement)?;
+
+    if let Some(increment) = increment {
+        body = Statement::Block(vec![body, Statement::Expression(increment)]);
+    }
+
+    body = Statement::While {
+        keyword: OwnedToken::from(keyword),
+        condition,
+        body: Box::new(body),
+    };
+
+    if let Some(initializer) = initializer {
+        body = Statement::Block(vec![initializer, body]);
+    }
+
+    Ok(body)
+}
+
+fn if_statement<'a, I>(iter: &mut Parser<I>, keyword: &'a Token<'a>) -> StatementResult
+where
+    I: Iterator<Item = &'a Token<'a>> + Clone,
+{
+    let else_ifs = iter.else_ifs;
+    let statement = if_chain(iter, keyword);
+    iter.else_ifs = else_ifs;
+    statement
+}
+
+// Parse an 'if' statement and the 'else if' branches following it. They are
+// parsed in turn rather than each inside the one before, so a long chain isn't
+// taken for deep nesting. The passes over the syntax tree still recurse
+// through the chain, though far less deeply than through nesting, so chains
+// are bounded separately.
+fn if_chain<'a, I>(iter: &mut Parser<I>, mut keyword: &'a Token<'a>) -> StatementResult
+where
+    I: Iterator<Item = &'a Token<'a>> + Clone,
+{
+    let mut branches = Vec::new();
+    let else_branch = loop {
+        consume(iter, TokenType::LeftParen, "expected '(' after 'if'")?;
+        let condition = expression(iter)?;
+        consume(
+            iter,
+            TokenType::RightParen,
+            "expected ')' after if condition",
+        )?;
+        branches.push((
+            OwnedToken::from(keyword),
+            condition,
+            Box::new(nested(iter, statement)?),
+        ));
+
+        if match_token(iter, TokenType::Else).is_none() {
+            break None;
+        }
+        match match_token(iter, TokenType::If) {
+            Some(token) => keyword = token,
+            None => break Some(Box::new(nested(iter, statement)?)),
+        }
+        if iter.else_ifs >= iter.max_nesting {
+            return too_deep(iter, "too many 'else if' branches");
+        }
+        iter.else_ifs += 1;
+    };
+
+    let (keyword, condition, then_branch) = branches.remove(0);
+    let else_branch = branches.into_iter().rev().fold(
+        else_branch,
+        |else_branch, (keyword, condition, then_branch)| {
+            Some(Box::new(Statement::If {
+                keyword,
+                condition,
+                then_branch,
+                else_branch,
+            }))
+        },
+    );
+
+    Ok(Statement::If {
+        keyword,
+        condition,
+        then_branch,
+        else_branch,
+    })
+}
+
+// Whether the '{' about to be parsed starts a map literal rather than a block,
+// which is the case when its first key is a single token followed by ':', as
+// in '{"a": 1}'. No statement can start that way.
+fn starts_map<'a, I>(iter: &Parser<I>) -> bool
+where
+    I: Iterator<Item = &'a Token<'a>> + Clone,
+{
+    let mut tokens = iter.iter.clone().skip(2);
+    matches!(tokens.next(), Some(token) if token.token_type == TokenType::Colon)
+}
+
+// Parse the declarations of a block whose '{' has already been consumed.
+fn block<'a, I>(iter: &mut Parser<I>) -> crate::result::Result<Vec<Statement<OwnedToken>>>
+where
+    I: Iterator<Item = &'a Token<'a>> + Clone,
+{
+    nested(iter, block_declarations)
+}
+
+fn block_declarations<'a, I>(
+    iter: &mut Parser<I>,
+) -> crate::result::Result<Vec<Statement<OwnedToken>>>
+where
+    I: Iterator<Item = &'a Token<'a>> + Clone,
+{
+    let mut statements = Vec::new();
+
+    while !is_at_end(iter) && !check(iter, TokenType::RightBrace) {
+        statements.push(declaration(iter)?);
+    }
+
+    consume(iter, TokenType::RightBrace, "expected '}' after block")?;
+
+    Ok(statements)
+}
+
+fn expression<'a, I>(iter: &mut Parser<I>) -> Result
 where
     I: Iterator<Item = &'a Token<'a>>,
 {
     comma(iter)
 }
 
-fn comma<'a, I>(iter: &mut Peekable<I>) -> Result<'a>
+fn comma<'a, I>(iter: &mut Parser<I>) -> Result
 where
     I: Iterator<Item = &'a Token<'a>>,
 {
-    let mut expr = ternary(iter)?;
+    let nesting = iter.nesting;
+    let mut expr = assignment(iter)?;
 
-    while let Some(&token) = match_token(iter, TokenType::Comma) {
+    while let Some(token) = match_token(iter, TokenType::Comma) {
+        link(iter)?;
         expr = Box::new(Expression::Binary {
-            operator: token,
+            operator: OwnedToken::from(token),
             left: expr,
-            right: ternary(iter)?,
+            right: assignment(iter)?,
         });
     }
 
+    iter.nesting = nesting;
     Ok(expr)
 }
 
-fn ternary<'a, I>(iter: &mut Peekable<I>) -> Result<'a>
+fn assignment<'a, I>(iter: &mut Parser<I>) -> Result
 where
     I: Iterator<Item = &'a Token<'a>>,
 {
-    let mut expr = equality(iter)?;
+    nested(iter, assignment_inner)
+}
 
-    if let Some(&token) = match_token(iter, TokenType::QuestionMark) {
-        let then_expr = ternary(iter)?;
+fn assignment_inner<'a, I>(iter: &mut Parser<I>) -> Result
+where
+    I: Iterator<Item = &'a Token<'a>>,
+{
+    let expr = ternary(iter)?;
 
-        if let Some(_) = match_token(iter, TokenType::Colon) {
-            let else_expr = ternary(iter)?;
+    if let Some(equals) = match_token(iter, TokenType::Equal) {
+        let value = assignment(iter)?;
 
-            expr = Box::new(Expression::Ternary {
-                operator: token,
-                left: expr,
-                middle: then_expr,
-                right: else_expr,
-            })
-        }
+        return match *expr {
+            Expression::Variable { name, .. } => Ok(Box::new(Expression::Assign {
+                name,
+                value,
+                depth: Depth::default(),
+            })),
+            Expression::Get { object, name } => Ok(Box::new(Expression::Set {
+                object,
+                name,
+                value,
+            })),
+            Expression::Index {
+                object,
+                bracket,
+                index,
+            } => Ok(Box::new(Expression::SetIndex {
+                object,
+                bracket,
+                index,
+                value,
+            })),
+            _ => Err(Error::ParseError {
+                message: "invalid assignment target".to_string(),
+                source_position: equals.source_position,
+            }),
+        };
+    }
+
+    Ok(expr)
+}
+
+fn ternary<'a, I>(iter: &mut Parser<I>) -> Result
+where
+    I: Iterator<Item = &'a Token<'a>>,
+{
+    let mut expr = or(iter)?;
+
+    if let Some(token) = match_token(iter, TokenType::QuestionMark) {
+        let then_expr = nested(iter, ternary)?;
+        consume(iter, TokenType::Colon, "expected ':' in ternary expression")?;
+        let else_expr = nested(iter, ternary)?;
+
+        expr = Box::new(Expression::Ternary {
+            operator: OwnedToken::from(token),
+            left: expr,
+            middle: then_expr,
+            right: else_expr,
+        })
+    }
+
+    Ok(expr)
+}
+
+fn or<'a, I>(iter: &mut Parser<I>) -> Result
+where
+    I: Iterator<Item = &'a Token<'a>>,
+{
+    let nesting = iter.nesting;
+    let mut expr = and(iter)?;
+
+    while let Some(token) = match_token(iter, TokenType::Or) {
+        link(iter)?;
+        expr = Box::new(Expression::Logical {
+            operator: OwnedToken::from(token),
+            left: expr,
+            right: and(iter)?,
+        });
+    }
+
+    iter.nesting = nesting;
+    Ok(expr)
+}
+
+fn and<'a, I>(iter: &mut Parser<I>) -> Result
+where
+    I: Iterator<Item = &'a Token<'a>>,
+{
+    let nesting = iter.nesting;
+    let mut expr = equality(iter)?;
+
+    while let Some(token) = match_token(iter, TokenType::And) {
+        link(iter)?;
+        expr = Box::new(Expression::Logical {
+            operator: OwnedToken::from(token),
+            left: expr,
+            right: equality(iter)?,
+        });
     }
 
+    iter.nesting = nesting;
     Ok(expr)
 }
 
-fn equality<'a, I>(iter: &mut Peekable<I>) -> Result<'a>
+fn equality<'a, I>(iter: &mut Parser<I>) -> Result
 where
     I: Iterator<Item = &'a Token<'a>>,
 {
+    let nesting = iter.nesting;
     let mut expr = comparison(iter)?;
 
-    while let Some(&token) = match_token_any(iter, &[TokenType::BangEqual, TokenType::EqualEqual]) {
+    while let Some(token) = match_token_any(iter, &[TokenType::BangEqual, TokenType::EqualEqual]) {
+        link(iter)?;
         expr = Box::new(Expression::Binary {
-            operator: token,
+            operator: OwnedToken::from(token),
             left: expr,
             right: comparison(iter)?,
         });
     }
 
+    iter.nesting = nesting;
     Ok(expr)
 }
 
-fn comparison<'a, I>(iter: &mut Peekable<I>) -> Result<'a>
+fn comparison<'a, I>(iter: &mut Parser<I>) -> Result
 where
     I: Iterator<Item = &'a Token<'a>>,
 {
+    let nesting = iter.nesting;
     let mut expr = term(iter)?;
 
     let tokens_to_match = [
@@ -125,66 +719,189 @@ where
         TokenType::LessThanOrEqual,
     ];
 
-    while let Some(&token) = match_token_any(iter, &tokens_to_match) {
+    while let Some(token) = match_token_any(iter, &tokens_to_match) {
+        link(iter)?;
         expr = Box::new(Expression::Binary {
-            operator: token,
+            operator: OwnedToken::from(token),
             left: expr,
             right: term(iter)?,
         });
     }
 
+    iter.nesting = nesting;
     Ok(expr)
 }
 
-fn term<'a, I>(iter: &mut Peekable<I>) -> Result<'a>
+fn term<'a, I>(iter: &mut Parser<I>) -> Result
 where
     I: Iterator<Item = &'a Token<'a>>,
 {
+    let nesting = iter.nesting;
     let mut expr = factor(iter)?;
 
-    while let Some(&token) = match_token_any(iter, &[TokenType::Plus, TokenType::Minus]) {
+    while let Some(token) = match_token_any(iter, &[TokenType::Plus, TokenType::Minus]) {
+        link(iter)?;
         expr = Box::new(Expression::Binary {
-            operator: token,
+            operator: OwnedToken::from(token),
             left: expr,
             right: factor(iter)?,
         })
     }
 
+    iter.nesting = nesting;
     Ok(expr)
 }
 
-fn factor<'a, I>(iter: &mut Peekable<I>) -> Result<'a>
+fn factor<'a, I>(iter: &mut Parser<I>) -> Result
 where
     I: Iterator<Item = &'a Token<'a>>,
 {
+    let nesting = iter.nesting;
     let mut expr = unary(iter)?;
 
-    while let Some(&token) = match_token_any(iter, &[TokenType::Asterisk, TokenType::Slash]) {
+    while let Some(token) = match_token_any(iter, &[TokenType::Asterisk, TokenType::Slash]) {
+        link(iter)?;
         expr = Box::new(Expression::Binary {
-            operator: token,
+            operator: OwnedToken::from(token),
             left: expr,
             right: unary(iter)?,
         });
     }
 
+    iter.nesting = nesting;
     Ok(expr)
 }
 
-fn unary<'a, I>(iter: &mut Peekable<I>) -> Result<'a>
+fn unary<'a, I>(iter: &mut Parser<I>) -> Result
 where
     I: Iterator<Item = &'a Token<'a>>,
 {
-    if let Some(&token) = match_token_any(iter, &[TokenType::Bang, TokenType::Minus]) {
+    if let Some(token) = match_token_any(iter, &[TokenType::Bang, TokenType::Minus]) {
         Ok(Box::new(Expression::Unary {
-            operator: token,
-            right: unary(iter)?,
+            operator: OwnedToken::from(token),
+            right: nested(iter, unary)?,
         }))
     } else {
-        primary(iter)
+        call(iter)
+    }
+}
+
+fn call<'a, I>(iter: &mut Parser<I>) -> Result
+where
+    I: Iterator<Item = &'a Token<'a>>,
+{
+    let nesting = iter.nesting;
+    let mut expr = primary(iter)?;
+
+    loop {
+        if match_token(iter, TokenType::LeftParen).is_some() {
+            link(iter)?;
+            expr = finish_call(iter, expr)?;
+        } else if match_token(iter, TokenType::Dot).is_some() {
+            link(iter)?;
+            let name = consume(
+                iter,
+                TokenType::Identifier,
+                "expected property name after '.'",
+            )?;
+            expr = Box::new(Expression::Get {
+                object: expr,
+                name: OwnedToken::from(name),
+            });
+        } else if let Some(bracket) = match_token(iter, TokenType::LeftBracket) {
+            link(iter)?;
+            expr = finish_index(iter, expr, bracket)?;
+        } else {
+            break;
+        }
+    }
+
+    iter.nesting = nesting;
+    Ok(expr)
+}
+
+// Parse the arguments of a call to 'callee' whose '(' has already been
+// consumed. Arguments are parsed above the comma operator so that ',' separates
+// them.
+fn finish_call<'a, I>(iter: &mut Parser<I>, callee: Box<Expression<OwnedToken>>) -> Result
+where
+    I: Iterator<Item = &'a Token<'a>>,
+{
+    let mut arguments = Vec::new();
+
+    if !check(iter, TokenType::RightParen) {
+        loop {
+            if arguments.len() >= MAX_ARGUMENTS {
+                let position = iter.peek().map_or((0, 0), |token| token.source_position);
+                return Err(Error::ParseError {
+                    message: format!("can't have more than {MAX_ARGUMENTS} arguments"),
+                    source_position: position,
+                });
+            }
+            arguments.push(*assignment(iter)?);
+
+            if match_token(iter, TokenType::Comma).is_none() {
+                break;
+            }
+        }
+    }
+
+    let paren = consume(iter, TokenType::RightParen, "expected ')' after arguments")?;
+
+    Ok(Box::new(Expression::Call {
+        callee,
+        paren: OwnedToken::from(paren),
+        arguments,
+    }))
+}
+
+// Parse an index or a slice of 'object' whose '[' has already been consumed.
+fn finish_index<'a, I>(
+    iter: &mut Parser<I>,
+    object: Box<Expression<OwnedToken>>,
+    bracket: &'a Token<'a>,
+) -> Result
+where
+    I: Iterator<Item = &'a Token<'a>>,
+{
+    let start = match iter.peek() {
+        Some(&token) if token.token_type == TokenType::Colon => literal(LiteralValue::Nil, token),
+        _ => expression(iter)?,
+    };
+
+    if match_token(iter, TokenType::Colon).is_none() {
+        consume(iter, TokenType::RightBracket, "expected ']' after index")?;
+        return Ok(Box::new(Expression::Index {
+            object,
+            bracket: OwnedToken::from(bracket),
+            index: start,
+        }));
     }
+
+    let end = match iter.peek() {
+        Some(&token) if token.token_type == TokenType::RightBracket => {
+            literal(LiteralValue::Nil, token)
+        }
+        _ => expression(iter)?,
+    };
+    consume(iter, TokenType::RightBracket, "expected ']' after slice")?;
+
+    Ok(Box::new(Expression::Slice {
+        object,
+        bracket: OwnedToken::from(bracket),
+        start,
+        end,
+    }))
+}
+
+fn literal(value: LiteralValue, token: &Token) -> Box<Expression<OwnedToken>> {
+    Box::new(Expression::Literal {
+        value,
+        token: OwnedToken::from(token),
+    })
 }
 
-fn primary<'a, I>(iter: &mut Peekable<I>) -> Result<'a>
+fn primary<'a, I>(iter: &mut Parser<I>) -> Result
 where
     I: Iterator<Item = &'a Token<'a>>,
 {
@@ -198,25 +915,52 @@ where
     match token.token_type {
         TokenType::Number(n) => {
             iter.next();
-            Ok(Box::new(Expression::Literal(LiteralValue::Number(n))))
+            Ok(literal(LiteralValue::Number(n), token))
         }
         TokenType::Str(s) => {
             iter.next();
-            Ok(Box::new(Expression::Literal(LiteralValue::String(
-                s.to_string(),
-            ))))
+            Ok(literal(LiteralValue::String(s.to_string()), token))
         }
         TokenType::True => {
             iter.next();
-            Ok(Box::new(Expression::Literal(LiteralValue::True)))
+            Ok(literal(LiteralValue::True, token))
         }
         TokenType::False => {
             iter.next();
-            Ok(Box::new(Expression::Literal(LiteralValue::False)))
+            Ok(literal(LiteralValue::False, token))
         }
         TokenType::Nil => {
             iter.next();
-            Ok(Box::new(Expression::Literal(LiteralValue::Nil)))
+            Ok(literal(LiteralValue::Nil, token))
+        }
+        TokenType::This => {
+            iter.next();
+            Ok(Box::new(Expression::This {
+                keyword: OwnedToken::from(token),
+                depth: Depth::default(),
+            }))
+        }
+        TokenType::Super => {
+            iter.next();
+            consume(iter, TokenType::Dot, "expected '.' after 'super'")?;
+            let method = consume(
+                iter,
+                TokenType::Identifier,
+                "expected superclass method name",
+            )?;
+
+            Ok(Box::new(Expression::Super {
+                keyword: OwnedToken::from(token),
+                method: OwnedToken::from(method),
+                depth: Depth::default(),
+            }))
+        }
+        TokenType::Identifier => {
+            iter.next();
+            Ok(Box::new(Expression::Variable {
+                name: OwnedToken::from(token),
+                depth: Depth::default(),
+            }))
         }
         TokenType::LeftParen => {
             iter.next();
@@ -231,13 +975,147 @@ where
 
             Ok(Box::new(Expression::Grouping(inner_expr)))
         }
+        TokenType::LeftBracket => {
+            iter.next();
+            let mut elements = Vec::new();
+
+            if !check(iter, TokenType::RightBracket) {
+                loop {
+                    elements.push(*assignment(iter)?);
+
+                    if match_token(iter, TokenType::Comma).is_none() {
+                        break;
+                    }
+                }
+            }
+            consume(iter, TokenType::RightBracket, "expected ']' after elements")?;
+
+            Ok(Box::new(Expression::List {
+                bracket: OwnedToken::from(token),
+                elements,
+            }))
+        }
+        TokenType::LeftBrace => {
+            iter.next();
+            let mut entries = Vec::new();
+
+            if !check(iter, TokenType::RightBrace) {
+                loop {
+                    let key = assignment(iter)?;
+                    consume(iter, TokenType::Colon, "expected ':' after map key")?;
+                    entries.push((*key, *assignment(iter)?));
+
+                    if match_token(iter, TokenType::Comma).is_none() {
+                        break;
+                    }
+                }
+            }
+            consume(iter, TokenType::RightBrace, "expected '}' after entries")?;
+
+            Ok(Box::new(Expression::Map {
+                brace: OwnedToken::from(token),
+                entries,
+            }))
+        }
         _ => Err(create_error(token.source_position)),
     }
 }
 
+// Run 'parse' one level of nesting deeper, failing once the nesting exceeds the
+// maximum.
+fn nested<'a, I, T>(
+    iter: &mut Parser<I>,
+    parse: impl FnOnce(&mut Parser<I>) -> crate::result::Result<T>,
+) -> crate::result::Result<T>
+where
+    I: Iterator<Item = &'a Token<'a>>,
+{
+    if iter.nesting >= iter.max_nesting {
+        return too_deep(iter, "too deeply nested");
+    }
+
+    let nesting = iter.nesting;
+    iter.nesting += 1;
+    let result = parse(iter);
+    iter.nesting = nesting;
+    result
+}
+
+// Charge a node folded into a left-associative chain, such as '1 + 2 + 3',
+// 'a.b.c' or 'f()()', one level of nesting. The chain is parsed in a loop,
+// but the passes over the syntax tree recurse through it as through any other
+// nesting. The caller restores the nesting once the chain is complete.
+fn link<'a, I>(iter: &mut Parser<I>) -> crate::result::Result<()>
+where
+    I: Iterator<Item = &'a Token<'a>>,
+{
+    if iter.nesting >= iter.max_nesting {
+        return too_deep(iter, "too deeply nested");
+    }
+
+    iter.nesting += 1;
+    Ok(())
+}
+
+// Fail with 'message' at the next token, stopping the parse.
+fn too_deep<'a, I, T>(iter: &mut Parser<I>, message: &str) -> crate::result::Result<T>
+where
+    I: Iterator<Item = &'a Token<'a>>,
+{
+    iter.too_deep = true;
+    let position = iter.peek().map_or((0, 0), |token| token.source_position);
+    Err(Error::ParseError {
+        message: message.to_string(),
+        source_position: position,
+    })
+}
+
+// Peek ahead and check if the token type matches the specified 'token_type'
+// without advancing the iterator.
+fn check<'a, I>(iter: &mut Parser<I>, token_type: TokenType) -> bool
+where
+    I: Iterator<Item = &'a Token<'a>>,
+{
+    matches!(iter.peek(), Some(token) if token.token_type == token_type)
+}
+
+fn is_at_end<'a, I>(iter: &mut Parser<I>) -> bool
+where
+    I: Iterator<Item = &'a Token<'a>>,
+{
+    iter.peek()
+        .is_none_or(|token| token.token_type == TokenType::Eof)
+}
+
+// Advance the iterator if the next token matches 'token_type', otherwise return
+// a parse error with the specified 'message' positioned at the next token.
+fn consume<'a, I>(
+    iter: &mut Parser<I>,
+    token_type: TokenType,
+    message: &str,
+) -> crate::result::Result<&'a Token<'a>>
+where
+    I: Iterator<Item = &'a Token<'a>>,
+{
+    match iter.peek() {
+        Some(&token) if token.token_type == token_type => {
+            iter.next();
+            Ok(token)
+        }
+        Some(&token) => Err(Error::ParseError {
+            message: format!("{message}, found '{}'", token.lexeme),
+            source_position: token.source_position,
+        }),
+        None => Err(Error::ParseError {
+            message: message.to_string(),
+            source_position: (0, 0),
+        }),
+    }
+}
+
 // Peek ahead and check if the token type matches the specified 'token_type'.
 // Advance the iterator and return 'Some(token)' if true, and 'None' otherwise.
-fn match_token<'a, I>(iter: &mut Peekable<I>, token_type: TokenType) -> Option<&'a Token<'a>>
+fn match_token<'a, I>(iter: &mut Parser<I>, token_type: TokenType) -> Option<&'a Token<'a>>
 where
     I: Iterator<Item = &'a Token<'a>>,
 {
@@ -251,10 +1129,7 @@ where
 // Peek ahead and check if the token type matches any of the specified
 // 'token_types'.  Advance the iterator and return 'Some(token)' if true, and
 // 'None' otherwise.
-fn match_token_any<'a, I>(
-    iter: &mut Peekable<I>,
-    token_types: &[TokenType],
-) -> Option<&'a Token<'a>>
+fn match_token_any<'a, I>(iter: &mut Parser<I>, token_types: &[TokenType]) -> Option<&'a Token<'a>>
 where
     I: Iterator<Item = &'a Token<'a>>,
 {
@@ -268,8 +1143,7 @@ where
 // Consume tokens until we hit a synchronization point. A synchronization point
 // is either a semicolon or the start of a new statement (i.e. the keywork
 // class, fun, var, etc.).
-#[allow(dead_code)]
-fn synchronize<'a, I>(iter: &mut Peekable<I>)
+fn synchronize<'a, I>(iter: &mut Parser<I>)
 where
     I: Iterator<Item = &'a Token<'a>>,
 {
@@ -286,8 +1160,258 @@ where
             | TokenType::If
             | TokenType::While
             | TokenType::Print
-            | TokenType::Return => break,
-            _ => (),
+            | TokenType::Return
+            | TokenType::Eof => break,
+            _ => {
+                iter.next();
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex;
+
+    fn parse_to_string(source: &str) -> String {
+        let tokens = lex(source).unwrap();
+        let statements = parse(&tokens).unwrap();
+
+        statements
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    #[test]
+    fn precedence() {
+        let tokens = lex("1 + 2 * (3 - 1) / 4").unwrap();
+        let expr = parse_expression(&tokens).unwrap();
+
+        assert_eq!(expr.to_string(), "(+ 1 (/ (* 2 (group (- 3 1))) 4))");
+    }
+
+    #[test]
+    fn comma_and_ternary() {
+        let tokens = lex("a, b ? c : d ? e : f").unwrap();
+        let expr = parse_expression(&tokens).unwrap();
+
+        assert_eq!(expr.to_string(), "(, a (? b c (? d e f)))");
+    }
+
+    #[test]
+    fn declarations() {
+        assert_eq!(
+            parse_to_string("var a = 1; { var b; print a = b = 2; }"),
+            "(var a 1) (block (var b) (print (= a (= b 2))))"
+        );
+    }
+
+    #[test]
+    fn control_flow() {
+        assert_eq!(
+            parse_to_string("if (a or b and c) print 1; else while (x) x = x - 1;"),
+            "(if (or a (and b c)) (print 1) (while x (; (= x (- x 1)))))"
+        );
+    }
+
+    #[test]
+    fn for_loop_is_desugared() {
+        assert_eq!(
+            parse_to_string("for (var i = 0; i < 3; i = i + 1) print i;"),
+            "(block (var i 0) (while (< i 3) (block (print i) (; (= i (+ i 1))))))"
+        );
+        assert_eq!(
+            parse_to_string("for (;;) f();"),
+            "(while true (; (call f)))"
+        );
+    }
+
+    #[test]
+    fn functions_and_calls() {
+        assert_eq!(
+            parse_to_string("fun add(a, b) { return a + b; } print add(1, (2, 3))(4);"),
+            "(fun add (a b) (return (+ a b))) (print (call (call add 1 (group (, 2 3))) 4))"
+        );
+    }
+
+    #[test]
+    fn classes() {
+        assert_eq!(
+            parse_to_string(
+                "class B < A { init(x) { this.x = x; } get() { return super.get(); } }"
+            ),
+            "(class B (< A) (fun init (x) (; (= (. this x) x))) \
+             (fun get () (return (call (. super get)))))"
+        );
+    }
+
+    #[test]
+    fn lists() {
+        assert_eq!(
+            parse_to_string("var a = [1, [2], []]; a[0] = a[-1][1:]; print a[:2];"),
+            "(var a (list 1 (list 2) (list))) (; (= ([] a 0) ([:] ([] a (- 1)) 1 nil))) \
+             (print ([:] a nil 2))"
+        );
+        assert_eq!(
+            parse_to_string("print a[b ? 1 : 2 : c].push(3);"),
+            "(print (call (. ([:] a (? b 1 2) c) push) 3))"
+        );
+    }
+
+    #[test]
+    fn maps() {
+        assert_eq!(
+            parse_to_string("var m = {\"a\": 1, b: {}}; m[\"a\"] = {};"),
+            "(var m (map (: a 1) (: b (map)))) (; (= ([] m a) (map)))"
+        );
+        // A '{' starting a statement is a block unless its first key is
+        // followed by ':'.
+        assert_eq!(
+            parse_to_string("{} {a;} {1: 2}.keys();"),
+            "(block) (block (; a)) (; (call (. (map (: 1 2)) keys)))"
+        );
+    }
+
+    #[test]
+    fn invalid_assignment_target() {
+        let tokens = lex("1 = 2;").unwrap();
+
+        match parse(&tokens) {
+            Err(Error::ParseError {
+                source_position, ..
+            }) => assert_eq!(source_position, (1, 3)),
+            _ => panic!("Expected ParseError"),
+        }
+    }
+
+    #[test]
+    fn reports_every_error() {
+        let tokens = lex("print ; var = 1; print 2;").unwrap();
+
+        match parse(&tokens) {
+            Err(Error::MultipleErrors(errors)) => assert_eq!(errors.len(), 2),
+            _ => panic!("Expected MultipleErrors"),
+        }
+    }
+
+    #[test]
+    fn nesting_depth() {
+        let nested = |depth| format!("print {}1{};", "(".repeat(depth), ")".repeat(depth));
+        let source = nested(10);
+        let tokens = lex(&source).unwrap();
+        assert!(parse_with_max_nesting(&tokens, 11).is_ok());
+
+        match parse_with_max_nesting(&tokens, 10) {
+            Err(Error::ParseError {
+                message,
+                source_position,
+            }) => {
+                assert_eq!(message, "too deeply nested");
+                assert_eq!(source_position, (1, 17));
+            }
+            _ => panic!("Expected ParseError"),
+        }
+
+        let source = format!("{}print 1;{}", "{".repeat(100), "}".repeat(100));
+        let tokens = lex(&source).unwrap();
+        assert!(parse(&tokens).is_ok());
+        assert!(parse_with_max_nesting(&tokens, 100).is_err());
+
+        // Parsing stops at the first construct nested too deeply, rather than
+        // reporting the tokens after it as errors of their own.
+        let source = format!("var a; {}1; print a;", "a = ".repeat(70));
+        let tokens = lex(&source).unwrap();
+        assert!(parse(&tokens).is_ok());
+        assert!(matches!(
+            parse_with_max_nesting(&tokens, 64),
+            Err(Error::ParseError { .. })
+        ));
+    }
+
+    // Each operator, property access or call folded into a chain nests the
+    // syntax tree one level deeper, though the chain is parsed in a loop.
+    #[test]
+    fn chain_depth() {
+        let chains = [
+            |links: usize| format!("print 1{};", " + 1".repeat(links)),
+            |links: usize| format!("print a{};", ".x".repeat(links)),
+            |links: usize| format!("f{};", "()".repeat(links)),
+            |links: usize| format!("print a{};", " or a".repeat(links)),
+        ];
+
+        for chain in chains {
+            let source = chain(63);
+            let tokens = lex(&source).unwrap();
+            assert!(parse_with_max_nesting(&tokens, 64).is_ok(), "{source}");
+
+            let source = chain(65);
+            let tokens = lex(&source).unwrap();
+            match parse_with_max_nesting(&tokens, 64) {
+                Err(Error::ParseError { message, .. }) => {
+                    assert_eq!(message, "too deeply nested", "{source}")
+                }
+                _ => panic!("Expected ParseError for {source}"),
+            }
+
+            let source = chain(5000);
+            let tokens = lex(&source).unwrap();
+            assert!(parse(&tokens).is_err(), "{source}");
+        }
+
+        // A chain only nests what follows it in the same expression.
+        let source = format!(
+            "print 1{} ; print 1{};",
+            " + 1".repeat(60),
+            " + 1".repeat(60)
+        );
+        let tokens = lex(&source).unwrap();
+        assert!(parse_with_max_nesting(&tokens, 64).is_ok());
+        let source = format!("print (1{}) + (1{});", " + 1".repeat(60), " + 1".repeat(60));
+        let tokens = lex(&source).unwrap();
+        assert!(parse_with_max_nesting(&tokens, 64).is_ok());
+    }
+
+    #[test]
+    fn else_if_chains() {
+        let chain = |branches| {
+            format!(
+                "if (a) 1;{} else print {}1{};",
+                " else if (a) 1;".repeat(branches),
+                "(".repeat(68),
+                ")".repeat(68)
+            )
+        };
+        // The last branch may still be nested as deeply as any statement.
+        let source = chain(70);
+        let tokens = lex(&source).unwrap();
+        assert!(parse_with_max_nesting(&tokens, 70).is_ok());
+
+        let source = chain(71);
+        let tokens = lex(&source).unwrap();
+        match parse_with_max_nesting(&tokens, 70) {
+            Err(Error::ParseError {
+                message,
+                source_position,
+            }) => {
+                assert_eq!(message, "too many 'else if' branches");
+                assert_eq!(source_position, (1, 1069));
+            }
+            _ => panic!("Expected ParseError"),
+        }
+
+        // Each statement after a chain starts counting again.
+        let source = format!("{} {}", chain(70), chain(70));
+        let tokens = lex(&source).unwrap();
+        assert_eq!(parse_with_max_nesting(&tokens, 70).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn trailing_tokens_after_expression() {
+        let tokens = lex("1 2").unwrap();
+
+        assert!(parse_expression(&tokens).is_err());
+    }
+}
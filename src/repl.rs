@@ -0,0 +1,674 @@
+use crate::lexer::{self, SourcePosition, Token, TokenType};
+use crate::result::Error;
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::{Hinter, HistoryHinter};
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{Context, Helper};
+use std::borrow::Cow;
+use std::ops::Range;
+
+pub const PROMPT: &str = "> ";
+pub const CONTINUATION_PROMPT: &str = ".. ";
+
+// The state of the input entered so far, as far as the lexer can tell.
+#[derive(Debug, PartialEq, Eq)]
+pub enum InputState {
+    // The input can be handed to the interpreter.
+    Complete,
+    // The input ends inside a string literal, a block comment or an unclosed
+    // '(' / '{', so more lines are needed.
+    Incomplete,
+    // The input can never be completed by appending more lines.
+    Invalid(String),
+}
+
+// Classify the given 'source' by lexing it and checking that every '(' and
+// '{' is closed by the matching delimiter. Lexical errors other than an
+// unexpected end of input are treated as 'Complete' so that the interpreter
+// reports them.
+pub fn input_state(source: &str) -> InputState {
+    let tokens = match lexer::lex(source) {
+        Ok(tokens) => tokens,
+        Err(Error::UnexpectedEndOfInput { .. }) => return InputState::Incomplete,
+        Err(_) => return InputState::Complete,
+    };
+
+    let mut open_delimiters = Vec::new();
+
+    for token in &tokens {
+        match token.token_type {
+            TokenType::LeftParen | TokenType::LeftBrace | TokenType::LeftBracket => {
+                open_delimiters.push(token)
+            }
+            TokenType::RightParen | TokenType::RightBrace | TokenType::RightBracket => {
+                let expected = match token.token_type {
+                    TokenType::RightParen => TokenType::LeftParen,
+                    TokenType::RightBracket => TokenType::LeftBracket,
+                    _ => TokenType::LeftBrace,
+                };
+
+                match open_delimiters.pop() {
+                    Some(open) if open.token_type == expected => (),
+                    _ => {
+                        let (line, column) = token.source_position;
+                        return InputState::Invalid(format!(
+                            "unmatched '{}' [ln: {}, col: {}]",
+                            token.lexeme, line, column
+                        ));
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    if open_delimiters.is_empty() {
+        InputState::Complete
+    } else {
+        InputState::Incomplete
+    }
+}
+
+// A REPL meta-command. Commands start with ':' and are handled by the REPL
+// instead of being passed to the interpreter.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Help,
+    Quit,
+    Tokens(String),
+    Ast(String),
+    Load(String),
+    Reset,
+    Env,
+    Time(String),
+    Save(String),
+}
+
+pub const COMMAND_HELP: &str = "\
+:help            Show this message
+:quit            Exit the REPL
+:tokens <expr>   Show the tokens produced by the lexer
+:ast <expr>      Show the syntax tree of an expression
+:load <file>     Run a file in the current session
+:reset           Remove all global bindings
+:env             List global bindings and their values
+:time <expr>     Evaluate an expression and report how long it took
+:save <file>     Write the inputs that ran successfully since the last
+                 :reset to a script";
+
+// Return true if 'input' should be parsed as a meta-command.
+pub fn is_command(input: &str) -> bool {
+    input.trim_start().starts_with(':')
+}
+
+// Parse the meta-command in 'input', returning a usage message if the command
+// is unknown or is missing its argument.
+pub fn parse_command(input: &str) -> std::result::Result<Command, String> {
+    let input = input.trim();
+    let input = input.strip_prefix(':').unwrap_or(input);
+    let (name, argument) = match input.split_once(char::is_whitespace) {
+        Some((name, argument)) => (name, argument.trim()),
+        None => (input, ""),
+    };
+
+    let with_argument = |command: fn(String) -> Command, usage: &str| {
+        if argument.is_empty() {
+            Err(format!("usage: :{name} {usage}"))
+        } else {
+            Ok(command(argument.to_string()))
+        }
+    };
+    let without_argument = |command: Command| {
+        if argument.is_empty() {
+            Ok(command)
+        } else {
+            Err(format!("usage: :{name}"))
+        }
+    };
+
+    match name {
+        "help" => without_argument(Command::Help),
+        "quit" => without_argument(Command::Quit),
+        "tokens" => with_argument(Command::Tokens, "<expr>"),
+        "ast" => with_argument(Command::Ast, "<expr>"),
+        "load" => with_argument(Command::Load, "<file>"),
+        "reset" => without_argument(Command::Reset),
+        "env" => without_argument(Command::Env),
+        "time" => with_argument(Command::Time, "<expr>"),
+        "save" => with_argument(Command::Save, "<file>"),
+        _ => Err(format!(
+            "unknown command ':{name}', type :help for a list of commands"
+        )),
+    }
+}
+
+// ANSI colours used when highlighting input.
+const KEYWORD_STYLE: &str = "\x1b[1;35m";
+const STRING_STYLE: &str = "\x1b[32m";
+const NUMBER_STYLE: &str = "\x1b[33m";
+const OPERATOR_STYLE: &str = "\x1b[36m";
+const COMMENT_STYLE: &str = "\x1b[90m";
+const MATCHING_BRACKET_STYLE: &str = "\x1b[1;4;34m";
+const HINT_STYLE: &str = "\x1b[90m";
+const RESET_STYLE: &str = "\x1b[0m";
+
+// Line editor helper for the REPL. Input spanning multiple lines is collected
+// by the REPL loop in 'pending', which is used as the context for validating
+// and highlighting each new line.
+pub struct ReplHelper {
+    pending: String,
+    global_names: Vec<String>,
+    prompt: String,
+    continuation_prompt: String,
+}
+
+impl Default for ReplHelper {
+    fn default() -> Self {
+        ReplHelper::new()
+    }
+}
+
+impl ReplHelper {
+    pub fn new() -> ReplHelper {
+        ReplHelper::with_prompts(PROMPT, CONTINUATION_PROMPT)
+    }
+
+    pub fn with_prompts(prompt: &str, continuation_prompt: &str) -> ReplHelper {
+        ReplHelper {
+            pending: String::new(),
+            global_names: Vec::new(),
+            prompt: prompt.to_string(),
+            continuation_prompt: continuation_prompt.to_string(),
+        }
+    }
+
+    // The input entered so far that has not yet been run.
+    pub fn pending(&self) -> &str {
+        &self.pending
+    }
+
+    // Append 'line' to the pending input and return the resulting state.
+    pub fn push_line(&mut self, line: &str) -> InputState {
+        self.pending.push_str(line);
+        self.pending.push('\n');
+        input_state(&self.pending)
+    }
+
+    // Discard the pending input, returning it.
+    pub fn take_pending(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+
+    // Set the names offered for completion in addition to the keywords.
+    pub fn set_global_names(&mut self, names: Vec<String>) {
+        self.global_names = names;
+    }
+
+    pub fn prompt(&self) -> &str {
+        if self.pending.is_empty() {
+            &self.prompt
+        } else {
+            &self.continuation_prompt
+        }
+    }
+}
+
+impl Validator for ReplHelper {
+    // Lines are accepted one at a time so that the REPL can show a continuation
+    // prompt for each of them, but a line that leaves the pending input
+    // impossible to complete is rejected while it can still be edited.
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        if self.pending.is_empty() && is_command(ctx.input()) {
+            return Ok(ValidationResult::Valid(None));
+        }
+
+        let source = format!("{}{}\n", self.pending, ctx.input());
+
+        match input_state(&source) {
+            InputState::Invalid(message) => {
+                Ok(ValidationResult::Invalid(Some(format!("  <- {message}"))))
+            }
+            InputState::Complete | InputState::Incomplete => Ok(ValidationResult::Valid(None)),
+        }
+    }
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    // Complete the identifier ending at the cursor with a keyword or a global
+    // name.
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos]
+            .char_indices()
+            .rev()
+            .take_while(|&(_, c)| c.is_alphanumeric() || c == '_')
+            .last()
+            .map_or(pos, |(i, _)| i);
+        let prefix = &line[start..pos];
+
+        if prefix.is_empty() {
+            return Ok((pos, Vec::new()));
+        }
+
+        let mut candidates: Vec<String> = lexer::KEYWORDS
+            .iter()
+            .copied()
+            .chain(self.global_names.iter().map(String::as_str))
+            .filter(|name| name.starts_with(prefix))
+            .map(String::from)
+            .collect();
+        candidates.sort();
+        candidates.dedup();
+
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+
+    // Suggest the remainder of the most recent history entry that starts with
+    // the current line.
+    fn hint(&self, line: &str, pos: usize, ctx: &Context<'_>) -> Option<String> {
+        HistoryHinter {}.hint(line, pos, ctx)
+    }
+}
+
+impl Highlighter for ReplHelper {
+    fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
+        let source = format!("{}{}", self.pending, line);
+        let offset = self.pending.len();
+        let mut spans = styled_spans(&source);
+
+        if let Some((open, close)) = matching_brackets(&source, offset + pos) {
+            spans.retain(|(range, _)| range.start != open && range.start != close);
+            spans.push((open..open + 1, MATCHING_BRACKET_STYLE));
+            spans.push((close..close + 1, MATCHING_BRACKET_STYLE));
+            spans.sort_by_key(|(range, _)| range.start);
+        }
+
+        let mut highlighted = String::with_capacity(line.len());
+        let mut last = offset;
+
+        for (range, style) in spans {
+            let start = range.start.max(offset);
+            if range.end <= start {
+                continue;
+            }
+
+            highlighted.push_str(&source[last..start]);
+            highlighted.push_str(style);
+            highlighted.push_str(&source[start..range.end]);
+            highlighted.push_str(RESET_STYLE);
+            last = range.end;
+        }
+        highlighted.push_str(&source[last..]);
+
+        Cow::Owned(highlighted)
+    }
+
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        Cow::Owned(format!("{HINT_STYLE}{hint}{RESET_STYLE}"))
+    }
+
+    // Refresh on every edit and cursor movement, so that bracket matching
+    // follows the cursor.
+    fn highlight_char(&self, _line: &str, _pos: usize) -> bool {
+        true
+    }
+}
+
+impl Helper for ReplHelper {}
+
+// Return the byte ranges of 'source' to highlight together with their styles,
+// ordered by position. Input that stops lexing, such as an unterminated string
+// or an unrecognized character, is highlighted up to that point.
+fn styled_spans(source: &str) -> Vec<(Range<usize>, &'static str)> {
+    let (tokens, tail) = match lexer::lex(source) {
+        Ok(tokens) => (tokens, None),
+        Err(
+            Error::UnexpectedEndOfInput {
+                source_position, ..
+            }
+            | Error::SyntaxError {
+                source_position, ..
+            },
+        ) => {
+            let cut = byte_offset(source, source_position);
+            let style = match &source[cut..] {
+                rest if rest.starts_with('"') => Some(STRING_STYLE),
+                rest if rest.starts_with("/*") => Some(COMMENT_STYLE),
+                _ => None,
+            };
+
+            match lexer::lex(&source[..cut]) {
+                Ok(tokens) => (tokens, style.map(|style| (cut..source.len(), style))),
+                Err(_) => return Vec::new(),
+            }
+        }
+        Err(_) => return Vec::new(),
+    };
+
+    let mut spans = Vec::new();
+    let mut last = 0;
+
+    for token in tokens.iter().filter(|t| t.token_type != TokenType::Eof) {
+        let range = token_range(source, token);
+
+        // Anything other than whitespace between two tokens is a comment.
+        if let Some(comment) = trimmed(source, last..range.start) {
+            spans.push((comment, COMMENT_STYLE));
+        }
+
+        if let Some(style) = token_style(&token.token_type) {
+            spans.push((range.clone(), style));
+        }
+        last = range.end;
+    }
+
+    match tail {
+        Some(tail) => {
+            if let Some(comment) = trimmed(source, last..tail.0.start) {
+                spans.push((comment, COMMENT_STYLE));
+            }
+            spans.push(tail);
+        }
+        None => {
+            if let Some(comment) = trimmed(source, last..source.len()) {
+                spans.push((comment, COMMENT_STYLE));
+            }
+        }
+    }
+
+    spans
+}
+
+fn token_style(token_type: &TokenType) -> Option<&'static str> {
+    match token_type {
+        TokenType::Str(_) => Some(STRING_STYLE),
+        TokenType::Number(_) => Some(NUMBER_STYLE),
+        TokenType::And
+        | TokenType::Class
+        | TokenType::Else
+        | TokenType::False
+        | TokenType::Fun
+        | TokenType::For
+        | TokenType::If
+        | TokenType::Nil
+        | TokenType::Or
+        | TokenType::Print
+        | TokenType::Return
+        | TokenType::Super
+        | TokenType::This
+        | TokenType::True
+        | TokenType::Var
+        | TokenType::While => Some(KEYWORD_STYLE),
+        TokenType::Minus
+        | TokenType::Plus
+        | TokenType::Slash
+        | TokenType::Asterisk
+        | TokenType::Colon
+        | TokenType::QuestionMark
+        | TokenType::Bang
+        | TokenType::BangEqual
+        | TokenType::Equal
+        | TokenType::EqualEqual
+        | TokenType::GreaterThan
+        | TokenType::GreaterThanOrEqual
+        | TokenType::LessThan
+        | TokenType::LessThanOrEqual => Some(OPERATOR_STYLE),
+        _ => None,
+    }
+}
+
+// Find the bracket token under or immediately before the cursor 'pos' and
+// return the offsets of it and its matching bracket, ordered by position.
+fn matching_brackets(source: &str, pos: usize) -> Option<(usize, usize)> {
+    let tokens = lexer::lex(source).ok()?;
+    let brackets: Vec<(usize, TokenType)> = tokens
+        .iter()
+        .filter(|t| is_bracket(&t.token_type))
+        .map(|t| (token_range(source, t).start, t.token_type))
+        .collect();
+
+    let index = brackets
+        .iter()
+        .position(|&(offset, _)| offset == pos)
+        .or_else(|| {
+            let before = pos.checked_sub(1)?;
+            brackets.iter().position(|&(offset, _)| offset == before)
+        })?;
+
+    let (offset, token_type) = brackets[index];
+    let mut depth = 0;
+
+    match token_type {
+        TokenType::LeftParen | TokenType::LeftBrace | TokenType::LeftBracket => {
+            for &(other, other_type) in &brackets[index + 1..] {
+                match other_type {
+                    TokenType::LeftParen | TokenType::LeftBrace | TokenType::LeftBracket => {
+                        depth += 1
+                    }
+                    _ if depth > 0 => depth -= 1,
+                    _ if closes(token_type, other_type) => return Some((offset, other)),
+                    _ => return None,
+                }
+            }
+        }
+        _ => {
+            for &(other, other_type) in brackets[..index].iter().rev() {
+                match other_type {
+                    TokenType::RightParen | TokenType::RightBrace | TokenType::RightBracket => {
+                        depth += 1
+                    }
+                    _ if depth > 0 => depth -= 1,
+                    _ if closes(other_type, token_type) => return Some((other, offset)),
+                    _ => return None,
+                }
+            }
+        }
+    }
+
+    None
+}
+
+fn is_bracket(token_type: &TokenType) -> bool {
+    matches!(
+        token_type,
+        TokenType::LeftParen
+            | TokenType::RightParen
+            | TokenType::LeftBrace
+            | TokenType::RightBrace
+            | TokenType::LeftBracket
+            | TokenType::RightBracket
+    )
+}
+
+fn closes(open: TokenType, close: TokenType) -> bool {
+    matches!(
+        (open, close),
+        (TokenType::LeftParen, TokenType::RightParen)
+            | (TokenType::LeftBrace, TokenType::RightBrace)
+            | (TokenType::LeftBracket, TokenType::RightBracket)
+    )
+}
+
+// The byte range of 'token' within the 'source' it was lexed from.
+fn token_range(source: &str, token: &Token) -> Range<usize> {
+    let start = byte_offset(source, token.source_position);
+    start..start + token.lexeme.len()
+}
+
+// Convert a 1-based line and column into a byte offset into 'source'.
+fn byte_offset(source: &str, (line, column): SourcePosition) -> usize {
+    let line_start: usize = source
+        .split_inclusive('\n')
+        .take(line.saturating_sub(1))
+        .map(str::len)
+        .sum();
+
+    line_start + column - 1
+}
+
+// Shrink 'range' to exclude leading and trailing whitespace, returning None if
+// nothing remains.
+fn trimmed(source: &str, range: Range<usize>) -> Option<Range<usize>> {
+    let text = &source[range.clone()];
+    let start = range.start + (text.len() - text.trim_start().len());
+    let end = range.end - (text.len() - text.trim_end().len());
+
+    if start < end {
+        Some(start..end)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn complete_expression() {
+        assert_eq!(input_state("1 + (2 * 3)\n"), InputState::Complete);
+    }
+
+    #[test]
+    fn unbalanced_delimiters() {
+        assert_eq!(input_state("(1 + 2\n"), InputState::Incomplete);
+        assert_eq!(input_state("if (a) {\n"), InputState::Incomplete);
+        assert_eq!(input_state("if (a) {\n}\n"), InputState::Complete);
+        assert_eq!(input_state("var a = [1,\n"), InputState::Incomplete);
+    }
+
+    #[test]
+    fn unterminated_string_and_comment() {
+        assert_eq!(input_state("\"abc\n"), InputState::Incomplete);
+        assert_eq!(input_state("1 /* comment\n"), InputState::Incomplete);
+        assert_eq!(input_state("1 /* comment\n */\n"), InputState::Complete);
+    }
+
+    #[test]
+    fn mismatched_delimiters() {
+        assert!(matches!(input_state("(1 + 2}\n"), InputState::Invalid(_)));
+        assert!(matches!(input_state(")\n"), InputState::Invalid(_)));
+        assert!(matches!(input_state("[1, 2)\n"), InputState::Invalid(_)));
+    }
+
+    #[test]
+    fn lexical_errors_are_complete() {
+        assert_eq!(input_state("1 # 2\n"), InputState::Complete);
+    }
+
+    #[test]
+    fn pending_lines() {
+        let mut helper = ReplHelper::new();
+        assert_eq!(helper.prompt(), PROMPT);
+
+        assert_eq!(helper.push_line("(1 +"), InputState::Incomplete);
+        assert_eq!(helper.prompt(), CONTINUATION_PROMPT);
+
+        assert_eq!(helper.push_line("2)"), InputState::Complete);
+        assert_eq!(helper.take_pending(), "(1 +\n2)\n");
+        assert_eq!(helper.prompt(), PROMPT);
+    }
+
+    #[test]
+    fn custom_prompts() {
+        let mut helper = ReplHelper::with_prompts("lox> ", "...> ");
+        assert_eq!(helper.prompt(), "lox> ");
+
+        helper.push_line("{");
+        assert_eq!(helper.prompt(), "...> ");
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(parse_command(":help"), Ok(Command::Help));
+        assert_eq!(parse_command("  :quit  "), Ok(Command::Quit));
+        assert_eq!(
+            parse_command(":tokens 1 + (2)"),
+            Ok(Command::Tokens(String::from("1 + (2)")))
+        );
+        assert_eq!(
+            parse_command(":load  script.lox "),
+            Ok(Command::Load(String::from("script.lox")))
+        );
+        assert!(parse_command(":ast").is_err());
+        assert!(parse_command(":env x").is_err());
+        assert!(parse_command(":frobnicate").is_err());
+        assert!(is_command(" :env"));
+        assert!(!is_command("a ? b : c;"));
+    }
+
+    #[test]
+    fn highlight_tokens() {
+        let helper = ReplHelper::new();
+        let highlighted = helper.highlight("var a = \"s\"; // c", 0);
+
+        assert_eq!(
+            highlighted,
+            format!(
+                "{KEYWORD_STYLE}var{RESET_STYLE} a {OPERATOR_STYLE}={RESET_STYLE} \
+                 {STRING_STYLE}\"s\"{RESET_STYLE}; {COMMENT_STYLE}// c{RESET_STYLE}"
+            )
+        );
+    }
+
+    #[test]
+    fn highlight_unterminated_string() {
+        let helper = ReplHelper::new();
+        let highlighted = helper.highlight("1 \"ab", 0);
+
+        assert_eq!(
+            highlighted,
+            format!("{NUMBER_STYLE}1{RESET_STYLE} {STRING_STYLE}\"ab{RESET_STYLE}")
+        );
+    }
+
+    #[test]
+    fn highlight_continuation_line() {
+        let mut helper = ReplHelper::new();
+        helper.push_line("\"first");
+
+        assert_eq!(
+            helper.highlight("second\" 2", 0),
+            format!("{STRING_STYLE}second\"{RESET_STYLE} {NUMBER_STYLE}2{RESET_STYLE}")
+        );
+    }
+
+    #[test]
+    fn brackets() {
+        assert_eq!(matching_brackets("(a(b))", 0), Some((0, 5)));
+        assert_eq!(matching_brackets("(a(b))", 4), Some((2, 4)));
+        assert_eq!(matching_brackets("(a(b))", 6), Some((0, 5)));
+        assert_eq!(matching_brackets("{ \"(\" }", 7), Some((0, 6)));
+        assert_eq!(matching_brackets("a[(b)]", 1), Some((1, 5)));
+        assert_eq!(matching_brackets("(a}", 0), None);
+        assert_eq!(matching_brackets("a", 1), None);
+    }
+
+    #[test]
+    fn complete_keywords_and_globals() {
+        let mut helper = ReplHelper::new();
+        helper.set_global_names(vec![String::from("fib"), String::from("value")]);
+
+        let history = rustyline::history::History::new();
+        let ctx = Context::new(&history);
+
+        let (start, candidates) = helper.complete("print f", 7, &ctx).unwrap();
+        assert_eq!(start, 6);
+        assert_eq!(candidates, ["false", "fib", "for", "fun"]);
+
+        let (start, candidates) = helper.complete("1 + va", 6, &ctx).unwrap();
+        assert_eq!(start, 4);
+        assert_eq!(candidates, ["value", "var"]);
+    }
+}
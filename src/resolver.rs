@@ -0,0 +1,400 @@
+use crate::ast::{Depth, Expression, FunctionDeclaration, Statement};
+use crate::lexer::OwnedToken;
+use crate::result::{Error, Result};
+use crate::symbol::{self, Symbol};
+use std::collections::HashMap;
+
+// Resolve every variable reference in 'statements' to the scope that declares
+// it, recording the distance in the reference's 'Depth'. References that are
+// not found in any enclosing local scope are left unresolved and looked up as
+// globals at runtime.
+pub fn resolve(statements: &[Statement<OwnedToken>]) -> Result<()> {
+    let mut resolver = Resolver::default();
+    resolver.resolve_statements(statements);
+    resolver.finish()
+}
+
+// Resolve a single expression evaluated at the top level.
+pub fn resolve_expression(expression: &Expression<OwnedToken>) -> Result<()> {
+    let mut resolver = Resolver::default();
+    resolver.resolve_expression(expression);
+    resolver.finish()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FunctionType {
+    None,
+    Function,
+    Initializer,
+    Method,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ClassType {
+    None,
+    Class,
+    Subclass,
+}
+
+struct Resolver {
+    // Local scopes, innermost last. Each maps a name to whether its
+    // initializer has finished resolving.
+    scopes: Vec<HashMap<Symbol, bool>>,
+    function: FunctionType,
+    class: ClassType,
+    errors: Vec<Error>,
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Resolver {
+            scopes: Vec::new(),
+            function: FunctionType::None,
+            class: ClassType::None,
+            errors: Vec::new(),
+        }
+    }
+}
+
+impl Resolver {
+    fn finish(mut self) -> Result<()> {
+        match self.errors.len() {
+            0 => Ok(()),
+            1 => Err(self.errors.remove(0)),
+            _ => Err(Error::MultipleErrors(self.errors)),
+        }
+    }
+
+    fn error(&mut self, token: &OwnedToken, message: &str) {
+        self.errors.push(Error::ParseError {
+            message: message.to_string(),
+            source_position: token.source_position,
+        });
+    }
+
+    fn resolve_statements(&mut self, statements: &[Statement<OwnedToken>]) {
+        for statement in statements {
+            self.resolve_statement(statement);
+        }
+    }
+
+    fn resolve_statement(&mut self, statement: &Statement<OwnedToken>) {
+        match statement {
+            Statement::Expression(expression) | Statement::Print { expression, .. } => {
+                self.resolve_expression(expression)
+            }
+            Statement::Var {
+                name, initializer, ..
+            } => {
+                self.declare(name);
+                if let Some(initializer) = initializer {
+                    self.resolve_expression(initializer);
+                }
+                self.define(name);
+            }
+            Statement::Block(statements) => {
+                self.scopes.push(HashMap::new());
+                self.resolve_statements(statements);
+                self.scopes.pop();
+            }
+            Statement::If {
+                condition,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                self.resolve_expression(condition);
+                self.resolve_statement(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.resolve_statement(else_branch);
+                }
+            }
+            Statement::While {
+                condition, body, ..
+            } => {
+                self.resolve_expression(condition);
+                self.resolve_statement(body);
+            }
+            Statement::Function {
+                declaration: function,
+                ..
+            } => {
+                self.declare(&function.name);
+                self.define(&function.name);
+                self.resolve_function(function, FunctionType::Function);
+            }
+            Statement::Return { keyword, value } => {
+                if self.function == FunctionType::None {
+                    self.error(keyword, "can't return from top-level code");
+                }
+
+                if let Some(value) = value {
+                    if self.function == FunctionType::Initializer {
+                        self.error(keyword, "can't return a value from an initializer");
+                    }
+                    self.resolve_expression(value);
+                }
+            }
+            Statement::Class {
+                name,
+                superclass,
+                methods,
+                ..
+            } => {
+                let enclosing_class = self.class;
+                self.class = ClassType::Class;
+
+                self.declare(name);
+                self.define(name);
+
+                if let Some(superclass) = superclass {
+                    if let Expression::Variable {
+                        name: superclass_name,
+                        ..
+                    } = &**superclass
+                    {
+                        if superclass_name.lexeme == name.lexeme {
+                            self.error(superclass_name, "a class can't inherit from itself");
+                        }
+                    }
+
+                    self.class = ClassType::Subclass;
+                    self.resolve_expression(superclass);
+
+                    self.scopes.push(HashMap::new());
+                    self.define_name(symbol::SUPER);
+                }
+
+                self.scopes.push(HashMap::new());
+                self.define_name(symbol::THIS);
+
+                for method in methods {
+                    let function_type = if method.name.lexeme == symbol::INIT {
+                        FunctionType::Initializer
+                    } else {
+                        FunctionType::Method
+                    };
+                    self.resolve_function(method, function_type);
+                }
+
+                self.scopes.pop();
+                if superclass.is_some() {
+                    self.scopes.pop();
+                }
+
+                self.class = enclosing_class;
+            }
+        }
+    }
+
+    fn resolve_function(
+        &mut self,
+        function: &FunctionDeclaration<OwnedToken>,
+        function_type: FunctionType,
+    ) {
+        let enclosing_function = self.function;
+        self.function = function_type;
+
+        self.scopes.push(HashMap::new());
+        for param in &function.params {
+            self.declare(param);
+            self.define(param);
+        }
+        self.resolve_statements(&function.body);
+        self.scopes.pop();
+
+        self.function = enclosing_function;
+    }
+
+    fn resolve_expression(&mut self, expression: &Expression<OwnedToken>) {
+        match expression {
+            Expression::Literal { .. } => (),
+            Expression::Unary { right, .. } => self.resolve_expression(right),
+            Expression::Binary { left, right, .. } | Expression::Logical { left, right, .. } => {
+                self.resolve_expression(left);
+                self.resolve_expression(right);
+            }
+            Expression::Ternary {
+                left,
+                middle,
+                right,
+                ..
+            } => {
+                self.resolve_expression(left);
+                self.resolve_expression(middle);
+                self.resolve_expression(right);
+            }
+            Expression::Grouping(expression) => self.resolve_expression(expression),
+            Expression::Variable { name, depth } => {
+                let in_own_initializer =
+                    self.scopes.last().and_then(|scope| scope.get(&name.lexeme)) == Some(&false);
+                if in_own_initializer {
+                    self.error(name, "can't read local variable in its own initializer");
+                }
+
+                self.resolve_local(name.lexeme, depth);
+            }
+            Expression::Assign { name, value, depth } => {
+                self.resolve_expression(value);
+                self.resolve_local(name.lexeme, depth);
+            }
+            Expression::Call {
+                callee, arguments, ..
+            } => {
+                self.resolve_expression(callee);
+                for argument in arguments {
+                    self.resolve_expression(argument);
+                }
+            }
+            Expression::Get { object, .. } => self.resolve_expression(object),
+            Expression::Set { object, value, .. } => {
+                self.resolve_expression(value);
+                self.resolve_expression(object);
+            }
+            Expression::List { elements, .. } => {
+                for element in elements {
+                    self.resolve_expression(element);
+                }
+            }
+            Expression::Map { entries, .. } => {
+                for (key, value) in entries {
+                    self.resolve_expression(key);
+                    self.resolve_expression(value);
+                }
+            }
+            Expression::Index { object, index, .. } => {
+                self.resolve_expression(object);
+                self.resolve_expression(index);
+            }
+            Expression::Slice {
+                object, start, end, ..
+            } => {
+                self.resolve_expression(object);
+                self.resolve_expression(start);
+                self.resolve_expression(end);
+            }
+            Expression::SetIndex {
+                object,
+                index,
+                value,
+                ..
+            } => {
+                self.resolve_expression(object);
+                self.resolve_expression(index);
+                self.resolve_expression(value);
+            }
+            Expression::This { keyword, depth } => {
+                if self.class == ClassType::None {
+                    self.error(keyword, "can't use 'this' outside of a class");
+                    return;
+                }
+
+                self.resolve_local(symbol::THIS, depth);
+            }
+            Expression::Super { keyword, depth, .. } => {
+                match self.class {
+                    ClassType::None => self.error(keyword, "can't use 'super' outside of a class"),
+                    ClassType::Class => {
+                        self.error(keyword, "can't use 'super' in a class with no superclass")
+                    }
+                    ClassType::Subclass => (),
+                }
+
+                self.resolve_local(symbol::SUPER, depth);
+            }
+        }
+    }
+
+    // Record the distance to the innermost scope declaring 'name', if any.
+    fn resolve_local(&mut self, name: Symbol, depth: &Depth) {
+        let distance = self
+            .scopes
+            .iter()
+            .rev()
+            .position(|scope| scope.contains_key(&name));
+
+        depth.set(distance);
+    }
+
+    fn declare(&mut self, name: &OwnedToken) {
+        let Some(scope) = self.scopes.last_mut() else {
+            return;
+        };
+
+        if scope.contains_key(&name.lexeme) {
+            self.error(name, "already a variable with this name in this scope");
+            return;
+        }
+
+        scope.insert(name.lexeme, false);
+    }
+
+    fn define(&mut self, name: &OwnedToken) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.lexeme, true);
+        }
+    }
+
+    fn define_name(&mut self, name: Symbol) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name, true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex;
+    use crate::parser::parse;
+
+    fn resolve_source(source: &str) -> Result<()> {
+        let tokens = lex(source)?;
+        let statements = parse(&tokens)?;
+        resolve(&statements)
+    }
+
+    #[test]
+    fn depths() {
+        let tokens = lex("var a; { var b; { a; b; } }").unwrap();
+        let statements = parse(&tokens).unwrap();
+        resolve(&statements).unwrap();
+
+        let Statement::Block(outer) = &statements[1] else {
+            panic!("Expected Block");
+        };
+        let Statement::Block(inner) = &outer[1] else {
+            panic!("Expected Block");
+        };
+        let depths: Vec<Option<usize>> = inner
+            .iter()
+            .map(|statement| match statement {
+                Statement::Expression(expression) => match &**expression {
+                    Expression::Variable { depth, .. } => depth.get(),
+                    _ => panic!("Expected Variable"),
+                },
+                _ => panic!("Expected Expression"),
+            })
+            .collect();
+
+        assert_eq!(depths, [None, Some(1)]);
+    }
+
+    #[test]
+    fn static_errors() {
+        assert!(resolve_source("{ var a = a; }").is_err());
+        assert!(resolve_source("{ var a; var a; }").is_err());
+        assert!(resolve_source("return 1;").is_err());
+        assert!(resolve_source("class A { init() { return 1; } }").is_err());
+        assert!(resolve_source("print this;").is_err());
+        assert!(resolve_source("class A { f() { super.f(); } }").is_err());
+        assert!(resolve_source("class A < A {}").is_err());
+    }
+
+    #[test]
+    fn valid_programs() {
+        assert!(resolve_source("var a = 1; var a = a;").is_ok());
+        assert!(resolve_source("class A { init() { return; } }").is_ok());
+        assert!(resolve_source("class A {} class B < A { f() { super.f(); } }").is_ok());
+    }
+}
@@ -5,6 +5,7 @@ use std::result;
 pub type Result<T> = result::Result<T, Error>;
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     SyntaxError {
         message: String,
@@ -14,17 +15,111 @@ pub enum Error {
         message: String,
         source_position: SourcePosition,
     },
+    // The source ended while a construct (string literal, block comment) was
+    // still open. Distinct from 'SyntaxError' so that callers such as the REPL
+    // can ask for more input instead of reporting an error.
+    UnexpectedEndOfInput {
+        message: String,
+        source_position: SourcePosition,
+    },
+    // 'trace' holds the calls in progress when the error happened, innermost
+    // first. It ends with the script itself, or is empty if the error didn't
+    // happen while running one.
+    RuntimeError {
+        message: String,
+        source_position: SourcePosition,
+        trace: Vec<TraceFrame>,
+    },
+    // A compiled bytecode file that could not be loaded.
+    InvalidBytecode(String),
     MultipleErrors(Vec<Error>),
+    // Something suspicious that doesn't stop the program from running, such
+    // as code that can never run. See 'Severity'.
+    Warning {
+        message: String,
+        source_position: SourcePosition,
+    },
+}
+
+// A call in progress when a runtime error happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceFrame {
+    // The name of the function called, or 'None' for the script itself.
+    pub function: Option<String>,
+    // The file the function is defined in, if it came from one.
+    pub file: Option<String>,
+    // The line the function was running: where the error happened for the
+    // innermost frame, and where the next call was made for the others.
+    pub line: usize,
+    // The number of calls in tail position this frame replaced, which no
+    // longer appear in the trace.
+    pub tail_calls: usize,
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "[{}, line {}] in ", file, self.line)?,
+            None => write!(f, "[line {}] in ", self.line)?,
+        }
+        match &self.function {
+            Some(name) => write!(f, "{}()", name)?,
+            None => write!(f, "script")?,
+        }
+        match self.tail_calls {
+            0 => Ok(()),
+            1 => write!(f, " (1 tail call omitted)"),
+            n => write!(f, " ({} tail calls omitted)", n),
+        }
+    }
+}
+
+// How serious a diagnostic is. Warnings are reported but don't stop the
+// program; everything else does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl Error {
+    // The severity of the diagnostic, or of the most severe one for
+    // 'MultipleErrors'.
+    pub fn severity(&self) -> Severity {
+        match self {
+            Error::Warning { .. } => Severity::Warning,
+            Error::MultipleErrors(errors) => errors
+                .iter()
+                .map(Error::severity)
+                .max()
+                .unwrap_or(Severity::Warning),
+            _ => Severity::Error,
+        }
+    }
+
+    // Attribute the frames of a runtime error's trace to 'file'.
+    pub fn in_file(mut self, file: &str) -> Error {
+        if let Error::RuntimeError { trace, .. } = &mut self {
+            for frame in trace {
+                frame.file = Some(file.to_string());
+            }
+        }
+        self
+    }
 }
 
 impl std::error::Error for Error {}
 
-impl<'a> fmt::Display for Error {
+impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Error::SyntaxError {
                 message: ref m,
                 source_position: (l, c),
+            }
+            | Error::UnexpectedEndOfInput {
+                message: ref m,
+                source_position: (l, c),
             } => write!(f, "Syntax Error [ln: {}, col: {}]: {}", l, c, m),
             Error::ParseError {
                 message: ref m,
@@ -32,8 +127,27 @@ impl<'a> fmt::Display for Error {
             } => {
                 write!(f, "Parse Error [ln: {}, col: {}]: {}", l, c, m)
             }
+            Error::RuntimeError {
+                message: ref m,
+                source_position: (l, c),
+                ref trace,
+            } => {
+                write!(f, "Runtime Error [ln: {}, col: {}]: {}", l, c, m)?;
+                for frame in trace {
+                    write!(f, "\n{}", frame)?;
+                }
+                Ok(())
+            }
+            Error::InvalidBytecode(ref m) => write!(f, "Invalid Bytecode: {}", m),
+            Error::Warning {
+                message: ref m,
+                source_position: (l, c),
+            } => write!(f, "Warning [ln: {}, col: {}]: {}", l, c, m),
             Error::MultipleErrors(ref errors) => {
-                for error in errors {
+                for (i, error) in errors.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
                     write!(f, "{}", error)?;
                 }
                 Ok(())
@@ -41,3 +155,62 @@ impl<'a> fmt::Display for Error {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn severity() {
+        let warning = Error::Warning {
+            message: "unreachable code".to_string(),
+            source_position: (3, 5),
+        };
+        let error = Error::ParseError {
+            message: "expected ';'".to_string(),
+            source_position: (1, 1),
+        };
+
+        assert_eq!(
+            warning.to_string(),
+            "Warning [ln: 3, col: 5]: unreachable code"
+        );
+        assert_eq!(warning.severity(), Severity::Warning);
+        assert_eq!(error.severity(), Severity::Error);
+        assert_eq!(
+            Error::MultipleErrors(vec![warning, error]).severity(),
+            Severity::Error
+        );
+    }
+
+    #[test]
+    fn runtime_error_trace() {
+        let frame = |function: Option<&str>, line, tail_calls| TraceFrame {
+            function: function.map(str::to_string),
+            file: None,
+            line,
+            tail_calls,
+        };
+        let error = Error::RuntimeError {
+            message: "operand must be a number".to_string(),
+            source_position: (12, 5),
+            trace: vec![
+                frame(Some("fib"), 12, 0),
+                frame(Some("loop"), 20, 3),
+                frame(None, 31, 0),
+            ],
+        };
+
+        assert_eq!(
+            error.to_string(),
+            "Runtime Error [ln: 12, col: 5]: operand must be a number\n\
+             [line 12] in fib()\n\
+             [line 20] in loop() (3 tail calls omitted)\n\
+             [line 31] in script"
+        );
+        assert!(error
+            .in_file("fib.lox")
+            .to_string()
+            .ends_with("\n[fib.lox, line 31] in script"));
+    }
+}
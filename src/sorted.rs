@@ -0,0 +1,229 @@
+// Behaviour shared by the sorted maps and sets of every backend: the methods
+// each has, which values can be keys and the order keys are kept in. The C
+// runtime in 'c/loxi.h' follows the same rules.
+use crate::binary_tree::{SortedMap, SortedSet};
+use std::cmp::Ordering;
+use std::ops::Bound;
+
+pub const NOT_A_KEY: &str = "sorted keys must be strings or numbers other than NaN";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Kind {
+    Map,
+    Set,
+}
+
+impl Kind {
+    // The name of the native class whose instances are of this kind.
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Map => "SortedMap",
+            Kind::Set => "SortedSet",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    Add,
+    Has,
+    Remove,
+    Keys,
+    Values,
+    Len,
+    Range,
+}
+
+pub const METHODS: [Method; 7] = [
+    Method::Add,
+    Method::Has,
+    Method::Remove,
+    Method::Keys,
+    Method::Values,
+    Method::Len,
+    Method::Range,
+];
+
+impl Method {
+    // Sets are added to rather than indexed, and their keys are their
+    // values.
+    pub fn from_name(kind: Kind, name: &str) -> Option<Method> {
+        METHODS.into_iter().find(|&method| {
+            method.name() == name
+                && match kind {
+                    Kind::Map => method != Method::Add,
+                    Kind::Set => method != Method::Keys,
+                }
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Method::Add => "add",
+            Method::Has => "has",
+            Method::Remove => "remove",
+            Method::Keys => "keys",
+            Method::Values => "values",
+            Method::Len => "len",
+            Method::Range => "range",
+        }
+    }
+
+    pub fn arity(self) -> usize {
+        match self {
+            Method::Keys | Method::Values | Method::Len => 0,
+            Method::Add | Method::Has | Method::Remove => 1,
+            Method::Range => 2,
+        }
+    }
+}
+
+// A key of a sorted map or set. Numbers come before strings, which are
+// ordered by their bytes. Strings are whatever each backend can compare
+// without looking them up.
+#[derive(Debug, Clone)]
+pub enum Key<S> {
+    Number(f64),
+    String(S),
+}
+
+impl<S> Key<S> {
+    // NaN can't be ordered, so it isn't a key. '-0' is the same key as '0'
+    // and is kept as '0'.
+    pub fn number(number: f64) -> Option<Key<S>> {
+        if number.is_nan() {
+            None
+        } else if number == 0.0 {
+            Some(Key::Number(0.0))
+        } else {
+            Some(Key::Number(number))
+        }
+    }
+}
+
+impl<S: Ord> Ord for Key<S> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Key::Number(a), Key::Number(b)) => a.partial_cmp(b).unwrap_or(Ordering::Equal),
+            (Key::Number(_), Key::String(_)) => Ordering::Less,
+            (Key::String(_), Key::Number(_)) => Ordering::Greater,
+            (Key::String(a), Key::String(b)) => a.cmp(b),
+        }
+    }
+}
+
+impl<S: Ord> PartialOrd for Key<S> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<S: Ord> PartialEq for Key<S> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<S: Ord> Eq for Key<S> {}
+
+#[derive(Debug, Clone)]
+pub enum Sorted<S, V> {
+    Map(SortedMap<Key<S>, V>),
+    Set(SortedSet<Key<S>>),
+}
+
+impl<S: Ord, V> Sorted<S, V> {
+    pub fn new(kind: Kind) -> Sorted<S, V> {
+        match kind {
+            Kind::Map => Sorted::Map(SortedMap::new()),
+            Kind::Set => Sorted::Set(SortedSet::new()),
+        }
+    }
+
+    pub fn kind(&self) -> Kind {
+        match self {
+            Sorted::Map(_) => Kind::Map,
+            Sorted::Set(_) => Kind::Set,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Sorted::Map(map) => map.len(),
+            Sorted::Set(set) => set.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, key: &Key<S>) -> bool {
+        match self {
+            Sorted::Map(map) => map.contains_key(key),
+            Sorted::Set(set) => set.contains(key),
+        }
+    }
+
+    // The keys in order.
+    pub fn keys(&self) -> Box<dyn Iterator<Item = &Key<S>> + '_> {
+        match self {
+            Sorted::Map(map) => Box::new(map.keys()),
+            Sorted::Set(set) => Box::new(set.iter()),
+        }
+    }
+
+    // The keys from 'start' up to but not including 'end', in order. A
+    // missing bound leaves that end of the range open.
+    pub fn range(
+        &self,
+        start: Option<Key<S>>,
+        end: Option<Key<S>>,
+    ) -> Box<dyn Iterator<Item = &Key<S>> + '_> {
+        let range = (
+            start.map_or(Bound::Unbounded, Bound::Included),
+            end.map_or(Bound::Unbounded, Bound::Excluded),
+        );
+        match self {
+            Sorted::Map(map) => Box::new(map.range(range).map(|(key, _)| key)),
+            Sorted::Set(set) => Box::new(set.range(range)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys() {
+        let mut keys = vec![
+            Key::String("b"),
+            Key::number(2.0).unwrap(),
+            Key::String("a"),
+            Key::number(-1.5).unwrap(),
+        ];
+        keys.sort();
+
+        assert_eq!(
+            keys,
+            [
+                Key::Number(-1.5),
+                Key::Number(2.0),
+                Key::String("a"),
+                Key::String("b")
+            ]
+        );
+        assert_eq!(Key::<&str>::number(-0.0), Some(Key::Number(0.0)));
+        assert!(Key::<&str>::number(f64::NAN).is_none());
+    }
+
+    #[test]
+    fn methods() {
+        assert_eq!(Method::from_name(Kind::Map, "keys"), Some(Method::Keys));
+        assert_eq!(Method::from_name(Kind::Map, "add"), None);
+        assert_eq!(Method::from_name(Kind::Set, "add"), Some(Method::Add));
+        assert_eq!(Method::from_name(Kind::Set, "keys"), None);
+        assert_eq!(Method::from_name(Kind::Set, "range"), Some(Method::Range));
+    }
+}
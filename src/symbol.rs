@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{LazyLock, Mutex};
+
+// A handle to an interned string. Interning equal strings always gives the
+// same symbol, so symbols are compared and hashed as integers rather than by
+// their contents.
+//
+// Names are interned as tokens are created and string constants as they are
+// compiled. Interned strings live until the program exits, so strings created
+// at runtime are not interned.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Symbol(u32);
+
+// Names the interpreters look up themselves, interned in this order before
+// any other string.
+const PREDEFINED: [&str; 4] = ["this", "super", "init", "hash"];
+pub const THIS: Symbol = Symbol(0);
+pub const SUPER: Symbol = Symbol(1);
+pub const INIT: Symbol = Symbol(2);
+pub const HASH: Symbol = Symbol(3);
+
+struct Interner {
+    symbols: HashMap<&'static str, Symbol>,
+    strings: Vec<&'static str>,
+}
+
+static INTERNER: LazyLock<Mutex<Interner>> = LazyLock::new(|| {
+    let strings = PREDEFINED.to_vec();
+    let symbols = strings
+        .iter()
+        .enumerate()
+        .map(|(index, &string)| (string, Symbol(index as u32)))
+        .collect();
+    Mutex::new(Interner { symbols, strings })
+});
+
+impl Symbol {
+    pub fn intern(string: &str) -> Symbol {
+        let mut interner = INTERNER.lock().unwrap();
+        if let Some(&symbol) = interner.symbols.get(string) {
+            return symbol;
+        }
+
+        let symbol = Symbol(u32::try_from(interner.strings.len()).expect("too many symbols"));
+        let string: &'static str = Box::leak(Box::from(string));
+        interner.strings.push(string);
+        interner.symbols.insert(string, symbol);
+        symbol
+    }
+
+    pub fn as_str(self) -> &'static str {
+        INTERNER.lock().unwrap().strings[self.0 as usize]
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interning() {
+        let a = Symbol::intern("name");
+        let b = Symbol::intern(&String::from("name"));
+        let c = Symbol::intern("other");
+
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert_eq!(a.as_str(), "name");
+        assert_eq!(format!("{a} {c:?}"), "name \"other\"");
+    }
+
+    #[test]
+    fn predefined() {
+        assert_eq!(Symbol::intern("this"), THIS);
+        assert_eq!(Symbol::intern("super"), SUPER);
+        assert_eq!(INIT.as_str(), "init");
+        assert_eq!(HASH.as_str(), "hash");
+    }
+}
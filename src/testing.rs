@@ -0,0 +1,51 @@
+// Helpers shared by the tests of the backends and passes.
+
+use std::cell::RefCell;
+use std::fmt::Display;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+// An output buffer that can be inspected after handing it to a backend.
+#[derive(Clone, Default)]
+pub struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Output {
+    // Everything written so far.
+    pub fn contents(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+
+    // Everything written so far followed by the error 'result' holds, if
+    // any, which is how the tests compare runs of the same script.
+    pub fn transcript<T, E: Display>(&self, result: Result<T, E>) -> String {
+        let mut transcript = self.contents();
+        if let Err(error) = result {
+            transcript.push_str(&format!("error: {error}\n"));
+        }
+        transcript
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// The scripts in 'tests/corpus', in order.
+pub fn corpus() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus");
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "lox"))
+        .collect();
+    files.sort();
+    files
+}
@@ -0,0 +1,859 @@
+use crate::ast::{Expression, FunctionDeclaration, LiteralValue, Statement};
+use crate::lexer::{OwnedToken, SourcePosition, TokenType};
+use crate::symbol::{self, Symbol};
+use std::collections::HashMap;
+use std::fmt::Write;
+
+// The runtime the generated programs include, see 'c/loxi.h'.
+pub const RUNTIME: &str = include_str!("c/loxi.h");
+pub const RUNTIME_NAME: &str = "loxi.h";
+
+// Translate a program to C. The program must have been checked with
+// 'resolver::resolve' first, whose depths decide which scope each variable
+// is found in.
+//
+// Every Lox function becomes a C function, and the top-level code becomes
+// 'main'. Scopes are created at runtime just as the tree-walking interpreter
+// creates environments, and the variables of each are numbered in the order
+// they are declared, so a local variable is found by walking up the depth the
+// resolver recorded and indexing that scope. Values being worked on are kept
+// in an array of temporaries that the garbage collector sees: each
+// expression is evaluated into a temporary, using the ones after it for its
+// operands.
+//
+// Runtime errors trace their frames to 'file', the script the statements were
+// parsed from, if there is one. Calls can nest 'max_depth' deep before a stack
+// overflow unless the C is compiled with another 'LOX_MAX_DEPTH'.
+pub fn transpile(
+    statements: &[Statement<OwnedToken>],
+    file: Option<&str>,
+    max_depth: usize,
+) -> String {
+    let mut transpiler = Transpiler {
+        functions: Vec::new(),
+        function_count: 0,
+        prototypes: String::new(),
+        definitions: String::new(),
+        scopes: Vec::new(),
+        globals: Table::default(),
+        constants: Table::default(),
+        names: Table::default(),
+    };
+    // The runtime looks these up by name.
+    transpiler.globals.index("clock");
+    transpiler.globals.index("SortedMap");
+    transpiler.globals.index("SortedSet");
+    transpiler.names.index("init");
+    transpiler.names.index("hash");
+
+    transpiler.functions.push(FunctionState {
+        indent: 1,
+        ..FunctionState::default()
+    });
+    for statement in statements {
+        transpiler.statement(statement);
+    }
+    let main = transpiler.functions.pop().expect("top-level function");
+
+    transpiler.program(main, file, max_depth)
+}
+
+// Strings numbered in the order they are first used.
+#[derive(Default)]
+struct Table {
+    indices: HashMap<String, usize>,
+    strings: Vec<String>,
+}
+
+impl Table {
+    fn index(&mut self, string: &str) -> usize {
+        if let Some(&index) = self.indices.get(string) {
+            return index;
+        }
+        self.strings.push(string.to_string());
+        self.indices
+            .insert(string.to_string(), self.strings.len() - 1);
+        self.strings.len() - 1
+    }
+}
+
+// The C function being generated for a Lox function.
+#[derive(Default)]
+struct FunctionState {
+    body: String,
+    indent: usize,
+    // The number of temporaries the function uses.
+    temporaries: usize,
+}
+
+struct Transpiler {
+    // The functions being generated, innermost last.
+    functions: Vec<FunctionState>,
+    // The number of Lox functions generated so far, which numbers their C
+    // functions.
+    function_count: usize,
+    prototypes: String,
+    definitions: String,
+    // The variables of each local scope in the order they are declared,
+    // innermost last, matching the resolver's scopes.
+    scopes: Vec<Vec<Symbol>>,
+    globals: Table,
+    constants: Table,
+    // The names of properties, methods and classes.
+    names: Table,
+}
+
+impl Transpiler {
+    fn function(&mut self) -> &mut FunctionState {
+        self.functions.last_mut().expect("function being generated")
+    }
+
+    fn line(&mut self, code: &str) {
+        let function = self.function();
+        for _ in 0..function.indent {
+            function.body.push_str("    ");
+        }
+        function.body.push_str(code);
+        function.body.push('\n');
+    }
+
+    fn indent(&mut self) {
+        self.function().indent += 1;
+    }
+
+    fn dedent(&mut self) {
+        self.function().indent -= 1;
+    }
+
+    // The C expression for temporary 't', which the function is noted as
+    // using.
+    fn temporary(&mut self, t: usize) -> String {
+        let function = self.function();
+        function.temporaries = function.temporaries.max(t + 1);
+        format!("t[{t}]")
+    }
+
+    fn statement(&mut self, statement: &Statement<OwnedToken>) {
+        match statement {
+            Statement::Expression(expression) => self.expression(expression, 0),
+            Statement::Print { expression, .. } => {
+                self.expression(expression, 0);
+                self.line("lox_print(t[0]);");
+            }
+            Statement::Var {
+                name, initializer, ..
+            } => {
+                match initializer {
+                    Some(initializer) => self.expression(initializer, 0),
+                    None => {
+                        let t = self.temporary(0);
+                        self.line(&format!("{t} = lox_nil();"));
+                    }
+                }
+                self.define(name.lexeme, "t[0]");
+            }
+            Statement::Block(statements) => {
+                self.line("{");
+                self.indent();
+                self.begin_scope(declarations(statements));
+                for statement in statements {
+                    self.statement(statement);
+                }
+                self.end_scope();
+                self.dedent();
+                self.line("}");
+            }
+            Statement::If {
+                condition,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                self.expression(condition, 0);
+                self.line("if (lox_truthy(t[0])) {");
+                self.indent();
+                self.statement(then_branch);
+                self.dedent();
+                if let Some(else_branch) = else_branch {
+                    self.line("} else {");
+                    self.indent();
+                    self.statement(else_branch);
+                    self.dedent();
+                }
+                self.line("}");
+            }
+            Statement::While {
+                condition, body, ..
+            } => {
+                self.line("for (;;) {");
+                self.indent();
+                self.expression(condition, 0);
+                self.line("if (!lox_truthy(t[0])) {");
+                self.line("    break;");
+                self.line("}");
+                self.statement(body);
+                self.dedent();
+                self.line("}");
+            }
+            Statement::Function { declaration, .. } => {
+                // The function is declared before its body so that it can
+                // call itself.
+                self.declare(declaration.name.lexeme);
+                let info = self.function_declaration(declaration);
+                let t = self.temporary(0);
+                self.line(&format!("{t} = lox_closure(&{info}, frame.env);"));
+                self.define(declaration.name.lexeme, "t[0]");
+            }
+            Statement::Return { value, .. } => {
+                match value.as_deref() {
+                    // Calls in tail position are made once this function has
+                    // returned, see 'lox_tail_call'.
+                    Some(Expression::Call {
+                        callee,
+                        paren,
+                        arguments,
+                    }) => {
+                        self.expression(callee, 0);
+                        for (i, argument) in arguments.iter().enumerate() {
+                            self.expression(argument, 1 + i);
+                        }
+                        let (line, column) = paren.source_position;
+                        self.line(&format!(
+                            "return lox_tail_call(&frame, &t[0], {}, {line}, {column});",
+                            arguments.len()
+                        ));
+                        return;
+                    }
+                    Some(value) => self.expression(value, 0),
+                    None => {
+                        let t = self.temporary(0);
+                        self.line(&format!("{t} = lox_nil();"));
+                    }
+                }
+                self.line("return lox_return(&frame, t[0]);");
+            }
+            Statement::Class {
+                name,
+                superclass,
+                methods,
+                ..
+            } => self.class(name, superclass.as_deref(), methods),
+        }
+    }
+
+    fn class(
+        &mut self,
+        name: &OwnedToken,
+        superclass: Option<&Expression<OwnedToken>>,
+        methods: &[std::rc::Rc<FunctionDeclaration<OwnedToken>>],
+    ) {
+        let class_name = self.names.index(&name.lexeme.to_string());
+
+        self.line("{");
+        self.indent();
+        match superclass {
+            Some(expression) => {
+                self.expression(expression, 0);
+                let (line, column) = match expression {
+                    Expression::Variable { name, .. } => name.source_position,
+                    _ => name.source_position,
+                };
+                self.line(&format!("lox_check_superclass(t[0], {line}, {column});"));
+            }
+            None => {
+                let t = self.temporary(0);
+                self.line(&format!("{t} = lox_nil();"));
+            }
+        }
+        self.declare(name.lexeme);
+        self.define(name.lexeme, "lox_nil()");
+
+        // Methods of a subclass close over an extra scope binding 'super'.
+        if superclass.is_some() {
+            self.begin_scope(1);
+            self.declare(symbol::SUPER);
+            self.line("frame.env->slots[0] = t[0];");
+        }
+        let class = self.temporary(1);
+        self.line(&format!(
+            "{class} = lox_class({class_name} /* {} */, t[0]);",
+            name.lexeme
+        ));
+
+        // Methods are bound to an instance in a scope of their own.
+        self.scopes.push(vec![symbol::THIS]);
+        for method in methods {
+            let method_name = self.names.index(&method.name.lexeme.to_string());
+            let info = self.function_declaration(method);
+            let closure = self.temporary(2);
+            self.line(&format!("{closure} = lox_closure(&{info}, frame.env);"));
+            self.line(&format!(
+                "lox_add_method(t[1], {method_name} /* {} */, t[2]);",
+                method.name.lexeme
+            ));
+        }
+        self.scopes.pop();
+
+        if superclass.is_some() {
+            self.end_scope();
+        }
+        self.define(name.lexeme, "t[1]");
+        self.dedent();
+        self.line("}");
+    }
+
+    // Generate the C function for 'declaration', returning the name of its
+    // 'LoxFunctionInfo'.
+    fn function_declaration(&mut self, declaration: &FunctionDeclaration<OwnedToken>) -> String {
+        let index = self.function_count;
+        self.function_count += 1;
+        let code = format!("lox_code_{index}");
+        let info = format!("lox_function_{index}");
+        let name = declaration.name.lexeme.to_string();
+        let arity = declaration.params.len();
+        writeln!(
+            self.prototypes,
+            "static LoxValue {code}(LoxFunction *function, LoxValue *arguments);"
+        )
+        .unwrap();
+
+        self.functions.push(FunctionState {
+            indent: 1,
+            ..FunctionState::default()
+        });
+        let size = arity + declarations(&declaration.body);
+        self.line(&format!("frame.env = lox_new_env(frame.env, {size});"));
+        self.scopes.push(Vec::new());
+        for (slot, param) in declaration.params.iter().enumerate() {
+            self.declare(param.lexeme);
+            self.line(&format!("frame.env->slots[{slot}] = arguments[{slot}];"));
+        }
+        for statement in &declaration.body {
+            self.statement(statement);
+        }
+        self.scopes.pop();
+        self.line("return lox_return(&frame, lox_nil());");
+        let function = self.functions.pop().expect("function being generated");
+
+        let temporaries = function.temporaries.max(1);
+        let mut definition = String::new();
+        writeln!(definition, "// fun {}({})", name, params(declaration)).unwrap();
+        writeln!(
+            definition,
+            "static LoxValue {code}(LoxFunction *function, LoxValue *arguments) {{"
+        )
+        .unwrap();
+        writeln!(definition, "    LoxValue t[{temporaries}];").unwrap();
+        writeln!(definition, "    LoxFrame frame;").unwrap();
+        writeln!(
+            definition,
+            "    lox_enter(&frame, function, t, {temporaries});"
+        )
+        .unwrap();
+        definition.push_str(&function.body);
+        writeln!(definition, "}}").unwrap();
+        writeln!(
+            definition,
+            "static const LoxFunctionInfo {info} = {{{}, {arity}, {code}}};",
+            c_string(&name)
+        )
+        .unwrap();
+        definition.push('\n');
+        self.definitions.push_str(&definition);
+
+        info
+    }
+
+    fn begin_scope(&mut self, size: usize) {
+        self.line(&format!("frame.env = lox_new_env(frame.env, {size});"));
+        self.scopes.push(Vec::new());
+    }
+
+    fn end_scope(&mut self) {
+        self.line("frame.env = frame.env->enclosing;");
+        self.scopes.pop();
+    }
+
+    // Add 'name' to the innermost local scope, if any. Globals are defined
+    // by 'define'.
+    fn declare(&mut self, name: Symbol) {
+        if let Some(scope) = self.scopes.last_mut() {
+            if !scope.contains(&name) {
+                scope.push(name);
+            }
+        }
+    }
+
+    // Give the variable 'name' declared in the innermost scope its first
+    // value.
+    fn define(&mut self, name: Symbol, value: &str) {
+        if self.scopes.is_empty() {
+            let global = self.globals.index(&name.to_string());
+            self.line(&format!(
+                "lox_define_global({global} /* {name} */, {value});"
+            ));
+        } else {
+            self.declare(name);
+            let slot = self.slot(name, 0);
+            self.line(&format!("frame.env->slots[{slot}] = {value};"));
+        }
+    }
+
+    fn assign(
+        &mut self,
+        name: Symbol,
+        depth: Option<usize>,
+        value: &str,
+        (line, column): SourcePosition,
+    ) {
+        match depth {
+            Some(depth) => {
+                let variable = self.local(name, depth);
+                self.line(&format!("{variable} = {value};"));
+            }
+            None => {
+                let global = self.globals.index(&name.to_string());
+                self.line(&format!(
+                    "lox_set_global({global} /* {name} */, {value}, {line}, {column});"
+                ));
+            }
+        }
+    }
+
+    // The index of 'name' in the scope 'depth' levels up.
+    fn slot(&self, name: Symbol, depth: usize) -> usize {
+        let scope = &self.scopes[self.scopes.len() - 1 - depth];
+        scope
+            .iter()
+            .position(|&declared| declared == name)
+            .expect("variable resolved to a scope declaring it")
+    }
+
+    // The C expression for the local variable 'name' found 'depth' scopes up.
+    fn local(&self, name: Symbol, depth: usize) -> String {
+        let slot = self.slot(name, depth);
+        match depth {
+            0 => format!("frame.env->slots[{slot}]"),
+            _ => format!("lox_env_at(frame.env, {depth})->slots[{slot}]"),
+        }
+    }
+
+    fn variable(&mut self, name: Symbol, depth: Option<usize>, t: usize, position: SourcePosition) {
+        let target = self.temporary(t);
+        match depth {
+            Some(depth) => {
+                let variable = self.local(name, depth);
+                self.line(&format!("{target} = {variable};"));
+            }
+            None => {
+                let global = self.globals.index(&name.to_string());
+                let (line, column) = position;
+                self.line(&format!(
+                    "{target} = lox_get_global({global} /* {name} */, {line}, {column});"
+                ));
+            }
+        }
+    }
+
+    // Evaluate 'expression' into temporary 't', using those after it for its
+    // operands.
+    fn expression(&mut self, expression: &Expression<OwnedToken>, t: usize) {
+        let target = self.temporary(t);
+        match expression {
+            Expression::Literal { value, .. } => {
+                let value = match value {
+                    LiteralValue::Number(number) => format!("lox_number({})", c_number(*number)),
+                    LiteralValue::String(string) => {
+                        let constant = self.constants.index(string);
+                        format!("lox.constants[{constant}]")
+                    }
+                    LiteralValue::True => "lox_bool(true)".to_string(),
+                    LiteralValue::False => "lox_bool(false)".to_string(),
+                    LiteralValue::Nil => "lox_nil()".to_string(),
+                };
+                self.line(&format!("{target} = {value};"));
+            }
+            Expression::Grouping(expression) => self.expression(expression, t),
+            Expression::Unary { operator, right } => {
+                self.expression(right, t);
+                let (line, column) = operator.source_position;
+                let code = match operator.token_type {
+                    TokenType::Minus => format!("lox_negate({target}, {line}, {column})"),
+                    _ => format!("lox_not({target})"),
+                };
+                self.line(&format!("{target} = {code};"));
+            }
+            Expression::Binary {
+                operator,
+                left,
+                right,
+            } => {
+                if operator.token_type == TokenType::Comma {
+                    self.expression(left, t);
+                    self.expression(right, t);
+                    return;
+                }
+
+                self.expression(left, t);
+                self.expression(right, t + 1);
+                let operand = format!("t[{}]", t + 1);
+                let (line, column) = operator.source_position;
+                let code = match operator.token_type {
+                    TokenType::EqualEqual => format!("lox_bool(lox_equal({target}, {operand}))"),
+                    TokenType::BangEqual => format!("lox_bool(!lox_equal({target}, {operand}))"),
+                    token_type => {
+                        let function = match token_type {
+                            TokenType::Plus => "lox_add",
+                            TokenType::Minus => "lox_subtract",
+                            TokenType::Asterisk => "lox_multiply",
+                            TokenType::Slash => "lox_divide",
+                            TokenType::GreaterThan => "lox_greater",
+                            TokenType::GreaterThanOrEqual => "lox_greater_equal",
+                            TokenType::LessThan => "lox_less",
+                            _ => "lox_less_equal",
+                        };
+                        format!("{function}({target}, {operand}, {line}, {column})")
+                    }
+                };
+                self.line(&format!("{target} = {code};"));
+            }
+            Expression::Ternary {
+                left,
+                middle,
+                right,
+                ..
+            } => {
+                self.expression(left, t);
+                self.line(&format!("if (lox_truthy({target})) {{"));
+                self.indent();
+                self.expression(middle, t);
+                self.dedent();
+                self.line("} else {");
+                self.indent();
+                self.expression(right, t);
+                self.dedent();
+                self.line("}");
+            }
+            Expression::Logical {
+                operator,
+                left,
+                right,
+            } => {
+                self.expression(left, t);
+                let negation = if operator.token_type == TokenType::Or {
+                    "!"
+                } else {
+                    ""
+                };
+                self.line(&format!("if ({negation}lox_truthy({target})) {{"));
+                self.indent();
+                self.expression(right, t);
+                self.dedent();
+                self.line("}");
+            }
+            Expression::Variable { name, depth } => {
+                self.variable(name.lexeme, depth.get(), t, name.source_position)
+            }
+            Expression::Assign { name, value, depth } => {
+                self.expression(value, t);
+                self.assign(name.lexeme, depth.get(), &target, name.source_position);
+            }
+            Expression::Call {
+                callee,
+                paren,
+                arguments,
+            } => {
+                self.expression(callee, t);
+                for (i, argument) in arguments.iter().enumerate() {
+                    self.expression(argument, t + 1 + i);
+                }
+                let (line, column) = paren.source_position;
+                self.line(&format!(
+                    "{target} = lox_call(&{target}, {}, {line}, {column});",
+                    arguments.len()
+                ));
+            }
+            Expression::Get { object, name } => {
+                self.expression(object, t);
+                let property = self.names.index(&name.lexeme.to_string());
+                let (line, column) = name.source_position;
+                self.line(&format!(
+                    "{target} = lox_get_property({target}, {property} /* {} */, {line}, {column});",
+                    name.lexeme
+                ));
+            }
+            Expression::Set {
+                object,
+                name,
+                value,
+            } => {
+                self.expression(object, t);
+                self.expression(value, t + 1);
+                let property = self.names.index(&name.lexeme.to_string());
+                let (line, column) = name.source_position;
+                self.line(&format!(
+                    "{target} = lox_set_property({target}, {property} /* {} */, t[{}], {line}, {column});",
+                    name.lexeme,
+                    t + 1
+                ));
+            }
+            Expression::List { elements, .. } => {
+                for (i, element) in elements.iter().enumerate() {
+                    self.expression(element, t + i);
+                }
+                self.line(&format!(
+                    "{target} = lox_list(&{target}, {});",
+                    elements.len()
+                ));
+            }
+            Expression::Map { brace, entries } => {
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    self.expression(key, t + 2 * i);
+                    self.expression(value, t + 2 * i + 1);
+                }
+                let (line, column) = brace.source_position;
+                self.line(&format!(
+                    "{target} = lox_map(&{target}, {}, {line}, {column});",
+                    entries.len()
+                ));
+            }
+            Expression::Index {
+                object,
+                bracket,
+                index,
+            } => {
+                self.expression(object, t);
+                self.expression(index, t + 1);
+                let (line, column) = bracket.source_position;
+                self.line(&format!(
+                    "{target} = lox_get_index({target}, t[{}], {line}, {column});",
+                    t + 1
+                ));
+            }
+            Expression::Slice {
+                object,
+                bracket,
+                start,
+                end,
+            } => {
+                self.expression(object, t);
+                self.expression(start, t + 1);
+                self.expression(end, t + 2);
+                let (line, column) = bracket.source_position;
+                self.line(&format!(
+                    "{target} = lox_get_slice({target}, t[{}], t[{}], {line}, {column});",
+                    t + 1,
+                    t + 2
+                ));
+            }
+            Expression::SetIndex {
+                object,
+                bracket,
+                index,
+                value,
+            } => {
+                self.expression(object, t);
+                self.expression(index, t + 1);
+                self.expression(value, t + 2);
+                let (line, column) = bracket.source_position;
+                self.line(&format!(
+                    "{target} = lox_set_index({target}, t[{}], t[{}], {line}, {column});",
+                    t + 1,
+                    t + 2
+                ));
+            }
+            Expression::This { keyword, depth } => {
+                self.variable(symbol::THIS, depth.get(), t, keyword.source_position)
+            }
+            Expression::Super { method, depth, .. } => {
+                // The resolver places 'this' in the scope just inside the one
+                // binding 'super'.
+                let depth = depth.get().expect("'super' resolved to a local scope");
+                let superclass = self.local(symbol::SUPER, depth);
+                let this = self.local(symbol::THIS, depth - 1);
+                let name = self.names.index(&method.lexeme.to_string());
+                let (line, column) = method.source_position;
+                self.line(&format!(
+                    "{target} = lox_super({superclass}, {this}, {name} /* {} */, {line}, {column});",
+                    method.lexeme
+                ));
+            }
+        }
+    }
+
+    // Put the generated functions and the tables the runtime needs together
+    // with 'main'.
+    fn program(self, main: FunctionState, file: Option<&str>, max_depth: usize) -> String {
+        let mut program = String::new();
+        writeln!(program, "// Generated by 'loxi emit-c'.").unwrap();
+        writeln!(program, "#ifndef LOX_MAX_DEPTH").unwrap();
+        writeln!(program, "#define LOX_MAX_DEPTH {max_depth}").unwrap();
+        writeln!(program, "#endif").unwrap();
+        writeln!(program, "#include \"{RUNTIME_NAME}\"").unwrap();
+        writeln!(program).unwrap();
+
+        writeln!(program, "static const char *const lox_global_names[] = {{").unwrap();
+        for global in &self.globals.strings {
+            writeln!(program, "    {},", c_string(global)).unwrap();
+        }
+        writeln!(program, "}};").unwrap();
+
+        writeln!(program, "static const char *const lox_names[] = {{").unwrap();
+        for name in &self.names.strings {
+            writeln!(program, "    {},", c_string(name)).unwrap();
+        }
+        writeln!(program, "}};").unwrap();
+
+        // An empty array isn't valid C, so there is always at least one
+        // constant even if the program doesn't use it.
+        writeln!(program, "static const LoxConstant lox_constants[] = {{").unwrap();
+        for constant in &self.constants.strings {
+            writeln!(
+                program,
+                "    {{{}, {}}},",
+                c_string(constant),
+                constant.len()
+            )
+            .unwrap();
+        }
+        if self.constants.strings.is_empty() {
+            writeln!(program, "    {{\"\", 0}},").unwrap();
+        }
+        writeln!(program, "}};").unwrap();
+        writeln!(program).unwrap();
+
+        program.push_str(&self.prototypes);
+        writeln!(program).unwrap();
+        program.push_str(&self.definitions);
+
+        let temporaries = main.temporaries.max(1);
+        writeln!(program, "int main(void) {{").unwrap();
+        writeln!(program, "    LoxValue t[{temporaries}];").unwrap();
+        writeln!(program, "    LoxFrame frame;").unwrap();
+        writeln!(
+            program,
+            "    lox_init({}, lox_global_names, {}, lox_constants, {}, lox_names, {});",
+            file.map_or_else(|| String::from("NULL"), c_string),
+            self.globals.strings.len(),
+            self.constants.strings.len(),
+            self.names.strings.len()
+        )
+        .unwrap();
+        writeln!(program, "    lox_enter(&frame, NULL, t, {temporaries});").unwrap();
+        program.push_str(&main.body);
+        writeln!(program, "    lox_return(&frame, lox_nil());").unwrap();
+        writeln!(program, "    fflush(stdout);").unwrap();
+        writeln!(program, "    return 0;").unwrap();
+        writeln!(program, "}}").unwrap();
+        program
+    }
+}
+
+// The number of variables 'statements' declare in the scope they are in. The
+// branches of 'if' and 'while' are single statements, which can't be
+// declarations.
+fn declarations(statements: &[Statement<OwnedToken>]) -> usize {
+    statements
+        .iter()
+        .filter(|statement| {
+            matches!(
+                statement,
+                Statement::Var { .. } | Statement::Function { .. } | Statement::Class { .. }
+            )
+        })
+        .count()
+}
+
+fn params(declaration: &FunctionDeclaration<OwnedToken>) -> String {
+    declaration
+        .params
+        .iter()
+        .map(|param| param.lexeme.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// 'number' as a C expression. Folding constants can produce numbers that
+// have no literal.
+fn c_number(number: f64) -> String {
+    if number.is_nan() {
+        "0.0 / 0.0".to_string()
+    } else if number.is_infinite() {
+        format!("{}1.0 / 0.0", if number < 0.0 { "-" } else { "" })
+    } else {
+        format!("{number:?}")
+    }
+}
+
+// 'string' as a C string literal. Anything other than printable ASCII is
+// written as an octal escape, which unlike a hex escape can't run into the
+// characters after it.
+fn c_string(string: &str) -> String {
+    let mut literal = String::from("\"");
+    for byte in string.bytes() {
+        match byte {
+            b'"' => literal.push_str("\\\""),
+            b'\\' => literal.push_str("\\\\"),
+            b'\n' => literal.push_str("\\n"),
+            // '??' could start a trigraph.
+            b'?' => literal.push_str("\\?"),
+            b' '..=b'~' => literal.push(byte as char),
+            _ => write!(literal, "\\{byte:03o}").unwrap(),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex;
+    use crate::parser::{parse, DEFAULT_MAX_DEPTH};
+    use crate::resolver::resolve;
+
+    fn transpile_source(source: &str) -> String {
+        let tokens = lex(source).unwrap();
+        let statements = parse(&tokens).unwrap();
+        resolve(&statements).unwrap();
+        transpile(&statements, None, DEFAULT_MAX_DEPTH)
+    }
+
+    #[test]
+    fn string_literals() {
+        assert_eq!(c_string("plain"), "\"plain\"");
+        assert_eq!(c_string("a\"b\\c\n"), "\"a\\\"b\\\\c\\n\"");
+        assert_eq!(c_string("é1"), "\"\\303\\2511\"");
+        assert_eq!(c_string("??="), "\"\\?\\?=\"");
+    }
+
+    #[test]
+    fn number_literals() {
+        assert_eq!(c_number(2.5), "2.5");
+        assert_eq!(c_number(1e300), "1e300");
+        assert_eq!(c_number(f64::NEG_INFINITY), "-1.0 / 0.0");
+        assert_eq!(c_number(f64::NAN), "0.0 / 0.0");
+    }
+
+    #[test]
+    fn scope_sizes() {
+        let tokens = lex("var a; fun f() {} if (a) { var b; } print a; class C {}").unwrap();
+        let statements = parse(&tokens).unwrap();
+
+        assert_eq!(declarations(&statements), 3);
+    }
+
+    #[test]
+    fn variables() {
+        let program = transpile_source(
+            "var a = 1;
+             fun f(x) { var y = x; fun g() { return y + a; } return g; }",
+        );
+
+        assert!(program.contains("lox_define_global(3 /* a */, t[0]);"));
+        assert!(program.contains("frame.env->slots[1] = t[0];"));
+        assert!(program.contains("t[0] = lox_env_at(frame.env, 1)->slots[1];"));
+        assert!(program.contains("t[1] = lox_get_global(3 /* a */, 2, 57);"));
+        assert!(program.contains("static const LoxFunctionInfo lox_function_1 = {\"g\", 0"));
+    }
+}
@@ -0,0 +1,299 @@
+use crate::ast::{FunctionDeclaration, LiteralValue};
+use crate::environment::Environment;
+use crate::lexer::OwnedToken;
+use crate::list;
+use crate::map;
+use crate::sorted;
+use crate::symbol::{self, Symbol};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+// A runtime value produced by evaluating an expression.
+#[derive(Clone)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Function(Rc<Function>),
+    NativeFunction(Rc<NativeFunction>),
+    Class(Rc<Class>),
+    Instance(Rc<RefCell<Instance>>),
+    List(Rc<RefCell<Vec<Value>>>),
+    // A method of a list, bound to the list it was looked up on.
+    ListMethod(Rc<RefCell<Vec<Value>>>, list::Method),
+    Map(Rc<RefCell<Map>>),
+    MapMethod(Rc<RefCell<Map>>, map::Method),
+    // 'SortedMap' or 'SortedSet', which create an empty sorted map or set
+    // when called.
+    SortedClass(sorted::Kind),
+    Sorted(Rc<RefCell<Sorted>>),
+    SortedMethod(Rc<RefCell<Sorted>>, sorted::Method),
+}
+
+// The entries of a map, each holding the key it was first inserted with
+// alongside its value.
+pub type Map = map::Map<map::Key<String>, (Value, Value)>;
+
+pub type Sorted = sorted::Sorted<String, Value>;
+
+// Keys of sorted maps and sets are plain numbers and strings.
+impl From<&sorted::Key<String>> for Value {
+    fn from(key: &sorted::Key<String>) -> Value {
+        match key {
+            sorted::Key::Number(number) => Value::Number(*number),
+            sorted::Key::String(string) => Value::String(string.clone()),
+        }
+    }
+}
+
+// A function or method declared in Lox, together with the environment it
+// closes over.
+pub struct Function {
+    pub declaration: Rc<FunctionDeclaration<OwnedToken>>,
+    pub closure: Rc<RefCell<Environment>>,
+    pub is_initializer: bool,
+}
+
+impl Function {
+    pub fn arity(&self) -> usize {
+        self.declaration.params.len()
+    }
+
+    // Create a copy of this method whose closure binds 'this' to 'instance'.
+    pub fn bind(&self, instance: Value) -> Function {
+        let mut environment = Environment::with_enclosing(Rc::clone(&self.closure));
+        environment.define(symbol::THIS, instance);
+
+        Function {
+            declaration: Rc::clone(&self.declaration),
+            closure: Rc::new(RefCell::new(environment)),
+            is_initializer: self.is_initializer,
+        }
+    }
+}
+
+// A function implemented in Rust. Errors are reported as a message, which the
+// interpreter positions at the call.
+pub struct NativeFunction {
+    pub name: &'static str,
+    pub arity: usize,
+    pub function: fn(&[Value]) -> std::result::Result<Value, String>,
+}
+
+pub struct Class {
+    pub name: String,
+    pub superclass: Option<Rc<Class>>,
+    pub methods: HashMap<Symbol, Rc<Function>>,
+}
+
+impl Class {
+    // Look up the method 'name' in this class or the closest superclass that
+    // defines it.
+    pub fn find_method(&self, name: Symbol) -> Option<Rc<Function>> {
+        match self.methods.get(&name) {
+            Some(method) => Some(Rc::clone(method)),
+            None => self.superclass.as_ref()?.find_method(name),
+        }
+    }
+
+    // Calling a class runs its initializer, so it takes the same arguments.
+    pub fn arity(&self) -> usize {
+        self.find_method(symbol::INIT)
+            .map_or(0, |init| init.arity())
+    }
+}
+
+pub struct Instance {
+    pub class: Rc<Class>,
+    pub fields: HashMap<Symbol, Value>,
+}
+
+impl Instance {
+    pub fn new(class: Rc<Class>) -> Instance {
+        Instance {
+            class,
+            fields: HashMap::new(),
+        }
+    }
+}
+
+impl Value {
+    // 'nil' and 'false' are falsey, everything else is truthy.
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Bool(false))
+    }
+}
+
+impl From<&LiteralValue> for Value {
+    fn from(literal: &LiteralValue) -> Self {
+        match literal {
+            LiteralValue::Number(n) => Value::Number(*n),
+            LiteralValue::String(s) => Value::String(s.clone()),
+            LiteralValue::True => Value::Bool(true),
+            LiteralValue::False => Value::Bool(false),
+            LiteralValue::Nil => Value::Nil,
+        }
+    }
+}
+
+// Numbers, strings and booleans compare by value, everything else by identity.
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+            (Value::NativeFunction(a), Value::NativeFunction(b)) => Rc::ptr_eq(a, b),
+            (Value::Class(a), Value::Class(b)) => Rc::ptr_eq(a, b),
+            (Value::Instance(a), Value::Instance(b)) => Rc::ptr_eq(a, b),
+            (Value::List(a), Value::List(b)) => Rc::ptr_eq(a, b),
+            (Value::Map(a), Value::Map(b)) => Rc::ptr_eq(a, b),
+            (Value::SortedClass(a), Value::SortedClass(b)) => a == b,
+            (Value::Sorted(a), Value::Sorted(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_value(f, self, &mut Vec::new())
+    }
+}
+
+// Write 'value', printing a list or map that contains itself, directly or
+// through the others in 'open', as '[...]' or '{...}'.
+fn write_value(f: &mut fmt::Formatter, value: &Value, open: &mut Vec<*const ()>) -> fmt::Result {
+    match value {
+        Value::Nil => write!(f, "nil"),
+        Value::Bool(value) => write!(f, "{value}"),
+        Value::Number(value) => write!(f, "{value}"),
+        Value::String(value) => write!(f, "{value}"),
+        Value::Function(function) => write!(f, "<fn {}>", function.declaration.name),
+        Value::NativeFunction(_)
+        | Value::ListMethod(..)
+        | Value::MapMethod(..)
+        | Value::SortedMethod(..) => write!(f, "<native fn>"),
+        Value::SortedClass(kind) => write!(f, "{}", kind.name()),
+        Value::Class(class) => write!(f, "{}", class.name),
+        Value::Instance(instance) => write!(f, "{} instance", instance.borrow().class.name),
+        Value::List(list) => {
+            let pointer = Rc::as_ptr(list) as *const ();
+            if open.contains(&pointer) {
+                return write!(f, "[...]");
+            }
+
+            open.push(pointer);
+            write!(f, "[")?;
+            for (i, element) in list.borrow().iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write_value(f, element, open)?;
+            }
+            open.pop();
+            write!(f, "]")
+        }
+        Value::Map(map) => {
+            let pointer = Rc::as_ptr(map) as *const ();
+            if open.contains(&pointer) {
+                return write!(f, "{{...}}");
+            }
+
+            open.push(pointer);
+            write!(f, "{{")?;
+            for (i, (key, value)) in map.borrow().values().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write_value(f, key, open)?;
+                write!(f, ": ")?;
+                write_value(f, value, open)?;
+            }
+            open.pop();
+            write!(f, "}}")
+        }
+        Value::Sorted(sorted) => {
+            let pointer = Rc::as_ptr(sorted) as *const ();
+            if open.contains(&pointer) {
+                return write!(f, "{{...}}");
+            }
+
+            open.push(pointer);
+            write!(f, "{{")?;
+            match &*sorted.borrow() {
+                sorted::Sorted::Map(map) => {
+                    for (i, (key, value)) in map.iter().enumerate() {
+                        if i > 0 {
+                            write!(f, ", ")?;
+                        }
+                        write!(f, "{}: ", Value::from(key))?;
+                        write_value(f, value, open)?;
+                    }
+                }
+                sorted::Sorted::Set(set) => {
+                    for (i, key) in set.iter().enumerate() {
+                        if i > 0 {
+                            write!(f, ", ")?;
+                        }
+                        write!(f, "{}", Value::from(key))?;
+                    }
+                }
+            }
+            open.pop();
+            write!(f, "}}")
+        }
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::String(value) => write!(f, "{value:?}"),
+            _ => write!(f, "{self}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truthiness() {
+        assert!(!Value::Nil.is_truthy());
+        assert!(!Value::Bool(false).is_truthy());
+        assert!(Value::Bool(true).is_truthy());
+        assert!(Value::Number(0.0).is_truthy());
+        assert!(Value::String(String::new()).is_truthy());
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(Value::Number(3.0).to_string(), "3");
+        assert_eq!(Value::Number(2.5).to_string(), "2.5");
+        assert_eq!(Value::String(String::from("abc")).to_string(), "abc");
+        assert_eq!(Value::Nil.to_string(), "nil");
+
+        let list = Rc::new(RefCell::new(vec![Value::Number(1.0), Value::Nil]));
+        list.borrow_mut().push(Value::List(Rc::clone(&list)));
+        let outer = Value::List(Rc::new(RefCell::new(vec![Value::List(list)])));
+        assert_eq!(outer.to_string(), "[[1, nil, [...]]]");
+
+        let map = Rc::new(RefCell::new(Map::default()));
+        let key = map::Key::String(String::from("a"));
+        map.borrow_mut()
+            .insert(key, (Value::String(String::from("a")), outer));
+        map.borrow_mut()
+            .insert(map::Key::Nil, (Value::Nil, Value::Map(Rc::clone(&map))));
+        assert_eq!(
+            Value::Map(map).to_string(),
+            "{a: [[1, nil, [...]]], nil: {...}}"
+        );
+    }
+}
@@ -0,0 +1,464 @@
+use crate::chunk::{Constant, FunctionProto, OpCode};
+use crate::result::{Error, Result};
+use std::fmt::Display;
+
+// Check that 'script' and the functions nested within it can be run by the VM
+// without reading outside of their code, constants, upvalues or stack frame.
+// The compiler always produces valid bytecode; this guards against files that
+// were damaged or written by something else.
+//
+// Each function is checked in two passes. The first decodes every instruction
+// and checks its operands, the second follows the control flow from the entry
+// point tracking the depth of the stack, which must be the same however an
+// instruction is reached.
+pub fn verify(script: &FunctionProto) -> Result<()> {
+    if script.arity != 0 || script.upvalue_count != 0 {
+        return Err(Error::InvalidBytecode(
+            "the script cannot have parameters or upvalues".to_string(),
+        ));
+    }
+
+    verify_function(script)
+}
+
+fn verify_function(function: &FunctionProto) -> Result<()> {
+    let verifier = FunctionVerifier { function };
+    verifier.check_instructions()?;
+    verifier.check_stack()?;
+
+    for constant in &function.chunk.constants {
+        if let Constant::Function(nested) = constant {
+            verify_function(nested)?;
+        }
+    }
+
+    Ok(())
+}
+
+struct FunctionVerifier<'a> {
+    function: &'a FunctionProto,
+}
+
+impl FunctionVerifier<'_> {
+    fn code(&self) -> &[u8] {
+        &self.function.chunk.code
+    }
+
+    fn error(&self, offset: usize, message: impl Display) -> Error {
+        let name = if self.function.name.is_empty() {
+            String::from("<script>")
+        } else {
+            format!("<fn {}>", self.function.name)
+        };
+        Error::InvalidBytecode(format!("{name} at {offset:04}: {message}"))
+    }
+
+    fn op(&self, offset: usize) -> Result<OpCode> {
+        let byte = self.code()[offset];
+        OpCode::from_byte(byte).ok_or_else(|| self.error(offset, format!("unknown opcode {byte}")))
+    }
+
+    // The operand byte at 'at' of the instruction at 'offset'.
+    fn byte(&self, offset: usize, at: usize) -> Result<u8> {
+        self.code()
+            .get(at)
+            .copied()
+            .ok_or_else(|| self.error(offset, "instruction is truncated"))
+    }
+
+    fn u16(&self, offset: usize, at: usize) -> Result<usize> {
+        let high = self.byte(offset, at)?;
+        let low = self.byte(offset, at + 1)?;
+        Ok(u16::from_be_bytes([high, low]) as usize)
+    }
+
+    fn constant(&self, offset: usize) -> Result<&Constant> {
+        let index = self.u16(offset, offset + 1)?;
+        self.function
+            .chunk
+            .constants
+            .get(index)
+            .ok_or_else(|| self.error(offset, format!("constant {index} does not exist")))
+    }
+
+    // Check that every instruction is known and that its operands are within
+    // the code and refer to constants and upvalues of the right kind, and that
+    // jumps land on an instruction.
+    fn check_instructions(&self) -> Result<()> {
+        let mut starts = vec![false; self.code().len()];
+        let mut jumps = Vec::new();
+
+        let mut offset = 0;
+        while offset < self.code().len() {
+            starts[offset] = true;
+
+            let op = self.op(offset)?;
+            if matches!(op, OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop) {
+                jumps.push(offset);
+            }
+            offset = self.check_operands(offset, op)?;
+        }
+
+        for offset in jumps {
+            let target = self.jump_target(offset)?;
+            if !starts.get(target).copied().unwrap_or(false) {
+                return Err(self.error(
+                    offset,
+                    format!("jump to {target:04} is not the start of an instruction"),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Check the operands of the instruction at 'offset', returning the offset
+    // of the next instruction.
+    fn check_operands(&self, offset: usize, op: OpCode) -> Result<usize> {
+        match op {
+            OpCode::Constant => match self.constant(offset)? {
+                Constant::Function(_) => Err(self.error(offset, "expected a number or string")),
+                _ => Ok(offset + 3),
+            },
+            OpCode::GetGlobal
+            | OpCode::DefineGlobal
+            | OpCode::SetGlobal
+            | OpCode::GetSuper
+            | OpCode::Class
+            | OpCode::Method => match self.constant(offset)? {
+                Constant::String(_) => Ok(offset + 3),
+                _ => Err(self.error(offset, "expected a name")),
+            },
+            OpCode::GetProperty | OpCode::SetProperty | OpCode::Invoke => {
+                let Constant::String(_) = self.constant(offset)? else {
+                    return Err(self.error(offset, "expected a name"));
+                };
+                let cache = self.u16(offset, offset + 3)?;
+                if cache >= self.function.cache_count {
+                    return Err(self.error(offset, format!("inline cache {cache} does not exist")));
+                }
+
+                if op == OpCode::Invoke {
+                    self.byte(offset, offset + 5)?;
+                    Ok(offset + 6)
+                } else {
+                    Ok(offset + 5)
+                }
+            }
+            OpCode::GetUpvalue | OpCode::SetUpvalue => {
+                let index = self.byte(offset, offset + 1)? as usize;
+                if index >= self.function.upvalue_count {
+                    return Err(self.error(offset, format!("upvalue {index} does not exist")));
+                }
+                Ok(offset + 2)
+            }
+            // Local slots are checked against the depth of the stack.
+            OpCode::GetLocal | OpCode::SetLocal | OpCode::CloseLocal | OpCode::Call => {
+                self.byte(offset, offset + 1)?;
+                Ok(offset + 2)
+            }
+            OpCode::BuildList
+            | OpCode::BuildMap
+            | OpCode::Jump
+            | OpCode::JumpIfFalse
+            | OpCode::Loop => {
+                self.u16(offset, offset + 1)?;
+                Ok(offset + 3)
+            }
+            OpCode::Closure => {
+                let Constant::Function(function) = self.constant(offset)? else {
+                    return Err(self.error(offset, "expected a function"));
+                };
+
+                let mut next = offset + 3;
+                for _ in 0..function.upvalue_count {
+                    let is_local = self.byte(offset, next)?;
+                    let index = self.byte(offset, next + 1)? as usize;
+                    match is_local {
+                        0 if index >= self.function.upvalue_count => {
+                            return Err(
+                                self.error(offset, format!("upvalue {index} does not exist"))
+                            )
+                        }
+                        0 | 1 => (),
+                        _ => return Err(self.error(offset, "invalid upvalue")),
+                    }
+                    next += 2;
+                }
+                Ok(next)
+            }
+            OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::Pop
+            | OpCode::Equal
+            | OpCode::NotEqual
+            | OpCode::Greater
+            | OpCode::GreaterEqual
+            | OpCode::Less
+            | OpCode::LessEqual
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Not
+            | OpCode::Negate
+            | OpCode::Print
+            | OpCode::CloseUpvalue
+            | OpCode::Return
+            | OpCode::Inherit
+            | OpCode::GetIndex
+            | OpCode::SetIndex
+            | OpCode::GetSlice => Ok(offset + 1),
+        }
+    }
+
+    // The target of the jump at 'offset', whose operand has been checked.
+    fn jump_target(&self, offset: usize) -> Result<usize> {
+        let jump = self.u16(offset, offset + 1)?;
+        let target = if self.op(offset)? == OpCode::Loop {
+            (offset + 3).checked_sub(jump)
+        } else {
+            Some(offset + 3 + jump)
+        };
+        target.ok_or_else(|| self.error(offset, "jump is before the start of the code"))
+    }
+
+    // Follow every path through the function from its entry, where the stack
+    // holds the function and its arguments, checking that no instruction pops
+    // or reads a local slot below the function's frame and that the code does
+    // not run past its end.
+    fn check_stack(&self) -> Result<()> {
+        let mut depths: Vec<Option<usize>> = vec![None; self.code().len()];
+        let mut worklist = vec![(0, self.function.arity + 1)];
+
+        while let Some((offset, depth)) = worklist.pop() {
+            match depths.get(offset) {
+                None => return Err(self.error(offset, "execution runs past the end of the code")),
+                Some(&Some(seen)) if seen == depth => continue,
+                Some(&Some(seen)) => {
+                    return Err(self.error(
+                        offset,
+                        format!("stack depth is {seen} on one path and {depth} on another"),
+                    ))
+                }
+                Some(None) => depths[offset] = Some(depth),
+            }
+
+            let op = self.op(offset)?;
+            let (pops, pushes) = match op {
+                OpCode::Constant
+                | OpCode::Nil
+                | OpCode::True
+                | OpCode::False
+                | OpCode::GetGlobal
+                | OpCode::GetUpvalue
+                | OpCode::Class => (0, 1),
+                OpCode::GetLocal => {
+                    self.check_slot(offset, depth, self.code()[offset + 1])?;
+                    (0, 1)
+                }
+                OpCode::Closure => {
+                    if let Constant::Function(function) = self.constant(offset)? {
+                        for upvalue in 0..function.upvalue_count {
+                            let at = offset + 3 + upvalue * 2;
+                            if self.code()[at] == 1 {
+                                self.check_slot(offset, depth, self.code()[at + 1])?;
+                            }
+                        }
+                    }
+                    (0, 1)
+                }
+                OpCode::Pop | OpCode::DefineGlobal | OpCode::Print | OpCode::CloseUpvalue => (1, 0),
+                OpCode::SetLocal => {
+                    self.check_slot(offset, depth, self.code()[offset + 1])?;
+                    (1, 1)
+                }
+                OpCode::CloseLocal => {
+                    self.check_slot(offset, depth, self.code()[offset + 1])?;
+                    (0, 0)
+                }
+                OpCode::SetGlobal
+                | OpCode::SetUpvalue
+                | OpCode::GetProperty
+                | OpCode::Not
+                | OpCode::Negate
+                | OpCode::JumpIfFalse => (1, 1),
+                OpCode::SetProperty
+                | OpCode::GetSuper
+                | OpCode::Equal
+                | OpCode::NotEqual
+                | OpCode::Greater
+                | OpCode::GreaterEqual
+                | OpCode::Less
+                | OpCode::LessEqual
+                | OpCode::Add
+                | OpCode::Subtract
+                | OpCode::Multiply
+                | OpCode::Divide
+                | OpCode::Inherit
+                | OpCode::Method
+                | OpCode::GetIndex => (2, 1),
+                OpCode::SetIndex | OpCode::GetSlice => (3, 1),
+                OpCode::BuildList => (self.function.chunk.read_u16(offset + 1) as usize, 1),
+                OpCode::BuildMap => (2 * self.function.chunk.read_u16(offset + 1) as usize, 1),
+                OpCode::Call => (self.code()[offset + 1] as usize + 1, 1),
+                OpCode::Invoke => (self.code()[offset + 5] as usize + 1, 1),
+                OpCode::Jump | OpCode::Loop => (0, 0),
+                OpCode::Return => (1, 0),
+            };
+
+            if depth < pops {
+                return Err(self.error(
+                    offset,
+                    format!("{op:?} needs {pops} values but the stack holds {depth}"),
+                ));
+            }
+            let depth = depth - pops + pushes;
+
+            let next = self.check_operands(offset, op)?;
+            match op {
+                OpCode::Return => (),
+                OpCode::Jump | OpCode::Loop => worklist.push((self.jump_target(offset)?, depth)),
+                OpCode::JumpIfFalse => {
+                    worklist.push((self.jump_target(offset)?, depth));
+                    worklist.push((next, depth));
+                }
+                _ => worklist.push((next, depth)),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_slot(&self, offset: usize, depth: usize, slot: u8) -> Result<()> {
+        if slot as usize >= depth {
+            return Err(self.error(
+                offset,
+                format!("local slot {slot} is beyond the stack depth of {depth}"),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::Chunk;
+    use crate::compiler::compile;
+    use crate::lexer::lex;
+    use crate::parser::parse;
+    use crate::resolver::resolve;
+    use crate::symbol::Symbol;
+    use std::fs;
+    use std::path::Path;
+    use std::rc::Rc;
+
+    fn script(code: Vec<u8>, constants: Vec<Constant>) -> FunctionProto {
+        let positions = vec![(1, 1); code.len()];
+        FunctionProto {
+            name: Rc::from(""),
+            arity: 0,
+            upvalue_count: 0,
+            cache_count: 0,
+            chunk: Chunk {
+                code,
+                constants,
+                positions,
+            },
+        }
+    }
+
+    fn error(code: Vec<u8>, constants: Vec<Constant>) -> String {
+        verify(&script(code, constants)).unwrap_err().to_string()
+    }
+
+    const NIL: u8 = OpCode::Nil as u8;
+    const POP: u8 = OpCode::Pop as u8;
+    const RETURN: u8 = OpCode::Return as u8;
+
+    #[test]
+    fn accepts_compiled_programs() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+        for subdir in ["corpus", "disasm"] {
+            for entry in fs::read_dir(dir.join(subdir)).unwrap() {
+                let path = entry.unwrap().path();
+                if path.extension().is_none_or(|ext| ext != "lox") {
+                    continue;
+                }
+
+                let source = fs::read_to_string(&path).unwrap();
+                let Ok(tokens) = lex(&source) else { continue };
+                let Ok(statements) = parse(&tokens) else {
+                    continue;
+                };
+                if resolve(&statements).is_err() {
+                    continue;
+                }
+                let script = compile(&statements).unwrap();
+
+                assert!(
+                    verify(&script).is_ok(),
+                    "rejected {}: {}",
+                    path.display(),
+                    verify(&script).unwrap_err()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_bad_operands() {
+        assert!(error(vec![0xFF], vec![]).contains("unknown opcode 255"));
+        assert!(error(vec![OpCode::Constant as u8, 0], vec![]).contains("truncated"));
+        assert!(error(vec![OpCode::Constant as u8, 0, 1, RETURN], vec![])
+            .contains("constant 1 does not exist"));
+        assert!(error(
+            vec![OpCode::GetGlobal as u8, 0, 0, RETURN],
+            vec![Constant::Number(1.0)]
+        )
+        .contains("expected a name"));
+        assert!(error(vec![OpCode::GetUpvalue as u8, 0, RETURN], vec![])
+            .contains("upvalue 0 does not exist"));
+        assert!(error(
+            vec![NIL, OpCode::GetProperty as u8, 0, 0, 0, 0, RETURN],
+            vec![Constant::String(Symbol::intern("field"))]
+        )
+        .contains("inline cache 0 does not exist"));
+    }
+
+    #[test]
+    fn rejects_bad_jumps() {
+        let jump = OpCode::Jump as u8;
+        assert!(error(vec![jump, 0, 9, NIL, RETURN], vec![]).contains("jump to 0012"));
+        assert!(
+            error(vec![jump, 0, 1, OpCode::GetLocal as u8, 0, RETURN], vec![])
+                .contains("not the start")
+        );
+        assert!(
+            error(vec![OpCode::Loop as u8, 0, 9, NIL, RETURN], vec![]).contains("before the start")
+        );
+    }
+
+    #[test]
+    fn rejects_stack_violations() {
+        assert!(error(vec![POP, POP, NIL, RETURN], vec![]).contains("Pop needs 1 values"));
+        assert!(error(vec![OpCode::GetLocal as u8, 1, RETURN], vec![])
+            .contains("local slot 1 is beyond the stack depth of 1"));
+        assert!(error(vec![NIL, POP], vec![]).contains("runs past the end"));
+        assert!(error(vec![OpCode::BuildList as u8, 0, 3, RETURN], vec![])
+            .contains("BuildList needs 3 values but the stack holds 1"));
+
+        // The branch that skips 'nil' reaches 'Return' with one value fewer.
+        let code = vec![
+            OpCode::True as u8,
+            OpCode::JumpIfFalse as u8,
+            0,
+            1,
+            NIL,
+            RETURN,
+        ];
+        assert!(error(code, vec![]).contains("stack depth is"));
+    }
+}
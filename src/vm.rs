@@ -0,0 +1,1956 @@
+use crate::chunk::{Constant, FunctionProto, OpCode};
+use crate::disassembler;
+use crate::heap::{
+    BoundMethod, Class, Closure, GcMode, GcStats, Heap, Instance, ListMethod, Map, MapMethod,
+    Native, ObjRef, Object, Sorted, SortedMethod, Unboxed, Upvalue, Value,
+};
+use crate::inline_cache::{InlineCache, Target};
+#[cfg(feature = "jit")]
+use crate::jit::{Jit, Outcome};
+use crate::list::{self, Method};
+use crate::map;
+use crate::native::{self, NativeCode};
+use crate::parser::DEFAULT_MAX_DEPTH;
+use crate::result::{Error, Result, TraceFrame};
+use crate::sorted;
+use crate::symbol::{self, Symbol};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// Stack-based virtual machine executing functions compiled by
+// 'compiler::compile'. Global bindings persist across calls to 'interpret'.
+pub struct Vm {
+    heap: Heap,
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    globals: HashMap<Symbol, Value>,
+    // Upvalues still referring to stack slots, in no particular order.
+    open_upvalues: Vec<ObjRef>,
+    output: Box<dyn Write>,
+    // Where to write the stack and each instruction as it is executed.
+    trace: Option<Box<dyn Write>>,
+    // Incremented whenever a method is added to a class, which invalidates
+    // the methods recorded in inline caches.
+    method_epoch: u32,
+    // The number of frames, including the script's, that can be active before
+    // a call reports a stack overflow.
+    max_frames: usize,
+    // Compiles hot functions to native code, if enabled.
+    #[cfg(feature = "jit")]
+    jit: Option<Jit>,
+    // The code compiled ahead of time for each function, keyed by the
+    // address of its prototype, which the entry holds on to.
+    native: HashMap<*const FunctionProto, (Rc<FunctionProto>, NativeCode)>,
+}
+
+// An active call. 'slots' is the index of the stack slot holding the callee,
+// which is followed by the arguments and locals.
+struct CallFrame {
+    closure: ObjRef,
+    function: Rc<FunctionProto>,
+    constants: Rc<[Value]>,
+    caches: Rc<[RefCell<InlineCache>]>,
+    ip: usize,
+    slots: usize,
+    // The number of frames this one replaced by being called in tail
+    // position, see 'Vm::elide_frame'.
+    tail_calls: usize,
+    native: Option<NativeCode>,
+}
+
+// What the VM does after executing an instruction: carry on with the next
+// one, carry on in the frame now on top after a call or return, or stop
+// because the frame 'run' was executing has returned.
+pub(crate) enum Flow {
+    Next,
+    Switch,
+    Exit,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Vm::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Vm {
+        Vm::with_output(Box::new(io::stdout()))
+    }
+
+    // Create a VM that writes the output of 'print' statements to 'output'.
+    pub fn with_output(output: Box<dyn Write>) -> Vm {
+        Vm::with_gc_mode(output, GcMode::MarkSweep)
+    }
+
+    // Create a VM whose heap is collected in the given 'mode'.
+    pub fn with_gc_mode(output: Box<dyn Write>, mode: GcMode) -> Vm {
+        let mut vm = Vm {
+            heap: Heap::with_mode(mode),
+            stack: Vec::new(),
+            frames: Vec::new(),
+            globals: HashMap::new(),
+            open_upvalues: Vec::new(),
+            output,
+            trace: None,
+            method_epoch: 0,
+            max_frames: DEFAULT_MAX_DEPTH,
+            #[cfg(feature = "jit")]
+            jit: None,
+            native: HashMap::new(),
+        };
+
+        let natives = [Native {
+            name: "clock",
+            arity: 0,
+            function: |_| {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_err(|e| e.to_string())?;
+                Ok(Value::from(now.as_secs_f64()))
+            },
+        }];
+
+        for native in natives {
+            let name = Symbol::intern(native.name);
+            let native = vm.heap.allocate(Object::Native(native));
+            vm.globals.insert(name, Value::from(native));
+        }
+        for kind in [sorted::Kind::Map, sorted::Kind::Set] {
+            let class = vm.heap.allocate(Object::SortedClass(kind));
+            vm.globals
+                .insert(Symbol::intern(kind.name()), Value::from(class));
+        }
+
+        vm
+    }
+
+    pub fn gc_stats(&self) -> &GcStats {
+        self.heap.stats()
+    }
+
+    // Collect garbage before every allocation rather than when the heap has
+    // grown, so that a value the VM forgot to root is freed while still in
+    // use.
+    pub fn set_gc_stress(&mut self, stress: bool) {
+        self.heap.set_stress(stress);
+    }
+
+    // Report a stack overflow once a call would nest more than 'max_depth'
+    // frames, counting the script's own frame as one of them.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_frames = max_depth;
+    }
+
+    // Write the value stack and the instruction about to be executed to
+    // 'trace' before each instruction.
+    pub fn set_trace(&mut self, trace: Box<dyn Write>) {
+        self.trace = Some(trace);
+    }
+
+    // Compile functions to native code once they have been called
+    // 'threshold' times. Returns false if the JIT doesn't support this
+    // machine, in which case everything is interpreted.
+    #[cfg(feature = "jit")]
+    pub fn enable_jit(&mut self, threshold: u32) -> bool {
+        self.jit = Jit::new(threshold);
+        self.jit.is_some()
+    }
+
+    // Run functions natively with the code compiled ahead of time for them,
+    // leaving the others to the interpreter.
+    //
+    // # Safety
+    //
+    // Each pointer must be code compiled by 'aot::build' for its function.
+    pub unsafe fn set_native_code(&mut self, code: Vec<(Rc<FunctionProto>, *const u8)>) {
+        self.native = code
+            .into_iter()
+            .map(|(proto, code)| {
+                let code = std::mem::transmute::<*const u8, NativeCode>(code);
+                (Rc::as_ptr(&proto), (proto, code))
+            })
+            .collect();
+    }
+
+    // The number of functions the JIT has compiled so far.
+    #[cfg(feature = "jit")]
+    pub fn compiled_functions(&self) -> usize {
+        self.jit.as_ref().map_or(0, Jit::compiled_functions)
+    }
+
+    // Run a compiled script, stopping at the first runtime error.
+    pub fn interpret(&mut self, script: Rc<FunctionProto>) -> Result<()> {
+        let function = self.heap.load_function(script);
+        let closure = self.allocate(Object::Closure(Closure {
+            function,
+            upvalues: Vec::new(),
+        }));
+        self.stack.push(Value::from(closure));
+
+        let result = self.call_closure(closure, 0).and_then(|_| self.run(0));
+        if result.is_err() {
+            self.stack.clear();
+            self.frames.clear();
+            self.open_upvalues.clear();
+        }
+
+        result
+    }
+
+    // Execute instructions until the frame above the first 'base' frames
+    // returns, leaving its result on the stack unless it was the script's.
+    fn run(&mut self, base: usize) -> Result<()> {
+        loop {
+            // Functions compiled ahead of time run natively until they make a
+            // call or return, unless each instruction is being traced.
+            if let (Some(code), None) = (self.frame().native, &self.trace) {
+                let ip = self.frame().ip;
+                match unsafe { native::call(self, code, base, ip) }? {
+                    Flow::Exit => return Ok(()),
+                    Flow::Next | Flow::Switch => continue,
+                }
+            }
+
+            loop {
+                if self.trace.is_some() {
+                    self.trace_instruction();
+                }
+
+                let byte = self.read_byte();
+                let Some(op) = OpCode::from_byte(byte) else {
+                    return Err(self.error(format!("unknown opcode {byte}")));
+                };
+
+                match self.execute(op, base)? {
+                    Flow::Next => {}
+                    Flow::Switch => break,
+                    Flow::Exit => return Ok(()),
+                }
+            }
+        }
+    }
+
+    // Execute 'op', whose operands follow the current frame's 'ip', in the
+    // frame above the first 'base' frames.
+    #[inline(always)]
+    pub(crate) fn execute(&mut self, op: OpCode, base: usize) -> Result<Flow> {
+        match op {
+            OpCode::Constant => {
+                let value = self.read_constant();
+                self.push(value);
+            }
+            OpCode::Nil => self.push(Value::NIL),
+            OpCode::True => self.push(Value::TRUE),
+            OpCode::False => self.push(Value::FALSE),
+            OpCode::Pop => {
+                self.pop();
+            }
+            OpCode::GetLocal => {
+                let slot = self.frame().slots + self.read_byte() as usize;
+                self.push(self.stack[slot]);
+            }
+            OpCode::SetLocal => {
+                let slot = self.frame().slots + self.read_byte() as usize;
+                self.stack[slot] = self.peek(0);
+            }
+            OpCode::GetGlobal => {
+                let name = self.read_string();
+                match self.globals.get(&name) {
+                    Some(&value) => self.push(value),
+                    None => return Err(self.error(format!("undefined variable '{name}'"))),
+                }
+            }
+            OpCode::DefineGlobal => {
+                let name = self.read_string();
+                let value = self.pop();
+                self.globals.insert(name, value);
+            }
+            OpCode::SetGlobal => {
+                let name = self.read_string();
+                let value = self.peek(0);
+                match self.globals.get_mut(&name) {
+                    Some(global) => *global = value,
+                    None => return Err(self.error(format!("undefined variable '{name}'"))),
+                }
+            }
+            OpCode::GetUpvalue => {
+                let upvalue = self.frame_upvalue();
+                let value = match *self.heap.upvalue(upvalue) {
+                    Upvalue::Open(slot) => self.stack[slot],
+                    Upvalue::Closed(value) => value,
+                };
+                self.push(value);
+            }
+            OpCode::SetUpvalue => {
+                let upvalue = self.frame_upvalue();
+                let value = self.peek(0);
+                match self.heap.upvalue_mut(upvalue) {
+                    Upvalue::Open(slot) => {
+                        let slot = *slot;
+                        self.stack[slot] = value;
+                    }
+                    Upvalue::Closed(closed) => {
+                        *closed = value;
+                        self.heap.write_barrier(upvalue, value);
+                    }
+                }
+            }
+            OpCode::GetProperty => {
+                let name = self.read_string();
+                let cache = self.read_u16() as usize;
+                if let Some(list) = self.heap.as_list(self.peek(0)) {
+                    let method = self.list_method(name)?;
+                    let bound = self.allocate(Object::ListMethod(ListMethod { list, method }));
+                    self.pop();
+                    self.push(Value::from(bound));
+                    return Ok(Flow::Next);
+                }
+                if let Some(map) = self.heap.as_map(self.peek(0)) {
+                    let method = self.map_method(name)?;
+                    let bound = self.allocate(Object::MapMethod(MapMethod { map, method }));
+                    self.pop();
+                    self.push(Value::from(bound));
+                    return Ok(Flow::Next);
+                }
+                if let Some(sorted) = self.heap.as_sorted(self.peek(0)) {
+                    let method = self.sorted_method(sorted, name)?;
+                    let bound =
+                        self.allocate(Object::SortedMethod(SortedMethod { sorted, method }));
+                    self.pop();
+                    self.push(Value::from(bound));
+                    return Ok(Flow::Next);
+                }
+                let Some(instance) = self.as_instance(self.peek(0)) else {
+                    return Err(self.error("only instances have properties".to_string()));
+                };
+
+                match self.find_property(instance, name, cache)? {
+                    Target::Field(slot) => {
+                        let value = self.heap.instance(instance).fields[slot];
+                        self.pop();
+                        self.push(value);
+                    }
+                    Target::Method { method, .. } => self.bind(method),
+                    Target::Transition(_) => unreachable!("not cached by 'GetProperty'"),
+                }
+            }
+            OpCode::SetProperty => {
+                let name = self.read_string();
+                let cache = self.read_u16() as usize;
+                let Some(instance) = self.as_instance(self.peek(1)) else {
+                    return Err(self.error("only instances have fields".to_string()));
+                };
+
+                let shape = self.heap.instance(instance).shape;
+                let cached = self.frame().caches[cache].borrow().lookup(shape);
+                let target = match cached {
+                    Some(target) => target,
+                    None => {
+                        let target = match self.heap.field_slot(shape, name) {
+                            Some(slot) => Target::Field(slot),
+                            None => Target::Transition(self.heap.add_field(shape, name)),
+                        };
+                        self.frame().caches[cache]
+                            .borrow_mut()
+                            .update(shape, target);
+                        target
+                    }
+                };
+
+                let value = self.pop();
+                let fields = self.heap.instance_mut(instance);
+                match target {
+                    Target::Field(slot) => fields.fields[slot] = value,
+                    Target::Transition(next) => {
+                        fields.shape = next;
+                        fields.fields.push(value);
+                        self.heap.resize(instance);
+                    }
+                    Target::Method { .. } => unreachable!("not cached by 'SetProperty'"),
+                }
+                self.heap.write_barrier(instance, value);
+                self.pop();
+                self.push(value);
+            }
+            OpCode::GetSuper => {
+                let name = self.read_string();
+                let Some(superclass) = self.pop().as_object() else {
+                    return Err(self.error("invalid use of 'super'".to_string()));
+                };
+                self.bind_method(superclass, name)?;
+            }
+            OpCode::Equal => {
+                let b = self.pop();
+                let a = self.pop();
+                self.push(Value::from(self.heap.values_equal(a, b)));
+            }
+            OpCode::NotEqual => {
+                let b = self.pop();
+                let a = self.pop();
+                self.push(Value::from(!self.heap.values_equal(a, b)));
+            }
+            OpCode::Greater => self.comparison(|a, b| a > b)?,
+            OpCode::GreaterEqual => self.comparison(|a, b| a >= b)?,
+            OpCode::Less => self.comparison(|a, b| a < b)?,
+            OpCode::LessEqual => self.comparison(|a, b| a <= b)?,
+            OpCode::Add => {
+                let b = self.pop();
+                let a = self.pop();
+                let value = match (a.unbox(), b.unbox()) {
+                    (Unboxed::Number(a), Unboxed::Number(b)) => Value::from(a + b),
+                    _ => match (self.heap.as_string(a), self.heap.as_string(b)) {
+                        (Some(a), Some(b)) => {
+                            let concatenated = format!("{a}{b}");
+                            Value::from(match self.heap.find_string(&concatenated) {
+                                Some(string) => string,
+                                None => self.allocate(Object::String(Rc::from(concatenated))),
+                            })
+                        }
+                        _ => {
+                            return Err(self
+                                .error("operands must be two numbers or two strings".to_string()))
+                        }
+                    },
+                };
+                self.push(value);
+            }
+            OpCode::Subtract => self.arithmetic(|a, b| a - b)?,
+            OpCode::Multiply => self.arithmetic(|a, b| a * b)?,
+            OpCode::Divide => self.arithmetic(|a, b| a / b)?,
+            OpCode::Not => {
+                let value = self.pop();
+                self.push(Value::from(!value.is_truthy()));
+            }
+            OpCode::Negate => match self.pop().as_number() {
+                Some(n) => self.push(Value::from(-n)),
+                None => return Err(self.error("operand must be a number".to_string())),
+            },
+            OpCode::Print => {
+                let value = self.pop();
+                writeln!(self.output, "{}", self.heap.display(value))
+                    .expect("failed to write output");
+            }
+            OpCode::Jump => {
+                let offset = self.read_u16() as usize;
+                self.frame_mut().ip += offset;
+            }
+            OpCode::JumpIfFalse => {
+                let offset = self.read_u16() as usize;
+                if !self.peek(0).is_truthy() {
+                    self.frame_mut().ip += offset;
+                }
+            }
+            OpCode::Loop => {
+                let offset = self.read_u16() as usize;
+                self.frame_mut().ip -= offset;
+            }
+            OpCode::Call => {
+                let argument_count = self.read_byte() as usize;
+                let (tail, frames) = (self.is_tail_call(), self.frames.len());
+                self.call_value(self.peek(argument_count), argument_count)?;
+                return Ok(self.entered(tail, frames));
+            }
+            OpCode::Invoke => {
+                let name = self.read_string();
+                let cache = self.read_u16() as usize;
+                // The argument count is positioned at the call, so it is
+                // only read once errors in the lookup have been reported.
+                let frame = self.frame();
+                let argument_count = frame.function.chunk.code[frame.ip] as usize;
+                if let Some(list) = self.heap.as_list(self.peek(argument_count)) {
+                    let method = self.list_method(name)?;
+                    self.read_byte();
+                    self.call_list_method(list, method, argument_count)?;
+                    return Ok(Flow::Next);
+                }
+                if let Some(map) = self.heap.as_map(self.peek(argument_count)) {
+                    let method = self.map_method(name)?;
+                    self.read_byte();
+                    self.call_map_method(map, method, argument_count)?;
+                    return Ok(Flow::Next);
+                }
+                if let Some(sorted) = self.heap.as_sorted(self.peek(argument_count)) {
+                    let method = self.sorted_method(sorted, name)?;
+                    self.read_byte();
+                    self.call_sorted_method(sorted, method, argument_count)?;
+                    return Ok(Flow::Next);
+                }
+                let Some(instance) = self.as_instance(self.peek(argument_count)) else {
+                    return Err(self.error("only instances have properties".to_string()));
+                };
+
+                let target = self.find_property(instance, name, cache)?;
+                self.read_byte();
+                let (tail, frames) = (self.is_tail_call(), self.frames.len());
+                match target {
+                    Target::Field(slot) => {
+                        let value = self.heap.instance(instance).fields[slot];
+                        let callee_slot = self.stack.len() - argument_count - 1;
+                        self.stack[callee_slot] = value;
+                        self.call_value(value, argument_count)?;
+                    }
+                    Target::Method { method, .. } => self.call_closure(method, argument_count)?,
+                    Target::Transition(_) => unreachable!("not cached by 'Invoke'"),
+                }
+                return Ok(self.entered(tail, frames));
+            }
+            OpCode::Closure => {
+                let Some(function) = self.read_constant().as_object() else {
+                    return Err(self.error("expected a function constant".to_string()));
+                };
+                let upvalue_count = self.heap.function(function).proto.upvalue_count;
+
+                let mut upvalues = Vec::with_capacity(upvalue_count);
+                for _ in 0..upvalue_count {
+                    let is_local = self.read_byte() == 1;
+                    let index = self.read_byte() as usize;
+                    let upvalue = if is_local {
+                        self.capture_upvalue(self.frame().slots + index)
+                    } else {
+                        self.heap.closure(self.frame().closure).upvalues[index]
+                    };
+                    upvalues.push(upvalue);
+                }
+
+                let closure = self.allocate(Object::Closure(Closure { function, upvalues }));
+                self.push(Value::from(closure));
+            }
+            OpCode::CloseUpvalue => {
+                self.close_upvalues(self.stack.len() - 1);
+                self.pop();
+            }
+            OpCode::CloseLocal => {
+                let slot = self.frame().slots + self.read_byte() as usize;
+                self.close_upvalues_where(|s| s == slot);
+            }
+            OpCode::Return => {
+                let result = self.pop();
+                let frame = self.frames.pop().expect("call frame");
+                self.close_upvalues(frame.slots);
+                self.stack.truncate(frame.slots);
+
+                if self.frames.is_empty() {
+                    return Ok(Flow::Exit);
+                }
+                self.push(result);
+                if self.frames.len() == base {
+                    return Ok(Flow::Exit);
+                }
+                return Ok(Flow::Switch);
+            }
+            OpCode::Class => {
+                let name = self.read_string();
+                let shape = self.heap.new_shape();
+                let class = self.allocate(Object::Class(Class {
+                    name,
+                    methods: HashMap::new(),
+                    shape,
+                }));
+                self.push(Value::from(class));
+            }
+            OpCode::Inherit => {
+                let Some(superclass) = self.as_class(self.peek(1)) else {
+                    return Err(self.error("superclass must be a class".to_string()));
+                };
+                let Some(subclass) = self.as_class(self.peek(0)) else {
+                    return Err(self.error("expected a class".to_string()));
+                };
+
+                let methods = self.heap.class(superclass).methods.clone();
+                for &method in methods.values() {
+                    self.heap.write_barrier(subclass, Value::from(method));
+                }
+                self.heap.class_mut(subclass).methods.extend(methods);
+                self.heap.resize(subclass);
+                self.method_epoch = self.method_epoch.wrapping_add(1);
+                self.pop();
+            }
+            OpCode::BuildList => {
+                let count = self.read_u16() as usize;
+                let elements = self.stack[self.stack.len() - count..].to_vec();
+                let list = self.allocate(Object::List(elements));
+                self.stack.truncate(self.stack.len() - count);
+                self.push(Value::from(list));
+            }
+            OpCode::GetIndex => {
+                if let Some(map) = self.heap.as_map(self.peek(1)) {
+                    let key = self.map_key(self.peek(0))?;
+                    let Some(&(_, value)) = self.heap.map(map).get(&key) else {
+                        return Err(self.error(map::KEY_NOT_FOUND.to_string()));
+                    };
+                    self.stack.truncate(self.stack.len() - 2);
+                    self.push(value);
+                    return Ok(Flow::Next);
+                }
+                if let Some(sorted) = self.indexed_sorted_map(1) {
+                    let key = self.sorted_key(self.peek(0))?;
+                    let sorted::Sorted::Map(map) = self.heap.sorted(sorted) else {
+                        unreachable!("only sorted maps are indexed");
+                    };
+                    let Some(&value) = map.get(&key) else {
+                        return Err(self.error(map::KEY_NOT_FOUND.to_string()));
+                    };
+                    self.stack.truncate(self.stack.len() - 2);
+                    self.push(value);
+                    return Ok(Flow::Next);
+                }
+                let list = self.indexed_list(1)?;
+                let len = self.heap.list(list).len();
+                let index = self.list_index(self.peek(0), len, list::index)?;
+                let value = self.heap.list(list)[index];
+                self.stack.truncate(self.stack.len() - 2);
+                self.push(value);
+            }
+            OpCode::SetIndex => {
+                if let Some(map) = self.heap.as_map(self.peek(2)) {
+                    let key = self.map_key(self.peek(1))?;
+                    let value = self.peek(0);
+                    self.set_entry(map, key, self.peek(1), value);
+                    self.stack.truncate(self.stack.len() - 3);
+                    self.push(value);
+                    return Ok(Flow::Next);
+                }
+                if let Some(sorted) = self.indexed_sorted_map(2) {
+                    let key = self.sorted_key(self.peek(1))?;
+                    let value = self.peek(0);
+                    if let sorted::Sorted::Map(map) = self.heap.sorted_mut(sorted) {
+                        map.insert(key, value);
+                    }
+                    self.heap.resize(sorted);
+                    self.heap.write_barrier(sorted, value);
+                    self.stack.truncate(self.stack.len() - 3);
+                    self.push(value);
+                    return Ok(Flow::Next);
+                }
+                let list = self.indexed_list(2)?;
+                let len = self.heap.list(list).len();
+                let index = self.list_index(self.peek(1), len, list::index)?;
+                let value = self.peek(0);
+                self.heap.list_mut(list)[index] = value;
+                self.heap.write_barrier(list, value);
+                self.stack.truncate(self.stack.len() - 3);
+                self.push(value);
+            }
+            OpCode::GetSlice => {
+                let Some(list) = self.heap.as_list(self.peek(2)) else {
+                    return Err(self.error("can only slice lists".to_string()));
+                };
+                let bound = |bound: Value| match bound.unbox() {
+                    Unboxed::Nil => Ok(None),
+                    Unboxed::Number(bound) => Ok(Some(bound)),
+                    _ => Err(list::BOUND_NOT_INTEGER),
+                };
+                let range = bound(self.peek(1))
+                    .and_then(|start| {
+                        list::slice(start, bound(self.peek(0))?, self.heap.list(list).len())
+                    })
+                    .map_err(|message| self.error(message.to_string()))?;
+
+                let elements = self.heap.list(list)[range].to_vec();
+                let slice = self.allocate(Object::List(elements));
+                self.stack.truncate(self.stack.len() - 3);
+                self.push(Value::from(slice));
+            }
+            OpCode::BuildMap => {
+                let count = self.read_u16() as usize;
+                let start = self.stack.len() - 2 * count;
+                let map = self.allocate(Object::Map(Map::default()));
+                self.push(Value::from(map));
+                // The keys and values stay on the stack while keys are
+                // hashed, which may call 'hash' methods.
+                for entry in 0..count {
+                    let (key, value) = (
+                        self.stack[start + 2 * entry],
+                        self.stack[start + 2 * entry + 1],
+                    );
+                    let hashed = self.map_key(key)?;
+                    self.set_entry(map, hashed, key, value);
+                }
+                self.stack.truncate(start);
+                self.push(Value::from(map));
+            }
+            OpCode::Method => {
+                let name = self.read_string();
+                let (Some(method), Some(class)) =
+                    (self.peek(0).as_object(), self.as_class(self.peek(1)))
+                else {
+                    return Err(self.error("expected a class".to_string()));
+                };
+
+                self.heap.class_mut(class).methods.insert(name, method);
+                self.heap.resize(class);
+                self.heap.write_barrier(class, Value::from(method));
+                self.method_epoch = self.method_epoch.wrapping_add(1);
+                self.pop();
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    // Whether a call made from the frame that was on top of 'frames' frames
+    // entered a new frame, which replaces the caller if the call was in tail
+    // position.
+    fn entered(&mut self, tail: bool, frames: usize) -> Flow {
+        if self.frames.len() == frames {
+            return Flow::Next;
+        }
+        if tail {
+            self.elide_frame(frames - 1);
+        }
+        Flow::Switch
+    }
+
+    fn trace_instruction(&mut self) {
+        let mut text = String::from("          ");
+        for &value in &self.stack {
+            text.push_str(&format!("[ {} ]", self.heap.display(value)));
+        }
+        text.push('\n');
+
+        let frame = self.frame();
+        disassembler::disassemble_instruction(&frame.function.chunk, frame.ip, false, &mut text);
+
+        if let Some(trace) = &mut self.trace {
+            trace
+                .write_all(text.as_bytes())
+                .expect("failed to write trace");
+        }
+    }
+
+    // Allocate 'object', first collecting garbage if the heap has grown enough.
+    // Every allocation made while running goes through here so that the roots
+    // are known.
+    fn allocate(&mut self, object: Object) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage(&object);
+        }
+        self.heap.allocate(object)
+    }
+
+    // Free the objects that are unreachable from the roots: the stack, the
+    // closures of active calls, open upvalues and globals, along with the
+    // objects referred to by 'pending', which is about to be allocated.
+    //
+    // The compiler produces constants rather than heap objects, so it has no
+    // roots of its own. A compiled script is loaded into the heap by
+    // 'Heap::load_function', which never collects, and is rooted as soon as
+    // its closure is allocated.
+    fn collect_garbage(&mut self, pending: &Object) {
+        let started = Instant::now();
+        self.heap.begin_collection();
+
+        for &value in &self.stack {
+            self.heap.mark_value(value);
+        }
+        for frame in &self.frames {
+            self.heap.mark_object(frame.closure);
+        }
+        for &upvalue in &self.open_upvalues {
+            self.heap.mark_object(upvalue);
+        }
+        for &value in self.globals.values() {
+            self.heap.mark_value(value);
+        }
+        self.heap.mark_references(pending);
+
+        self.heap.collect(started);
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("call frame")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("call frame")
+    }
+
+    fn native_code(&self, proto: &Rc<FunctionProto>) -> Option<NativeCode> {
+        let (_, code) = self.native.get(&Rc::as_ptr(proto))?;
+        Some(*code)
+    }
+
+    // Continue the current frame at 'ip'.
+    pub(crate) fn jump_to(&mut self, ip: usize) {
+        self.frame_mut().ip = ip;
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frame_mut();
+        let byte = frame.function.chunk.code[frame.ip];
+        frame.ip += 1;
+        byte
+    }
+
+    fn read_u16(&mut self) -> u16 {
+        let frame = self.frame_mut();
+        let value = frame.function.chunk.read_u16(frame.ip);
+        frame.ip += 2;
+        value
+    }
+
+    fn read_constant(&mut self) -> Value {
+        let index = self.read_u16() as usize;
+        self.frame().constants[index]
+    }
+
+    // Names are read from the compiled constants rather than the heap, so
+    // looking them up hashes a symbol rather than a string.
+    fn read_string(&mut self) -> Symbol {
+        let index = self.read_u16() as usize;
+        match self.frame().function.chunk.constants[index] {
+            Constant::String(name) => name,
+            _ => panic!("expected a string constant"),
+        }
+    }
+
+    // The upvalue of the current closure named by the next operand.
+    fn frame_upvalue(&mut self) -> ObjRef {
+        let index = self.read_byte() as usize;
+        self.heap.closure(self.frame().closure).upvalues[index]
+    }
+
+    fn push(&mut self, value: Value) {
+        self.stack.push(value);
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("stack underflow")
+    }
+
+    pub(crate) fn peek(&self, distance: usize) -> Value {
+        self.stack[self.stack.len() - 1 - distance]
+    }
+
+    // A runtime error at the instruction being executed. Every byte of an
+    // instruction shares its position, so the last byte read identifies it.
+    fn error(&self, message: String) -> Error {
+        let source_position = match self.frames.last() {
+            Some(frame) => frame.function.chunk.positions[frame.ip.saturating_sub(1)],
+            None => (0, 0),
+        };
+
+        Error::RuntimeError {
+            message,
+            source_position,
+            trace: self.trace(),
+        }
+    }
+
+    // The active frames, innermost first, each at the instruction it is
+    // running.
+    fn trace(&self) -> Vec<TraceFrame> {
+        self.frames
+            .iter()
+            .rev()
+            .map(|frame| TraceFrame {
+                function: (!frame.function.name.is_empty())
+                    .then(|| frame.function.name.to_string()),
+                file: None,
+                line: frame.function.chunk.positions[frame.ip.saturating_sub(1)].0,
+                tail_calls: frame.tail_calls,
+            })
+            .collect()
+    }
+
+    fn number_operands(&mut self) -> Result<(f64, f64)> {
+        match (self.peek(1).as_number(), self.peek(0).as_number()) {
+            (Some(a), Some(b)) => {
+                self.stack.truncate(self.stack.len() - 2);
+                Ok((a, b))
+            }
+            _ => Err(self.error("operands must be numbers".to_string())),
+        }
+    }
+
+    fn arithmetic(&mut self, op: fn(f64, f64) -> f64) -> Result<()> {
+        let (a, b) = self.number_operands()?;
+        self.push(Value::from(op(a, b)));
+        Ok(())
+    }
+
+    fn comparison(&mut self, op: fn(f64, f64) -> bool) -> Result<()> {
+        let (a, b) = self.number_operands()?;
+        self.push(Value::from(op(a, b)));
+        Ok(())
+    }
+
+    fn as_instance(&self, value: Value) -> Option<ObjRef> {
+        let reference = value.as_object()?;
+        matches!(self.heap.get(reference), Object::Instance(_)).then_some(reference)
+    }
+
+    fn as_class(&self, value: Value) -> Option<ObjRef> {
+        let reference = value.as_object()?;
+        matches!(self.heap.get(reference), Object::Class(_)).then_some(reference)
+    }
+
+    // Find the property 'name' of 'instance', a field if it has one or
+    // otherwise a method of its class, using and updating the inline cache
+    // 'cache' of the current function.
+    fn find_property(&mut self, instance: ObjRef, name: Symbol, cache: usize) -> Result<Target> {
+        let Instance { class, shape, .. } = *self.heap.instance(instance);
+        match self.frame().caches[cache].borrow().lookup(shape) {
+            Some(Target::Method { epoch, .. }) if epoch != self.method_epoch => (),
+            Some(target) => return Ok(target),
+            None => (),
+        }
+
+        let target = match self.heap.field_slot(shape, name) {
+            Some(slot) => Target::Field(slot),
+            None => match self.heap.class(class).methods.get(&name) {
+                Some(&method) => Target::Method {
+                    method,
+                    epoch: self.method_epoch,
+                },
+                None => return Err(self.error(format!("undefined property '{name}'"))),
+            },
+        };
+        self.frame().caches[cache]
+            .borrow_mut()
+            .update(shape, target);
+        Ok(target)
+    }
+
+    // Replace the receiver on top of the stack with its method 'name' from
+    // 'class', bound to the receiver.
+    fn bind_method(&mut self, class: ObjRef, name: Symbol) -> Result<()> {
+        let Some(&method) = self.heap.class(class).methods.get(&name) else {
+            return Err(self.error(format!("undefined property '{name}'")));
+        };
+
+        self.bind(method);
+        Ok(())
+    }
+
+    // Replace the receiver on top of the stack with 'method' bound to it.
+    fn bind(&mut self, method: ObjRef) {
+        let receiver = self.pop();
+        let bound = self.allocate(Object::BoundMethod(BoundMethod { receiver, method }));
+        self.push(Value::from(bound));
+    }
+
+    fn call_value(&mut self, callee: Value, argument_count: usize) -> Result<()> {
+        let Some(reference) = callee.as_object() else {
+            return Err(self.error("can only call functions and classes".to_string()));
+        };
+
+        let callee_slot = self.stack.len() - argument_count - 1;
+        match self.heap.get(reference) {
+            Object::Closure(_) => self.call_closure(reference, argument_count),
+            Object::BoundMethod(bound) => {
+                let method = bound.method;
+                self.stack[callee_slot] = bound.receiver;
+                self.call_closure(method, argument_count)
+            }
+            Object::Class(class) => {
+                let initializer = class.methods.get(&symbol::INIT).copied();
+                let shape = class.shape;
+                let instance = self.allocate(Object::Instance(Instance {
+                    class: reference,
+                    shape,
+                    fields: Vec::new(),
+                }));
+                self.stack[callee_slot] = Value::from(instance);
+
+                match initializer {
+                    Some(initializer) => self.call_closure(initializer, argument_count),
+                    None if argument_count != 0 => {
+                        Err(self.error(format!("expected 0 arguments but got {argument_count}")))
+                    }
+                    None => Ok(()),
+                }
+            }
+            Object::ListMethod(bound) => {
+                let (list, method) = (bound.list, bound.method);
+                self.call_list_method(list, method, argument_count)
+            }
+            Object::MapMethod(bound) => {
+                let (map, method) = (bound.map, bound.method);
+                self.call_map_method(map, method, argument_count)
+            }
+            Object::SortedMethod(bound) => {
+                let (sorted, method) = (bound.sorted, bound.method);
+                self.call_sorted_method(sorted, method, argument_count)
+            }
+            &Object::SortedClass(kind) => {
+                if argument_count != 0 {
+                    return Err(
+                        self.error(format!("expected 0 arguments but got {argument_count}"))
+                    );
+                }
+                let sorted = self.allocate(Object::Sorted(Sorted::new(kind)));
+                self.stack.truncate(callee_slot);
+                self.push(Value::from(sorted));
+                Ok(())
+            }
+            Object::Native(native) => {
+                let (arity, function) = (native.arity, native.function);
+                if argument_count != arity {
+                    return Err(self.error(format!(
+                        "expected {arity} arguments but got {argument_count}"
+                    )));
+                }
+
+                let result = function(&self.stack[callee_slot + 1..])
+                    .map_err(|message| self.error(message))?;
+                self.stack.truncate(callee_slot);
+                self.push(result);
+                Ok(())
+            }
+            _ => Err(self.error("can only call functions and classes".to_string())),
+        }
+    }
+
+    // Call 'callee' with 'arguments' on behalf of a list method, running it
+    // to completion, and return its result. The call nests a level deeper
+    // even when the method was called in tail position.
+    fn call_back(&mut self, callee: Value, arguments: &[Value]) -> Result<Value> {
+        if self.frames.len() >= self.max_frames {
+            return Err(self.error("stack overflow".to_string()));
+        }
+
+        self.push(callee);
+        self.stack.extend_from_slice(arguments);
+        let frames = self.frames.len();
+        self.call_value(callee, arguments.len())?;
+        if self.frames.len() > frames {
+            self.run(frames)?;
+        }
+        Ok(self.pop())
+    }
+
+    fn list_method(&self, name: Symbol) -> Result<Method> {
+        Method::from_name(name.as_str())
+            .ok_or_else(|| self.error(format!("undefined property '{name}'")))
+    }
+
+    fn map_method(&self, name: Symbol) -> Result<map::Method> {
+        map::Method::from_name(name.as_str())
+            .ok_or_else(|| self.error(format!("undefined property '{name}'")))
+    }
+
+    // The list 'distance' slots below the top of the stack, which an index
+    // instruction is applied to.
+    fn indexed_list(&self, distance: usize) -> Result<ObjRef> {
+        self.heap
+            .as_list(self.peek(distance))
+            .ok_or_else(|| self.error("can only index lists and maps".to_string()))
+    }
+
+    fn sorted_method(&self, sorted: ObjRef, name: Symbol) -> Result<sorted::Method> {
+        sorted::Method::from_name(self.heap.sorted(sorted).kind(), name.as_str())
+            .ok_or_else(|| self.error(format!("undefined property '{name}'")))
+    }
+
+    // The sorted map 'distance' slots below the top of the stack, if that's
+    // what an index instruction is applied to. Sorted sets can't be indexed.
+    fn indexed_sorted_map(&self, distance: usize) -> Option<ObjRef> {
+        let sorted = self.heap.as_sorted(self.peek(distance))?;
+        (self.heap.sorted(sorted).kind() == sorted::Kind::Map).then_some(sorted)
+    }
+
+    fn sorted_key(&self, value: Value) -> Result<sorted::Key<Rc<str>>> {
+        let key = match value.unbox() {
+            Unboxed::Number(number) => sorted::Key::number(number),
+            Unboxed::Object(reference) => match self.heap.get(reference) {
+                Object::String(string) => Some(sorted::Key::String(Rc::clone(string))),
+                _ => None,
+            },
+            _ => None,
+        };
+        key.ok_or_else(|| self.error(sorted::NOT_A_KEY.to_string()))
+    }
+
+    // A bound of 'range', where 'nil' leaves that end of the range open.
+    fn range_bound(&self, bound: Value) -> Result<Option<sorted::Key<Rc<str>>>> {
+        match bound.unbox() {
+            Unboxed::Nil => Ok(None),
+            _ => self.sorted_key(bound).map(Some),
+        }
+    }
+
+    // Call 'method' on 'sorted' with the arguments on top of the stack,
+    // replacing the callee and arguments with the result.
+    fn call_sorted_method(
+        &mut self,
+        sorted: ObjRef,
+        method: sorted::Method,
+        argument_count: usize,
+    ) -> Result<()> {
+        let arity = method.arity();
+        if argument_count != arity {
+            return Err(self.error(format!(
+                "expected {arity} arguments but got {argument_count}"
+            )));
+        }
+
+        let callee_slot = self.stack.len() - argument_count - 1;
+        let argument = |vm: &Vm, index: usize| vm.stack[callee_slot + 1 + index];
+        let result = match method {
+            sorted::Method::Add => {
+                let key = self.sorted_key(argument(self, 0))?;
+                let added = match self.heap.sorted_mut(sorted) {
+                    sorted::Sorted::Set(set) => set.insert(key),
+                    sorted::Sorted::Map(_) => unreachable!("maps have no 'add' method"),
+                };
+                self.heap.resize(sorted);
+                Value::from(added)
+            }
+            sorted::Method::Has => {
+                let key = self.sorted_key(argument(self, 0))?;
+                Value::from(self.heap.sorted(sorted).contains(&key))
+            }
+            sorted::Method::Remove => {
+                let key = self.sorted_key(argument(self, 0))?;
+                let removed = match self.heap.sorted_mut(sorted) {
+                    sorted::Sorted::Map(map) => map.remove(&key).ok_or(map::KEY_NOT_FOUND),
+                    sorted::Sorted::Set(set) => Ok(Value::from(set.remove(&key))),
+                };
+                self.heap.resize(sorted);
+                removed.map_err(|message| self.error(message.to_string()))?
+            }
+            sorted::Method::Keys => {
+                let keys: Vec<_> = self.heap.sorted(sorted).keys().cloned().collect();
+                self.key_list(&keys)
+            }
+            sorted::Method::Values => match self.heap.sorted(sorted) {
+                sorted::Sorted::Map(map) => {
+                    let values = map.values().copied().collect();
+                    Value::from(self.allocate(Object::List(values)))
+                }
+                sorted::Sorted::Set(set) => {
+                    let keys: Vec<_> = set.iter().cloned().collect();
+                    self.key_list(&keys)
+                }
+            },
+            sorted::Method::Len => Value::from(self.heap.sorted(sorted).len() as f64),
+            sorted::Method::Range => {
+                let start = self.range_bound(argument(self, 0))?;
+                let end = self.range_bound(argument(self, 1))?;
+                let keys: Vec<_> = self
+                    .heap
+                    .sorted(sorted)
+                    .range(start, end)
+                    .cloned()
+                    .collect();
+                self.key_list(&keys)
+            }
+        };
+
+        self.stack.truncate(callee_slot);
+        self.push(result);
+        Ok(())
+    }
+
+    // A list of the values of 'keys'. Interning a string never collects, and
+    // the list's elements are kept alive while it is allocated.
+    fn key_list(&mut self, keys: &[sorted::Key<Rc<str>>]) -> Value {
+        let elements = keys.iter().map(|key| self.heap.key_value(key)).collect();
+        Value::from(self.allocate(Object::List(elements)))
+    }
+
+    // The key 'value' is looked up by in a map. An instance is looked up by
+    // what its 'hash' method returns, so 'value' must be kept alive while the
+    // method runs.
+    fn map_key(&mut self, value: Value) -> Result<map::Key<ObjRef>> {
+        let Some(instance) = self.as_instance(value) else {
+            return self
+                .hashable(value)
+                .ok_or_else(|| self.error(map::UNHASHABLE.to_string()));
+        };
+
+        let class = self.heap.instance(instance).class;
+        let Some(&method) = self.heap.class(class).methods.get(&symbol::HASH) else {
+            return Err(self.error(map::UNHASHABLE.to_string()));
+        };
+        let bound = self.allocate(Object::BoundMethod(BoundMethod {
+            receiver: value,
+            method,
+        }));
+        let hash = self.call_back(Value::from(bound), &[])?;
+        self.hashable(hash)
+            .ok_or_else(|| self.error(map::NOT_A_HASH.to_string()))
+    }
+
+    // The key of a value that is looked up by itself rather than by a 'hash'
+    // method.
+    fn hashable(&self, value: Value) -> Option<map::Key<ObjRef>> {
+        match value.unbox() {
+            Unboxed::Nil => Some(map::Key::Nil),
+            Unboxed::Bool(value) => Some(map::Key::Bool(value)),
+            Unboxed::Number(value) => Some(map::Key::number(value)),
+            Unboxed::Object(reference) => match self.heap.get(reference) {
+                Object::String(_) => Some(map::Key::String(reference)),
+                _ => None,
+            },
+        }
+    }
+
+    // Set the value of 'key' in 'map', keeping the key it was first inserted
+    // with if it is already there.
+    fn set_entry(&mut self, map: ObjRef, key: map::Key<ObjRef>, original: Value, value: Value) {
+        if let map::Key::String(string) = key {
+            self.heap.write_barrier(map, Value::from(string));
+        }
+        match self.heap.map_mut(map).get_mut(&key) {
+            Some(entry) => entry.1 = value,
+            None => {
+                self.heap.map_mut(map).insert(key, (original, value));
+                self.heap.resize(map);
+                self.heap.write_barrier(map, original);
+            }
+        }
+        self.heap.write_barrier(map, value);
+    }
+
+    // Call 'method' on 'map' with the arguments on top of the stack,
+    // replacing the callee and arguments with the result. Keys are hashed
+    // while the arguments are still on the stack.
+    fn call_map_method(
+        &mut self,
+        map: ObjRef,
+        method: map::Method,
+        argument_count: usize,
+    ) -> Result<()> {
+        let arity = method.arity();
+        if argument_count != arity {
+            return Err(self.error(format!(
+                "expected {arity} arguments but got {argument_count}"
+            )));
+        }
+
+        let callee_slot = self.stack.len() - argument_count - 1;
+        let result = match method {
+            map::Method::Has => {
+                let key = self.map_key(self.peek(0))?;
+                Value::from(self.heap.map(map).contains_key(&key))
+            }
+            map::Method::Remove => {
+                let key = self.map_key(self.peek(0))?;
+                let removed = self.heap.map_mut(map).remove(&key);
+                self.heap.resize(map);
+                match removed {
+                    Some((_, value)) => value,
+                    None => return Err(self.error(map::KEY_NOT_FOUND.to_string())),
+                }
+            }
+            map::Method::Keys => {
+                let keys = self.heap.map(map).values().map(|&(key, _)| key).collect();
+                Value::from(self.allocate(Object::List(keys)))
+            }
+            map::Method::Values => {
+                let values = self
+                    .heap
+                    .map(map)
+                    .values()
+                    .map(|&(_, value)| value)
+                    .collect();
+                Value::from(self.allocate(Object::List(values)))
+            }
+            map::Method::Len => Value::from(self.heap.map(map).len() as f64),
+        };
+
+        self.stack.truncate(callee_slot);
+        self.push(result);
+        Ok(())
+    }
+
+    // The position 'index' refers to in a list of 'len' elements, as
+    // 'position' counts it.
+    fn list_index(
+        &self,
+        index: Value,
+        len: usize,
+        position: fn(f64, usize) -> std::result::Result<usize, &'static str>,
+    ) -> Result<usize> {
+        index
+            .as_number()
+            .ok_or(list::INDEX_NOT_INTEGER)
+            .and_then(|index| position(index, len))
+            .map_err(|message| self.error(message.to_string()))
+    }
+
+    // Call 'method' on 'list' with the arguments on top of the stack,
+    // replacing the callee and arguments with the result.
+    //
+    // Methods taking a function call it on a copy of the elements, which is
+    // kept on the stack along with the list being built, so that the
+    // collector finds them while the function runs.
+    fn call_list_method(
+        &mut self,
+        list: ObjRef,
+        method: Method,
+        argument_count: usize,
+    ) -> Result<()> {
+        let arity = method.arity();
+        if argument_count != arity {
+            return Err(self.error(format!(
+                "expected {arity} arguments but got {argument_count}"
+            )));
+        }
+
+        let callee_slot = self.stack.len() - argument_count - 1;
+        let arguments = self.stack[callee_slot + 1..].to_vec();
+        let len = self.heap.list(list).len();
+        let result = match method {
+            Method::Push => {
+                self.heap.list_mut(list).push(arguments[0]);
+                self.heap.resize(list);
+                self.heap.write_barrier(list, arguments[0]);
+                Value::NIL
+            }
+            Method::Pop => {
+                let popped = self.heap.list_mut(list).pop();
+                self.heap.resize(list);
+                match popped {
+                    Some(value) => value,
+                    None => return Err(self.error(list::POP_EMPTY.to_string())),
+                }
+            }
+            Method::Insert => {
+                let index = self.list_index(arguments[0], len, list::insert_index)?;
+                self.heap.list_mut(list).insert(index, arguments[1]);
+                self.heap.resize(list);
+                self.heap.write_barrier(list, arguments[1]);
+                Value::NIL
+            }
+            Method::Remove => {
+                let index = self.list_index(arguments[0], len, list::index)?;
+                let removed = self.heap.list_mut(list).remove(index);
+                self.heap.resize(list);
+                removed
+            }
+            Method::Len => Value::from(len as f64),
+            Method::Map => {
+                let elements = self.snapshot(list);
+                let mapped = self.allocate(Object::List(Vec::with_capacity(len)));
+                self.push(Value::from(mapped));
+                for element in elements {
+                    let value = self.call_back(arguments[0], &[element])?;
+                    self.heap.list_mut(mapped).push(value);
+                    self.heap.resize(mapped);
+                    self.heap.write_barrier(mapped, value);
+                }
+                Value::from(mapped)
+            }
+            Method::Filter => {
+                let elements = self.snapshot(list);
+                let kept = self.allocate(Object::List(Vec::new()));
+                self.push(Value::from(kept));
+                for element in elements {
+                    if self.call_back(arguments[0], &[element])?.is_truthy() {
+                        self.heap.list_mut(kept).push(element);
+                        self.heap.resize(kept);
+                        self.heap.write_barrier(kept, element);
+                    }
+                }
+                Value::from(kept)
+            }
+            Method::Reduce => {
+                let elements = self.snapshot(list);
+                let mut accumulator = arguments[1];
+                for element in elements {
+                    accumulator = self.call_back(arguments[0], &[accumulator, element])?;
+                }
+                accumulator
+            }
+            Method::Sort => {
+                let mut elements = self.snapshot(list);
+                list::sort(&mut elements, |&a, &b| {
+                    match self.call_back(arguments[0], &[a, b])?.as_number() {
+                        Some(order) => Ok(order > 0.0),
+                        None => Err(self.error(list::NOT_A_COMPARISON.to_string())),
+                    }
+                })?;
+                for &element in &elements {
+                    self.heap.write_barrier(list, element);
+                }
+                *self.heap.list_mut(list) = elements;
+                Value::NIL
+            }
+        };
+
+        self.stack.truncate(callee_slot);
+        self.push(result);
+        Ok(())
+    }
+
+    // A copy of the elements of 'list', also pushed onto the stack as a list
+    // of its own to keep them alive.
+    fn snapshot(&mut self, list: ObjRef) -> Vec<Value> {
+        let elements = self.heap.list(list).clone();
+        let copy = self.allocate(Object::List(elements.clone()));
+        self.push(Value::from(copy));
+        elements
+    }
+
+    fn call_closure(&mut self, closure: ObjRef, argument_count: usize) -> Result<()> {
+        let arity = self
+            .heap
+            .function(self.heap.closure(closure).function)
+            .proto
+            .arity;
+        if argument_count != arity {
+            return Err(self.error(format!(
+                "expected {arity} arguments but got {argument_count}"
+            )));
+        }
+
+        // A call in tail position replaces the frame making it, so it doesn't
+        // nest any deeper.
+        if self.frames.len() >= self.max_frames && !self.is_tail_call() {
+            return Err(self.error("stack overflow".to_string()));
+        }
+
+        #[cfg(feature = "jit")]
+        if self.call_native(closure, argument_count) {
+            return Ok(());
+        }
+
+        let function = self.heap.function(self.heap.closure(closure).function);
+        let frame = CallFrame {
+            closure,
+            function: Rc::clone(&function.proto),
+            constants: Rc::clone(&function.constants),
+            caches: Rc::clone(&function.caches),
+            ip: 0,
+            slots: self.stack.len() - argument_count - 1,
+            tail_calls: 0,
+            native: self.native_code(&function.proto),
+        };
+        self.frames.push(frame);
+        Ok(())
+    }
+
+    // Whether the call just read returns its result straight away.
+    fn is_tail_call(&self) -> bool {
+        let frame = self.frame();
+        frame.function.chunk.code.get(frame.ip) == Some(&(OpCode::Return as u8))
+    }
+
+    // Remove the frame at 'index', whose call in tail position has pushed
+    // the frames above it, moving their slots down in place of its own. It
+    // would only have returned their result, so the call stack stays the
+    // same size however deep tail calls recurse.
+    fn elide_frame(&mut self, index: usize) {
+        let caller = self.frames.remove(index);
+        self.close_upvalues(caller.slots);
+
+        let start = self.frames[index].slots;
+        self.stack.drain(caller.slots..start);
+        for frame in &mut self.frames[index..] {
+            frame.slots -= start - caller.slots;
+        }
+        self.frames[index].tail_calls += caller.tail_calls + 1;
+    }
+
+    // Run a call in native code if the JIT has compiled the function,
+    // returning false if it should be interpreted instead. If the compiled
+    // code deoptimizes, the frames it was running are pushed for the
+    // interpreter to resume.
+    #[cfg(feature = "jit")]
+    fn call_native(&mut self, closure: ObjRef, argument_count: usize) -> bool {
+        let Some(jit) = self.jit.as_mut().filter(|_| self.trace.is_none()) else {
+            return false;
+        };
+
+        // Methods have their receiver in the callee slot, which compiled code
+        // has no way to use.
+        let slots = self.stack.len() - argument_count - 1;
+        if self.stack[slots].as_object() != Some(closure) {
+            return false;
+        }
+
+        let function = self.heap.function(self.heap.closure(closure).function);
+        let proto = Rc::clone(&function.proto);
+        let constants = Rc::clone(&function.constants);
+        let caches = Rc::clone(&function.caches);
+        let outcome = jit.call(
+            &self.heap,
+            &self.globals,
+            &proto,
+            Value::from(closure),
+            &self.stack[slots..],
+            self.max_frames.saturating_sub(self.frames.len()),
+        );
+        let Some(outcome) = outcome else {
+            return false;
+        };
+
+        self.stack.truncate(slots);
+        match outcome {
+            Outcome::Returned(value) => self.stack.push(value),
+            Outcome::Deoptimized(frames) => {
+                for frame in frames {
+                    self.frames.push(CallFrame {
+                        closure,
+                        function: Rc::clone(&proto),
+                        constants: Rc::clone(&constants),
+                        caches: Rc::clone(&caches),
+                        ip: frame.ip,
+                        slots: self.stack.len(),
+                        tail_calls: frame.tail_calls,
+                        native: self.native_code(&proto),
+                    });
+                    self.stack.extend(frame.values);
+                }
+            }
+        }
+        true
+    }
+
+    // The upvalue for the stack slot 'slot', reusing an open upvalue if the
+    // slot has already been captured.
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        let existing =
+            self.open_upvalues.iter().copied().find(
+                |&upvalue| matches!(self.heap.upvalue(upvalue), Upvalue::Open(s) if *s == slot),
+            );
+        if let Some(upvalue) = existing {
+            return upvalue;
+        }
+
+        let upvalue = self.allocate(Object::Upvalue(Upvalue::Open(slot)));
+        self.open_upvalues.push(upvalue);
+        upvalue
+    }
+
+    // Close every open upvalue referring to 'first_slot' or above, moving the
+    // value off the stack.
+    fn close_upvalues(&mut self, first_slot: usize) {
+        self.close_upvalues_where(|slot| slot >= first_slot);
+    }
+
+    // Close the open upvalues referring to the stack slots for which 'closes'
+    // holds.
+    fn close_upvalues_where(&mut self, closes: impl Fn(usize) -> bool) {
+        let heap = &mut self.heap;
+        let stack = &self.stack;
+
+        self.open_upvalues
+            .retain(|&upvalue| match *heap.upvalue(upvalue) {
+                Upvalue::Open(slot) if closes(slot) => {
+                    *heap.upvalue_mut(upvalue) = Upvalue::Closed(stack[slot]);
+                    heap.write_barrier(upvalue, stack[slot]);
+                    false
+                }
+                _ => true,
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::lexer::lex;
+    use crate::parser::parse;
+    use crate::resolver::resolve;
+    use crate::testing::Output;
+
+    fn run(source: &str) -> (String, Result<()>) {
+        let output = Output::default();
+        let mut vm = Vm::with_output(Box::new(output.clone()));
+
+        let result = lex(source)
+            .and_then(|tokens| parse(&tokens))
+            .and_then(|statements| {
+                resolve(&statements)?;
+                compile(&statements)
+            })
+            .and_then(|script| vm.interpret(script));
+
+        let printed = output.contents();
+        (printed, result)
+    }
+
+    fn output(source: &str) -> String {
+        let (printed, result) = run(source);
+        result.unwrap();
+        printed
+    }
+
+    #[test]
+    fn arithmetic_and_strings() {
+        assert_eq!(output("print 1 + 2 * 3;"), "7\n");
+        assert_eq!(output("print (1, 2) >= 2 ? \"a\" + \"b\" : nil;"), "ab\n");
+        assert_eq!(
+            output("print \"a\" == \"a\"; print nil != false;"),
+            "true\ntrue\n"
+        );
+    }
+
+    #[test]
+    fn control_flow() {
+        assert_eq!(
+            output("var s = 0; for (var i = 0; i < 5; i = i + 1) if (i != 2) s = s + i; print s;"),
+            "8\n"
+        );
+        assert_eq!(
+            output("print nil or \"x\"; print 1 and false;"),
+            "x\nfalse\n"
+        );
+    }
+
+    #[test]
+    fn closures() {
+        assert_eq!(
+            output(
+                "fun counter() { var i = 0; fun next() { i = i + 1; return i; } return next; }
+                 var c = counter(); c(); print c();
+                 var fs; { var a = 1; fun f() { return a; } fs = f; a = 2; } print fs();"
+            ),
+            "2\n2\n"
+        );
+    }
+
+    #[test]
+    fn classes() {
+        assert_eq!(
+            output(
+                "class A { init(x) { this.x = x; } get() { return this.x; } }
+                 class B < A { get() { return super.get() * 2; } }
+                 var b = B(21); print b.get(); print b; print B; print b.get;"
+            ),
+            "42\nB instance\nB\n<fn get>\n"
+        );
+    }
+
+    #[test]
+    fn lists() {
+        assert_eq!(
+            output(
+                "var xs = [3, 1, 2]; xs[0] = xs[-1] + xs[1];
+                 fun twice(x) { return x * 2; } fun order(a, b) { return a - b; }
+                 var doubled = xs.map(twice); doubled.sort(order);
+                 print doubled; print doubled[1:]; print xs.pop(); print xs;
+                 var len = xs.len; print len(); xs.push(xs); print xs;"
+            ),
+            "[2, 4, 6]\n[4, 6]\n2\n[3, 1]\n2\n[3, 1, [...]]\n"
+        );
+
+        let (_, result) = run("var xs = [1];\nprint xs[\n1];");
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Runtime Error [ln: 2, col: 9]: list index out of range\n[line 2] in script"
+        );
+        let (_, result) = run("fun f(x) { return [].pop(); }\nprint [1].map(f);");
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .starts_with("Runtime Error [ln: 1, col: 26]: can't pop from an empty list"));
+    }
+
+    #[test]
+    fn maps() {
+        assert_eq!(
+            output(
+                "class K { init(k) { this.k = k; } hash() { return \"k\" + this.k; } }
+                 var m = {\"a\": 1, K(\"1\"): 2, -0: 3};
+                 m[0] = m[\"a\"] + m[\"k1\"];
+                 print m.remove(K(\"1\")); print m.has(\"k1\"); print m;
+                 var keys = m.keys; print keys(); print m.values().len();
+                 m[nil] = m; print m;"
+            ),
+            "2\nfalse\n{a: 1, -0: 3}\n[a, -0]\n2\n{a: 1, -0: 3, nil: {...}}\n"
+        );
+
+        let (_, result) = run("var m = {};\nprint m[\n1];");
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Runtime Error [ln: 2, col: 8]: key not found in map\n[line 2] in script"
+        );
+        let (_, result) = run("class A {}\nprint {A(): 1};");
+        assert!(result.unwrap_err().to_string().starts_with(
+            "Runtime Error [ln: 2, col: 7]: map keys must be nil, booleans, numbers, strings"
+        ));
+    }
+
+    #[test]
+    fn sorted() {
+        assert_eq!(
+            output(
+                "var m = SortedMap();
+                 m[\"b\"] = 1; m[3] = 2; m[-0] = 3; m[0] = 4;
+                 print m.remove(3); print m; print m.values();
+                 var s = SortedSet(); var add = s.add;
+                 print add(2) and add(1) and !add(2); print s.range(nil, 2);
+                 m[\"self\"] = m; print m; print SortedSet;"
+            ),
+            "2\n{0: 4, b: 1}\n[4, 1]\ntrue\n[1]\n{0: 4, b: 1, self: {...}}\nSortedSet\n"
+        );
+
+        let (_, result) = run("var s = SortedSet();\ns.add(\nnil);");
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Runtime Error [ln: 3, col: 4]: sorted keys must be strings or numbers other than NaN\n[line 3] in script"
+        );
+        let (_, result) = run("var s = SortedSet();\nprint s[1];");
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .starts_with("Runtime Error [ln: 2, col: 8]: can only index lists and maps"));
+    }
+
+    // One site sees instances of several shapes, more than its cache holds,
+    // and fields that shadow methods once they are added.
+    #[test]
+    fn inline_caches() {
+        assert_eq!(
+            output(
+                "class A { m() { return \"method\"; } }
+                 fun get(o) { return o.x; }
+                 fun call(o) { return o.m(); }
+                 var a = A(); a.x = 1;
+                 var b = A(); b.y = 0; b.x = 2;
+                 var c = A(); c.z = 0; c.y = 0; c.x = 3;
+                 class B { init() { this.x = 4; } }
+                 class C { init() { this.w = 0; this.x = 5; } }
+                 var s = 0;
+                 for (var i = 0; i < 3; i = i + 1) {
+                   s = s + get(a) + get(b) + get(c) + get(B()) + get(C());
+                 }
+                 print s;
+                 print call(a);
+                 fun field() { return \"field\"; }
+                 a.m = field;
+                 print call(a); print call(b);"
+            ),
+            "45\nmethod\nfield\nmethod\n"
+        );
+    }
+
+    #[test]
+    fn invoke_errors() {
+        let (printed, result) = run("class A {}\nvar a = A();\nprint a.missing(1);");
+        assert_eq!(printed, "");
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Runtime Error [ln: 3, col: 9]: undefined property 'missing'\n[line 3] in script"
+        );
+
+        let (_, result) = run("class A { m(x) {} }\nA().m(\n);");
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Runtime Error [ln: 3, col: 1]: expected 1 arguments but got 0\n[line 3] in script"
+        );
+    }
+
+    #[test]
+    fn runtime_errors() {
+        let (printed, result) = run("print 1;\nprint -\"a\";");
+        assert_eq!(printed, "1\n");
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Runtime Error [ln: 2, col: 7]: operand must be a number\n[line 2] in script"
+        );
+
+        let (_, result) = run("fun f(x) {\n  return -x;\n}\nfun g(n) {\n  if (n > 0) return g(n - 1);\n  return 1 + f(nil);\n}\ng(2);");
+        match result {
+            Err(Error::RuntimeError { trace, .. }) => assert_eq!(
+                trace,
+                [
+                    TraceFrame {
+                        function: Some("f".to_string()),
+                        file: None,
+                        line: 2,
+                        tail_calls: 0,
+                    },
+                    TraceFrame {
+                        function: Some("g".to_string()),
+                        file: None,
+                        line: 6,
+                        tail_calls: 2,
+                    },
+                    TraceFrame {
+                        function: None,
+                        file: None,
+                        line: 8,
+                        tail_calls: 0,
+                    },
+                ]
+            ),
+            _ => panic!("Expected RuntimeError"),
+        }
+
+        assert!(run("undefined;").1.is_err());
+        assert!(run("fun f(a) {} f();").1.is_err());
+        assert!(run("fun f() { f(); } f();").1.is_err());
+        assert!(run("var x = 1; x.y = 2;").1.is_err());
+    }
+
+    #[test]
+    fn trace() {
+        let trace = Output::default();
+        let mut vm = Vm::with_output(Box::new(Output::default()));
+        vm.set_trace(Box::new(trace.clone()));
+
+        let tokens = lex("print 1 + 2;").unwrap();
+        let statements = parse(&tokens).unwrap();
+        vm.interpret(compile(&statements).unwrap()).unwrap();
+
+        let trace = trace.contents();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines[0], "          [ <script> ]");
+        assert_eq!(lines[1], "0000    1 Constant            0 1");
+        assert_eq!(lines[4], "          [ <script> ][ 1 ][ 2 ]");
+        assert_eq!(lines[5], "0006    1 Add");
+    }
+
+    #[test]
+    fn collects_garbage() {
+        let source = "
+            class Node { init(parent) { this.parent = parent; this.child = nil; } }
+            fun cycle() {
+                var node = Node(nil);
+                node.child = Node(node);
+                fun f() { return f; }
+                return node.child.parent == node and f() == f;
+            }
+            var all = true;
+            for (var i = 0; i < 20000; i = i + 1) all = all and cycle();
+            print all;";
+
+        let tokens = lex(source).unwrap();
+        let statements = parse(&tokens).unwrap();
+        resolve(&statements).unwrap();
+        let script = compile(&statements).unwrap();
+
+        for mode in [GcMode::MarkSweep, GcMode::Generational] {
+            let mut vm = Vm::with_gc_mode(Box::new(Output::default()), mode);
+            vm.interpret(Rc::clone(&script)).unwrap();
+
+            // Each iteration allocates four objects, nearly all of which are
+            // garbage by the end.
+            assert!(vm.heap.len() < 20000, "{} objects live", vm.heap.len());
+
+            let stats = vm.gc_stats();
+            match mode {
+                GcMode::MarkSweep => assert_eq!(stats.young.collections, 0),
+                GcMode::Generational => assert!(stats.young.collections > stats.full.collections),
+            }
+            assert!(stats.young.bytes_freed + stats.full.bytes_freed > 0);
+        }
+    }
+
+    // Lists growing after they are allocated bring collections closer, and
+    // are counted as freed at their full size.
+    #[test]
+    fn collects_grown_objects() {
+        let source = "
+            for (var i = 0; i < 200; i = i + 1) {
+                var list = [];
+                for (var j = 0; j < 2000; j = j + 1) list.push(j);
+            }";
+
+        let tokens = lex(source).unwrap();
+        let statements = parse(&tokens).unwrap();
+        resolve(&statements).unwrap();
+        let script = compile(&statements).unwrap();
+        let pushed = 200 * 2000 * std::mem::size_of::<Value>();
+
+        for mode in [GcMode::MarkSweep, GcMode::Generational] {
+            let mut vm = Vm::with_gc_mode(Box::new(Output::default()), mode);
+            vm.interpret(Rc::clone(&script)).unwrap();
+
+            let stats = vm.gc_stats();
+            assert!(stats.full.collections + stats.young.collections > 1);
+            assert!(stats.young.bytes_freed + stats.full.bytes_freed > pushed / 2);
+        }
+    }
+
+    // Each run of a class declaration makes a class with shapes of its own,
+    // which are freed along with it.
+    #[test]
+    fn collects_shapes() {
+        let source = "
+            fun make(i) {
+                class A { init() { this.x = i; this.y = 2; this.z = 3; } }
+                return A().x;
+            }
+            for (var i = 0; i < 50000; i = i + 1) make(i);";
+
+        let tokens = lex(source).unwrap();
+        let statements = parse(&tokens).unwrap();
+        resolve(&statements).unwrap();
+        let script = compile(&statements).unwrap();
+
+        for mode in [GcMode::MarkSweep, GcMode::Generational] {
+            let mut vm = Vm::with_gc_mode(Box::new(Output::default()), mode);
+            vm.interpret(Rc::clone(&script)).unwrap();
+
+            // Four shapes are made per call, and only those of the classes
+            // made since the last collection are left.
+            assert!(vm.heap.shape_count() < 20000);
+        }
+    }
+
+    #[test]
+    fn gc_stress() {
+        let output = Output::default();
+        let mut vm = Vm::with_output(Box::new(output.clone()));
+        vm.set_gc_stress(true);
+
+        let tokens = lex("
+            fun make() { var s = \"a\"; fun add() { s = s + \"b\"; return s; } return add; }
+            class P { init(x) { this.x = x; } get() { return this.x; } }
+            var add = make(); add();
+            var p = P(add());
+            var get = p.get;
+            print get() + \"!\";")
+        .unwrap();
+        let statements = parse(&tokens).unwrap();
+        vm.interpret(compile(&statements).unwrap()).unwrap();
+
+        assert_eq!(output.contents(), "abb!\n");
+    }
+
+    // Under stress every object is old by the time the next is allocated, so
+    // each new value stored in an object is lost at the next allocation
+    // unless the store goes through the write barrier.
+    #[test]
+    fn write_barrier() {
+        let output = Output::default();
+        let mut vm = Vm::with_gc_mode(Box::new(output.clone()), GcMode::Generational);
+        vm.set_gc_stress(true);
+
+        let tokens = lex("
+            class Box {}
+            var box = Box();
+            box.field = \"a\" + \"b\";
+            var junk = \"c\" + \"d\";
+            fun outer() {
+                var captured;
+                fun get() { return captured; }
+                captured = \"e\" + \"f\";
+                return get;
+            }
+            var get = outer();
+            junk = \"i\" + \"j\";
+            print box.field + get();")
+        .unwrap();
+        let statements = parse(&tokens).unwrap();
+        resolve(&statements).unwrap();
+        vm.interpret(compile(&statements).unwrap()).unwrap();
+
+        assert_eq!(output.contents(), "abef\n");
+    }
+
+    #[test]
+    fn globals_persist() {
+        let mut vm = Vm::with_output(Box::new(Output::default()));
+        for source in ["var a = 1;", "a = a + 1;", "undefined;", "var b = a;"] {
+            let tokens = lex(source).unwrap();
+            let statements = parse(&tokens).unwrap();
+            let _ = vm.interpret(compile(&statements).unwrap());
+        }
+
+        assert!(
+            matches!(vm.globals.get(&Symbol::intern("b")), Some(value) if value.as_number() == Some(2.0))
+        );
+    }
+}